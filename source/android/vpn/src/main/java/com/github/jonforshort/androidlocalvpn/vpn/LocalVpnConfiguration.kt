@@ -32,9 +32,15 @@ import kotlinx.parcelize.Parcelize
 @Parcelize
 data class LocalVpnConfiguration(
     val allowedApps: List<PackageName>? = null,
-    val disallowedApps: List<PackageName>? = null
+    val disallowedApps: List<PackageName>? = null,
+    val relayAddress: RelayAddress? = null
 ) : Parcelable
 
 @JvmInline
 @Parcelize
-value class PackageName(val packageName: String) : Parcelable
\ No newline at end of file
+value class PackageName(val packageName: String) : Parcelable
+
+@Parcelize
+data class RelayAddress(val host: String, val port: Int) : Parcelable {
+    override fun toString() = if (host.contains(':')) "[$host]:$port" else "$host:$port"
+}
\ No newline at end of file
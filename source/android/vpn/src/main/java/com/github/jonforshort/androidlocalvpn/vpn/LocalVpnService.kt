@@ -78,9 +78,18 @@ internal class LocalVpnService : VpnService() {
 
     private lateinit var vpnInterface: ParcelFileDescriptor
 
+    private var isRelaying = false
+
+    private var isVpnStarted = false
+
     companion object {
         private const val VPN_ADDRESS = "10.0.0.2"
         private const val VPN_ROUTE = "0.0.0.0"
+        private const val VPN_ADDRESS_IPV6 = "fd00::2"
+        private const val VPN_ROUTE_IPV6 = "::"
+
+        // must match the default tun mtu of the native library.
+        private const val VPN_MTU = 1500
 
         internal const val INTENT_ACTION_START_VPN = "LocalVpnServiceStartVpn"
         internal const val INTENT_ACTION_STOP_VPN = "LocalVpnServiceStopVpn"
@@ -89,6 +98,18 @@ internal class LocalVpnService : VpnService() {
         init {
             System.loadLibrary("vpn")
         }
+
+        /**
+         * Returns the metrics of the native library in the prometheus text format.
+         */
+        @JvmStatic
+        internal external fun getMetrics(): String?
+
+        /**
+         * Receives a json record for every closed session, on a thread of the native library.
+         */
+        @Volatile
+        internal var connectionLogListener: ((String) -> Unit)? = null
     }
 
     override fun onStartCommand(intent: Intent?, flags: Int, startId: Int): Int {
@@ -105,7 +126,13 @@ internal class LocalVpnService : VpnService() {
     }
 
     private fun stopVpn() {
-        onStopVpn()
+        if (isRelaying) {
+            onStopRelay()
+            isRelaying = false
+        } else {
+            onStopVpn()
+            isVpnStarted = false
+        }
         stopForeground(STOP_FOREGROUND_REMOVE)
         stopSelf()
         closeVpnInterface()
@@ -121,14 +148,31 @@ internal class LocalVpnService : VpnService() {
 
     private fun startVpn(configuration: LocalVpnConfiguration?) {
         setUpVpnInterface(configuration)
+        if (isVpnStarted && configuration?.relayAddress == null) {
+            // re-established interfaces are handed over without dropping sessions.
+            onReplaceVpn(vpnInterface.detachFd())
+            return
+        }
         onCreateNative(this)
-        onStartVpn(vpnInterface.detachFd())
+        val relayAddress = configuration?.relayAddress
+        if (relayAddress == null) {
+            onStartVpn(vpnInterface.detachFd())
+            isVpnStarted = true
+        } else {
+            isRelaying = onStartRelay(vpnInterface.detachFd(), relayAddress.toString())
+            if (!isRelaying) {
+                e("failed to start relay to $relayAddress")
+            }
+        }
     }
 
     private fun setUpVpnInterface(configuration: LocalVpnConfiguration?) {
         val vpnServiceBuilder = super.Builder().apply {
             addAddress(VPN_ADDRESS, 32)
             addRoute(VPN_ROUTE, 0)
+            addAddress(VPN_ADDRESS_IPV6, 128)
+            addRoute(VPN_ROUTE_IPV6, 0)
+            setMtu(VPN_MTU)
         }
 
         configuration?.allowedApps?.forEach {
@@ -150,13 +194,31 @@ internal class LocalVpnService : VpnService() {
         onDestroyNative()
     }
 
+    override fun onTrimMemory(level: Int) {
+        super.onTrimMemory(level)
+        onTrimMemoryNative(level)
+    }
+
+    @Suppress("unused")
+    private fun onConnectionClosed(record: String) {
+        connectionLogListener?.invoke(record)
+    }
+
     private external fun onCreateNative(vpnService: VpnService)
 
     private external fun onDestroyNative()
 
     private external fun onStartVpn(fileDescriptor: Int)
 
+    private external fun onReplaceVpn(fileDescriptor: Int)
+
     private external fun onStopVpn()
+
+    private external fun onTrimMemoryNative(level: Int)
+
+    private external fun onStartRelay(fileDescriptor: Int, address: String): Boolean
+
+    private external fun onStopRelay()
 }
 
 private inline fun <reified T : Parcelable> Intent.getParcelableExtraCompat(key: String) = when {
@@ -0,0 +1,127 @@
+// This is free and unencumbered software released into the public domain.
+//
+// Anyone is free to copy, modify, publish, use, compile, sell, or
+// distribute this software, either in source code form or as a compiled
+// binary, for any purpose, commercial or non-commercial, and by any
+// means.
+//
+// In jurisdictions that recognize copyright laws, the author or authors
+// of this software dedicate any and all copyright interest in the
+// software to the public domain. We make this dedication for the benefit
+// of the public at large and to the detriment of our heirs and
+// successors. We intend this dedication to be an overt act of
+// relinquishment in perpetuity of all present and future rights to this
+// software under copyright law.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS BE LIABLE FOR ANY CLAIM, DAMAGES OR
+// OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE,
+// ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR
+// OTHER DEALINGS IN THE SOFTWARE.
+//
+// For more information, please refer to <https://unlicense.org>
+
+extern crate crossbeam;
+extern crate jni;
+
+use core::connection_log::ConnectionRecord;
+use crossbeam::channel::{unbounded, Receiver, Sender};
+use jni::objects::{GlobalRef, JObject, JValue};
+use jni::{JNIEnv, JavaVM};
+use std::sync::Mutex;
+use std::thread::JoinHandle;
+
+lazy_static! {
+    static ref CONNECTION_LOGGER: Mutex<Option<ConnectionLogger>> = Mutex::new(None);
+}
+
+//
+// Hands the records of closed sessions to the vpn service on a thread of its
+// own, attached to the java vm, so that the processor is never held up by java.
+// The thread has its own references as the socket protecting thread keeps the
+// shared jni state locked while running.
+//
+pub struct ConnectionLogger {
+    sender: Sender<String>,
+    thread_join_handle: Option<JoinHandle<()>>,
+}
+
+impl ConnectionLogger {
+    pub fn init(env: &JNIEnv, object: &JObject) {
+        let java_vm = env.get_java_vm().unwrap();
+        let object = env.new_global_ref(object).unwrap();
+        let (sender, receiver) = unbounded();
+        let thread_join_handle = std::thread::spawn(move || {
+            ConnectionLogger::deliver_records(java_vm, object, receiver);
+        });
+        *CONNECTION_LOGGER.lock().unwrap() = Some(ConnectionLogger {
+            sender,
+            thread_join_handle: Some(thread_join_handle),
+        });
+    }
+
+    pub fn release() {
+        let connection_logger = CONNECTION_LOGGER.lock().unwrap().take();
+        if let Some(mut connection_logger) = connection_logger {
+            let thread_join_handle = connection_logger.thread_join_handle.take();
+            //
+            // dropping the sender stops the thread once pending records are delivered.
+            //
+            drop(connection_logger);
+            if let Some(thread_join_handle) = thread_join_handle {
+                thread_join_handle.join().unwrap();
+            }
+        }
+    }
+
+    pub fn log(record: &ConnectionRecord) {
+        if let Some(connection_logger) = CONNECTION_LOGGER.lock().unwrap().as_ref() {
+            match serde_json::to_string(record) {
+                Ok(record) => {
+                    let _ = connection_logger.sender.send(record);
+                }
+                Err(error) => {
+                    log::error!("failed to serialize connection record, error={:?}", error);
+                }
+            }
+        }
+    }
+
+    fn deliver_records(java_vm: JavaVM, object: GlobalRef, receiver: Receiver<String>) {
+        log::trace!("connection logging thread is started");
+        match java_vm.attach_current_thread_permanently() {
+            Ok(mut jni_env) => {
+                for record in receiver {
+                    ConnectionLogger::deliver_record(&mut jni_env, &object, &record);
+                }
+            }
+            Err(error) => {
+                log::error!("failed to attach to current thread, error={:?}", error);
+            }
+        }
+        log::trace!("connection logging thread is stopping");
+    }
+
+    fn deliver_record(jni_env: &mut JNIEnv, object: &GlobalRef, record: &str) {
+        let record = match jni_env.new_string(record) {
+            Ok(record) => record,
+            Err(error) => {
+                log::error!("failed to create record string, error={:?}", error);
+                return;
+            }
+        };
+        let result = jni_env.call_method(
+            object,
+            "onConnectionClosed",
+            "(Ljava/lang/String;)V",
+            &[JValue::Object(&record)],
+        );
+        if let Err(error) = result {
+            log::error!("failed to deliver connection record, error={:?}", error);
+            let _ = jni_env.exception_clear();
+        }
+        let _ = jni_env.delete_local_ref(record);
+    }
+}
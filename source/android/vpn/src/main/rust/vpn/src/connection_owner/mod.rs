@@ -0,0 +1,189 @@
+// This is free and unencumbered software released into the public domain.
+//
+// Anyone is free to copy, modify, publish, use, compile, sell, or
+// distribute this software, either in source code form or as a compiled
+// binary, for any purpose, commercial or non-commercial, and by any
+// means.
+//
+// In jurisdictions that recognize copyright laws, the author or authors
+// of this software dedicate any and all copyright interest in the
+// software to the public domain. We make this dedication for the benefit
+// of the public at large and to the detriment of our heirs and
+// successors. We intend this dedication to be an overt act of
+// relinquishment in perpetuity of all present and future rights to this
+// software under copyright law.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS BE LIABLE FOR ANY CLAIM, DAMAGES OR
+// OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE,
+// ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR
+// OTHER DEALINGS IN THE SOFTWARE.
+//
+// For more information, please refer to <https://unlicense.org>
+
+extern crate jni;
+
+use core::config::Protocol;
+use core::process::ConnectionOwner;
+use jni::objects::{GlobalRef, JObject, JString, JValue};
+use jni::{JNIEnv, JavaVM};
+use std::net::{IpAddr, SocketAddr};
+
+const OWNER_UID_SIGNATURE: &str = "(ILjava/net/InetSocketAddress;Ljava/net/InetSocketAddress;)I";
+
+//
+// Asks the ConnectivityManager for the uids owning connections, available
+// from android 10 on, and the PackageManager for their packages. Lookups are
+// made on the thread looking up processes, which is attached to the java vm
+// on its first lookup; like the connection logger, it has references of its
+// own.
+//
+pub struct JniConnectionOwner {
+    java_vm: JavaVM,
+    connectivity_manager: GlobalRef,
+    package_manager: GlobalRef,
+}
+
+impl JniConnectionOwner {
+    pub fn new(env: &mut JNIEnv, context: &JObject) -> Option<JniConnectionOwner> {
+        match Self::try_new(env, context) {
+            Ok(connection_owner) => Some(connection_owner),
+            Err(error) => {
+                log::info!("connection owners are not available, error={:?}", error);
+                let _ = env.exception_clear();
+                None
+            }
+        }
+    }
+
+    fn try_new(env: &mut JNIEnv, context: &JObject) -> jni::errors::Result<JniConnectionOwner> {
+        env.get_method_id(
+            "android/net/ConnectivityManager",
+            "getConnectionOwnerUid",
+            OWNER_UID_SIGNATURE,
+        )?;
+        let name = env.new_string("connectivity")?;
+        let connectivity_manager = env
+            .call_method(
+                context,
+                "getSystemService",
+                "(Ljava/lang/String;)Ljava/lang/Object;",
+                &[JValue::Object(&name)],
+            )?
+            .l()?;
+        let package_manager = env
+            .call_method(
+                context,
+                "getPackageManager",
+                "()Landroid/content/pm/PackageManager;",
+                &[],
+            )?
+            .l()?;
+        Ok(JniConnectionOwner {
+            java_vm: env.get_java_vm()?,
+            connectivity_manager: env.new_global_ref(connectivity_manager)?,
+            package_manager: env.new_global_ref(package_manager)?,
+        })
+    }
+
+    fn attach(&self) -> Option<JNIEnv<'_>> {
+        match self.java_vm.attach_current_thread_permanently() {
+            Ok(env) => Some(env),
+            Err(error) => {
+                log::error!("failed to attach to current thread, error={:?}", error);
+                None
+            }
+        }
+    }
+
+    //
+    // The thread stays attached, so local references are released along with
+    // a frame of their own.
+    //
+    fn call<T>(
+        &self,
+        description: &str,
+        call: impl FnOnce(&mut JNIEnv) -> jni::errors::Result<T>,
+    ) -> Option<T> {
+        let mut env = self.attach()?;
+        match env.with_local_frame(8, call) {
+            Ok(value) => Some(value),
+            Err(error) => {
+                log::error!("failed to {}, error={:?}", description, error);
+                let _ = env.exception_clear();
+                None
+            }
+        }
+    }
+
+    fn socket_address<'local>(
+        env: &mut JNIEnv<'local>,
+        address: SocketAddr,
+    ) -> jni::errors::Result<JObject<'local>> {
+        let bytes = match address.ip() {
+            IpAddr::V4(ip) => ip.octets().to_vec(),
+            IpAddr::V6(ip) => ip.octets().to_vec(),
+        };
+        let bytes = env.byte_array_from_slice(&bytes)?;
+        let ip = env
+            .call_static_method(
+                "java/net/InetAddress",
+                "getByAddress",
+                "([B)Ljava/net/InetAddress;",
+                &[JValue::Object(&bytes)],
+            )?
+            .l()?;
+        env.new_object(
+            "java/net/InetSocketAddress",
+            "(Ljava/net/InetAddress;I)V",
+            &[JValue::Object(&ip), JValue::Int(i32::from(address.port()))],
+        )
+    }
+}
+
+impl ConnectionOwner for JniConnectionOwner {
+    fn owner_uid(&self, protocol: Protocol, local: SocketAddr, remote: SocketAddr) -> Option<u32> {
+        let protocol = match protocol {
+            Protocol::Tcp => libc::IPPROTO_TCP,
+            Protocol::Udp => libc::IPPROTO_UDP,
+        };
+        let uid = self.call("get connection owner", |env| {
+            let local = Self::socket_address(env, local)?;
+            let remote = Self::socket_address(env, remote)?;
+            env.call_method(
+                &self.connectivity_manager,
+                "getConnectionOwnerUid",
+                OWNER_UID_SIGNATURE,
+                &[
+                    JValue::Int(protocol),
+                    JValue::Object(&local),
+                    JValue::Object(&remote),
+                ],
+            )?
+            .i()
+        })?;
+        // Process.INVALID_UID when no app owns the connection.
+        u32::try_from(uid).ok()
+    }
+
+    fn package_name(&self, uid: u32) -> Option<String> {
+        self.call("get package name", |env| {
+            let name = env
+                .call_method(
+                    &self.package_manager,
+                    "getNameForUid",
+                    "(I)Ljava/lang/String;",
+                    &[JValue::Int(uid as i32)],
+                )?
+                .l()?;
+            if name.is_null() {
+                return Ok(None);
+            }
+            let name: String = env.get_string(&JString::from(name))?.into();
+            Ok(Some(name))
+        })
+        .flatten()
+    }
+}
@@ -62,7 +62,7 @@ impl Jni {
         *jni = None;
     }
 
-    pub fn new_context(&self) -> Option<JniContext> {
+    pub fn new_context(&self) -> Option<JniContext<'_>> {
         match self.java_vm.attach_current_thread_permanently() {
             Ok(jni_env) => match Jni::get_protect_method_id(unsafe { jni_env.unsafe_clone() }) {
                 Some(protect_method_id) => {
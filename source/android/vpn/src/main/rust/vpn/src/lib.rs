@@ -23,6 +23,9 @@
 //
 // For more information, please refer to <https://unlicense.org>
 
+mod connection_logger;
+mod connection_owner;
+
 #[macro_use]
 mod jni;
 
@@ -37,22 +40,30 @@ pub mod android {
     extern crate jni;
     extern crate log;
 
+    use crate::connection_logger::ConnectionLogger;
+    use crate::connection_owner::JniConnectionOwner;
     use crate::jni::Jni;
     use crate::socket_protector::SocketProtector;
 
     use android_logger::Config;
+    use core::metrics::METRICS;
+    use core::process::{AppProcessLookup, ProcessLookup};
+    use core::relay;
     use core::tun;
     use core::tun_callbacks;
-    use jni::objects::{JClass, JObject};
+    use jni::objects::{JClass, JObject, JString};
+    use jni::sys::{jboolean, jstring, JNI_FALSE, JNI_TRUE};
     use jni::JNIEnv;
+    use std::net::SocketAddr;
     use std::process;
+    use std::sync::Arc;
 
     /// # Safety
     ///
     /// This function should only be used in jni context.
     #[no_mangle]
     pub unsafe extern "C" fn Java_com_github_jonforshort_androidlocalvpn_vpn_LocalVpnService_onCreateNative(
-        env: JNIEnv,
+        mut env: JNIEnv,
         class: JClass,
         object: JObject,
     ) {
@@ -63,6 +74,11 @@ pub mod android {
         );
         log::trace!("onCreateNative");
         set_panic_handler();
+        ConnectionLogger::init(&env, &object);
+        tun_callbacks::set_connection_closed_callback(Some(ConnectionLogger::log));
+        let process_lookup = JniConnectionOwner::new(&mut env, &object)
+            .map(|owner| Arc::new(AppProcessLookup::new(owner)) as Arc<dyn ProcessLookup>);
+        tun_callbacks::set_process_lookup(process_lookup);
         Jni::init(env, class, object);
         SocketProtector::init();
         tun::create();
@@ -78,6 +94,9 @@ pub mod android {
     ) {
         log::trace!("onDestroyNative");
         tun::destroy();
+        tun_callbacks::set_connection_closed_callback(None);
+        tun_callbacks::set_process_lookup(None);
+        ConnectionLogger::release();
         SocketProtector::release();
         Jni::release();
         remove_panic_handler();
@@ -95,7 +114,28 @@ pub mod android {
         log::trace!("onStartVpn, pid={}, fd={}", process::id(), file_descriptor);
         tun_callbacks::set_socket_created_callback(Some(on_socket_created));
         socket_protector!().start();
-        tun::start(file_descriptor);
+        if let Err(error) = tun::start(file_descriptor) {
+            log::error!("failed to start vpn, error={:?}", error);
+        }
+    }
+
+    /// # Safety
+    ///
+    /// This function should only be used in jni context.
+    #[no_mangle]
+    pub unsafe extern "C" fn Java_com_github_jonforshort_androidlocalvpn_vpn_LocalVpnService_onReplaceVpn(
+        _: JNIEnv,
+        _: JClass,
+        file_descriptor: i32,
+    ) {
+        log::trace!(
+            "onReplaceVpn, pid={}, fd={}",
+            process::id(),
+            file_descriptor
+        );
+        if let Err(error) = tun::replace(file_descriptor) {
+            log::error!("failed to replace vpn, error={:?}", error);
+        }
     }
 
     /// # Safety
@@ -112,6 +152,93 @@ pub mod android {
         tun_callbacks::set_socket_created_callback(None);
     }
 
+    /// # Safety
+    ///
+    /// This function should only be used in jni context.
+    #[no_mangle]
+    pub unsafe extern "C" fn Java_com_github_jonforshort_androidlocalvpn_vpn_LocalVpnService_onTrimMemoryNative(
+        _: JNIEnv,
+        _: JClass,
+        level: i32,
+    ) {
+        log::trace!("onTrimMemoryNative, pid={}, level={}", process::id(), level);
+        tun::trim_memory();
+    }
+
+    /// # Safety
+    ///
+    /// This function should only be used in jni context.
+    #[no_mangle]
+    pub unsafe extern "C" fn Java_com_github_jonforshort_androidlocalvpn_vpn_LocalVpnService_getMetrics(
+        env: JNIEnv,
+        _: JClass,
+    ) -> jstring {
+        match env.new_string(METRICS.render()) {
+            Ok(metrics) => metrics.into_raw(),
+            Err(error) => {
+                log::error!("failed to create metrics string, error={:?}", error);
+                std::ptr::null_mut()
+            }
+        }
+    }
+
+    /// # Safety
+    ///
+    /// This function should only be used in jni context.
+    #[no_mangle]
+    pub unsafe extern "C" fn Java_com_github_jonforshort_androidlocalvpn_vpn_LocalVpnService_onStartRelay(
+        mut env: JNIEnv,
+        _: JClass,
+        file_descriptor: i32,
+        address: JString,
+    ) -> jboolean {
+        let address: String = match env.get_string(&address) {
+            Ok(address) => address.into(),
+            Err(error) => {
+                log::error!("failed to get relay address, error={:?}", error);
+                return JNI_FALSE;
+            }
+        };
+        log::trace!(
+            "onStartRelay, pid={}, fd={}, address={}",
+            process::id(),
+            file_descriptor,
+            address
+        );
+        let address: SocketAddr = match address.parse() {
+            Ok(address) => address,
+            Err(error) => {
+                log::error!("invalid relay address, error={:?}", error);
+                return JNI_FALSE;
+            }
+        };
+        tun_callbacks::set_socket_created_callback(Some(on_socket_created));
+        socket_protector!().start();
+        match relay::start(file_descriptor, address) {
+            Ok(_) => JNI_TRUE,
+            Err(error) => {
+                log::error!("failed to start relay, error={:?}", error);
+                socket_protector!().stop();
+                tun_callbacks::set_socket_created_callback(None);
+                JNI_FALSE
+            }
+        }
+    }
+
+    /// # Safety
+    ///
+    /// This function should only be used in jni context.
+    #[no_mangle]
+    pub unsafe extern "C" fn Java_com_github_jonforshort_androidlocalvpn_vpn_LocalVpnService_onStopRelay(
+        _: JNIEnv,
+        _: JClass,
+    ) {
+        log::trace!("onStopRelay, pid={}", process::id());
+        relay::stop();
+        socket_protector!().stop();
+        tun_callbacks::set_socket_created_callback(None);
+    }
+
     fn set_panic_handler() {
         std::panic::set_hook(Box::new(|panic_info| {
             log::error!("*** PANIC [{:?}]", panic_info);
@@ -0,0 +1,642 @@
+// This is free and unencumbered software released into the public domain.
+//
+// Anyone is free to copy, modify, publish, use, compile, sell, or
+// distribute this software, either in source code form or as a compiled
+// binary, for any purpose, commercial or non-commercial, and by any
+// means.
+//
+// In jurisdictions that recognize copyright laws, the author or authors
+// of this software dedicate any and all copyright interest in the
+// software to the public domain. We make this dedication for the benefit
+// of the public at large and to the detriment of our heirs and
+// successors. We intend this dedication to be an overt act of
+// relinquishment in perpetuity of all present and future rights to this
+// software under copyright law.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS BE LIABLE FOR ANY CLAIM, DAMAGES OR
+// OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE,
+// ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR
+// OTHER DEALINGS IN THE SOFTWARE.
+//
+// For more information, please refer to <https://unlicense.org>
+
+use crate::process::ProcessInfo;
+use std::{
+    fmt::{self, Display, Formatter},
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    num::ParseIntError,
+    ops::RangeInclusive,
+    str::FromStr,
+    time::Duration,
+};
+
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
+pub struct Config {
+    pub access: AccessConfig,
+    pub shaping: ShapingConfig,
+    pub emulation: EmulationConfig,
+    pub reassembly: ReassemblyConfig,
+    pub nat64: Option<Nat64Config>,
+    pub tun: TunConfig,
+    pub memory: MemoryConfig,
+    pub sessions: SessionLimits,
+    pub shutdown: ShutdownConfig,
+}
+
+#[derive(PartialEq, Eq, Hash, Debug, Clone, Copy)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+#[derive(PartialEq, Eq, Hash, Debug, Clone, Copy)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(try_from = "String", into = "String")
+)]
+pub struct Network {
+    pub address: IpAddr,
+    pub prefix_length: u8,
+}
+
+impl Network {
+    pub fn new(address: IpAddr, prefix_length: u8) -> Network {
+        Network {
+            address,
+            prefix_length,
+        }
+    }
+
+    pub fn contains(&self, address: &IpAddr) -> bool {
+        match (self.address, address) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => {
+                let mask = Self::mask(self.prefix_length, 32) as u32;
+                u32::from(network) & mask == u32::from(*address) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                let mask = Self::mask(self.prefix_length, 128);
+                u128::from(network) & mask == u128::from(*address) & mask
+            }
+            _ => false,
+        }
+    }
+
+    fn mask(prefix_length: u8, bits: u32) -> u128 {
+        let prefix_length = u32::from(prefix_length).min(bits);
+        if prefix_length == 0 {
+            0
+        } else {
+            (u128::MAX << (128 - prefix_length)) >> (128 - bits)
+        }
+    }
+}
+
+impl Display for Network {
+    fn fmt(&self, formatter: &mut Formatter<'_>) -> fmt::Result {
+        write!(formatter, "{}/{}", self.address, self.prefix_length)
+    }
+}
+
+impl From<Network> for String {
+    fn from(network: Network) -> Self {
+        network.to_string()
+    }
+}
+
+impl TryFrom<String> for Network {
+    type Error = ParseNetworkError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl FromStr for Network {
+    type Err = ParseNetworkError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (address, prefix_length) = match value.split_once('/') {
+            Some((address, prefix_length)) => (address, Some(prefix_length)),
+            None => (value, None),
+        };
+        let address: IpAddr = address.parse().map_err(|_| ParseNetworkError)?;
+        let maximum_prefix_length = if address.is_ipv4() { 32 } else { 128 };
+        let prefix_length = match prefix_length {
+            Some(prefix_length) => prefix_length
+                .parse()
+                .map_err(|_: ParseIntError| ParseNetworkError)?,
+            None => maximum_prefix_length,
+        };
+        if prefix_length > maximum_prefix_length {
+            return Err(ParseNetworkError);
+        }
+        Ok(Network::new(address, prefix_length))
+    }
+}
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+#[error("invalid network, expected address or address/prefix")]
+pub struct ParseNetworkError;
+
+//
+// Matches the destination of a session; fields which are not set match any
+// destination.
+//
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
+pub struct Destination {
+    pub network: Option<Network>,
+    pub ports: Option<RangeInclusive<u16>>,
+    pub protocol: Option<Protocol>,
+}
+
+impl Destination {
+    pub fn matches(&self, destination: &SocketAddr, protocol: Protocol) -> bool {
+        self.network
+            .is_none_or(|network| network.contains(&destination.ip()))
+            && self
+                .ports
+                .as_ref()
+                .is_none_or(|ports| ports.contains(&destination.port()))
+            && self.protocol.is_none_or(|expected| expected == protocol)
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Default, Clone, Copy)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum Action {
+    #[default]
+    Allow,
+    Deny,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
+pub struct AccessRule {
+    pub destination: Destination,
+    // the rule only matches sessions of the process when set.
+    pub process: Option<ProcessMatcher>,
+    pub action: Action,
+}
+
+//
+// Matches the process owning the socket of the client of a session; fields
+// which are not set match any process.
+//
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
+pub struct ProcessMatcher {
+    pub uid: Option<u32>,
+    // name of the executable, or the package of an android app.
+    pub name: Option<String>,
+}
+
+impl ProcessMatcher {
+    pub fn matches(&self, process: &ProcessInfo) -> bool {
+        self.uid.is_none_or(|uid| process.uid == Some(uid))
+            && self
+                .name
+                .as_ref()
+                .is_none_or(|name| process.name.as_ref() == Some(name))
+    }
+}
+
+//
+// Decides whether sessions may be created; packets of denied sessions are
+// rejected with icmp errors.
+//
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
+pub struct AccessConfig {
+    // applies to sessions which no rule matches.
+    pub default: Action,
+    // first matching rule applies.
+    pub rules: Vec<AccessRule>,
+}
+
+impl AccessConfig {
+    //
+    // Rules which match processes do not match sessions whose process is not
+    // known.
+    //
+    pub fn allows(&self, destination: &SocketAddr, protocol: Protocol) -> bool {
+        self.allows_process(destination, protocol, Some(&ProcessInfo::default()))
+    }
+
+    //
+    // Takes no process while it is being looked up, in which case the session
+    // is allowed when some process could be allowed; it is decided again once
+    // the process is known, see AccessConfig::may_deny_process.
+    //
+    pub fn allows_process(
+        &self,
+        destination: &SocketAddr,
+        protocol: Protocol,
+        process: Option<&ProcessInfo>,
+    ) -> bool {
+        self.may_take(Action::Allow, destination, protocol, process)
+    }
+
+    //
+    // Whether the session of a process which is not known yet could be denied
+    // once it is, in which case its data is held until then.
+    //
+    pub fn may_deny_process(&self, destination: &SocketAddr, protocol: Protocol) -> bool {
+        self.may_take(Action::Deny, destination, protocol, None)
+    }
+
+    // rules which match processes may match a process which is not known.
+    fn may_take(
+        &self,
+        action: Action,
+        destination: &SocketAddr,
+        protocol: Protocol,
+        process: Option<&ProcessInfo>,
+    ) -> bool {
+        for rule in self.rules.iter() {
+            if !rule.destination.matches(destination, protocol) {
+                continue;
+            }
+            let is_match = match (&rule.process, process) {
+                (None, _) => true,
+                (Some(_), None) => rule.action == action,
+                (Some(matcher), Some(process)) => matcher.matches(process),
+            };
+            if is_match {
+                return rule.action == action;
+            }
+        }
+        self.default == action
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RateLimit {
+    pub bytes_per_second: u64,
+    pub burst_bytes: u64,
+}
+
+impl RateLimit {
+    pub fn new(bytes_per_second: u64, burst_bytes: u64) -> RateLimit {
+        RateLimit {
+            bytes_per_second,
+            burst_bytes,
+        }
+    }
+}
+
+//
+// Upload is data sent from the client to the server and download is data sent
+// from the server to the client.
+//
+#[derive(PartialEq, Eq, Debug, Default, Clone, Copy)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
+pub struct RateLimits {
+    pub upload: Option<RateLimit>,
+    pub download: Option<RateLimit>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
+pub struct ShapingRule {
+    pub destination: Destination,
+    // limits shared by all sessions matching the rule.
+    pub shared: RateLimits,
+    // limits applied to each session matching the rule.
+    pub session: RateLimits,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
+pub struct ShapingConfig {
+    // limits shared by all sessions.
+    pub global: RateLimits,
+    // limits applied to each session.
+    pub session: RateLimits,
+    // first matching rule applies in addition to the limits above.
+    pub rules: Vec<ShapingRule>,
+}
+
+#[derive(PartialEq, Eq, Debug, Default, Clone, Copy)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum Distribution {
+    // delays are spread evenly within latency +/- jitter.
+    #[default]
+    Uniform,
+    // jitter is the standard deviation around latency.
+    Normal,
+}
+
+#[derive(PartialEq, Eq, Debug, Default, Clone, Copy)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
+pub struct Delay {
+    pub latency: Duration,
+    pub jitter: Duration,
+    pub distribution: Distribution,
+}
+
+//
+// Probabilities are given as values between 0.0 and 1.0. Reordered packets
+// skip the delay and therefore overtake delayed packets, as with netem.
+//
+#[derive(PartialEq, Debug, Default, Clone, Copy)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
+pub struct Impairments {
+    pub delay: Delay,
+    pub loss: f64,
+    pub duplicate: f64,
+    pub reorder: f64,
+}
+
+impl Impairments {
+    pub fn is_none(&self) -> bool {
+        *self == Impairments::default()
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
+pub struct EmulationRule {
+    pub destination: Destination,
+    pub upload: Impairments,
+    pub download: Impairments,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
+pub struct EmulationConfig {
+    // seeds the random number generator so that emulation is reproducible.
+    pub seed: u64,
+    pub upload: Impairments,
+    pub download: Impairments,
+    // first matching rule replaces the impairments above.
+    pub rules: Vec<EmulationRule>,
+}
+
+//
+// Limits on fragments held while waiting for the remaining fragments of a
+// packet; incomplete packets are dropped once either limit is reached.
+//
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
+pub struct ReassemblyConfig {
+    pub timeout: Duration,
+    // memory held by all incomplete packets, in bytes.
+    pub max_memory: usize,
+}
+
+impl Default for ReassemblyConfig {
+    fn default() -> Self {
+        ReassemblyConfig {
+            timeout: Duration::from_secs(30),
+            max_memory: 4 * 1024 * 1024,
+        }
+    }
+}
+
+//
+// Connects ipv4 sessions upstream over ipv6 by embedding the ipv4 destination
+// in the prefix as described in rfc 6052; the prefix length must be one of 32,
+// 40, 48, 56, 64 or 96. With dns64 enabled, AAAA answers are synthesized from
+// A records for names without AAAA records, see rfc 6147.
+//
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Nat64Config {
+    pub prefix: Network,
+    pub dns64: bool,
+}
+
+impl Nat64Config {
+    // well-known prefix 64:ff9b::/96.
+    pub const WELL_KNOWN_PREFIX: Network = Network {
+        address: IpAddr::V6(Ipv6Addr::new(0x64, 0xff9b, 0, 0, 0, 0, 0, 0)),
+        prefix_length: 96,
+    };
+
+    pub fn new(prefix: Network, dns64: bool) -> Nat64Config {
+        Nat64Config { prefix, dns64 }
+    }
+}
+
+//
+// The mtu of the tun device limits the size of packets sent to the client;
+// larger packets from the client are rejected with icmp errors unless they may
+// be fragmented. The mss option of tcp syns sent in either direction is clamped
+// to fit the mtu, or to max_segment_size when set lower. The mtu is limited to
+// 65535 and raised to the minimum of each internet protocol, 68 for ipv4 and
+// 1280 for ipv6.
+//
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
+pub struct TunConfig {
+    pub mtu: usize,
+    pub max_segment_size: Option<u16>,
+}
+
+impl Default for TunConfig {
+    fn default() -> Self {
+        TunConfig {
+            mtu: 1500,
+            max_segment_size: None,
+        }
+    }
+}
+
+//
+// Socket buffers are sized per protocol and allocated when a session is
+// created, or taken over from a pool of sockets of closed sessions; data
+// queued between the client and the server is bounded per direction, with
+// reads from the sending side paused while the queue is full. Sessions are
+// only created while the memory of all sessions and pooled sockets stays
+// within max_memory, for which pooled sockets are released and idle udp
+// sessions are closed when needed. Trimming memory releases pooled sockets,
+// shrinks the queues of all sessions and closes udp sessions idle for at least
+// trim_idle_time; the socket buffers of open sessions keep their size.
+//
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
+pub struct MemoryConfig {
+    // size of the send and receive buffers of tcp sessions, each.
+    pub tcp_buffer_size: usize,
+    // size of the send and receive buffers of udp sessions, each; at least 64 KiB
+    // so that the largest datagram fits.
+    pub udp_buffer_size: usize,
+    // number of datagrams held by the send and receive buffers of udp sessions.
+    pub udp_buffer_datagrams: usize,
+    // bytes queued per session and direction.
+    pub max_queued: usize,
+    pub max_memory: usize,
+    pub trim_idle_time: Duration,
+}
+
+impl Default for MemoryConfig {
+    fn default() -> Self {
+        MemoryConfig {
+            tcp_buffer_size: 128 * 1024,
+            udp_buffer_size: 64 * 1024,
+            udp_buffer_datagrams: 64,
+            max_queued: 256 * 1024,
+            max_memory: 256 * 1024 * 1024,
+            trim_idle_time: Duration::from_secs(10),
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SessionRate {
+    pub sessions_per_second: u64,
+    pub burst: u64,
+}
+
+impl SessionRate {
+    pub fn new(sessions_per_second: u64, burst: u64) -> SessionRate {
+        SessionRate {
+            sessions_per_second,
+            burst,
+        }
+    }
+}
+
+//
+// Once a limit on concurrent sessions is reached, the least recently active
+// session is closed to make room for a new one; closed tcp connections are
+// reset. Packets which would create sessions beyond a rate limit are dropped.
+//
+#[derive(PartialEq, Eq, Debug, Default, Clone, Copy)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
+pub struct SessionLimits {
+    pub max_sessions: Option<usize>,
+    pub max_tcp_sessions: Option<usize>,
+    pub max_udp_sessions: Option<usize>,
+    // new sessions per destination address.
+    pub destination_rate: Option<SessionRate>,
+    // new sessions per source address.
+    pub source_rate: Option<SessionRate>,
+}
+
+#[derive(PartialEq, Eq, Debug, Default, Clone, Copy)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum ShutdownMode {
+    // sessions are dropped without notifying clients.
+    #[default]
+    Immediate,
+    // pending data is flushed and tcp connections are closed with a fin.
+    Drain,
+    // tcp connections are reset.
+    Reset,
+}
+
+//
+// Stopping the vpn in drain mode flushes the data queued in both directions,
+// closes the upstream sockets and closes tcp connections towards the clients,
+// waiting for them to complete until the deadline; connections which are still
+// open by then are reset.
+//
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
+pub struct ShutdownConfig {
+    pub mode: ShutdownMode,
+    pub deadline: Duration,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        ShutdownConfig {
+            mode: ShutdownMode::default(),
+            deadline: Duration::from_secs(5),
+        }
+    }
+}
@@ -0,0 +1,110 @@
+// This is free and unencumbered software released into the public domain.
+//
+// Anyone is free to copy, modify, publish, use, compile, sell, or
+// distribute this software, either in source code form or as a compiled
+// binary, for any purpose, commercial or non-commercial, and by any
+// means.
+//
+// In jurisdictions that recognize copyright laws, the author or authors
+// of this software dedicate any and all copyright interest in the
+// software to the public domain. We make this dedication for the benefit
+// of the public at large and to the detriment of our heirs and
+// successors. We intend this dedication to be an overt act of
+// relinquishment in perpetuity of all present and future rights to this
+// software under copyright law.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS BE LIABLE FOR ANY CLAIM, DAMAGES OR
+// OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE,
+// ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR
+// OTHER DEALINGS IN THE SOFTWARE.
+//
+// For more information, please refer to <https://unlicense.org>
+
+use crate::config::Protocol;
+use crate::process::ProcessInfo;
+use std::{net::SocketAddr, time::SystemTime};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum CloseReason {
+    // the client closed the connection with a fin.
+    ClientFin,
+    // the server closed the connection with a fin.
+    ServerFin,
+    // the client or the server reset the connection.
+    Reset,
+    // the session was idle for too long.
+    Timeout,
+    // the server could not be reached or failed.
+    Error,
+    // the session was closed to make room for others.
+    Evicted,
+    // the session was closed as the access rules deny it.
+    Denied,
+    // the vpn was stopped.
+    Shutdown,
+}
+
+//
+// Describes a session once it has been destroyed. Bytes count the payload
+// written to the server and to the client, packets those exchanged with the
+// client through the tun.
+//
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ConnectionRecord {
+    pub source: SocketAddr,
+    pub destination: SocketAddr,
+    pub protocol: Protocol,
+    // from the tls server name, the http host or an earlier dns answer.
+    pub hostname: Option<String>,
+    // address connected to, which differs from the destination with nat64.
+    pub upstream: SocketAddr,
+    // local address of the upstream socket, identifying the uplink used.
+    pub outbound: Option<SocketAddr>,
+    // owner of the socket of the client, where known, see ProcessLookup.
+    pub process: Option<ProcessInfo>,
+    #[cfg_attr(feature = "serde", serde(with = "unix_milliseconds"))]
+    pub start_time: SystemTime,
+    #[cfg_attr(feature = "serde", serde(with = "unix_milliseconds"))]
+    pub end_time: SystemTime,
+    pub bytes_to_server: u64,
+    pub bytes_to_client: u64,
+    pub packets_from_client: u64,
+    pub packets_to_client: u64,
+    pub close_reason: CloseReason,
+}
+
+//
+// Times are written as milliseconds since the unix epoch.
+//
+#[cfg(feature = "serde")]
+mod unix_milliseconds {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    pub(super) fn serialize<S: Serializer>(
+        time: &SystemTime,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let milliseconds = time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        milliseconds.serialize(serializer)
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<SystemTime, D::Error> {
+        let milliseconds = u64::deserialize(deserializer)?;
+        Ok(UNIX_EPOCH + Duration::from_millis(milliseconds))
+    }
+}
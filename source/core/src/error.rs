@@ -14,6 +14,30 @@ pub enum Error {
 
     #[error("smoltcp::socket::udp::RecvError {0:?}")]
     UdpRecv(#[from] smoltcp::socket::udp::RecvError),
+
+    #[error("smoltcp::wire::Error {0:?}")]
+    Wire(#[from] smoltcp::wire::Error),
+
+    #[error("unsupported internet protocol version {0}")]
+    UnsupportedInternetProtocol(u8),
+
+    #[error("unsupported transport protocol {0}")]
+    UnsupportedTransportProtocol(smoltcp::wire::IpProtocol),
+
+    #[error("fragmented packet")]
+    Fragmented,
+
+    #[error("invalid fragment")]
+    InvalidFragment,
+
+    #[error("fragment exceeds reassembly memory limit")]
+    ReassemblyLimit,
+
+    #[error("vpn is already started")]
+    AlreadyStarted,
+
+    #[error("vpn is stopped")]
+    Stopped,
 }
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
@@ -23,17 +23,34 @@
 //
 // For more information, please refer to <https://unlicense.org>
 
+pub mod config;
+pub mod connection_log;
 mod error;
+pub mod metrics;
+pub mod process;
+pub mod statistics;
 mod vpn;
 pub use error::{Error, Result};
+pub use vpn::Listeners;
+pub use vpn::Vpn;
+
+#[cfg(feature = "fuzzing")]
+#[doc(hidden)]
+pub use vpn::fuzzing;
 
 pub mod tun {
-    use crate::vpn::Vpn;
+    use crate::config::Config;
+    use crate::statistics::{SessionSummary, Statistics};
+    use crate::vpn::{Listeners, Vpn};
+    use std::fs::File;
+    use std::net::TcpStream;
+    use std::path::Path;
     use std::process;
     use std::sync::Mutex;
 
     lazy_static::lazy_static! {
         static ref VPN: Mutex<Option<Vpn>> = Mutex::new(None);
+        static ref CONFIG: Mutex<Config> = Mutex::new(Config::default());
     }
 
     macro_rules! vpn {
@@ -50,11 +67,52 @@ pub mod tun {
         log::trace!("destroy, pid={}", process::id());
     }
 
-    pub fn start(file_descriptor: i32) {
+    pub fn start(file_descriptor: i32) -> crate::Result<()> {
+        start_with_listeners(file_descriptor, Listeners::new())
+    }
+
+    //
+    // Starts the vpn with the connections and datagrams of the listeners
+    // forwarded next to the packets of the tun.
+    //
+    pub fn start_with_listeners(file_descriptor: i32, listeners: Listeners) -> crate::Result<()> {
         log::trace!("start, pid={}, fd={}", process::id(), file_descriptor);
         update_vpn(file_descriptor);
-        vpn!().start();
+        vpn!().listen(listeners);
+        vpn!().start(config())?;
         log::trace!("started, pid={}, fd={}", process::id(), file_descriptor);
+        Ok(())
+    }
+
+    //
+    // Starts a vpn without a tun, which only forwards the connections and
+    // datagrams of the listeners.
+    //
+    pub fn start_listeners(listeners: Listeners) -> crate::Result<()> {
+        log::trace!("start listeners, pid={}", process::id());
+        *VPN.lock().unwrap() = Some(Vpn::new_listeners(listeners));
+        vpn!().start(config())?;
+        log::trace!("started listeners, pid={}", process::id());
+        Ok(())
+    }
+
+    //
+    // Hands a running vpn over to a new tun file descriptor, keeping its
+    // sessions; starts the vpn when it is not running.
+    //
+    pub fn replace(file_descriptor: i32) -> crate::Result<()> {
+        log::trace!("replace, pid={}, fd={}", process::id(), file_descriptor);
+        let mut vpn = VPN.lock().unwrap();
+        match vpn.as_mut().filter(|vpn| vpn.is_running()) {
+            Some(vpn) => vpn.replace_tun(file_descriptor)?,
+            None => {
+                let mut new_vpn = Vpn::new(file_descriptor);
+                new_vpn.start(config())?;
+                *vpn = Some(new_vpn);
+            }
+        }
+        log::trace!("replaced, pid={}, fd={}", process::id(), file_descriptor);
+        Ok(())
     }
 
     pub fn stop() {
@@ -63,18 +121,143 @@ pub mod tun {
         log::trace!("stopped, pid={}", process::id());
     }
 
+    pub fn start_relay(stream: TcpStream) -> crate::Result<()> {
+        log::trace!(
+            "start relay, pid={}, peer={:?}",
+            process::id(),
+            stream.peer_addr()
+        );
+        *VPN.lock().unwrap() = Some(Vpn::new_relay(stream)?);
+        vpn!().start(config())?;
+        log::trace!("started relay, pid={}", process::id());
+        Ok(())
+    }
+
+    //
+    // Configuration is used by subsequent starts and applied to a running vpn.
+    //
+    pub fn configure(config: Config) {
+        log::trace!("configure, pid={}", process::id());
+        *CONFIG.lock().unwrap() = config.clone();
+        if let Some(vpn) = VPN.lock().unwrap().as_ref() {
+            vpn.configure(config);
+        }
+    }
+
+    //
+    // Like configure, additionally closing sessions of a running vpn which the
+    // new access rules deny.
+    //
+    pub fn reload(config: Config) {
+        log::trace!("reload, pid={}", process::id());
+        *CONFIG.lock().unwrap() = config.clone();
+        if let Some(vpn) = VPN.lock().unwrap().as_ref() {
+            vpn.reload(config);
+        }
+    }
+
+    pub fn trim_memory() {
+        log::trace!("trim memory, pid={}", process::id());
+        if let Some(vpn) = VPN.lock().unwrap().as_ref() {
+            vpn.trim_memory();
+        }
+    }
+
+    pub fn sessions() -> Vec<SessionSummary> {
+        match VPN.lock().unwrap().as_ref() {
+            Some(vpn) => vpn.sessions(),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn statistics() -> Statistics {
+        match VPN.lock().unwrap().as_ref() {
+            Some(vpn) => vpn.statistics(),
+            None => Statistics::default(),
+        }
+    }
+
+    pub fn start_capture(path: &Path) -> crate::Result<()> {
+        log::trace!("start capture, pid={}, path={:?}", process::id(), path);
+        let file = File::create(path)?;
+        if let Some(vpn) = VPN.lock().unwrap().as_ref() {
+            vpn.start_capture(file);
+        }
+        Ok(())
+    }
+
+    pub fn stop_capture() {
+        log::trace!("stop capture, pid={}", process::id());
+        if let Some(vpn) = VPN.lock().unwrap().as_ref() {
+            vpn.stop_capture();
+        }
+    }
+
+    pub fn config() -> Config {
+        CONFIG.lock().unwrap().clone()
+    }
+
+    pub fn wait() {
+        log::trace!("wait, pid={}", process::id());
+        //
+        // lock is released before joining so that stop can still be called.
+        //
+        let thread_join_handle = vpn!().take_thread_join_handle();
+        if let Some(thread_join_handle) = thread_join_handle {
+            thread_join_handle.join().unwrap();
+        }
+        log::trace!("finished waiting, pid={}", process::id());
+    }
+
     fn update_vpn(file_descriptor: i32) {
         let mut vpn = VPN.lock().unwrap();
         *vpn = Some(Vpn::new(file_descriptor));
     }
 }
 
+pub mod relay {
+    use crate::vpn::Relay;
+    use std::net::SocketAddr;
+    use std::process;
+    use std::sync::Mutex;
+
+    lazy_static::lazy_static! {
+        static ref RELAY: Mutex<Option<Relay>> = Mutex::new(None);
+    }
+
+    pub fn start(file_descriptor: i32, address: SocketAddr) -> crate::Result<()> {
+        log::trace!("start relay, pid={}, fd={}", process::id(), file_descriptor);
+        let mut relay = Relay::new(file_descriptor, address);
+        relay.start()?;
+        *RELAY.lock().unwrap() = Some(relay);
+        log::trace!(
+            "started relay, pid={}, address={:?}",
+            process::id(),
+            address
+        );
+        Ok(())
+    }
+
+    pub fn stop() {
+        log::trace!("stop relay, pid={}", process::id());
+        if let Some(mut relay) = RELAY.lock().unwrap().take() {
+            relay.stop();
+        }
+        log::trace!("stopped relay, pid={}", process::id());
+    }
+}
+
 pub mod tun_callbacks {
 
-    use std::sync::RwLock;
+    use crate::connection_log::ConnectionRecord;
+    use crate::process::ProcessLookup;
+    use std::sync::{Arc, RwLock};
 
     lazy_static::lazy_static! {
         static ref CALLBACK: RwLock<fn(i32)> = RwLock::new(on_socket_created_stub);
+        static ref CONNECTION_CLOSED_CALLBACK: RwLock<Option<fn(&ConnectionRecord)>> =
+            RwLock::new(None);
+        static ref PROCESS_LOOKUP: RwLock<Option<Arc<dyn ProcessLookup>>> = RwLock::new(None);
     }
 
     pub fn set_socket_created_callback(callback: Option<fn(i32)>) {
@@ -91,4 +274,34 @@ pub mod tun_callbacks {
     }
 
     fn on_socket_created_stub(_socket: i32) {}
+
+    //
+    // The callback receives a record for every session destroyed, on the
+    // thread of the vpn, and should hand it off rather than block.
+    //
+    pub fn set_connection_closed_callback(callback: Option<fn(&ConnectionRecord)>) {
+        *CONNECTION_CLOSED_CALLBACK.write().unwrap() = callback;
+    }
+
+    pub(crate) fn is_connection_log_enabled() -> bool {
+        CONNECTION_CLOSED_CALLBACK.read().unwrap().is_some()
+    }
+
+    pub(crate) fn on_connection_closed(record: &ConnectionRecord) {
+        if let Some(callback) = *CONNECTION_CLOSED_CALLBACK.read().unwrap() {
+            callback(record);
+        }
+    }
+
+    //
+    // Sessions created once a lookup is set are attributed to the process
+    // owning the socket of their client, see ProcessLookup.
+    //
+    pub fn set_process_lookup(lookup: Option<Arc<dyn ProcessLookup>>) {
+        *PROCESS_LOOKUP.write().unwrap() = lookup;
+    }
+
+    pub(crate) fn process_lookup() -> Option<Arc<dyn ProcessLookup>> {
+        PROCESS_LOOKUP.read().unwrap().clone()
+    }
 }
@@ -0,0 +1,281 @@
+// This is free and unencumbered software released into the public domain.
+//
+// Anyone is free to copy, modify, publish, use, compile, sell, or
+// distribute this software, either in source code form or as a compiled
+// binary, for any purpose, commercial or non-commercial, and by any
+// means.
+//
+// In jurisdictions that recognize copyright laws, the author or authors
+// of this software dedicate any and all copyright interest in the
+// software to the public domain. We make this dedication for the benefit
+// of the public at large and to the detriment of our heirs and
+// successors. We intend this dedication to be an overt act of
+// relinquishment in perpetuity of all present and future rights to this
+// software under copyright law.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS BE LIABLE FOR ANY CLAIM, DAMAGES OR
+// OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE,
+// ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR
+// OTHER DEALINGS IN THE SOFTWARE.
+//
+// For more information, please refer to <https://unlicense.org>
+
+use crate::config::Protocol;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::time::Duration;
+
+// upper bounds in seconds of the buckets of the processor loop latency.
+const LOOP_LATENCY_BOUNDS: [f64; 9] = [0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0];
+
+//
+// Metrics of all vpns of the process; they are only updated by the vpn and can
+// be read by anyone, e.g. rendered for prometheus.
+//
+pub static METRICS: Metrics = Metrics::new();
+
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    const fn new() -> Counter {
+        Counter(AtomicU64::new(0))
+    }
+
+    pub(crate) fn increment(&self) {
+        self.add(1);
+    }
+
+    pub(crate) fn add(&self, value: u64) {
+        self.0.fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+pub struct Gauge(AtomicI64);
+
+impl Gauge {
+    const fn new() -> Gauge {
+        Gauge(AtomicI64::new(0))
+    }
+
+    pub(crate) fn increment(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn decrement(&self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+//
+// Counts observed durations per bucket; unlike in the prometheus format, each
+// bucket only counts the observations above the bound of the previous bucket.
+//
+pub struct Histogram<const N: usize> {
+    bounds: &'static [f64; N],
+    buckets: [AtomicU64; N],
+    count: AtomicU64,
+    sum_nanoseconds: AtomicU64,
+}
+
+impl<const N: usize> Histogram<N> {
+    const fn new(bounds: &'static [f64; N]) -> Histogram<N> {
+        Histogram {
+            bounds,
+            buckets: [const { AtomicU64::new(0) }; N],
+            count: AtomicU64::new(0),
+            sum_nanoseconds: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        if let Some(index) = self.bounds.iter().position(|bound| seconds <= *bound) {
+            self.buckets[index].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_nanoseconds
+            .fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    pub fn sum(&self) -> Duration {
+        Duration::from_nanos(self.sum_nanoseconds.load(Ordering::Relaxed))
+    }
+}
+
+pub struct Metrics {
+    pub tcp_sessions: Gauge,
+    pub udp_sessions: Gauge,
+    pub tcp_sessions_created: Counter,
+    pub udp_sessions_created: Counter,
+    pub tcp_sessions_destroyed: Counter,
+    pub udp_sessions_destroyed: Counter,
+    // payload written to servers and to clients.
+    pub bytes_to_server: Counter,
+    pub bytes_to_client: Counter,
+    pub tun_read_errors: Counter,
+    pub tun_write_errors: Counter,
+    // upstream sockets which could not be created or connected.
+    pub connect_failures: Counter,
+    // time spent handling the events of one iteration of the processor loop.
+    pub loop_latency: Histogram<{ LOOP_LATENCY_BOUNDS.len() }>,
+}
+
+impl Metrics {
+    const fn new() -> Metrics {
+        Metrics {
+            tcp_sessions: Gauge::new(),
+            udp_sessions: Gauge::new(),
+            tcp_sessions_created: Counter::new(),
+            udp_sessions_created: Counter::new(),
+            tcp_sessions_destroyed: Counter::new(),
+            udp_sessions_destroyed: Counter::new(),
+            bytes_to_server: Counter::new(),
+            bytes_to_client: Counter::new(),
+            tun_read_errors: Counter::new(),
+            tun_write_errors: Counter::new(),
+            connect_failures: Counter::new(),
+            loop_latency: Histogram::new(&LOOP_LATENCY_BOUNDS),
+        }
+    }
+
+    pub(crate) fn session_created(&self, protocol: Protocol) {
+        match protocol {
+            Protocol::Tcp => {
+                self.tcp_sessions.increment();
+                self.tcp_sessions_created.increment();
+            }
+            Protocol::Udp => {
+                self.udp_sessions.increment();
+                self.udp_sessions_created.increment();
+            }
+        }
+    }
+
+    pub(crate) fn session_destroyed(&self, protocol: Protocol) {
+        match protocol {
+            Protocol::Tcp => {
+                self.tcp_sessions.decrement();
+                self.tcp_sessions_destroyed.increment();
+            }
+            Protocol::Udp => {
+                self.udp_sessions.decrement();
+                self.udp_sessions_destroyed.increment();
+            }
+        }
+    }
+
+    //
+    // Renders the metrics in the prometheus text exposition format.
+    //
+    pub fn render(&self) -> String {
+        let mut text = String::new();
+        let protocols =
+            |tcp: i64, udp: i64| vec![("protocol=\"tcp\"", tcp), ("protocol=\"udp\"", udp)];
+        write_family(
+            &mut text,
+            "vpn_sessions",
+            "gauge",
+            "Active sessions.",
+            protocols(self.tcp_sessions.get(), self.udp_sessions.get()),
+        );
+        write_family(
+            &mut text,
+            "vpn_sessions_created_total",
+            "counter",
+            "Sessions created.",
+            protocols(
+                self.tcp_sessions_created.get() as i64,
+                self.udp_sessions_created.get() as i64,
+            ),
+        );
+        write_family(
+            &mut text,
+            "vpn_sessions_destroyed_total",
+            "counter",
+            "Sessions destroyed.",
+            protocols(
+                self.tcp_sessions_destroyed.get() as i64,
+                self.udp_sessions_destroyed.get() as i64,
+            ),
+        );
+        write_family(
+            &mut text,
+            "vpn_bytes_total",
+            "counter",
+            "Payload bytes written to servers and clients.",
+            vec![
+                ("direction=\"to_server\"", self.bytes_to_server.get() as i64),
+                ("direction=\"to_client\"", self.bytes_to_client.get() as i64),
+            ],
+        );
+        write_family(
+            &mut text,
+            "vpn_tun_errors_total",
+            "counter",
+            "Failed reads from and writes to the tun.",
+            vec![
+                ("operation=\"read\"", self.tun_read_errors.get() as i64),
+                ("operation=\"write\"", self.tun_write_errors.get() as i64),
+            ],
+        );
+        write_family(
+            &mut text,
+            "vpn_connect_failures_total",
+            "counter",
+            "Upstream sockets which could not be created or connected.",
+            vec![("", self.connect_failures.get() as i64)],
+        );
+        self.render_loop_latency(&mut text);
+        text
+    }
+
+    fn render_loop_latency(&self, text: &mut String) {
+        let name = "vpn_loop_duration_seconds";
+        let histogram = &self.loop_latency;
+        let _ = writeln!(
+            text,
+            "# HELP {} Time spent handling one iteration of the processor loop.",
+            name
+        );
+        let _ = writeln!(text, "# TYPE {} histogram", name);
+        let mut cumulative = 0;
+        for (bound, bucket) in histogram.bounds.iter().zip(&histogram.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(text, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+        }
+        let count = histogram.count();
+        let _ = writeln!(text, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+        let _ = writeln!(text, "{}_sum {}", name, histogram.sum().as_secs_f64());
+        let _ = writeln!(text, "{}_count {}", name, count);
+    }
+}
+
+fn write_family(text: &mut String, name: &str, kind: &str, help: &str, samples: Vec<(&str, i64)>) {
+    let _ = writeln!(text, "# HELP {} {}", name, help);
+    let _ = writeln!(text, "# TYPE {} {}", name, kind);
+    for (labels, value) in samples {
+        match labels {
+            "" => {
+                let _ = writeln!(text, "{} {}", name, value);
+            }
+            labels => {
+                let _ = writeln!(text, "{}{{{}}} {}", name, labels, value);
+            }
+        }
+    }
+}
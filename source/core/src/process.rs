@@ -0,0 +1,114 @@
+// This is free and unencumbered software released into the public domain.
+//
+// Anyone is free to copy, modify, publish, use, compile, sell, or
+// distribute this software, either in source code form or as a compiled
+// binary, for any purpose, commercial or non-commercial, and by any
+// means.
+//
+// In jurisdictions that recognize copyright laws, the author or authors
+// of this software dedicate any and all copyright interest in the
+// software to the public domain. We make this dedication for the benefit
+// of the public at large and to the detriment of our heirs and
+// successors. We intend this dedication to be an overt act of
+// relinquishment in perpetuity of all present and future rights to this
+// software under copyright law.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS BE LIABLE FOR ANY CLAIM, DAMAGES OR
+// OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE,
+// ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR
+// OTHER DEALINGS IN THE SOFTWARE.
+//
+// For more information, please refer to <https://unlicense.org>
+
+use crate::config::Protocol;
+use std::{collections::HashMap, net::SocketAddr, sync::Mutex};
+
+//
+// The process owning the socket of the client of a session; fields are not set
+// when they could not be looked up, e.g. the pid of a process of another user.
+//
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
+pub struct ProcessInfo {
+    pub pid: Option<u32>,
+    pub uid: Option<u32>,
+    // name of the executable, or the package of an android app.
+    pub name: Option<String>,
+}
+
+//
+// Looks up the process owning the socket which the client of a session has
+// bound to the local address, preferring one connected to the remote address:
+// the sockets of socks clients are connected to the proxy instead, and
+// unconnected udp sockets to no address at all. Lookups are made on a thread
+// of their own, one at a time, and may block; returns None when no socket
+// matches.
+//
+pub trait ProcessLookup: Send + Sync {
+    fn lookup(
+        &self,
+        protocol: Protocol,
+        local: SocketAddr,
+        remote: SocketAddr,
+    ) -> Option<ProcessInfo>;
+}
+
+//
+// Tells the uid of the app owning a connection and the package of a uid, as
+// the ConnectivityManager and the PackageManager of android do; only the
+// active vpn app may ask for the owners of connections.
+//
+pub trait ConnectionOwner: Send + Sync {
+    // the uid is not set when no app owns a matching socket.
+    fn owner_uid(&self, protocol: Protocol, local: SocketAddr, remote: SocketAddr) -> Option<u32>;
+
+    fn package_name(&self, uid: u32) -> Option<String>;
+}
+
+//
+// Attributes sessions to android apps by the uids owning their connections,
+// without pids; the package names of uids are only asked for once.
+//
+pub struct AppProcessLookup<O> {
+    owner: O,
+    package_names: Mutex<HashMap<u32, Option<String>>>,
+}
+
+impl<O: ConnectionOwner> AppProcessLookup<O> {
+    pub fn new(owner: O) -> AppProcessLookup<O> {
+        AppProcessLookup {
+            owner,
+            package_names: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<O: ConnectionOwner> ProcessLookup for AppProcessLookup<O> {
+    fn lookup(
+        &self,
+        protocol: Protocol,
+        local: SocketAddr,
+        remote: SocketAddr,
+    ) -> Option<ProcessInfo> {
+        let uid = self.owner.owner_uid(protocol, local, remote)?;
+        let name = self
+            .package_names
+            .lock()
+            .unwrap()
+            .entry(uid)
+            .or_insert_with(|| self.owner.package_name(uid))
+            .clone();
+        Some(ProcessInfo {
+            pid: None,
+            uid: Some(uid),
+            name,
+        })
+    }
+}
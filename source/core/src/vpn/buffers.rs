@@ -38,16 +38,55 @@ impl Buffers {
         }
     }
 
-    pub(crate) fn write_data<F>(&mut self, direction: OutgoingDirection, mut write_fn: F)
+    pub(crate) fn is_empty(&mut self, direction: &OutgoingDirection) -> bool {
+        match self {
+            Buffers::Tcp(tcp_buf) => tcp_buf.peek_data(direction).is_empty(),
+            Buffers::Udp(udp_buf) => udp_buf.peek_data(direction).is_empty(),
+        }
+    }
+
+    //
+    // Returns the number of bytes queued in the given direction.
+    //
+    pub(crate) fn len(&self, direction: &OutgoingDirection) -> usize {
+        match self {
+            Buffers::Tcp(tcp_buf) => tcp_buf.len(direction),
+            Buffers::Udp(udp_buf) => udp_buf.len(direction),
+        }
+    }
+
+    pub(crate) fn shrink_to_fit(&mut self) {
+        match self {
+            Buffers::Tcp(tcp_buf) => tcp_buf.shrink_to_fit(),
+            Buffers::Udp(udp_buf) => udp_buf.shrink_to_fit(),
+        }
+    }
+
+    //
+    // Writes at most allowance bytes and returns how many bytes were written;
+    // datagrams are never split so the last one written may exceed allowance.
+    //
+    pub(crate) fn write_data<F>(
+        &mut self,
+        direction: OutgoingDirection,
+        allowance: usize,
+        mut write_fn: F,
+    ) -> usize
     where
         F: FnMut(&[u8]) -> crate::Result<usize>,
     {
+        let mut written: usize = 0;
         match self {
             Buffers::Tcp(tcp_buf) => {
-                let buffer = tcp_buf.peek_data(&direction).to_vec();
+                let buffer = tcp_buf.peek_data(&direction);
+                let buffer = buffer[..buffer.len().min(allowance)].to_vec();
+                if buffer.is_empty() {
+                    return written;
+                }
                 match write_fn(&buffer[..]) {
                     Ok(consumed) => {
                         tcp_buf.consume_data(&direction, consumed);
+                        written = consumed;
                     }
                     Err(error) => match error {
                         crate::Error::Io(err) => {
@@ -82,6 +121,9 @@ impl Buffers {
                 let mut consumed: usize = 0;
                 // write udp packets one by one
                 for datagram in all_datagrams {
+                    if written >= allowance {
+                        break;
+                    }
                     if let Err(error) = write_fn(&datagram[..]) {
                         match error {
                             crate::Error::Io(err) => {
@@ -117,10 +159,12 @@ impl Buffers {
                         }
                     }
                     consumed += 1;
+                    written += datagram.len();
                 }
                 udp_buf.consume_data(&direction, consumed);
             }
         }
+        written
     }
 }
 
@@ -137,6 +181,18 @@ impl TcpBuffers {
         }
     }
 
+    pub(crate) fn len(&self, direction: &OutgoingDirection) -> usize {
+        match direction {
+            OutgoingDirection::ToServer => self.server.len(),
+            OutgoingDirection::ToClient => self.client.len(),
+        }
+    }
+
+    pub(crate) fn shrink_to_fit(&mut self) {
+        self.client.shrink_to_fit();
+        self.server.shrink_to_fit();
+    }
+
     pub(crate) fn peek_data(&mut self, direction: &OutgoingDirection) -> &[u8] {
         let buffer = match direction {
             OutgoingDirection::ToServer => &mut self.server,
@@ -170,6 +226,8 @@ impl TcpBuffers {
 pub(crate) struct UdpBuffers {
     client: VecDeque<Vec<u8>>,
     server: VecDeque<Vec<u8>>,
+    client_len: usize,
+    server_len: usize,
 }
 
 impl UdpBuffers {
@@ -177,9 +235,23 @@ impl UdpBuffers {
         UdpBuffers {
             client: Default::default(),
             server: Default::default(),
+            client_len: 0,
+            server_len: 0,
         }
     }
 
+    pub(crate) fn len(&self, direction: &OutgoingDirection) -> usize {
+        match direction {
+            OutgoingDirection::ToServer => self.server_len,
+            OutgoingDirection::ToClient => self.client_len,
+        }
+    }
+
+    pub(crate) fn shrink_to_fit(&mut self) {
+        self.client.shrink_to_fit();
+        self.server.shrink_to_fit();
+    }
+
     pub(crate) fn peek_data(&mut self, direction: &OutgoingDirection) -> &[Vec<u8>] {
         let buffer = match direction {
             OutgoingDirection::ToServer => &mut self.server,
@@ -189,19 +261,27 @@ impl UdpBuffers {
     }
 
     pub(crate) fn consume_data(&mut self, direction: &OutgoingDirection, size: usize) {
-        let buffer = match direction {
-            OutgoingDirection::ToServer => &mut self.server,
-            OutgoingDirection::ToClient => &mut self.client,
+        let (buffer, len) = match direction {
+            OutgoingDirection::ToServer => (&mut self.server, &mut self.server_len),
+            OutgoingDirection::ToClient => (&mut self.client, &mut self.client_len),
         };
-        buffer.drain(0..size);
+        for datagram in buffer.drain(0..size) {
+            *len -= datagram.len();
+        }
     }
 
     pub(crate) fn push_data(&mut self, event: IncomingDataEvent<'_>) {
         let direction = event.direction;
         let buffer = event.buffer;
         match direction {
-            IncomingDirection::FromServer => self.client.push_back(buffer.to_vec()),
-            IncomingDirection::FromClient => self.server.push_back(buffer.to_vec()),
+            IncomingDirection::FromServer => {
+                self.client_len += buffer.len();
+                self.client.push_back(buffer.to_vec());
+            }
+            IncomingDirection::FromClient => {
+                self.server_len += buffer.len();
+                self.server.push_back(buffer.to_vec());
+            }
         }
     }
 }
@@ -212,7 +292,7 @@ pub(crate) enum IncomingDirection {
     FromClient,
 }
 
-#[derive(Eq, PartialEq, Debug)]
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
 pub(crate) enum OutgoingDirection {
     ToServer,
     ToClient,
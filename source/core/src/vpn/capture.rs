@@ -0,0 +1,77 @@
+// This is free and unencumbered software released into the public domain.
+//
+// Anyone is free to copy, modify, publish, use, compile, sell, or
+// distribute this software, either in source code form or as a compiled
+// binary, for any purpose, commercial or non-commercial, and by any
+// means.
+//
+// In jurisdictions that recognize copyright laws, the author or authors
+// of this software dedicate any and all copyright interest in the
+// software to the public domain. We make this dedication for the benefit
+// of the public at large and to the detriment of our heirs and
+// successors. We intend this dedication to be an overt act of
+// relinquishment in perpetuity of all present and future rights to this
+// software under copyright law.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS BE LIABLE FOR ANY CLAIM, DAMAGES OR
+// OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE,
+// ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR
+// OTHER DEALINGS IN THE SOFTWARE.
+//
+// For more information, please refer to <https://unlicense.org>
+
+use std::{
+    fs::File,
+    io::{BufWriter, Result, Write},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const MAGIC_NUMBER: u32 = 0xa1b2c3d4;
+const VERSION_MAJOR: u16 = 2;
+const VERSION_MINOR: u16 = 4;
+const SNAPSHOT_LENGTH: u32 = 65535;
+// packets begin with the ip header.
+const LINKTYPE_RAW: u32 = 101;
+
+//
+// Writes packets exchanged with the tun in pcap format.
+//
+pub(crate) struct Capture {
+    writer: BufWriter<File>,
+}
+
+impl Capture {
+    pub(crate) fn new(file: File) -> Result<Capture> {
+        let mut writer = BufWriter::new(file);
+        writer.write_all(&MAGIC_NUMBER.to_ne_bytes())?;
+        writer.write_all(&VERSION_MAJOR.to_ne_bytes())?;
+        writer.write_all(&VERSION_MINOR.to_ne_bytes())?;
+        // time zone offset and timestamp accuracy.
+        writer.write_all(&0i32.to_ne_bytes())?;
+        writer.write_all(&0u32.to_ne_bytes())?;
+        writer.write_all(&SNAPSHOT_LENGTH.to_ne_bytes())?;
+        writer.write_all(&LINKTYPE_RAW.to_ne_bytes())?;
+        Ok(Capture { writer })
+    }
+
+    pub(crate) fn write(&mut self, bytes: &[u8]) -> Result<()> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let length = bytes.len().min(SNAPSHOT_LENGTH as usize);
+        self.writer
+            .write_all(&(timestamp.as_secs() as u32).to_ne_bytes())?;
+        self.writer
+            .write_all(&timestamp.subsec_micros().to_ne_bytes())?;
+        self.writer.write_all(&(length as u32).to_ne_bytes())?;
+        self.writer.write_all(&(bytes.len() as u32).to_ne_bytes())?;
+        self.writer.write_all(&bytes[..length])
+    }
+
+    pub(crate) fn flush(&mut self) -> Result<()> {
+        self.writer.flush()
+    }
+}
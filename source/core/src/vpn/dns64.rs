@@ -0,0 +1,404 @@
+// This is free and unencumbered software released into the public domain.
+//
+// Anyone is free to copy, modify, publish, use, compile, sell, or
+// distribute this software, either in source code form or as a compiled
+// binary, for any purpose, commercial or non-commercial, and by any
+// means.
+//
+// In jurisdictions that recognize copyright laws, the author or authors
+// of this software dedicate any and all copyright interest in the
+// software to the public domain. We make this dedication for the benefit
+// of the public at large and to the detriment of our heirs and
+// successors. We intend this dedication to be an overt act of
+// relinquishment in perpetuity of all present and future rights to this
+// software under copyright law.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS BE LIABLE FOR ANY CLAIM, DAMAGES OR
+// OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE,
+// ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR
+// OTHER DEALINGS IN THE SOFTWARE.
+//
+// For more information, please refer to <https://unlicense.org>
+
+use crate::config::Network;
+use crate::vpn::nat64::synthesize_address;
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+const HEADER_LENGTH: usize = 12;
+
+const TYPE_A: u16 = 1;
+const TYPE_CNAME: u16 = 5;
+const TYPE_AAAA: u16 = 28;
+const TYPE_OPT: u16 = 41;
+const CLASS_IN: u16 = 1;
+
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_AUTHORITATIVE: u16 = 0x0400;
+const FLAG_TRUNCATED: u16 = 0x0200;
+const FLAG_RECURSION_DESIRED: u16 = 0x0100;
+const OPCODE_MASK: u16 = 0x7800;
+const RESPONSE_CODE_MASK: u16 = 0x000f;
+
+const MAXIMUM_PENDING_QUERIES: usize = 256;
+const MAXIMUM_NAME_LENGTH: usize = 255;
+const MAXIMUM_POINTERS: usize = 16;
+
+// udp payload size advertised in the A queries sent for synthesis.
+const UDP_PAYLOAD_SIZE: u16 = 1232;
+
+pub(crate) enum Dns64Message {
+    ToClient(Vec<u8>),
+    ToServer(Vec<u8>),
+}
+
+enum PendingQuery {
+    // AAAA query forwarded from the client.
+    Aaaa {
+        name: Vec<u8>,
+    },
+    // A query sent in place of returning an empty AAAA answer to the client.
+    A {
+        name: Vec<u8>,
+        aaaa_response: Vec<u8>,
+    },
+}
+
+//
+// Synthesizes AAAA answers for the dns traffic of a udp session. AAAA queries
+// from the client are tracked by id; when the server answers one without any
+// AAAA records, an A query for the same name is sent instead and its A records
+// are returned to the client as AAAA records within the nat64 prefix. When no
+// A records exist either, the original answer is returned.
+//
+pub(crate) struct Dns64 {
+    prefix: Network,
+    queries: HashMap<u16, PendingQuery>,
+}
+
+impl Dns64 {
+    pub(crate) fn new(prefix: Network) -> Dns64 {
+        Dns64 {
+            prefix,
+            queries: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn handle_query(&mut self, message: &[u8]) {
+        let Some(header) = Header::parse(message) else {
+            return;
+        };
+        if header.flags & (FLAG_RESPONSE | OPCODE_MASK) != 0 {
+            return;
+        }
+        match Question::parse(message, &header) {
+            Some(question) if question.is_aaaa() => {
+                if self.queries.len() < MAXIMUM_PENDING_QUERIES {
+                    let name = question.name;
+                    self.queries.insert(header.id, PendingQuery::Aaaa { name });
+                }
+            }
+            _ => {
+                self.queries.remove(&header.id);
+            }
+        }
+    }
+
+    pub(crate) fn handle_response(&mut self, message: Vec<u8>) -> Dns64Message {
+        let Some(header) = Header::parse(&message) else {
+            return Dns64Message::ToClient(message);
+        };
+        if header.flags & FLAG_RESPONSE == 0 {
+            return Dns64Message::ToClient(message);
+        }
+        let Some(query) = self.queries.remove(&header.id) else {
+            return Dns64Message::ToClient(message);
+        };
+        match query {
+            PendingQuery::Aaaa { name } => {
+                let is_empty = header.is_successful()
+                    && Question::parse(&message, &header)
+                        .is_some_and(|question| question.matches(&name, TYPE_AAAA))
+                    && read_answers(&message, &header)
+                        .is_some_and(|answers| answers.iter().all(|a| a.record_type != TYPE_AAAA));
+                if !is_empty {
+                    return Dns64Message::ToClient(message);
+                }
+                log::trace!("querying A records for synthesis, id={:?}", header.id);
+                let query = a_query(header.id, header.flags & FLAG_RECURSION_DESIRED, &name);
+                let aaaa_response = message;
+                self.queries.insert(
+                    header.id,
+                    PendingQuery::A {
+                        name,
+                        aaaa_response,
+                    },
+                );
+                Dns64Message::ToServer(query)
+            }
+            PendingQuery::A {
+                name,
+                aaaa_response,
+            } => {
+                let is_answer = header.is_successful()
+                    && Question::parse(&message, &header)
+                        .is_some_and(|question| question.matches(&name, TYPE_A));
+                let synthesized = is_answer
+                    .then(|| self.synthesize(&message, &header, &name))
+                    .flatten();
+                match synthesized {
+                    Some(response) => Dns64Message::ToClient(response),
+                    None => Dns64Message::ToClient(aaaa_response),
+                }
+            }
+        }
+    }
+
+    fn synthesize(&self, message: &[u8], header: &Header, name: &[u8]) -> Option<Vec<u8>> {
+        let mut response = Vec::with_capacity(message.len() * 2);
+        response.extend_from_slice(&header.id.to_be_bytes());
+        response.extend_from_slice(&(header.flags & !FLAG_AUTHORITATIVE).to_be_bytes());
+        // counts of questions, answers, authority and additional records.
+        response.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+        response.extend_from_slice(name);
+        response.extend_from_slice(&TYPE_AAAA.to_be_bytes());
+        response.extend_from_slice(&CLASS_IN.to_be_bytes());
+
+        let mut answer_count: u16 = 0;
+        let mut synthesized_count = 0;
+        for answer in read_answers(message, header)? {
+            let data = match answer.record_type {
+                TYPE_CNAME => read_name(message, answer.data_offset)?.0,
+                TYPE_A if answer.data_length == 4 && answer.class == CLASS_IN => {
+                    let data = &message[answer.data_offset..answer.data_offset + 4];
+                    let address = Ipv4Addr::new(data[0], data[1], data[2], data[3]);
+                    synthesized_count += 1;
+                    synthesize_address(&self.prefix, address)?.octets().to_vec()
+                }
+                _ => continue,
+            };
+            let record_type = match answer.record_type {
+                TYPE_A => TYPE_AAAA,
+                record_type => record_type,
+            };
+            response.extend_from_slice(&answer.name);
+            response.extend_from_slice(&record_type.to_be_bytes());
+            response.extend_from_slice(&answer.class.to_be_bytes());
+            response.extend_from_slice(&answer.ttl.to_be_bytes());
+            response.extend_from_slice(&u16::try_from(data.len()).ok()?.to_be_bytes());
+            response.extend_from_slice(&data);
+            answer_count += 1;
+        }
+        if synthesized_count == 0 {
+            return None;
+        }
+        response[6..8].copy_from_slice(&answer_count.to_be_bytes());
+        Some(response)
+    }
+}
+
+//
+// Returns the name asked for by a successful response together with the
+// addresses of its A and AAAA records, which may belong to the canonical
+// name of the name asked for.
+//
+pub(crate) fn answered_addresses(message: &[u8]) -> Option<(String, Vec<IpAddr>)> {
+    let header = Header::parse(message)?;
+    if header.flags & FLAG_RESPONSE == 0 || !header.is_successful() {
+        return None;
+    }
+    let question = Question::parse(message, &header)?;
+    let addresses: Vec<IpAddr> = read_answers(message, &header)?
+        .iter()
+        .filter(|record| record.class == CLASS_IN)
+        .filter_map(|record| {
+            let data = message.get(record.data_offset..record.data_offset + record.data_length)?;
+            match (record.record_type, data.len()) {
+                (TYPE_A, 4) => Some(IpAddr::V4(Ipv4Addr::new(
+                    data[0], data[1], data[2], data[3],
+                ))),
+                (TYPE_AAAA, 16) => {
+                    let octets: [u8; 16] = data.try_into().ok()?;
+                    Some(IpAddr::V6(Ipv6Addr::from(octets)))
+                }
+                _ => None,
+            }
+        })
+        .collect();
+    if addresses.is_empty() {
+        return None;
+    }
+    Some((name_to_string(&question.name)?, addresses))
+}
+
+//
+// Converts an uncompressed name in wire format to its dotted form.
+//
+fn name_to_string(name: &[u8]) -> Option<String> {
+    let mut labels = Vec::new();
+    let mut position = 0;
+    loop {
+        let length = usize::from(*name.get(position)?);
+        if length == 0 {
+            break;
+        }
+        let label = name.get(position + 1..position + 1 + length)?;
+        labels.push(String::from_utf8_lossy(label).to_ascii_lowercase());
+        position += 1 + length;
+    }
+    Some(labels.join("."))
+}
+
+struct Header {
+    id: u16,
+    flags: u16,
+    question_count: u16,
+    answer_count: u16,
+}
+
+impl Header {
+    fn parse(message: &[u8]) -> Option<Header> {
+        Some(Header {
+            id: read_u16(message, 0)?,
+            flags: read_u16(message, 2)?,
+            question_count: read_u16(message, 4)?,
+            answer_count: read_u16(message, 6)?,
+        })
+    }
+
+    fn is_successful(&self) -> bool {
+        self.flags & (RESPONSE_CODE_MASK | FLAG_TRUNCATED) == 0
+    }
+}
+
+struct Question {
+    // uncompressed name in wire format.
+    name: Vec<u8>,
+    record_type: u16,
+    class: u16,
+    end: usize,
+}
+
+impl Question {
+    fn parse(message: &[u8], header: &Header) -> Option<Question> {
+        if header.question_count != 1 {
+            return None;
+        }
+        let (name, offset) = read_name(message, HEADER_LENGTH)?;
+        Some(Question {
+            name,
+            record_type: read_u16(message, offset)?,
+            class: read_u16(message, offset + 2)?,
+            end: offset + 4,
+        })
+    }
+
+    fn is_aaaa(&self) -> bool {
+        self.record_type == TYPE_AAAA && self.class == CLASS_IN
+    }
+
+    fn matches(&self, name: &[u8], record_type: u16) -> bool {
+        self.record_type == record_type
+            && self.class == CLASS_IN
+            && self.name.eq_ignore_ascii_case(name)
+    }
+}
+
+struct Record {
+    // uncompressed name in wire format.
+    name: Vec<u8>,
+    record_type: u16,
+    class: u16,
+    ttl: u32,
+    data_offset: usize,
+    data_length: usize,
+}
+
+fn read_answers(message: &[u8], header: &Header) -> Option<Vec<Record>> {
+    let mut offset = Question::parse(message, header)?.end;
+    let mut answers = Vec::new();
+    for _ in 0..header.answer_count {
+        let (name, name_end) = read_name(message, offset)?;
+        let record = Record {
+            name,
+            record_type: read_u16(message, name_end)?,
+            class: read_u16(message, name_end + 2)?,
+            ttl: read_u32(message, name_end + 4)?,
+            data_offset: name_end + 10,
+            data_length: usize::from(read_u16(message, name_end + 8)?),
+        };
+        offset = record.data_offset + record.data_length;
+        if offset > message.len() {
+            return None;
+        }
+        answers.push(record);
+    }
+    Some(answers)
+}
+
+//
+// Reads a possibly compressed name and returns it uncompressed together with
+// the offset following the name in the message.
+//
+fn read_name(message: &[u8], offset: usize) -> Option<(Vec<u8>, usize)> {
+    let mut name = Vec::new();
+    let mut position = offset;
+    let mut end = None;
+    let mut pointers = 0;
+    loop {
+        let length = usize::from(*message.get(position)?);
+        match length & 0xc0 {
+            0xc0 => {
+                pointers += 1;
+                if pointers > MAXIMUM_POINTERS {
+                    return None;
+                }
+                end.get_or_insert(position + 2);
+                position = usize::from(read_u16(message, position)? & 0x3fff);
+            }
+            0x00 if length == 0 => {
+                name.push(0);
+                return Some((name, end.unwrap_or(position + 1)));
+            }
+            0x00 => {
+                name.extend_from_slice(message.get(position..position + 1 + length)?);
+                if name.len() >= MAXIMUM_NAME_LENGTH {
+                    return None;
+                }
+                position += 1 + length;
+            }
+            _ => return None,
+        }
+    }
+}
+
+fn a_query(id: u16, flags: u16, name: &[u8]) -> Vec<u8> {
+    let mut query = Vec::with_capacity(HEADER_LENGTH + name.len() + 15);
+    query.extend_from_slice(&id.to_be_bytes());
+    query.extend_from_slice(&flags.to_be_bytes());
+    // one question and one additional record for edns.
+    query.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 1]);
+    query.extend_from_slice(name);
+    query.extend_from_slice(&TYPE_A.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+    query.push(0);
+    query.extend_from_slice(&TYPE_OPT.to_be_bytes());
+    query.extend_from_slice(&UDP_PAYLOAD_SIZE.to_be_bytes());
+    query.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+    query
+}
+
+fn read_u16(message: &[u8], offset: usize) -> Option<u16> {
+    let bytes = message.get(offset..offset + 2)?;
+    Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_u32(message: &[u8], offset: usize) -> Option<u32> {
+    let bytes = message.get(offset..offset + 4)?;
+    Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
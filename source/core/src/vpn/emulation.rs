@@ -0,0 +1,317 @@
+// This is free and unencumbered software released into the public domain.
+//
+// Anyone is free to copy, modify, publish, use, compile, sell, or
+// distribute this software, either in source code form or as a compiled
+// binary, for any purpose, commercial or non-commercial, and by any
+// means.
+//
+// In jurisdictions that recognize copyright laws, the author or authors
+// of this software dedicate any and all copyright interest in the
+// software to the public domain. We make this dedication for the benefit
+// of the public at large and to the detriment of our heirs and
+// successors. We intend this dedication to be an overt act of
+// relinquishment in perpetuity of all present and future rights to this
+// software under copyright law.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS BE LIABLE FOR ANY CLAIM, DAMAGES OR
+// OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE,
+// ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR
+// OTHER DEALINGS IN THE SOFTWARE.
+//
+// For more information, please refer to <https://unlicense.org>
+
+use crate::config::{Distribution, EmulationConfig, Impairments};
+use crate::vpn::{buffers::OutgoingDirection, session::StreamDelay, session_info::SessionInfo};
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::BinaryHeap,
+    time::{Duration, Instant},
+};
+
+//
+// Upper bound of delayed packets; packets beyond it are dropped.
+//
+const MAXIMUM_DELAYED_PACKETS: usize = 16 * 1024;
+
+//
+// Upper bound of delays, which keeps deadlines representable however large
+// the configured latency and jitter are.
+//
+const MAXIMUM_DELAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+//
+// Applies network impairments to packets travelling between the tun and the
+// sessions. Packets travelling to the server are emulated before they are
+// received by the session device and packets travelling to the client are
+// emulated after they are transmitted by the session device. Clients with
+// sockets of their own have no packets; the data read from either side is
+// emulated instead, see emulate_payload.
+//
+pub(crate) struct Emulator {
+    config: EmulationConfig,
+    random: Random,
+    delayed_packets: BinaryHeap<Reverse<DelayedPacket>>,
+    next_sequence: u64,
+}
+
+pub(crate) struct DelayedPacket {
+    pub(crate) deadline: Instant,
+    sequence: u64,
+    pub(crate) direction: OutgoingDirection,
+    pub(crate) session_info: SessionInfo,
+    pub(crate) bytes: Vec<u8>,
+    // set for data of clients with sockets, which is queued rather than
+    // handled as a packet.
+    pub(crate) is_payload: bool,
+}
+
+impl Emulator {
+    pub(crate) fn new(config: EmulationConfig) -> Emulator {
+        Emulator {
+            random: Random::new(config.seed),
+            config,
+            delayed_packets: BinaryHeap::new(),
+            next_sequence: 0,
+        }
+    }
+
+    //
+    // Applies a new configuration; the random number generator is only
+    // reseeded when the seed changes, so that reloading does not replay the
+    // same losses and delays.
+    //
+    pub(crate) fn configure(&mut self, config: EmulationConfig) {
+        if config.seed != self.config.seed {
+            self.random = Random::new(config.seed);
+        }
+        self.config = config;
+    }
+
+    //
+    // Returns the packets which should be handled immediately; delayed packets
+    // are returned by take_due_packets once their deadline has passed.
+    //
+    pub(crate) fn emulate(
+        &mut self,
+        direction: OutgoingDirection,
+        session_info: &SessionInfo,
+        bytes: Vec<u8>,
+        now: Instant,
+    ) -> Vec<Vec<u8>> {
+        self.emulate_datagram(direction, session_info, bytes, now, false)
+    }
+
+    //
+    // Emulates a datagram exchanged with a client through a socket like a
+    // packet.
+    //
+    pub(crate) fn emulate_payload(
+        &mut self,
+        direction: OutgoingDirection,
+        session_info: &SessionInfo,
+        bytes: Vec<u8>,
+        now: Instant,
+    ) -> Vec<Vec<u8>> {
+        self.emulate_datagram(direction, session_info, bytes, now, true)
+    }
+
+    //
+    // Only delays the data of a tcp connection, which is neither lost,
+    // duplicated nor reordered, and not before the data delayed before it;
+    // returns the data unless it is delayed. Delayed data is bounded by the
+    // queue limit of the session rather than by the emulator.
+    //
+    pub(crate) fn emulate_stream(
+        &mut self,
+        direction: OutgoingDirection,
+        session_info: &SessionInfo,
+        bytes: Vec<u8>,
+        now: Instant,
+        delay: &mut StreamDelay,
+    ) -> Option<Vec<u8>> {
+        let impairments = self.impairments(&direction, session_info);
+        let deadline = if impairments.is_none() {
+            now
+        } else {
+            now + self.delay(&impairments)
+        };
+        let deadline = delay.deadline.map_or(deadline, |last| last.max(deadline));
+        // bytes still delayed are delivered first to keep the stream in order.
+        if deadline <= now && delay.bytes == 0 {
+            return Some(bytes);
+        }
+        delay.deadline = Some(deadline);
+        delay.bytes += bytes.len();
+        self.delayed_packets.push(Reverse(DelayedPacket {
+            deadline,
+            sequence: self.next_sequence,
+            direction,
+            session_info: *session_info,
+            bytes,
+            is_payload: true,
+        }));
+        self.next_sequence += 1;
+        None
+    }
+
+    fn emulate_datagram(
+        &mut self,
+        direction: OutgoingDirection,
+        session_info: &SessionInfo,
+        bytes: Vec<u8>,
+        now: Instant,
+        is_payload: bool,
+    ) -> Vec<Vec<u8>> {
+        let impairments = self.impairments(&direction, session_info);
+        if impairments.is_none() {
+            return vec![bytes];
+        }
+
+        if self.random.chance(impairments.loss) {
+            log::trace!("emulating loss, session={:?}", session_info);
+            return vec![];
+        }
+
+        let copies = if self.random.chance(impairments.duplicate) {
+            log::trace!("emulating duplicate, session={:?}", session_info);
+            2
+        } else {
+            1
+        };
+
+        let mut ready_packets = Vec::new();
+        for _ in 0..copies {
+            let delay = if self.random.chance(impairments.reorder) {
+                log::trace!("emulating reorder, session={:?}", session_info);
+                Duration::ZERO
+            } else {
+                self.delay(&impairments)
+            };
+            if delay.is_zero() {
+                ready_packets.push(bytes.clone());
+            } else if self.delayed_packets.len() >= MAXIMUM_DELAYED_PACKETS {
+                log::warn!("dropped packet, too many delayed packets");
+            } else {
+                self.delayed_packets.push(Reverse(DelayedPacket {
+                    deadline: now + delay,
+                    sequence: self.next_sequence,
+                    direction,
+                    session_info: *session_info,
+                    bytes: bytes.clone(),
+                    is_payload,
+                }));
+                self.next_sequence += 1;
+            }
+        }
+        ready_packets
+    }
+
+    pub(crate) fn next_deadline(&self) -> Option<Instant> {
+        self.delayed_packets
+            .peek()
+            .map(|Reverse(packet)| packet.deadline)
+    }
+
+    pub(crate) fn take_due_packets(&mut self, now: Instant) -> Vec<DelayedPacket> {
+        let mut packets = Vec::new();
+        while let Some(Reverse(packet)) = self.delayed_packets.peek() {
+            if packet.deadline > now {
+                break;
+            }
+            if let Some(Reverse(packet)) = self.delayed_packets.pop() {
+                packets.push(packet);
+            }
+        }
+        packets
+    }
+
+    fn impairments(
+        &self,
+        direction: &OutgoingDirection,
+        session_info: &SessionInfo,
+    ) -> Impairments {
+        let rule = self.config.rules.iter().find(|rule| {
+            rule.destination.matches(
+                &session_info.destination,
+                session_info.transport_protocol.into(),
+            )
+        });
+        match (rule, direction) {
+            (Some(rule), OutgoingDirection::ToServer) => rule.upload,
+            (Some(rule), OutgoingDirection::ToClient) => rule.download,
+            (None, OutgoingDirection::ToServer) => self.config.upload,
+            (None, OutgoingDirection::ToClient) => self.config.download,
+        }
+    }
+
+    fn delay(&mut self, impairments: &Impairments) -> Duration {
+        let delay = &impairments.delay;
+        let latency = delay.latency.as_secs_f64();
+        let jitter = delay.jitter.as_secs_f64();
+        let offset = match delay.distribution {
+            Distribution::Uniform => jitter * (self.random.next_f64() * 2.0 - 1.0),
+            Distribution::Normal => jitter * self.random.next_normal(),
+        };
+        Duration::try_from_secs_f64((latency + offset).max(0.0))
+            .map_or(MAXIMUM_DELAY, |delay| delay.min(MAXIMUM_DELAY))
+    }
+}
+
+impl PartialEq for DelayedPacket {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for DelayedPacket {}
+
+impl PartialOrd for DelayedPacket {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for DelayedPacket {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.deadline, self.sequence).cmp(&(other.deadline, other.sequence))
+    }
+}
+
+//
+// SplitMix64; small and fast, and deterministic for a given seed.
+//
+struct Random {
+    state: u64,
+}
+
+impl Random {
+    fn new(seed: u64) -> Random {
+        Random { state: seed }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut value = self.state;
+        value = (value ^ (value >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        value = (value ^ (value >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        value ^ (value >> 31)
+    }
+
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn next_normal(&mut self) -> f64 {
+        // box-muller transform.
+        let u1 = 1.0 - self.next_f64();
+        let u2 = self.next_f64();
+        (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos()
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        probability > 0.0 && self.next_f64() < probability
+    }
+}
@@ -0,0 +1,71 @@
+// This is free and unencumbered software released into the public domain.
+//
+// Anyone is free to copy, modify, publish, use, compile, sell, or
+// distribute this software, either in source code form or as a compiled
+// binary, for any purpose, commercial or non-commercial, and by any
+// means.
+//
+// In jurisdictions that recognize copyright laws, the author or authors
+// of this software dedicate any and all copyright interest in the
+// software to the public domain. We make this dedication for the benefit
+// of the public at large and to the detriment of our heirs and
+// successors. We intend this dedication to be an overt act of
+// relinquishment in perpetuity of all present and future rights to this
+// software under copyright law.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS BE LIABLE FOR ANY CLAIM, DAMAGES OR
+// OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE,
+// ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR
+// OTHER DEALINGS IN THE SOFTWARE.
+//
+// For more information, please refer to <https://unlicense.org>
+
+//
+// Packets relayed over a stream are framed by prefixing each packet with its
+// length encoded as a big-endian u16. IP packets never exceed 65535 bytes so
+// the length always fits.
+//
+
+pub(crate) const LENGTH_PREFIX_SIZE: usize = 2;
+
+pub(crate) fn encode(packet: &[u8], output: &mut Vec<u8>) {
+    let length = packet.len() as u16;
+    output.extend_from_slice(&length.to_be_bytes());
+    output.extend_from_slice(packet);
+}
+
+pub(crate) struct Decoder {
+    buffer: Vec<u8>,
+}
+
+impl Decoder {
+    pub(crate) fn new() -> Decoder {
+        Decoder { buffer: Vec::new() }
+    }
+
+    pub(crate) fn push(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    pub(crate) fn next_packet(&mut self) -> Option<Vec<u8>> {
+        loop {
+            if self.buffer.len() < LENGTH_PREFIX_SIZE {
+                return None;
+            }
+            let length = u16::from_be_bytes([self.buffer[0], self.buffer[1]]) as usize;
+            let frame_length = LENGTH_PREFIX_SIZE + length;
+            if self.buffer.len() < frame_length {
+                return None;
+            }
+            let packet = self.buffer[LENGTH_PREFIX_SIZE..frame_length].to_vec();
+            self.buffer.drain(0..frame_length);
+            // empty frames carry no packet and are skipped.
+            if !packet.is_empty() {
+                return Some(packet);
+            }
+        }
+    }
+}
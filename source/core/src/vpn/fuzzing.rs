@@ -0,0 +1,83 @@
+// This is free and unencumbered software released into the public domain.
+//
+// Anyone is free to copy, modify, publish, use, compile, sell, or
+// distribute this software, either in source code form or as a compiled
+// binary, for any purpose, commercial or non-commercial, and by any
+// means.
+//
+// In jurisdictions that recognize copyright laws, the author or authors
+// of this software dedicate any and all copyright interest in the
+// software to the public domain. We make this dedication for the benefit
+// of the public at large and to the detriment of our heirs and
+// successors. We intend this dedication to be an overt act of
+// relinquishment in perpetuity of all present and future rights to this
+// software under copyright law.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS BE LIABLE FOR ANY CLAIM, DAMAGES OR
+// OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE,
+// ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR
+// OTHER DEALINGS IN THE SOFTWARE.
+//
+// For more information, please refer to <https://unlicense.org>
+
+//
+// Entry points used by the fuzz targets; these are not a supported api.
+//
+
+use crate::config::Config;
+use crate::vpn::{
+    packet_source::MemorySource, processor::Processor, session_info::SessionInfo, utils,
+};
+use std::sync::Once;
+
+pub fn session_info(bytes: &[u8]) {
+    let _ = SessionInfo::new(bytes);
+}
+
+pub fn log_packet(bytes: &[u8]) {
+    enable_logging();
+    let _ = utils::log_packet("fuzz", bytes);
+}
+
+//
+// Feeds the packets to a processor as if they had been read from the tun.
+// Sessions open real upstream sockets, so run this inside a network namespace
+// without routes.
+//
+pub fn handle_tun_event(packets: Vec<Vec<u8>>) {
+    enable_logging();
+    let mut processor = Processor::new(Box::new(MemorySource::new(packets)), Config::default());
+    processor.read_from_tun();
+}
+
+//
+// Installs a logger that formats and discards every record so that the
+// Display implementations used by logging are exercised as well.
+//
+fn enable_logging() {
+    static ONCE: Once = Once::new();
+    ONCE.call_once(|| {
+        if log::set_logger(&DISCARD_LOGGER).is_ok() {
+            log::set_max_level(log::LevelFilter::Trace);
+        }
+    });
+}
+
+struct DiscardLogger;
+
+static DISCARD_LOGGER: DiscardLogger = DiscardLogger;
+
+impl log::Log for DiscardLogger {
+    fn enabled(&self, _metadata: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        let _ = record.args().to_string();
+    }
+
+    fn flush(&self) {}
+}
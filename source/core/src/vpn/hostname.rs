@@ -0,0 +1,178 @@
+// This is free and unencumbered software released into the public domain.
+//
+// Anyone is free to copy, modify, publish, use, compile, sell, or
+// distribute this software, either in source code form or as a compiled
+// binary, for any purpose, commercial or non-commercial, and by any
+// means.
+//
+// In jurisdictions that recognize copyright laws, the author or authors
+// of this software dedicate any and all copyright interest in the
+// software to the public domain. We make this dedication for the benefit
+// of the public at large and to the detriment of our heirs and
+// successors. We intend this dedication to be an overt act of
+// relinquishment in perpetuity of all present and future rights to this
+// software under copyright law.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS BE LIABLE FOR ANY CLAIM, DAMAGES OR
+// OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE,
+// ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR
+// OTHER DEALINGS IN THE SOFTWARE.
+//
+// For more information, please refer to <https://unlicense.org>
+
+use crate::vpn::dns64;
+use std::{collections::HashMap, net::IpAddr};
+
+const TLS_HANDSHAKE: u8 = 0x16;
+const TLS_CLIENT_HELLO: u8 = 0x01;
+const TLS_RECORD_HEADER_LENGTH: usize = 5;
+const TLS_EXTENSION_SERVER_NAME: u16 = 0x0000;
+const TLS_SERVER_NAME_HOST: u8 = 0x00;
+
+const HTTP_METHODS: [&[u8]; 9] = [
+    b"GET ",
+    b"HEAD ",
+    b"POST ",
+    b"PUT ",
+    b"DELETE ",
+    b"CONNECT ",
+    b"OPTIONS ",
+    b"TRACE ",
+    b"PATCH ",
+];
+
+const MAXIMUM_HOSTNAME_LENGTH: usize = 253;
+const MAXIMUM_RESOLVED_NAMES: usize = 4096;
+
+//
+// Returns the server name of a tls client hello or the host of an http
+// request at the start of the payload a client sends.
+//
+pub(crate) fn sniff(payload: &[u8]) -> Option<String> {
+    match payload.first() {
+        Some(&TLS_HANDSHAKE) => server_name(payload),
+        Some(_) => http_host(payload),
+        None => None,
+    }
+}
+
+//
+// Only a client hello contained in the first record is considered.
+//
+fn server_name(payload: &[u8]) -> Option<String> {
+    let record_length = usize::from(read_u16(payload, 3)?);
+    let handshake = payload.get(TLS_RECORD_HEADER_LENGTH..)?;
+    let handshake = &handshake[..record_length.min(handshake.len())];
+    if *handshake.first()? != TLS_CLIENT_HELLO {
+        return None;
+    }
+    // handshake header, client version and random.
+    let mut offset = 4 + 2 + 32;
+    let session_id_length = usize::from(*handshake.get(offset)?);
+    offset += 1 + session_id_length;
+    let cipher_suites_length = usize::from(read_u16(handshake, offset)?);
+    offset += 2 + cipher_suites_length;
+    let compression_methods_length = usize::from(*handshake.get(offset)?);
+    offset += 1 + compression_methods_length;
+    let extensions_length = usize::from(read_u16(handshake, offset)?);
+    offset += 2;
+    let extensions_end = (offset + extensions_length).min(handshake.len());
+    while offset + 4 <= extensions_end {
+        let extension_type = read_u16(handshake, offset)?;
+        let extension_length = usize::from(read_u16(handshake, offset + 2)?);
+        offset += 4;
+        if extension_type == TLS_EXTENSION_SERVER_NAME {
+            // server name list length, name type and name length.
+            if *handshake.get(offset + 2)? != TLS_SERVER_NAME_HOST {
+                return None;
+            }
+            let name_length = usize::from(read_u16(handshake, offset + 3)?);
+            let name = handshake.get(offset + 5..offset + 5 + name_length)?;
+            return to_hostname(name);
+        }
+        offset += extension_length;
+    }
+    None
+}
+
+fn http_host(payload: &[u8]) -> Option<String> {
+    if !HTTP_METHODS
+        .iter()
+        .any(|method| payload.starts_with(method))
+    {
+        return None;
+    }
+    let headers_end = find(payload, b"\r\n\r\n").unwrap_or(payload.len());
+    payload[..headers_end]
+        .split(|byte| *byte == b'\n')
+        .skip(1)
+        .find_map(|line| {
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            let (name, value) = line.split_at(line.iter().position(|byte| *byte == b':')?);
+            if !name.eq_ignore_ascii_case(b"host") {
+                return None;
+            }
+            let value = std::str::from_utf8(&value[1..]).ok()?.trim();
+            // ipv6 literals are enclosed in brackets; a port may follow.
+            let host = match value.strip_prefix('[') {
+                Some(value) => value.split(']').next()?,
+                None => value.split(':').next()?,
+            };
+            to_hostname(host.as_bytes())
+        })
+}
+
+fn to_hostname(name: &[u8]) -> Option<String> {
+    let is_valid = !name.is_empty()
+        && name.len() <= MAXIMUM_HOSTNAME_LENGTH
+        && name
+            .iter()
+            .all(|byte| byte.is_ascii_alphanumeric() || b"-._:".contains(byte));
+    is_valid.then(|| String::from_utf8_lossy(name).to_ascii_lowercase())
+}
+
+fn find(bytes: &[u8], pattern: &[u8]) -> Option<usize> {
+    bytes
+        .windows(pattern.len())
+        .position(|window| window == pattern)
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> Option<u16> {
+    let bytes = bytes.get(offset..offset + 2)?;
+    Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+//
+// Remembers the names which dns answers resolved to addresses, so that
+// sessions to these addresses can be attributed to the name. The names are
+// forgotten all at once when too many have been collected.
+//
+pub(crate) struct ResolvedNames {
+    names: HashMap<IpAddr, String>,
+}
+
+impl ResolvedNames {
+    pub(crate) fn new() -> ResolvedNames {
+        ResolvedNames {
+            names: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn record_response(&mut self, message: &[u8]) {
+        if let Some((name, addresses)) = dns64::answered_addresses(message) {
+            for address in addresses {
+                if self.names.len() >= MAXIMUM_RESOLVED_NAMES {
+                    self.names.clear();
+                }
+                self.names.insert(address, name.clone());
+            }
+        }
+    }
+
+    pub(crate) fn get(&self, address: &IpAddr) -> Option<&String> {
+        self.names.get(address)
+    }
+}
@@ -0,0 +1,163 @@
+// This is free and unencumbered software released into the public domain.
+//
+// Anyone is free to copy, modify, publish, use, compile, sell, or
+// distribute this software, either in source code form or as a compiled
+// binary, for any purpose, commercial or non-commercial, and by any
+// means.
+//
+// In jurisdictions that recognize copyright laws, the author or authors
+// of this software dedicate any and all copyright interest in the
+// software to the public domain. We make this dedication for the benefit
+// of the public at large and to the detriment of our heirs and
+// successors. We intend this dedication to be an overt act of
+// relinquishment in perpetuity of all present and future rights to this
+// software under copyright law.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS BE LIABLE FOR ANY CLAIM, DAMAGES OR
+// OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE,
+// ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR
+// OTHER DEALINGS IN THE SOFTWARE.
+//
+// For more information, please refer to <https://unlicense.org>
+
+use smoltcp::{
+    phy::ChecksumCapabilities,
+    wire::{
+        Icmpv4DstUnreachable, Icmpv4Packet, Icmpv4Repr, Icmpv6DstUnreachable, Icmpv6Packet,
+        Icmpv6Repr, IpAddress, IpProtocol, Ipv4Packet, Ipv4Repr, Ipv6Packet, Ipv6Repr,
+    },
+};
+
+const HOP_LIMIT: u8 = 64;
+
+// icmp errors including the quoted packet must fit the minimum mtu; see rfc 1812 and rfc 4443.
+const IPV4_MAXIMUM_ERROR_LENGTH: usize = 576;
+const IPV6_MAXIMUM_ERROR_LENGTH: usize = 1280;
+
+//
+// Errors sent back to the client in place of a packet which is dropped.
+//
+#[derive(Clone, Copy)]
+enum Reason {
+    Unreachable,
+    // the mtu of the link the packet did not fit.
+    PacketTooBig(u16),
+}
+
+//
+// Builds the icmp error sent back to the client when the destination of a
+// packet cannot be reached, so that connections fail immediately instead of
+// timing out; the error is sent from the destination of the packet. No error
+// is built for packets sent to or from multicast and broadcast addresses.
+//
+pub(crate) fn destination_unreachable(bytes: &[u8]) -> crate::Result<Option<Vec<u8>>> {
+    error_reply(bytes, Reason::Unreachable)
+}
+
+//
+// Builds the icmp error sent back to the client when a packet is larger than
+// the mtu and may not be fragmented, fragmentation needed for ipv4 and packet
+// too big for ipv6, so that the client lowers its path mtu.
+//
+pub(crate) fn packet_too_big(bytes: &[u8], mtu: u16) -> crate::Result<Option<Vec<u8>>> {
+    error_reply(bytes, Reason::PacketTooBig(mtu))
+}
+
+fn error_reply(bytes: &[u8], reason: Reason) -> crate::Result<Option<Vec<u8>>> {
+    match bytes.first().map(|byte| byte >> 4) {
+        Some(4) => ipv4_error_reply(bytes, reason),
+        Some(6) => ipv6_error_reply(bytes, reason),
+        Some(version) => Err(crate::Error::UnsupportedInternetProtocol(version)),
+        None => Err(crate::Error::Wire(smoltcp::wire::Error)),
+    }
+}
+
+fn ipv4_error_reply(bytes: &[u8], reason: Reason) -> crate::Result<Option<Vec<u8>>> {
+    let checksum_capabilities = ChecksumCapabilities::default();
+    let ip_packet = Ipv4Packet::new_checked(bytes)?;
+    let ip_repr = Ipv4Repr::parse(&ip_packet, &checksum_capabilities)?;
+    if !ip_repr.src_addr.is_unicast() || !ip_repr.dst_addr.is_unicast() {
+        return Ok(None);
+    }
+
+    let header_length = ip_repr.buffer_len();
+    let maximum_data_length = IPV4_MAXIMUM_ERROR_LENGTH - 2 * header_length - 8;
+    let payload = ip_packet.payload();
+    let icmp_repr = Icmpv4Repr::DstUnreachable {
+        reason: match reason {
+            Reason::Unreachable => Icmpv4DstUnreachable::HostUnreachable,
+            Reason::PacketTooBig(_) => Icmpv4DstUnreachable::FragRequired,
+        },
+        header: ip_repr,
+        data: &payload[..payload.len().min(maximum_data_length)],
+    };
+    let reply_repr = Ipv4Repr {
+        src_addr: ip_repr.dst_addr,
+        dst_addr: ip_repr.src_addr,
+        next_header: IpProtocol::Icmp,
+        payload_len: icmp_repr.buffer_len(),
+        hop_limit: HOP_LIMIT,
+    };
+
+    let mut reply = vec![0; header_length + icmp_repr.buffer_len()];
+    reply_repr.emit(
+        &mut Ipv4Packet::new_unchecked(&mut reply[..]),
+        &checksum_capabilities,
+    );
+    icmp_repr.emit(
+        &mut Icmpv4Packet::new_unchecked(&mut reply[header_length..]),
+        &checksum_capabilities,
+    );
+    if let Reason::PacketTooBig(mtu) = reason {
+        // the next-hop mtu takes the second half of the unused field, see rfc 1191.
+        let icmp_bytes = &mut reply[header_length..];
+        icmp_bytes[6..8].copy_from_slice(&mtu.to_be_bytes());
+        Icmpv4Packet::new_unchecked(icmp_bytes).fill_checksum();
+    }
+    Ok(Some(reply))
+}
+
+fn ipv6_error_reply(bytes: &[u8], reason: Reason) -> crate::Result<Option<Vec<u8>>> {
+    let ip_packet = Ipv6Packet::new_checked(bytes)?;
+    let ip_repr = Ipv6Repr::parse(&ip_packet)?;
+    if !ip_repr.src_addr.is_unicast() || !ip_repr.dst_addr.is_unicast() {
+        return Ok(None);
+    }
+
+    let header_length = ip_repr.buffer_len();
+    let maximum_data_length = IPV6_MAXIMUM_ERROR_LENGTH - 2 * header_length - 8;
+    let payload = ip_packet.payload();
+    let data = &payload[..payload.len().min(maximum_data_length)];
+    let icmp_repr = match reason {
+        Reason::Unreachable => Icmpv6Repr::DstUnreachable {
+            reason: Icmpv6DstUnreachable::AddrUnreachable,
+            header: ip_repr,
+            data,
+        },
+        Reason::PacketTooBig(mtu) => Icmpv6Repr::PktTooBig {
+            mtu: u32::from(mtu),
+            header: ip_repr,
+            data,
+        },
+    };
+    let reply_repr = Ipv6Repr {
+        src_addr: ip_repr.dst_addr,
+        dst_addr: ip_repr.src_addr,
+        next_header: IpProtocol::Icmpv6,
+        payload_len: icmp_repr.buffer_len(),
+        hop_limit: HOP_LIMIT,
+    };
+
+    let mut reply = vec![0; header_length + icmp_repr.buffer_len()];
+    reply_repr.emit(&mut Ipv6Packet::new_unchecked(&mut reply[..]));
+    icmp_repr.emit(
+        &IpAddress::Ipv6(reply_repr.src_addr),
+        &IpAddress::Ipv6(reply_repr.dst_addr),
+        &mut Icmpv6Packet::new_unchecked(&mut reply[header_length..]),
+        &ChecksumCapabilities::default(),
+    );
+    Ok(Some(reply))
+}
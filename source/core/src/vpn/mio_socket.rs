@@ -23,6 +23,7 @@
 //
 // For more information, please refer to <https://unlicense.org>
 
+use crate::metrics::METRICS;
 use crate::tun_callbacks::on_socket_created;
 use mio::{
     net::{TcpStream, UdpSocket},
@@ -31,7 +32,7 @@ use mio::{
 use std::{
     io::{ErrorKind, Result},
     net::{Shutdown, SocketAddr},
-    os::unix::io::{AsRawFd, FromRawFd},
+    os::unix::io::AsRawFd,
 };
 
 pub(crate) enum TransportProtocol {
@@ -45,7 +46,6 @@ pub(crate) enum InternetProtocol {
 }
 
 pub(crate) struct Socket {
-    _socket: socket2::Socket, // Need to retain so socket does not get closed.
     connection: Connection,
 }
 
@@ -60,7 +60,14 @@ impl Socket {
         internet_protocol: InternetProtocol,
         remote_address: SocketAddr,
     ) -> Option<Socket> {
-        let socket = Self::create_socket(&transport_protocol, &internet_protocol);
+        let socket = match Self::create_socket(&transport_protocol, &internet_protocol) {
+            Ok(socket) => socket,
+            Err(error) => {
+                log::error!("failed to create socket, error={:?}", error);
+                METRICS.connect_failures.increment();
+                return None;
+            }
+        };
 
         on_socket_created(socket.as_raw_fd());
 
@@ -83,17 +90,31 @@ impl Socket {
                         error,
                         remote_address
                     );
+                    METRICS.connect_failures.increment();
                     return None;
                 }
             }
         }
 
-        let connection = Self::create_connection(&transport_protocol, &socket);
+        let connection = Self::create_connection(&transport_protocol, socket);
+
+        Some(Socket { connection })
+    }
 
-        Some(Socket {
-            _socket: socket,
-            connection,
-        })
+    //
+    // Wraps a socket connected to a client, e.g. one accepted by a transparent
+    // listener.
+    //
+    pub(crate) fn from_tcp_stream(stream: TcpStream) -> Socket {
+        Socket {
+            connection: Connection::Tcp(stream),
+        }
+    }
+
+    pub(crate) fn from_udp_socket(socket: UdpSocket) -> Socket {
+        Socket {
+            connection: Connection::Udp(socket),
+        }
     }
 
     pub(crate) fn register_poll(&mut self, poll: &mut Poll, token: Token) -> std::io::Result<()> {
@@ -123,10 +144,14 @@ impl Socket {
         }
     }
 
-    pub(crate) fn read(&mut self) -> Result<(Vec<Vec<u8>>, bool)> {
+    //
+    // Reads until the socket would block or at least limit bytes were read;
+    // returns the data read and whether the connection was closed.
+    //
+    pub(crate) fn read(&mut self, limit: usize) -> Result<(Vec<Vec<u8>>, bool)> {
         match &mut self.connection {
-            Connection::Tcp(connection) => Self::read_all(connection),
-            Connection::Udp(connection) => Self::read_all(connection),
+            Connection::Tcp(connection) => Self::read_all(connection, limit),
+            Connection::Udp(connection) => Self::read_all(connection, limit),
         }
     }
 
@@ -143,10 +168,59 @@ impl Socket {
         }
     }
 
+    pub(crate) fn shutdown_write(&self) {
+        if let Connection::Tcp(connection) = &self.connection {
+            if let Err(error) = connection.shutdown(Shutdown::Write) {
+                log::trace!("failed to shutdown tcp stream, error={:?}", error);
+            }
+        }
+    }
+
+    //
+    // Tcp connections are reset rather than closed once the socket is dropped.
+    //
+    pub(crate) fn reset_on_close(&self) {
+        if let Connection::Tcp(connection) = &self.connection {
+            let linger = libc::linger {
+                l_onoff: 1,
+                l_linger: 0,
+            };
+            let result = unsafe {
+                libc::setsockopt(
+                    connection.as_raw_fd(),
+                    libc::SOL_SOCKET,
+                    libc::SO_LINGER,
+                    &linger as *const libc::linger as *const libc::c_void,
+                    std::mem::size_of::<libc::linger>() as libc::socklen_t,
+                )
+            };
+            if result == -1 {
+                log::trace!(
+                    "failed to set linger, error={:?}",
+                    std::io::Error::last_os_error()
+                );
+            }
+        }
+    }
+
+    pub(crate) fn take_error(&self) -> Result<Option<std::io::Error>> {
+        match &self.connection {
+            Connection::Tcp(connection) => connection.take_error(),
+            Connection::Udp(connection) => connection.take_error(),
+        }
+    }
+
+    pub(crate) fn local_address(&self) -> Result<SocketAddr> {
+        match &self.connection {
+            Connection::Tcp(connection) => connection.local_addr(),
+            Connection::Udp(connection) => connection.local_addr(),
+        }
+    }
+
     fn create_socket(
         transport_protocol: &TransportProtocol,
         internet_protocol: &InternetProtocol,
-    ) -> socket2::Socket {
+    ) -> Result<socket2::Socket> {
         let domain = match internet_protocol {
             InternetProtocol::Ipv4 => socket2::Domain::IPV4,
             InternetProtocol::Ipv6 => socket2::Domain::IPV6,
@@ -162,38 +236,40 @@ impl Socket {
             TransportProtocol::Udp => socket2::Type::DGRAM,
         };
 
-        let socket = socket2::Socket::new(domain, socket_type, Some(protocol)).unwrap();
+        let socket = socket2::Socket::new(domain, socket_type, Some(protocol))?;
 
-        socket.set_nonblocking(true).unwrap();
+        socket.set_nonblocking(true)?;
 
-        socket
+        Ok(socket)
     }
 
     fn create_connection(
         transport_protocol: &TransportProtocol,
-        socket: &socket2::Socket,
+        socket: socket2::Socket,
     ) -> Connection {
+        // The connection takes ownership of the file descriptor so that it is closed exactly once.
         match transport_protocol {
-            TransportProtocol::Tcp => {
-                let tcp_stream = unsafe { TcpStream::from_raw_fd(socket.as_raw_fd()) };
-                Connection::Tcp(tcp_stream)
-            }
-            TransportProtocol::Udp => {
-                let udp_socket = unsafe { UdpSocket::from_raw_fd(socket.as_raw_fd()) };
-                Connection::Udp(udp_socket)
-            }
+            TransportProtocol::Tcp => Connection::Tcp(TcpStream::from_std(socket.into())),
+            TransportProtocol::Udp => Connection::Udp(UdpSocket::from_std(socket.into())),
         }
     }
 
-    fn read_all<R>(reader: &mut R) -> Result<(Vec<Vec<u8>>, bool)>
+    fn read_all<R>(reader: &mut R, limit: usize) -> Result<(Vec<Vec<u8>>, bool)>
     where
         R: Read,
     {
         let mut bytes: Vec<Vec<u8>> = Vec::new();
         let mut buffer = [0; 1 << 16]; // maximum UDP packet size
         let mut is_closed = false;
-        loop {
-            match reader.read(&mut buffer[..]) {
+        let mut total = 0;
+        while total < limit {
+            // datagrams are read whole, streams only up to the limit.
+            let length = if R::IS_STREAM {
+                buffer.len().min(limit - total)
+            } else {
+                buffer.len()
+            };
+            match reader.read(&mut buffer[..length]) {
                 Ok(count) => {
                     if count == 0 {
                         is_closed = true;
@@ -201,6 +277,7 @@ impl Socket {
                     }
                     // bytes.extend_from_slice(&buffer[..count]);
                     let data = buffer[..count].to_vec();
+                    total += count;
                     bytes.push(data)
                 }
                 Err(error_code) => {
@@ -217,16 +294,22 @@ impl Socket {
 }
 
 trait Read {
+    const IS_STREAM: bool;
+
     fn read(&mut self, buf: &mut [u8]) -> Result<usize>;
 }
 
 impl Read for mio::net::UdpSocket {
+    const IS_STREAM: bool = false;
+
     fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
         self.recv(buf)
     }
 }
 
 impl Read for mio::net::TcpStream {
+    const IS_STREAM: bool = true;
+
     fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
         <mio::net::TcpStream as std::io::Read>::read(self, buf)
     }
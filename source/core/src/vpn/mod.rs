@@ -24,40 +24,247 @@
 // For more information, please refer to <https://unlicense.org>
 
 mod buffers;
+mod capture;
+mod dns64;
+mod emulation;
+mod framing;
+#[cfg(feature = "fuzzing")]
+pub mod fuzzing;
+mod hostname;
+mod icmp;
 mod mio_socket;
+mod mtu;
+mod nat64;
+mod packet_source;
+mod process_lookups;
 mod processor;
+mod reassembly;
+mod relay;
 mod session;
 mod session_info;
+mod session_rate;
+mod shaping;
 mod smoltcp_socket;
+mod socks;
+mod transparent;
+#[cfg(target_os = "linux")]
+mod transparent_socket;
+mod tun;
 mod utils;
 mod vpn_device;
 
-use mio::Waker;
-use processor::Processor;
+use crate::config::Config;
+use crate::statistics::{SessionSummary, Statistics};
+use packet_source::{DatagramSource, EmptySource, PacketSource, StreamSource, TunSource};
+use processor::{Command, Control, Processor};
+use std::fs::File;
+use std::net::TcpStream;
+use std::os::unix::net::UnixDatagram;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc::channel, Arc};
 
-pub(super) struct Vpn {
-    file_descriptor: i32,
-    stop_waker: Option<Waker>,
+pub(super) use relay::Relay;
+pub use transparent::Listeners;
+
+pub struct Vpn {
+    packet_source: Option<Box<dyn PacketSource>>,
+    listeners: Option<Listeners>,
+    control: Option<Control>,
     thread_join_handle: Option<std::thread::JoinHandle<()>>,
+    // set by the thread of the processor once it has finished, which still
+    // tells while the join handle is held by a waiting caller.
+    is_finished: Arc<AtomicBool>,
 }
 
 impl Vpn {
     pub fn new(file_descriptor: i32) -> Self {
+        Self::with_packet_source(Box::new(TunSource::new(file_descriptor)))
+    }
+
+    pub fn new_relay(stream: TcpStream) -> crate::Result<Self> {
+        Ok(Self::with_packet_source(Box::new(StreamSource::new(
+            stream,
+        )?)))
+    }
+
+    //
+    // Each datagram received on the socket is handled as a packet read from the
+    // tun, which allows driving the vpn through a socketpair.
+    //
+    pub fn new_datagram(socket: UnixDatagram) -> crate::Result<Self> {
+        Ok(Self::with_packet_source(Box::new(DatagramSource::new(
+            socket,
+        )?)))
+    }
+
+    //
+    // A vpn without a tun, whose sessions all come from the listeners.
+    //
+    pub fn new_listeners(listeners: Listeners) -> Self {
+        let mut vpn = Self::with_packet_source(Box::new(EmptySource));
+        vpn.listen(listeners);
+        vpn
+    }
+
+    fn with_packet_source(packet_source: Box<dyn PacketSource>) -> Self {
         Self {
-            file_descriptor,
-            stop_waker: None,
+            packet_source: Some(packet_source),
+            listeners: None,
+            control: None,
             thread_join_handle: None,
+            is_finished: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    //
+    // Hands a running vpn over to a new tun file descriptor, e.g. when the tun
+    // has been re-established, keeping all sessions; the previous file is
+    // closed. Fails when the processor has already stopped.
+    //
+    pub fn replace_tun(&mut self, file_descriptor: i32) -> crate::Result<()> {
+        self.replace_packet_source(Box::new(TunSource::new(file_descriptor)))
+    }
+
+    pub fn replace_datagram(&mut self, socket: UnixDatagram) -> crate::Result<()> {
+        self.replace_packet_source(Box::new(DatagramSource::new(socket)?))
+    }
+
+    fn replace_packet_source(&mut self, packet_source: Box<dyn PacketSource>) -> crate::Result<()> {
+        match &self.control {
+            Some(control) => control.try_send(Command::ReplacePacketSource(packet_source)),
+            None => {
+                self.packet_source = Some(packet_source);
+                Ok(())
+            }
+        }
+    }
+
+    //
+    // Forwards the connections and datagrams of the listeners next to the
+    // packets of the vpn; takes effect when the vpn is started.
+    //
+    pub fn listen(&mut self, listeners: Listeners) {
+        self.listeners = Some(listeners);
+    }
+
+    //
+    // Whether the processor has been started and not yet finished, also while
+    // another caller waits for it.
+    //
+    pub fn is_running(&self) -> bool {
+        let is_thread_finished = match &self.thread_join_handle {
+            Some(thread_join_handle) => thread_join_handle.is_finished(),
+            None => self.is_finished.load(Ordering::Acquire),
+        };
+        self.control.is_some() && !is_thread_finished
+    }
+
+    //
+    // A vpn is started once; starting it again fails.
+    //
+    pub fn start(&mut self, config: Config) -> crate::Result<()> {
+        let Some(packet_source) = self.packet_source.take() else {
+            return Err(crate::Error::AlreadyStarted);
+        };
+        let mut processor = Processor::new(packet_source, config);
+        processor.register()?;
+        if let Some(listeners) = self.listeners.take() {
+            processor.listen(listeners)?;
         }
+        self.control = Some(processor.new_control()?);
+        let is_finished = self.is_finished.clone();
+        self.thread_join_handle = Some(std::thread::spawn(move || {
+            processor.run();
+            is_finished.store(true, Ordering::Release);
+        }));
+        Ok(())
     }
 
-    pub fn start(&mut self) {
-        let mut processor = Processor::new(self.file_descriptor);
-        self.stop_waker = Some(processor.new_stop_waker());
-        self.thread_join_handle = Some(std::thread::spawn(move || processor.run()));
+    pub fn configure(&self, config: Config) {
+        if let Some(control) = &self.control {
+            control.send(Command::Configure(Box::new(config), false));
+        }
+    }
+
+    //
+    // Applies the configuration like configure and additionally closes existing
+    // sessions which its access rules deny.
+    //
+    pub fn reload(&self, config: Config) {
+        if let Some(control) = &self.control {
+            control.send(Command::Configure(Box::new(config), true));
+        }
+    }
+
+    //
+    // Releases memory held by idle sessions, e.g. when the system is low on
+    // memory.
+    //
+    pub fn trim_memory(&self) {
+        if let Some(control) = &self.control {
+            control.send(Command::TrimMemory);
+        }
+    }
+
+    //
+    // Asks the processor to stop without waiting for it, e.g. while sessions
+    // are drained; stop waits for the processor to finish.
+    //
+    pub fn request_stop(&self) {
+        if let Some(control) = &self.control {
+            control.send(Command::Stop);
+        }
+    }
+
+    //
+    // Returns the sessions of a running vpn; the processor answers between
+    // event batches.
+    //
+    pub fn sessions(&self) -> Vec<SessionSummary> {
+        let Some(control) = &self.control else {
+            return Vec::new();
+        };
+        let (sender, receiver) = channel();
+        control.send(Command::Sessions(sender));
+        receiver.recv().unwrap_or_default()
+    }
+
+    pub fn statistics(&self) -> Statistics {
+        let Some(control) = &self.control else {
+            return Statistics::default();
+        };
+        let (sender, receiver) = channel();
+        control.send(Command::Statistics(sender));
+        receiver.recv().unwrap_or_default()
+    }
+
+    //
+    // Writes packets exchanged with the tun to the file in pcap format until
+    // the capture is stopped; a running capture is replaced.
+    //
+    pub fn start_capture(&self, file: File) {
+        if let Some(control) = &self.control {
+            control.send(Command::StartCapture(file));
+        }
+    }
+
+    pub fn stop_capture(&self) {
+        if let Some(control) = &self.control {
+            control.send(Command::StopCapture);
+        }
     }
 
     pub fn stop(&mut self) {
-        self.stop_waker.as_ref().unwrap().wake().unwrap();
-        self.thread_join_handle.take().unwrap().join().unwrap();
+        // a vpn which failed to start has nothing to stop.
+        if let Some(control) = &self.control {
+            control.send(Command::Stop);
+        }
+        if let Some(thread_join_handle) = self.thread_join_handle.take() {
+            thread_join_handle.join().unwrap();
+        }
+    }
+
+    pub(crate) fn take_thread_join_handle(&mut self) -> Option<std::thread::JoinHandle<()>> {
+        self.thread_join_handle.take()
     }
 }
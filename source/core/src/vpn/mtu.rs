@@ -0,0 +1,261 @@
+// This is free and unencumbered software released into the public domain.
+//
+// Anyone is free to copy, modify, publish, use, compile, sell, or
+// distribute this software, either in source code form or as a compiled
+// binary, for any purpose, commercial or non-commercial, and by any
+// means.
+//
+// In jurisdictions that recognize copyright laws, the author or authors
+// of this software dedicate any and all copyright interest in the
+// software to the public domain. We make this dedication for the benefit
+// of the public at large and to the detriment of our heirs and
+// successors. We intend this dedication to be an overt act of
+// relinquishment in perpetuity of all present and future rights to this
+// software under copyright law.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS BE LIABLE FOR ANY CLAIM, DAMAGES OR
+// OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE,
+// ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR
+// OTHER DEALINGS IN THE SOFTWARE.
+//
+// For more information, please refer to <https://unlicense.org>
+
+use crate::config::TunConfig;
+use crate::vpn::reassembly::parse_ipv6_headers;
+use crate::vpn::session_info::InternetProtocol;
+use crate::Error;
+use smoltcp::wire::{IpAddress, IpProtocol, Ipv4Packet, Ipv6Packet, TcpPacket};
+
+// smallest mtu allowed for ipv4 links, see rfc 791.
+const IPV4_MINIMUM_MTU: usize = 68;
+// smallest mtu allowed for ipv6 links, see rfc 8200.
+const IPV6_MINIMUM_MTU: usize = 1280;
+const MAXIMUM_MTU: usize = 65535;
+
+const IPV4_HEADER_LENGTH: usize = 20;
+const IPV6_HEADER_LENGTH: usize = 40;
+const IPV6_FRAGMENT_HEADER_LENGTH: usize = 8;
+const TCP_HEADER_LENGTH: usize = 20;
+
+const TCP_OPTION_END: u8 = 0;
+const TCP_OPTION_NOP: u8 = 1;
+const TCP_OPTION_MSS: u8 = 2;
+const TCP_OPTION_MSS_LENGTH: usize = 4;
+
+//
+// Returns the configured mtu, raised to the minimum of the internet protocol.
+//
+pub(crate) fn mtu(config: &TunConfig, internet_protocol: InternetProtocol) -> usize {
+    let minimum_mtu = match internet_protocol {
+        InternetProtocol::Ipv4 => IPV4_MINIMUM_MTU,
+        InternetProtocol::Ipv6 => IPV6_MINIMUM_MTU,
+    };
+    config.mtu.clamp(minimum_mtu, MAXIMUM_MTU)
+}
+
+//
+// Returns the mtu for the internet protocol of the packet; packets other than
+// ipv6 ones are taken for ipv4.
+//
+pub(crate) fn packet_mtu(bytes: &[u8], config: &TunConfig) -> usize {
+    let internet_protocol = match bytes.first().map(|byte| byte >> 4) {
+        Some(6) => InternetProtocol::Ipv6,
+        _ => InternetProtocol::Ipv4,
+    };
+    mtu(config, internet_protocol)
+}
+
+//
+// Returns true for packets larger than the mtu which may not be fragmented,
+// that is ipv6 packets and ipv4 packets with the don't fragment flag set.
+//
+pub(crate) fn is_too_big(bytes: &[u8], mtu: usize) -> bool {
+    if bytes.len() <= mtu {
+        return false;
+    }
+    match bytes.first().map(|byte| byte >> 4) {
+        Some(4) => Ipv4Packet::new_checked(bytes).is_ok_and(|packet| packet.dont_frag()),
+        Some(6) => true,
+        _ => false,
+    }
+}
+
+//
+// Lowers the mss option of tcp syns so that segments sent in reply fit the
+// mtu; other packets are left unchanged.
+//
+pub(crate) fn clamp_mss(bytes: &mut [u8], config: &TunConfig) -> crate::Result<()> {
+    let (source, destination, tcp_range, internet_protocol) =
+        match bytes.first().map(|byte| byte >> 4) {
+            Some(4) => {
+                let packet = Ipv4Packet::new_checked(&bytes[..])?;
+                let is_fragment = packet.more_frags() || packet.frag_offset() != 0;
+                if packet.next_header() != IpProtocol::Tcp || is_fragment {
+                    return Ok(());
+                }
+                let range = usize::from(packet.header_len())..usize::from(packet.total_len());
+                (
+                    IpAddress::Ipv4(packet.src_addr()),
+                    IpAddress::Ipv4(packet.dst_addr()),
+                    range,
+                    InternetProtocol::Ipv4,
+                )
+            }
+            Some(6) => {
+                let packet = Ipv6Packet::new_checked(&bytes[..])?;
+                let headers = parse_ipv6_headers(packet.next_header(), packet.payload())?;
+                if headers.protocol != IpProtocol::Tcp || headers.is_fragmented() {
+                    return Ok(());
+                }
+                let start = IPV6_HEADER_LENGTH + headers.offset;
+                let range = start..IPV6_HEADER_LENGTH + usize::from(packet.payload_len());
+                (
+                    IpAddress::Ipv6(packet.src_addr()),
+                    IpAddress::Ipv6(packet.dst_addr()),
+                    range,
+                    InternetProtocol::Ipv6,
+                )
+            }
+            Some(version) => return Err(Error::UnsupportedInternetProtocol(version)),
+            None => return Err(Error::Wire(smoltcp::wire::Error)),
+        };
+
+    let header_length = match internet_protocol {
+        InternetProtocol::Ipv4 => IPV4_HEADER_LENGTH,
+        InternetProtocol::Ipv6 => IPV6_HEADER_LENGTH,
+    };
+    let mtu = mtu(config, internet_protocol);
+    let maximum_segment_size = (mtu - header_length - TCP_HEADER_LENGTH) as u16;
+    let maximum_segment_size = config
+        .max_segment_size
+        .map_or(maximum_segment_size, |value| {
+            value.min(maximum_segment_size)
+        });
+
+    let segment = bytes
+        .get_mut(tcp_range)
+        .ok_or(Error::Wire(smoltcp::wire::Error))?;
+    if clamp_option(segment, maximum_segment_size)? {
+        TcpPacket::new_unchecked(segment).fill_checksum(&source, &destination);
+    }
+    Ok(())
+}
+
+fn clamp_option(segment: &mut [u8], maximum_segment_size: u16) -> crate::Result<bool> {
+    let packet = TcpPacket::new_checked(&segment[..])?;
+    if !packet.syn() {
+        return Ok(false);
+    }
+    let options_range = TCP_HEADER_LENGTH..usize::from(packet.header_len());
+    let options = &mut segment[options_range];
+
+    let mut offset = 0;
+    while offset < options.len() {
+        let kind = options[offset];
+        if kind == TCP_OPTION_END {
+            break;
+        }
+        if kind == TCP_OPTION_NOP {
+            offset += 1;
+            continue;
+        }
+        let length = usize::from(*options.get(offset + 1).unwrap_or(&0));
+        if length < 2 || offset + length > options.len() {
+            return Err(Error::Wire(smoltcp::wire::Error));
+        }
+        if kind == TCP_OPTION_MSS && length == TCP_OPTION_MSS_LENGTH {
+            let value = &mut options[offset + 2..offset + 4];
+            if u16::from_be_bytes([value[0], value[1]]) > maximum_segment_size {
+                value.copy_from_slice(&maximum_segment_size.to_be_bytes());
+                return Ok(true);
+            }
+            return Ok(false);
+        }
+        offset += length;
+    }
+    Ok(false)
+}
+
+//
+// Splits packets larger than the mtu into fragments. Only packets built by the
+// smoltcp interface are fragmented; these carry no extension headers, so the
+// fragment header of ipv6 packets directly follows the fixed header.
+//
+pub(crate) struct Fragmenter {
+    identification: u32,
+}
+
+impl Fragmenter {
+    pub(crate) fn new() -> Fragmenter {
+        Fragmenter { identification: 0 }
+    }
+
+    pub(crate) fn fragment(&mut self, bytes: Vec<u8>, mtu: usize) -> crate::Result<Vec<Vec<u8>>> {
+        if bytes.len() <= mtu {
+            return Ok(vec![bytes]);
+        }
+        self.identification = self.identification.wrapping_add(1);
+        match bytes.first().map(|byte| byte >> 4) {
+            Some(4) => self.fragment_ipv4(&bytes, mtu),
+            Some(6) => self.fragment_ipv6(&bytes, mtu),
+            Some(version) => Err(Error::UnsupportedInternetProtocol(version)),
+            None => Err(Error::Wire(smoltcp::wire::Error)),
+        }
+    }
+
+    fn fragment_ipv4(&self, bytes: &[u8], mtu: usize) -> crate::Result<Vec<Vec<u8>>> {
+        let packet = Ipv4Packet::new_checked(bytes)?;
+        let header = &bytes[..usize::from(packet.header_len())];
+        let fragment_length = (mtu - header.len()) & !7;
+
+        let chunks = packet.payload().chunks(fragment_length);
+        let count = chunks.len();
+        let fragments = chunks
+            .enumerate()
+            .map(|(index, chunk)| {
+                let mut fragment = [header, chunk].concat();
+                let mut fragment_packet = Ipv4Packet::new_unchecked(&mut fragment[..]);
+                fragment_packet.set_total_len((header.len() + chunk.len()) as u16);
+                fragment_packet.set_ident(self.identification as u16);
+                fragment_packet.set_dont_frag(false);
+                fragment_packet.set_more_frags(index + 1 < count);
+                fragment_packet.set_frag_offset((index * fragment_length) as u16);
+                fragment_packet.fill_checksum();
+                fragment
+            })
+            .collect();
+        Ok(fragments)
+    }
+
+    fn fragment_ipv6(&self, bytes: &[u8], mtu: usize) -> crate::Result<Vec<Vec<u8>>> {
+        let packet = Ipv6Packet::new_checked(bytes)?;
+        let header = &bytes[..IPV6_HEADER_LENGTH];
+        let next_header = u8::from(packet.next_header());
+        let fragment_length = (mtu - IPV6_HEADER_LENGTH - IPV6_FRAGMENT_HEADER_LENGTH) & !7;
+
+        let chunks = packet.payload().chunks(fragment_length);
+        let count = chunks.len();
+        let fragments = chunks
+            .enumerate()
+            .map(|(index, chunk)| {
+                let more_fragments = u16::from(index + 1 < count);
+                let offset_and_flags = (index * fragment_length) as u16 | more_fragments;
+
+                let mut fragment = header.to_vec();
+                fragment.extend_from_slice(&[next_header, 0]);
+                fragment.extend_from_slice(&offset_and_flags.to_be_bytes());
+                fragment.extend_from_slice(&self.identification.to_be_bytes());
+                fragment.extend_from_slice(chunk);
+
+                let mut fragment_packet = Ipv6Packet::new_unchecked(&mut fragment[..]);
+                fragment_packet.set_next_header(IpProtocol::Ipv6Frag);
+                fragment_packet.set_payload_len((IPV6_FRAGMENT_HEADER_LENGTH + chunk.len()) as u16);
+                fragment
+            })
+            .collect();
+        Ok(fragments)
+    }
+}
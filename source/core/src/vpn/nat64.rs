@@ -0,0 +1,87 @@
+// This is free and unencumbered software released into the public domain.
+//
+// Anyone is free to copy, modify, publish, use, compile, sell, or
+// distribute this software, either in source code form or as a compiled
+// binary, for any purpose, commercial or non-commercial, and by any
+// means.
+//
+// In jurisdictions that recognize copyright laws, the author or authors
+// of this software dedicate any and all copyright interest in the
+// software to the public domain. We make this dedication for the benefit
+// of the public at large and to the detriment of our heirs and
+// successors. We intend this dedication to be an overt act of
+// relinquishment in perpetuity of all present and future rights to this
+// software under copyright law.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS BE LIABLE FOR ANY CLAIM, DAMAGES OR
+// OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE,
+// ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR
+// OTHER DEALINGS IN THE SOFTWARE.
+//
+// For more information, please refer to <https://unlicense.org>
+
+use crate::config::{Nat64Config, Network};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+const VALID_PREFIX_LENGTHS: [u8; 6] = [32, 40, 48, 56, 64, 96];
+
+// octet which must be zero in addresses synthesized with prefixes shorter than 96.
+const RESERVED_OCTET: usize = 8;
+
+//
+// Returns the address the upstream socket of a session connects to.
+//
+pub(crate) fn upstream_address(
+    config: Option<&Nat64Config>,
+    destination: SocketAddr,
+) -> SocketAddr {
+    let (Some(config), IpAddr::V4(address)) = (config, destination.ip()) else {
+        return destination;
+    };
+    // the well-known prefix must not be used for non-global addresses, see rfc 6052 section 3.1.
+    if config.prefix == Nat64Config::WELL_KNOWN_PREFIX && !is_global(&address) {
+        return destination;
+    }
+    match synthesize_address(&config.prefix, address) {
+        Some(synthesized) => SocketAddr::new(IpAddr::V6(synthesized), destination.port()),
+        None => {
+            log::error!("invalid nat64 prefix, prefix={:?}", config.prefix);
+            destination
+        }
+    }
+}
+
+//
+// Embeds the ipv4 address in the prefix following rfc 6052 section 2.2.
+//
+pub(crate) fn synthesize_address(prefix: &Network, address: Ipv4Addr) -> Option<Ipv6Addr> {
+    let IpAddr::V6(prefix_address) = prefix.address else {
+        return None;
+    };
+    if !VALID_PREFIX_LENGTHS.contains(&prefix.prefix_length) {
+        return None;
+    }
+    let mut octets = [0; 16];
+    let prefix_octets = usize::from(prefix.prefix_length / 8);
+    octets[..prefix_octets].copy_from_slice(&prefix_address.octets()[..prefix_octets]);
+    let positions = (prefix_octets..16).filter(|position| *position != RESERVED_OCTET);
+    for (position, octet) in positions.zip(address.octets()) {
+        octets[position] = octet;
+    }
+    Some(Ipv6Addr::from(octets))
+}
+
+fn is_global(address: &Ipv4Addr) -> bool {
+    let is_shared = address.octets()[0] == 100 && (address.octets()[1] & 0xc0) == 64;
+    !(address.is_private()
+        || address.is_loopback()
+        || address.is_link_local()
+        || address.is_broadcast()
+        || address.is_documentation()
+        || address.is_unspecified()
+        || address.is_multicast()
+        || is_shared)
+}
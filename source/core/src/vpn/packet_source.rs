@@ -0,0 +1,310 @@
+// This is free and unencumbered software released into the public domain.
+//
+// Anyone is free to copy, modify, publish, use, compile, sell, or
+// distribute this software, either in source code form or as a compiled
+// binary, for any purpose, commercial or non-commercial, and by any
+// means.
+//
+// In jurisdictions that recognize copyright laws, the author or authors
+// of this software dedicate any and all copyright interest in the
+// software to the public domain. We make this dedication for the benefit
+// of the public at large and to the detriment of our heirs and
+// successors. We intend this dedication to be an overt act of
+// relinquishment in perpetuity of all present and future rights to this
+// software under copyright law.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS BE LIABLE FOR ANY CLAIM, DAMAGES OR
+// OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE,
+// ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR
+// OTHER DEALINGS IN THE SOFTWARE.
+//
+// For more information, please refer to <https://unlicense.org>
+
+use crate::vpn::framing::{self, Decoder};
+use mio::{
+    net::{TcpStream, UnixDatagram},
+    unix::SourceFd,
+    Interest, Registry, Token,
+};
+use std::{
+    fs::File,
+    io::{ErrorKind, Read, Result, Write},
+    os::unix::io::{AsRawFd, FromRawFd},
+};
+
+//
+// Source and sink of raw IP packets handled by the processor.
+//
+// Reading follows the semantics of reading from the tun file; a count of zero
+// means the source has been closed and an error of kind WouldBlock means no
+// packets are currently available.
+//
+pub(crate) trait PacketSource: Send {
+    fn register(&mut self, registry: &Registry, token: Token) -> Result<()>;
+
+    fn deregister(&mut self, registry: &Registry) -> Result<()>;
+
+    fn read_packet(&mut self, buffer: &mut [u8]) -> Result<usize>;
+
+    fn write_packet(&mut self, bytes: &[u8]) -> Result<()>;
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+pub(crate) struct TunSource {
+    file: File,
+}
+
+impl TunSource {
+    pub(crate) fn new(file_descriptor: i32) -> TunSource {
+        TunSource {
+            file: unsafe { File::from_raw_fd(file_descriptor) },
+        }
+    }
+}
+
+impl PacketSource for TunSource {
+    fn register(&mut self, registry: &Registry, token: Token) -> Result<()> {
+        let file_descriptor = self.file.as_raw_fd();
+        registry.register(&mut SourceFd(&file_descriptor), token, Interest::READABLE)
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> Result<()> {
+        let file_descriptor = self.file.as_raw_fd();
+        registry.deregister(&mut SourceFd(&file_descriptor))
+    }
+
+    fn read_packet(&mut self, buffer: &mut [u8]) -> Result<usize> {
+        self.file.read(buffer)
+    }
+
+    fn write_packet(&mut self, bytes: &[u8]) -> Result<()> {
+        self.file.write_all(bytes)
+    }
+}
+
+//
+// Frames written while the stream is backed up are queued up to this many
+// bytes; further packets are refused with WouldBlock, as a full tun queue
+// drops packets, until the queue is flushed.
+//
+const MAXIMUM_PENDING_WRITES: usize = 1 << 20;
+
+pub(crate) struct StreamSource {
+    stream: TcpStream,
+    decoder: Decoder,
+    pending_writes: Vec<u8>,
+    is_closed: bool,
+}
+
+impl StreamSource {
+    pub(crate) fn new(stream: std::net::TcpStream) -> Result<StreamSource> {
+        stream.set_nonblocking(true)?;
+        stream.set_nodelay(true)?;
+        Ok(StreamSource {
+            stream: TcpStream::from_std(stream),
+            decoder: Decoder::new(),
+            pending_writes: Vec::new(),
+            is_closed: false,
+        })
+    }
+
+    fn fill_decoder(&mut self) -> Result<()> {
+        let mut buffer = [0; 1 << 16];
+        loop {
+            match self.stream.read(&mut buffer) {
+                Ok(0) => {
+                    self.is_closed = true;
+                    return Ok(());
+                }
+                Ok(count) => {
+                    self.decoder.push(&buffer[..count]);
+                }
+                Err(error) if error.kind() == ErrorKind::WouldBlock => {
+                    return Ok(());
+                }
+                Err(error) if error.kind() == ErrorKind::Interrupted => {
+                    // retry read.
+                }
+                Err(error) => {
+                    return Err(error);
+                }
+            }
+        }
+    }
+}
+
+impl PacketSource for StreamSource {
+    fn register(&mut self, registry: &Registry, token: Token) -> Result<()> {
+        let interests = Interest::READABLE | Interest::WRITABLE;
+        registry.register(&mut self.stream, token, interests)
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> Result<()> {
+        registry.deregister(&mut self.stream)
+    }
+
+    fn read_packet(&mut self, buffer: &mut [u8]) -> Result<usize> {
+        if let Some(packet) = self.decoder.next_packet() {
+            return copy_packet(&packet, buffer);
+        }
+        if !self.is_closed {
+            self.fill_decoder()?;
+        }
+        match self.decoder.next_packet() {
+            Some(packet) => copy_packet(&packet, buffer),
+            None if self.is_closed => Ok(0),
+            None => Err(ErrorKind::WouldBlock.into()),
+        }
+    }
+
+    fn write_packet(&mut self, bytes: &[u8]) -> Result<()> {
+        let frame_length = framing::LENGTH_PREFIX_SIZE + bytes.len();
+        if self.pending_writes.len() + frame_length > MAXIMUM_PENDING_WRITES {
+            match self.flush() {
+                Err(error) if error.kind() == ErrorKind::WouldBlock => {}
+                result => result?,
+            }
+            if self.pending_writes.len() + frame_length > MAXIMUM_PENDING_WRITES {
+                return Err(ErrorKind::WouldBlock.into());
+            }
+        }
+        framing::encode(bytes, &mut self.pending_writes);
+        match self.flush() {
+            Err(error) if error.kind() == ErrorKind::WouldBlock => Ok(()),
+            result => result,
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        while !self.pending_writes.is_empty() {
+            match self.stream.write(&self.pending_writes) {
+                Ok(0) => {
+                    return Err(ErrorKind::WriteZero.into());
+                }
+                Ok(count) => {
+                    self.pending_writes.drain(0..count);
+                }
+                Err(error) if error.kind() == ErrorKind::Interrupted => {
+                    // retry write.
+                }
+                Err(error) => {
+                    return Err(error);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+//
+// Exchanges packets as datagrams over a unix socket, e.g. one end of a
+// socketpair, which allows running the processor without a tun device.
+//
+pub(crate) struct DatagramSource {
+    socket: UnixDatagram,
+}
+
+impl DatagramSource {
+    pub(crate) fn new(socket: std::os::unix::net::UnixDatagram) -> Result<DatagramSource> {
+        socket.set_nonblocking(true)?;
+        Ok(DatagramSource {
+            socket: UnixDatagram::from_std(socket),
+        })
+    }
+}
+
+impl PacketSource for DatagramSource {
+    fn register(&mut self, registry: &Registry, token: Token) -> Result<()> {
+        registry.register(&mut self.socket, token, Interest::READABLE)
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> Result<()> {
+        registry.deregister(&mut self.socket)
+    }
+
+    fn read_packet(&mut self, buffer: &mut [u8]) -> Result<usize> {
+        self.socket.recv(buffer)
+    }
+
+    fn write_packet(&mut self, bytes: &[u8]) -> Result<()> {
+        self.socket.send(bytes).map(|_| ())
+    }
+}
+
+//
+// Source of a vpn whose sessions all come from listeners, see Listeners; it
+// has no packets and is never closed.
+//
+pub(crate) struct EmptySource;
+
+impl PacketSource for EmptySource {
+    fn register(&mut self, _registry: &Registry, _token: Token) -> Result<()> {
+        Ok(())
+    }
+
+    fn deregister(&mut self, _registry: &Registry) -> Result<()> {
+        Ok(())
+    }
+
+    fn read_packet(&mut self, _buffer: &mut [u8]) -> Result<usize> {
+        Err(ErrorKind::WouldBlock.into())
+    }
+
+    fn write_packet(&mut self, _bytes: &[u8]) -> Result<()> {
+        Ok(())
+    }
+}
+
+//
+// Packets queued in memory; written packets are discarded. Used to drive the
+// processor from fuzz targets.
+//
+#[cfg(feature = "fuzzing")]
+pub(crate) struct MemorySource {
+    packets: std::collections::VecDeque<Vec<u8>>,
+}
+
+#[cfg(feature = "fuzzing")]
+impl MemorySource {
+    pub(crate) fn new(packets: Vec<Vec<u8>>) -> MemorySource {
+        MemorySource {
+            packets: packets.into(),
+        }
+    }
+}
+
+#[cfg(feature = "fuzzing")]
+impl PacketSource for MemorySource {
+    fn register(&mut self, _registry: &Registry, _token: Token) -> Result<()> {
+        Ok(())
+    }
+
+    fn deregister(&mut self, _registry: &Registry) -> Result<()> {
+        Ok(())
+    }
+
+    fn read_packet(&mut self, buffer: &mut [u8]) -> Result<usize> {
+        match self.packets.pop_front() {
+            Some(packet) => copy_packet(&packet, buffer),
+            None => Err(ErrorKind::WouldBlock.into()),
+        }
+    }
+
+    fn write_packet(&mut self, _bytes: &[u8]) -> Result<()> {
+        Ok(())
+    }
+}
+
+fn copy_packet(packet: &[u8], buffer: &mut [u8]) -> Result<usize> {
+    if packet.len() > buffer.len() {
+        return Err(ErrorKind::InvalidData.into());
+    }
+    buffer[..packet.len()].copy_from_slice(packet);
+    Ok(packet.len())
+}
@@ -0,0 +1,89 @@
+// This is free and unencumbered software released into the public domain.
+//
+// Anyone is free to copy, modify, publish, use, compile, sell, or
+// distribute this software, either in source code form or as a compiled
+// binary, for any purpose, commercial or non-commercial, and by any
+// means.
+//
+// In jurisdictions that recognize copyright laws, the author or authors
+// of this software dedicate any and all copyright interest in the
+// software to the public domain. We make this dedication for the benefit
+// of the public at large and to the detriment of our heirs and
+// successors. We intend this dedication to be an overt act of
+// relinquishment in perpetuity of all present and future rights to this
+// software under copyright law.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS BE LIABLE FOR ANY CLAIM, DAMAGES OR
+// OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE,
+// ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR
+// OTHER DEALINGS IN THE SOFTWARE.
+//
+// For more information, please refer to <https://unlicense.org>
+
+use crate::process::{ProcessInfo, ProcessLookup};
+use crate::tun_callbacks;
+use crate::vpn::session_info::SessionInfo;
+use std::sync::{
+    mpsc::{channel, Sender},
+    Arc,
+};
+
+//
+// Looks up the processes of sessions on a thread of its own, which ends once
+// the lookups are dropped, and hands each result to the callback.
+//
+pub(crate) struct ProcessLookups {
+    sender: Sender<SessionInfo>,
+}
+
+impl ProcessLookups {
+    pub(crate) fn new(
+        lookup: Arc<dyn ProcessLookup>,
+        on_lookup: impl Fn(SessionInfo, ProcessInfo) + Send + 'static,
+    ) -> ProcessLookups {
+        let (sender, receiver) = channel::<SessionInfo>();
+        std::thread::spawn(move || {
+            for session_info in receiver {
+                let process = lookup
+                    .lookup(
+                        session_info.transport_protocol.into(),
+                        session_info.source,
+                        session_info.destination,
+                    )
+                    .unwrap_or_default();
+                log::debug!(
+                    "looked up process, session={:?} process={:?}",
+                    session_info,
+                    process
+                );
+                on_lookup(session_info, process);
+            }
+        });
+        ProcessLookups { sender }
+    }
+
+    pub(crate) fn request(&self, session_info: &SessionInfo) {
+        let _ = self.sender.send(*session_info);
+    }
+}
+
+//
+// The process of a new session is unknown without a lookup and is looked up
+// otherwise, see AccessConfig::allows_process.
+//
+pub(crate) fn initial_process() -> Option<ProcessInfo> {
+    match tun_callbacks::process_lookup() {
+        Some(_) => None,
+        None => Some(ProcessInfo::default()),
+    }
+}
+
+// processes which could not be looked up are left out of records.
+pub(crate) fn known_process(process: &Option<ProcessInfo>) -> Option<ProcessInfo> {
+    process
+        .clone()
+        .filter(|process| *process != ProcessInfo::default())
+}
@@ -23,23 +23,51 @@
 //
 // For more information, please refer to <https://unlicense.org>
 
+use crate::config::{Config, Nat64Config, ShutdownMode};
+use crate::connection_log::CloseReason;
+use crate::metrics::METRICS;
+use crate::process::ProcessInfo;
+use crate::statistics::{SessionSummary, Statistics};
 use crate::vpn::{
     buffers::{IncomingDataEvent, IncomingDirection, OutgoingDirection},
-    session::Session,
-    session_info::SessionInfo,
+    capture::Capture,
+    dns64::{Dns64, Dns64Message},
+    emulation::Emulator,
+    hostname::ResolvedNames,
+    icmp,
+    mio_socket::Socket as MioSocket,
+    mtu::{self, Fragmenter},
+    nat64,
+    packet_source::PacketSource,
+    process_lookups::{self, ProcessLookups},
+    reassembly::Reassembler,
+    session::{Client, Session, SocketClient},
+    session_info::{SessionInfo, TransportProtocol},
+    session_rate::SessionRateLimiter,
+    shaping::Shaper,
+    smoltcp_socket::SocketPool,
+    transparent::{
+        self, Arrival, Connection, Datagram, DatagramOrigin, Listeners, Proxy, Resolution,
+    },
+    tun::Tun,
     utils::log_packet,
 };
-use mio::{event::Event, unix::SourceFd, Events, Interest, Poll, Token, Waker};
+use crate::{tun_callbacks, Error};
+use mio::{event::Event, Events, Poll, Registry, Token, Waker};
 use smoltcp::time::Instant;
 use std::{
-    collections::{hash_map::Entry, HashMap},
-    fs::File,
-    io::{ErrorKind, Read, Write},
-    os::unix::io::FromRawFd,
+    collections::HashMap,
+    io::ErrorKind,
+    sync::{
+        mpsc::{channel, Receiver, Sender},
+        Arc,
+    },
+    time::Duration,
 };
 
 type Sessions<'a> = HashMap<SessionInfo, Session<'a>>;
 type TokensToSessions = HashMap<Token, SessionInfo>;
+type DelayedWrites = HashMap<SessionInfo, std::time::Instant>;
 
 const EVENTS_CAPACITY: usize = 1024;
 
@@ -47,89 +75,921 @@ const TOKEN_TUN: Token = Token(0);
 const TOKEN_WAKER: Token = Token(1);
 const TOKEN_START_ID: usize = 2;
 
+const DNS_PORT: u16 = 53;
+
+// udp sessions of clients with sockets, which the kernel holds for each of
+// them, end once idle for this long.
+const SOCKET_UDP_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+// sessions are polled at least this often while draining, e.g. to retransmit fins.
+const DRAIN_INTERVAL: Duration = Duration::from_millis(100);
+
+pub(crate) enum Command {
+    Stop,
+    // existing sessions which the new access rules deny are closed when set.
+    Configure(Box<Config>, bool),
+    TrimMemory,
+    ReplacePacketSource(Box<dyn PacketSource>),
+    Sessions(Sender<Vec<SessionSummary>>),
+    Statistics(Sender<Statistics>),
+    StartCapture(std::fs::File),
+    StopCapture,
+    // the process of a session has been looked up.
+    Process(SessionInfo, ProcessInfo),
+    // a domain name sent by a socks client has been resolved.
+    Resolved(Resolution),
+}
+
+//
+// Sends commands to the processor thread; commands are handled between event
+// batches after the processor has been woken up.
+//
+pub(crate) struct Control<C = Command> {
+    // a poll supports a single waker, which clones share.
+    waker: Arc<Waker>,
+    sender: Sender<C>,
+}
+
+impl<C> Clone for Control<C> {
+    fn clone(&self) -> Self {
+        Control {
+            waker: self.waker.clone(),
+            sender: self.sender.clone(),
+        }
+    }
+}
+
+impl<C> Control<C> {
+    pub(crate) fn new(registry: &Registry, token: Token, sender: Sender<C>) -> crate::Result<Self> {
+        Ok(Control {
+            waker: Arc::new(Waker::new(registry, token)?),
+            sender,
+        })
+    }
+
+    pub(crate) fn send(&self, command: C) {
+        if self.try_send(command).is_err() {
+            log::debug!("failed to send command, processor is stopped");
+        }
+    }
+
+    //
+    // Like send, failing when the processor has stopped so that the command
+    // is not silently lost.
+    //
+    pub(crate) fn try_send(&self, command: C) -> crate::Result<()> {
+        if self.sender.send(command).is_err() {
+            return Err(crate::Error::Stopped);
+        }
+        if let Err(error) = self.waker.wake() {
+            log::error!("failed to wake processor, error={:?}", error);
+        }
+        Ok(())
+    }
+}
+
+//
+// Hands out the tokens of everything polled besides the tun and the waker.
+//
+pub(crate) struct Tokens {
+    next_id: usize,
+}
+
+impl Tokens {
+    fn new() -> Tokens {
+        Tokens {
+            next_id: TOKEN_START_ID,
+        }
+    }
+
+    pub(crate) fn next(&mut self) -> Token {
+        let token = Token(self.next_id);
+        self.next_id += 1;
+        token
+    }
+}
+
+//
+// Sessions come from the packets of the tun, whose clients smoltcp
+// terminates, and from the listeners of the proxy, whose clients have sockets
+// of the kernel; both are forwarded to upstream sockets alike.
+//
 pub(crate) struct Processor<'a> {
-    file_descriptor: i32,
-    file: File,
+    tun: Tun,
     poll: Poll,
     sessions: Sessions<'a>,
     tokens_to_sessions: TokensToSessions,
-    next_token_id: usize,
+    // the sessions of clients with sockets by the tokens of those.
+    tokens_to_clients: TokensToSessions,
+    tokens: Tokens,
+    proxy: Proxy,
+    command_sender: Sender<Command>,
+    command_receiver: Receiver<Command>,
+    // shares the waker with the control of the vpn, see new_control.
+    control: Option<Control>,
+    // started with the first session whose process is looked up.
+    process_lookups: Option<ProcessLookups>,
+    shaper: Shaper,
+    delayed_writes: DelayedWrites,
+    emulator: Emulator,
+    reassembler: Reassembler,
+    fragmenter: Fragmenter,
+    socket_pool: SocketPool<'a>,
+    session_rate: SessionRateLimiter,
+    resolved_names: ResolvedNames,
+    // set while sessions are drained before stopping.
+    shutdown_deadline: Option<std::time::Instant>,
+    // bytes read from and written to clients with sockets.
+    socket_bytes_received: u64,
+    socket_bytes_sent: u64,
+    config: Config,
 }
 
 impl<'a> Processor<'a> {
-    pub(crate) fn new(file_descriptor: i32) -> Processor<'a> {
+    pub(crate) fn new(packet_source: Box<dyn PacketSource>, config: Config) -> Processor<'a> {
+        let (command_sender, command_receiver) = channel();
         Processor {
-            file_descriptor,
-            file: unsafe { File::from_raw_fd(file_descriptor) },
+            tun: Tun::new(packet_source),
             poll: Poll::new().unwrap(),
             sessions: Sessions::new(),
             tokens_to_sessions: TokensToSessions::new(),
-            next_token_id: TOKEN_START_ID,
+            tokens_to_clients: TokensToSessions::new(),
+            tokens: Tokens::new(),
+            proxy: Proxy::new(),
+            command_sender,
+            command_receiver,
+            control: None,
+            process_lookups: None,
+            shaper: Shaper::new(config.shaping.clone()),
+            delayed_writes: DelayedWrites::new(),
+            emulator: Emulator::new(config.emulation.clone()),
+            reassembler: Reassembler::new(config.reassembly),
+            fragmenter: Fragmenter::new(),
+            socket_pool: SocketPool::new(config.memory),
+            session_rate: SessionRateLimiter::new(&config.sessions),
+            resolved_names: ResolvedNames::new(),
+            shutdown_deadline: None,
+            socket_bytes_received: 0,
+            socket_bytes_sent: 0,
+            config,
         }
     }
 
-    pub(crate) fn new_stop_waker(&self) -> Waker {
-        Waker::new(self.poll.registry(), TOKEN_WAKER).unwrap()
+    pub(crate) fn new_control(&mut self) -> crate::Result<Control> {
+        if let Some(control) = &self.control {
+            return Ok(control.clone());
+        }
+        let control = Control::new(
+            self.poll.registry(),
+            TOKEN_WAKER,
+            self.command_sender.clone(),
+        )?;
+        self.control = Some(control.clone());
+        Ok(control)
     }
 
-    pub(crate) fn run(&mut self) {
-        let registry = self.poll.registry();
-        registry
-            .register(
-                &mut SourceFd(&self.file_descriptor),
-                TOKEN_TUN,
-                Interest::READABLE,
-            )
-            .unwrap();
+    //
+    // Registers the packet source before the processor is run so that a failure
+    // is reported to whoever starts the vpn.
+    //
+    pub(crate) fn register(&mut self) -> crate::Result<()> {
+        self.tun.register(self.poll.registry(), TOKEN_TUN)?;
+        Ok(())
+    }
 
+    pub(crate) fn listen(&mut self, listeners: Listeners) -> crate::Result<()> {
+        let control = self.new_control()?;
+        self.proxy
+            .listen(listeners, self.poll.registry(), &mut self.tokens, control)
+    }
+
+    pub(crate) fn run(&mut self) {
         let mut events = Events::with_capacity(EVENTS_CAPACITY);
 
         'poll_loop: loop {
-            let _ = self.poll.poll(&mut events, None);
+            let timeout = self.next_timeout();
+            let _ = self.poll.poll(&mut events, timeout);
+            let started = std::time::Instant::now();
 
             log::trace!("handling events, count={:?}", events.iter().count());
 
             for event in events.iter() {
                 if event.token() == TOKEN_TUN {
-                    self.handle_tun_event(event);
+                    if !self.handle_tun_event(event) {
+                        log::info!("packet source is closed, stopping processor");
+                        break 'poll_loop;
+                    }
                 } else if event.token() == TOKEN_WAKER {
-                    break 'poll_loop;
+                    if !self.handle_commands() {
+                        break 'poll_loop;
+                    }
+                } else if self.tokens_to_clients.contains_key(&event.token()) {
+                    self.handle_client_event(event);
+                } else if self.proxy.owns(event.token()) {
+                    self.handle_listener_event(event);
                 } else {
                     self.handle_server_event(event);
                 }
             }
 
+            self.handle_delayed_writes();
+            self.handle_delayed_packets();
+            let now = std::time::Instant::now();
+            self.reassembler.expire(now);
+            self.proxy.expire(self.poll.registry(), now);
+            self.expire_socket_sessions(now);
+
+            if self.shutdown_deadline.is_some() && !self.drain_sessions() {
+                log::info!("sessions are drained, stopping processor");
+                break 'poll_loop;
+            }
+
+            METRICS.loop_latency.observe(started.elapsed());
+
             log::trace!("finished handling events");
         }
+
+        if let Err(error) = self.tun.deregister(self.poll.registry()) {
+            log::error!("failed to deregister packet source, error={:?}", error);
+        }
+
+        // sessions left, all of them when stopping immediately, are dropped
+        // without notifying the clients.
+        for (session_info, session) in std::mem::take(&mut self.sessions) {
+            Self::log_connection(&session_info, &session, CloseReason::Shutdown);
+            METRICS.session_destroyed(session_info.transport_protocol.into());
+        }
+    }
+
+    fn next_timeout(&self) -> Option<Duration> {
+        let now = std::time::Instant::now();
+        let next_write = self.delayed_writes.values().min().copied();
+        let next_packet = self.emulator.next_deadline();
+        let next_reassembly = self.reassembler.next_deadline();
+        let next_drain = self
+            .shutdown_deadline
+            .map(|deadline| deadline.min(now + DRAIN_INTERVAL));
+        let next_handshake = self.proxy.next_deadline();
+        let next_expiry = self
+            .sessions
+            .iter()
+            .filter(|(session_info, session)| Self::is_socket_udp_session(session_info, session))
+            .map(|(_, session)| session.last_activity + SOCKET_UDP_IDLE_TIMEOUT)
+            .min();
+        [
+            next_write,
+            next_packet,
+            next_reassembly,
+            next_drain,
+            next_handshake,
+            next_expiry,
+        ]
+        .into_iter()
+        .flatten()
+        .min()
+        .map(|deadline| deadline.saturating_duration_since(now))
+    }
+
+    fn handle_commands(&mut self) -> bool {
+        while let Ok(command) = self.command_receiver.try_recv() {
+            match command {
+                Command::Stop => {
+                    if !self.shutdown() {
+                        return false;
+                    }
+                }
+                Command::Configure(config, close_denied_sessions) => {
+                    self.configure(*config);
+                    if close_denied_sessions {
+                        self.close_denied_sessions();
+                    }
+                }
+                Command::TrimMemory => {
+                    self.trim_memory();
+                }
+                Command::ReplacePacketSource(packet_source) => {
+                    self.replace_packet_source(packet_source);
+                }
+                Command::Sessions(sender) => {
+                    let _ = sender.send(self.session_summaries());
+                }
+                Command::Statistics(sender) => {
+                    let _ = sender.send(self.statistics());
+                }
+                Command::StartCapture(file) => match Capture::new(file) {
+                    Ok(capture) => self.tun.start_capture(capture),
+                    Err(error) => log::error!("failed to start capture, error={:?}", error),
+                },
+                Command::StopCapture => {
+                    self.tun.stop_capture();
+                }
+                Command::Process(session_info, process) => {
+                    self.handle_process(&session_info, process);
+                }
+                Command::Resolved(resolution) => {
+                    let arrivals = self
+                        .proxy
+                        .handle_resolution(resolution, self.poll.registry());
+                    self.handle_arrivals(arrivals);
+                }
+            }
+        }
+        true
+    }
+
+    //
+    // Sessions, including their smoltcp state and upstream sockets, are kept;
+    // only packets from and to the client use the new source.
+    //
+    fn replace_packet_source(&mut self, packet_source: Box<dyn PacketSource>) {
+        self.tun
+            .replace(self.poll.registry(), TOKEN_TUN, packet_source);
+
+        log::info!("replaced packet source, sessions={:?}", self.sessions.len());
+    }
+
+    fn session_summaries(&self) -> Vec<SessionSummary> {
+        let now = std::time::Instant::now();
+        self.sessions
+            .iter()
+            .map(|(session_info, session)| SessionSummary {
+                source: session_info.source,
+                destination: session_info.destination,
+                protocol: session_info.transport_protocol.into(),
+                age: now.duration_since(session.created),
+                idle: now.duration_since(session.last_activity),
+                queued_to_server: session.buffers.len(&OutgoingDirection::ToServer),
+                queued_to_client: session.buffers.len(&OutgoingDirection::ToClient),
+                process: process_lookups::known_process(&session.process),
+            })
+            .collect()
+    }
+
+    fn statistics(&self) -> Statistics {
+        let count = |protocol| {
+            self.sessions
+                .keys()
+                .filter(|session_info| session_info.transport_protocol == protocol)
+                .count()
+        };
+        let counters = &self.tun.counters;
+        Statistics {
+            tcp_sessions: count(TransportProtocol::Tcp),
+            udp_sessions: count(TransportProtocol::Udp),
+            memory: self.memory(),
+            packets_received: counters.packets_received,
+            packets_sent: counters.packets_sent,
+            bytes_received: counters.bytes_received + self.socket_bytes_received,
+            bytes_sent: counters.bytes_sent + self.socket_bytes_sent,
+            tun_errors: counters.errors,
+        }
     }
 
-    fn create_session(&mut self, bytes: &Vec<u8>) -> Option<SessionInfo> {
-        if let Some(session_info) = SessionInfo::new(bytes) {
-            match self.sessions.entry(session_info) {
-                Entry::Vacant(entry) => {
-                    let token = Token(self.next_token_id);
-                    if let Some(session) = Session::new(&session_info, &mut self.poll, token) {
-                        self.tokens_to_sessions.insert(token, session_info);
-                        self.next_token_id += 1;
+    //
+    // Returns true while sessions are drained before stopping.
+    //
+    fn shutdown(&mut self) -> bool {
+        if self.shutdown_deadline.is_some() {
+            return true;
+        }
+        let session_infos: Vec<SessionInfo> = self.sessions.keys().copied().collect();
+        match self.config.shutdown.mode {
+            ShutdownMode::Immediate => false,
+            ShutdownMode::Reset => {
+                log::info!("resetting sessions, count={:?}", session_infos.len());
+                for session_info in session_infos.iter() {
+                    self.abort_session(session_info, CloseReason::Shutdown);
+                }
+                false
+            }
+            ShutdownMode::Drain => {
+                log::info!("draining sessions, count={:?}", session_infos.len());
+                let deadline = std::time::Instant::now() + self.config.shutdown.deadline;
+                self.shutdown_deadline = Some(deadline);
+                self.drain_sessions()
+            }
+        }
+    }
 
-                        entry.insert(session);
+    //
+    // Returns true while sessions remain; sessions still open at the deadline
+    // are reset.
+    //
+    fn drain_sessions(&mut self) -> bool {
+        let now = std::time::Instant::now();
+        let is_expired = self
+            .shutdown_deadline
+            .is_some_and(|deadline| now >= deadline);
+        let session_infos: Vec<SessionInfo> = self.sessions.keys().copied().collect();
+        if is_expired && !session_infos.is_empty() {
+            log::warn!(
+                "drain deadline reached, resetting sessions, count={:?}",
+                session_infos.len()
+            );
+        }
+        for session_info in session_infos.iter() {
+            if is_expired {
+                self.abort_session(session_info, CloseReason::Shutdown);
+            } else {
+                self.drain_session(session_info);
+            }
+        }
+        !self.sessions.is_empty()
+    }
 
-                        log::debug!("created session, session={:?}", session_info);
+    //
+    // Flushes the queues of the session, shuts down the upstream socket once
+    // everything has been written to it and closes the socket towards the
+    // client once everything has been written to that; the session is
+    // destroyed when both are closed.
+    //
+    fn drain_session(&mut self, session_info: &SessionInfo) {
+        self.read_from_smoltcp(session_info);
+        self.read_from_socket_client(session_info);
+        self.write_to_server(session_info);
+        self.write_to_smoltcp(session_info);
+        self.write_to_socket_client(session_info);
+        self.write_to_tun(session_info);
 
-                        return Some(session_info);
+        if let Some(session) = self.sessions.get_mut(session_info) {
+            if !session.is_upstream_closed && session.buffers.is_empty(&OutgoingDirection::ToServer)
+            {
+                session.mio_socket.close();
+                session.is_upstream_closed = true;
+            }
+            if session.buffers.is_empty(&OutgoingDirection::ToClient) {
+                match &mut session.client {
+                    Client::Tun(client) => {
+                        let mut smoltcp_socket = client.smoltcp_socket.get(&mut client.sockets);
+                        smoltcp_socket.close();
                     }
+                    Client::Socket(client) => client.socket.shutdown_write(),
+                    Client::Association(_, _) => {}
+                }
+            }
+        }
+        self.write_to_tun(session_info);
+
+        if let Some(session) = self.sessions.get_mut(session_info) {
+            let is_client_closed = match &mut session.client {
+                Client::Tun(client) => client.smoltcp_socket.get(&mut client.sockets).is_closed(),
+                Client::Socket(_) | Client::Association(_, _) => {
+                    session.buffers.is_empty(&OutgoingDirection::ToClient)
+                }
+            };
+            if session.is_upstream_closed && is_client_closed {
+                self.destroy_session(session_info, CloseReason::Shutdown);
+            }
+        }
+    }
+
+    fn configure(&mut self, config: Config) {
+        log::debug!("applying configuration, config={:?}", config);
+
+        self.shaper.configure(config.shaping.clone());
+        for (session_info, session) in self.sessions.iter_mut() {
+            self.shaper
+                .configure_session(&mut session.shaping, session_info);
+        }
+
+        self.emulator.configure(config.emulation.clone());
+        self.reassembler.configure(config.reassembly);
+        self.socket_pool.configure(config.memory);
+        self.session_rate.configure(&config.sessions);
+
+        for (session_info, session) in self.sessions.iter_mut() {
+            if let Client::Tun(client) = &mut session.client {
+                let mtu = mtu::mtu(&config.tun, session_info.internet_protocol);
+                client
+                    .device
+                    .set_mtu(Session::device_mtu(session_info, mtu));
+            }
+        }
+
+        self.config = config;
+    }
+
+    fn is_allowed(&self, session_info: &SessionInfo, process: Option<&ProcessInfo>) -> bool {
+        self.config.access.allows_process(
+            &session_info.destination,
+            session_info.transport_protocol.into(),
+            process,
+        )
+    }
+
+    //
+    // Tcp connections of sessions denied by the access rules are reset.
+    //
+    fn close_denied_sessions(&mut self) {
+        let denied_sessions: Vec<SessionInfo> = self
+            .sessions
+            .iter()
+            .filter(|(session_info, session)| {
+                !self.is_allowed(session_info, session.process.as_ref())
+            })
+            .map(|(session_info, _)| *session_info)
+            .collect();
+        for session_info in denied_sessions.iter() {
+            log::debug!("closing denied session, session={:?}", session_info);
+            self.abort_session(session_info, CloseReason::Denied);
+        }
+    }
+
+    //
+    // Returns the memory held by sessions and by pooled sockets.
+    //
+    fn memory(&self) -> usize {
+        let sessions: usize = self.sessions.values().map(Session::memory).sum();
+        sessions + self.socket_pool.memory()
+    }
+
+    //
+    // Closes udp sessions which have been idle for at least the trim idle
+    // time, releases pooled sockets and shrinks the queues of all sessions.
+    //
+    fn trim_memory(&mut self) {
+        let now = std::time::Instant::now();
+        let trim_idle_time = self.config.memory.trim_idle_time;
+        let idle_sessions: Vec<SessionInfo> = self
+            .sessions
+            .iter()
+            .filter(|(session_info, session)| {
+                Self::is_idle_udp_session(session_info, session)
+                    && now.duration_since(session.last_activity) >= trim_idle_time
+            })
+            .map(|(session_info, _)| *session_info)
+            .collect();
+
+        for session_info in idle_sessions.iter() {
+            self.destroy_session(session_info, CloseReason::Timeout);
+        }
+        self.socket_pool.clear();
+        for session in self.sessions.values_mut() {
+            session.buffers.shrink_to_fit();
+        }
+
+        log::debug!(
+            "trimmed memory, closed={:?} sessions={:?}",
+            idle_sessions.len(),
+            self.sessions.len()
+        );
+    }
+
+    //
+    // Makes room for the socket buffers of a new session within the memory
+    // budget by releasing pooled sockets and then closing idle udp sessions,
+    // least recently active first; returns false when not enough memory could
+    // be freed.
+    //
+    fn reserve_memory(&mut self, required: usize) -> bool {
+        let max_memory = self.config.memory.max_memory;
+        if self.memory() + required <= max_memory {
+            return true;
+        }
+        self.socket_pool.clear();
+        let mut used: usize = self.sessions.values().map(Session::memory).sum();
+        if used + required <= max_memory {
+            return true;
+        }
+
+        let mut idle_sessions: Vec<(std::time::Instant, SessionInfo, usize)> = self
+            .sessions
+            .iter()
+            .filter(|(session_info, session)| Self::is_idle_udp_session(session_info, session))
+            .map(|(session_info, session)| (session.last_activity, *session_info, session.memory()))
+            .collect();
+        idle_sessions.sort_by_key(|(last_activity, _, _)| *last_activity);
+
+        for (_, idle_session_info, memory) in idle_sessions {
+            if used + required <= max_memory {
+                break;
+            }
+            log::debug!(
+                "closing idle session to free memory, session={:?}",
+                idle_session_info
+            );
+            self.destroy_session(&idle_session_info, CloseReason::Evicted);
+            used -= memory;
+        }
+        used + required <= max_memory
+    }
+
+    //
+    // Tells whether the memory budget leaves room for the socket buffers of a
+    // new session once the given sessions have been closed, as reserve_memory
+    // would make by closing idle udp sessions.
+    //
+    fn has_memory_for(&self, required: usize, closed: &[SessionInfo]) -> bool {
+        let mut used = 0;
+        let mut idle = 0;
+        for (session_info, session) in self.sessions.iter() {
+            if closed.contains(session_info) {
+                continue;
+            }
+            used += session.memory();
+            if Self::is_idle_udp_session(session_info, session) {
+                idle += session.memory();
+            }
+        }
+        used - idle + required <= self.config.memory.max_memory
+    }
+
+    //
+    // Picks the sessions to close to make room for a new session within the
+    // configured session limits, the least recently active ones, first of the
+    // same protocol and then of any; returns None when a limit is zero.
+    //
+    fn sessions_to_evict(&self, session_info: &SessionInfo) -> Option<Vec<SessionInfo>> {
+        let limits = self.config.sessions;
+        let protocol = session_info.transport_protocol;
+        let protocol_limit = match protocol {
+            TransportProtocol::Tcp => limits.max_tcp_sessions,
+            TransportProtocol::Udp => limits.max_udp_sessions,
+        };
+        let mut evicted = Vec::new();
+        if let Some(max) = protocol_limit {
+            if !self.pick_evictions(max, Some(protocol), &mut evicted) {
+                return None;
+            }
+        }
+        if let Some(max) = limits.max_sessions {
+            if !self.pick_evictions(max, None, &mut evicted) {
+                return None;
+            }
+        }
+        Some(evicted)
+    }
+
+    fn pick_evictions(
+        &self,
+        max: usize,
+        protocol: Option<TransportProtocol>,
+        evicted: &mut Vec<SessionInfo>,
+    ) -> bool {
+        if max == 0 {
+            return false;
+        }
+        let is_counted = |session_info: &SessionInfo| {
+            protocol.is_none_or(|protocol| session_info.transport_protocol == protocol)
+                && !evicted.contains(session_info)
+        };
+        let mut sessions: Vec<(std::time::Instant, SessionInfo)> = self
+            .sessions
+            .iter()
+            .filter(|(session_info, _)| is_counted(session_info))
+            .map(|(session_info, session)| (session.last_activity, *session_info))
+            .collect();
+        if sessions.len() < max {
+            return true;
+        }
+        sessions.sort_by_key(|(last_activity, _)| *last_activity);
+
+        let count = sessions.len() + 1 - max;
+        evicted.extend(
+            sessions
+                .into_iter()
+                .take(count)
+                .map(|(_, session_info)| session_info),
+        );
+        true
+    }
+
+    fn is_idle_udp_session(session_info: &SessionInfo, session: &Session) -> bool {
+        session_info.transport_protocol == TransportProtocol::Udp
+            && session.buffers.len(&OutgoingDirection::ToServer) == 0
+            && session.buffers.len(&OutgoingDirection::ToClient) == 0
+    }
+
+    fn handle_delayed_writes(&mut self) {
+        let now = std::time::Instant::now();
+        let session_infos: Vec<SessionInfo> = self
+            .delayed_writes
+            .iter()
+            .filter(|(_, deadline)| **deadline <= now)
+            .map(|(session_info, _)| *session_info)
+            .collect();
+
+        for session_info in session_infos {
+            log::trace!("handle delayed write, session={:?}", session_info);
+
+            self.delayed_writes.remove(&session_info);
+            if self.is_tun_session(&session_info) {
+                self.write_to_server(&session_info);
+                self.resume_client_read(&session_info);
+                self.write_to_smoltcp(&session_info);
+                self.resume_server_read(&session_info);
+                self.write_to_tun(&session_info);
+            } else {
+                self.flush_socket_session(&session_info);
+            }
+        }
+    }
+
+    fn handle_delayed_packets(&mut self) {
+        let now = std::time::Instant::now();
+        for packet in self.emulator.take_due_packets(now) {
+            log::trace!("handle delayed packet, session={:?}", packet.session_info);
+
+            if packet.is_payload {
+                self.deliver_delayed_payload(&packet.session_info, packet.direction, packet.bytes);
+                continue;
+            }
+            match packet.direction {
+                OutgoingDirection::ToServer => {
+                    self.receive_packet(&packet.session_info, packet.bytes);
                 }
-                Entry::Occupied(_) => {
-                    return Some(session_info);
+                OutgoingDirection::ToClient => {
+                    self.tun.write_packet(&packet.bytes);
                 }
             }
+        }
+    }
+
+    fn create_session(&mut self, session_info: &SessionInfo) -> bool {
+        if self.sessions.contains_key(session_info) {
+            return true;
+        }
+        let process = process_lookups::initial_process();
+        let required = Session::socket_memory(session_info, &self.config);
+        if !self.admit_session(session_info, process.as_ref(), required) {
+            return false;
+        }
+        let Some(client) =
+            Session::new_tun_client(session_info, &self.config, &mut self.socket_pool)
+        else {
+            return false;
+        };
+        if let Err(Client::Tun(mut client)) = self.insert_session(session_info, client, process) {
+            client
+                .smoltcp_socket
+                .release(&mut client.sockets, &mut self.socket_pool);
+            return false;
+        }
+        true
+    }
+
+    //
+    // Checks whether a new session is allowed and makes room for it within the
+    // session limits and the memory budget, of which its client requires the
+    // given bytes.
+    //
+    fn admit_session(
+        &mut self,
+        session_info: &SessionInfo,
+        process: Option<&ProcessInfo>,
+        required: usize,
+    ) -> bool {
+        if self.shutdown_deadline.is_some() {
+            log::debug!("stopping, session={:?}", session_info);
+            return false;
+        }
+        if !self.is_allowed(session_info, process) {
+            log::debug!("session denied, session={:?}", session_info);
+            return false;
+        }
+        // both limits are checked before any session is closed.
+        let Some(evicted) = self.sessions_to_evict(session_info) else {
+            log::warn!("session limit reached, session={:?}", session_info);
+            return false;
+        };
+        if !self.has_memory_for(required, &evicted) {
+            log::warn!("memory budget exceeded, session={:?}", session_info);
+            return false;
+        }
+        for evicted_session_info in evicted {
+            log::debug!(
+                "closing least recently active session, session={:?}",
+                evicted_session_info
+            );
+            self.abort_session(&evicted_session_info, CloseReason::Evicted);
+        }
+        if !self.reserve_memory(required) {
+            log::warn!("memory budget exceeded, session={:?}", session_info);
+            return false;
+        }
+        true
+    }
+
+    //
+    // Creates the upstream socket of a new session; the client is handed back
+    // when that fails.
+    //
+    fn insert_session(
+        &mut self,
+        session_info: &SessionInfo,
+        client: Client<'a>,
+        process: Option<ProcessInfo>,
+    ) -> Result<(), Client<'a>> {
+        let token = self.tokens.next();
+        let shaping = self.shaper.new_session(session_info);
+        let upstream_address =
+            nat64::upstream_address(self.config.nat64.as_ref(), session_info.destination);
+        let mut session = Session::new(
+            session_info,
+            upstream_address,
+            &mut self.poll,
+            token,
+            shaping,
+            client,
+        )?;
+        if let Client::Socket(client) = &mut session.client {
+            if let Err(error) = client.socket.register_poll(&mut self.poll, client.token) {
+                log::error!("failed to register poll, error={:?}", error);
+                session.mio_socket.close();
+                let _ = session.mio_socket.deregister_poll(&mut self.poll);
+                return Err(session.client);
+            }
+            self.tokens_to_clients.insert(client.token, *session_info);
+        }
+        session.dns64 = Self::new_dns64(self.config.nat64.as_ref(), session_info);
+        session.hostname = self
+            .resolved_names
+            .get(&session_info.destination.ip())
+            .cloned();
+        let is_process_unknown = process.is_none();
+        session.process = process;
+        self.tokens_to_sessions.insert(token, *session_info);
+
+        self.sessions.insert(*session_info, session);
+        METRICS.session_created(session_info.transport_protocol.into());
+
+        log::debug!("created session, session={:?}", session_info);
+
+        if is_process_unknown {
+            self.look_up_process(session_info);
+        }
+        Ok(())
+    }
+
+    fn look_up_process(&mut self, session_info: &SessionInfo) {
+        if self.process_lookups.is_none() {
+            let Some(lookup) = tun_callbacks::process_lookup() else {
+                return;
+            };
+            let control = match self.new_control() {
+                Ok(control) => control,
+                Err(error) => {
+                    log::error!("failed to create control, error={:?}", error);
+                    return;
+                }
+            };
+            self.process_lookups =
+                Some(ProcessLookups::new(lookup, move |session_info, process| {
+                    control.send(Command::Process(session_info, process))
+                }));
+        }
+        if let Some(process_lookups) = &self.process_lookups {
+            process_lookups.request(session_info);
+        }
+    }
+
+    //
+    // Sessions which the access rules deny once their process is known are
+    // reset; the data held for the others is written to the server.
+    //
+    fn handle_process(&mut self, session_info: &SessionInfo, process: ProcessInfo) {
+        let is_allowed = self.is_allowed(session_info, Some(&process));
+        let Some(session) = self.sessions.get_mut(session_info) else {
+            return;
+        };
+        session.process = Some(process);
+        if !is_allowed {
+            log::debug!("session of denied process, session={:?}", session_info);
+            self.abort_session(session_info, CloseReason::Denied);
+            return;
+        }
+        if self.is_tun_session(session_info) {
+            self.write_to_server(session_info);
+            self.resume_client_read(session_info);
         } else {
-            log::error!("failed to get session for bytes, len={:?}", bytes.len());
+            self.flush_socket_session(session_info);
         }
-        None
     }
 
-    fn destroy_session(&mut self, session_info: &SessionInfo) {
+    //
+    // Data to the server is held while the process of the session is being
+    // looked up and the access rules could deny it once it is known.
+    //
+    fn is_held(&self, session_info: &SessionInfo) -> bool {
+        let is_process_unknown = self
+            .sessions
+            .get(session_info)
+            .is_some_and(|session| session.process.is_none());
+        is_process_unknown
+            && self.config.access.may_deny_process(
+                &session_info.destination,
+                session_info.transport_protocol.into(),
+            )
+    }
+
+    fn new_dns64(nat64: Option<&Nat64Config>, session_info: &SessionInfo) -> Option<Dns64> {
+        let nat64 = nat64.filter(|nat64| nat64.dns64)?;
+        let is_dns = session_info.transport_protocol == TransportProtocol::Udp
+            && session_info.destination.port() == DNS_PORT;
+        is_dns.then(|| Dns64::new(nat64.prefix))
+    }
+
+    fn destroy_session(&mut self, session_info: &SessionInfo, close_reason: CloseReason) {
         log::trace!("destroying session, session={:?}", session_info);
 
         // push any pending data back to tun device before destroying session.
@@ -137,73 +997,292 @@ impl<'a> Processor<'a> {
         self.write_to_tun(session_info);
 
         if let Some(session) = self.sessions.get_mut(session_info) {
-            let mut smoltcp_socket = session.smoltcp_socket.get(&mut session.sockets);
-            smoltcp_socket.close();
+            Self::log_connection(session_info, session, close_reason);
+
+            match &mut session.client {
+                Client::Tun(client) => {
+                    let mut smoltcp_socket = client.smoltcp_socket.get(&mut client.sockets);
+                    smoltcp_socket.close();
+                    client
+                        .smoltcp_socket
+                        .release(&mut client.sockets, &mut self.socket_pool);
+                }
+                Client::Socket(client) => {
+                    if let Err(error) = client.socket.deregister_poll(&mut self.poll) {
+                        log::error!("failed to deregister poll, error={:?}", error);
+                    }
+                    self.tokens_to_clients.remove(&client.token);
+                }
+                Client::Association(_, _) => {}
+            }
 
             let mio_socket = &mut session.mio_socket;
             mio_socket.close();
-            mio_socket.deregister_poll(&mut self.poll).unwrap();
+            if let Err(error) = mio_socket.deregister_poll(&mut self.poll) {
+                log::error!("failed to deregister poll, error={:?}", error);
+            }
 
             self.tokens_to_sessions.remove(&session.token);
 
             self.sessions.remove(session_info);
+            self.delayed_writes.remove(session_info);
+
+            METRICS.session_destroyed(session_info.transport_protocol.into());
         }
 
         log::trace!("finished destroying session, session={:?}", session_info);
     }
 
-    fn handle_tun_event(&mut self, event: &Event) {
+    //
+    // Resets tcp connections towards the client before destroying the session.
+    //
+    fn abort_session(&mut self, session_info: &SessionInfo, close_reason: CloseReason) {
+        if let Some(session) = self.sessions.get_mut(session_info) {
+            match &mut session.client {
+                Client::Tun(client) => {
+                    let mut smoltcp_socket = client.smoltcp_socket.get(&mut client.sockets);
+                    smoltcp_socket.abort();
+                }
+                Client::Socket(client) => client.socket.reset_on_close(),
+                Client::Association(_, _) => {}
+            }
+        }
+        self.write_to_tun(session_info);
+        self.destroy_session(session_info, close_reason);
+    }
+
+    fn log_connection(session_info: &SessionInfo, session: &Session, close_reason: CloseReason) {
+        if tun_callbacks::is_connection_log_enabled() {
+            let record = session.connection_record(session_info, close_reason);
+            tun_callbacks::on_connection_closed(&record);
+        }
+    }
+
+    //
+    // Tells why the upstream socket of a session has been closed, preferring
+    // the way the client closed the connection when it did so first.
+    //
+    fn server_close_reason(
+        &mut self,
+        session_info: &SessionInfo,
+        error: Option<std::io::Error>,
+    ) -> CloseReason {
+        let Some(session) = self.sessions.get_mut(session_info) else {
+            return CloseReason::Error;
+        };
+        let error = error.or_else(|| session.mio_socket.take_error().ok().flatten());
+        match error {
+            Some(error) if error.kind() == ErrorKind::ConnectionReset => CloseReason::Reset,
+            Some(_) => CloseReason::Error,
+            None => match &mut session.client {
+                Client::Tun(client) => {
+                    let smoltcp_socket = client.smoltcp_socket.get(&mut client.sockets);
+                    smoltcp_socket
+                        .client_close_reason()
+                        .unwrap_or(CloseReason::ServerFin)
+                }
+                Client::Socket(client) => client.close_reason.unwrap_or(CloseReason::ServerFin),
+                Client::Association(_, _) => CloseReason::ServerFin,
+            },
+        }
+    }
+
+    fn handle_tun_event(&mut self, event: &Event) -> bool {
+        let mut is_open = true;
+
+        if event.is_writable() {
+            log::trace!("handle tun event write");
+
+            if let Err(error) = self.tun.flush() {
+                if error.kind() != ErrorKind::WouldBlock {
+                    log::error!("failed to flush tun, error={:?}", error);
+                }
+            }
+
+            log::trace!("finished handle tun event write");
+        }
+
         if event.is_readable() {
             log::trace!("handle tun event");
 
-            let mut buffer: [u8; 65535] = [0; 65535];
-            loop {
-                match self.file.read(&mut buffer) {
-                    Ok(count) => {
-                        if count == 0 {
-                            break;
-                        }
-                        let read_buffer = buffer[..count].to_vec();
-                        log_packet("out", &read_buffer);
+            is_open = self.read_from_tun();
+
+            log::trace!("finished handle tun event");
+        }
 
-                        if let Some(session_info) = self.create_session(&read_buffer) {
-                            let session = self.sessions.get_mut(&session_info).unwrap();
-                            session.device.receive(read_buffer);
+        is_open
+    }
 
-                            self.write_to_tun(&session_info);
-                            self.read_from_smoltcp(&session_info);
-                            self.write_to_server(&session_info);
-                        }
+    //
+    // Reads packets until the source would block; returns false once the
+    // source has been closed.
+    //
+    pub(crate) fn read_from_tun(&mut self) -> bool {
+        let mut buffer: [u8; 65535] = [0; 65535];
+        loop {
+            match self.tun.read_packet(&mut buffer) {
+                Ok(count) => {
+                    if count == 0 {
+                        return false;
                     }
-                    Err(error) => {
-                        if error.kind() == ErrorKind::WouldBlock {
-                            // do nothing.
-                        } else {
-                            log::error!("failed to read from tun, error={:?}", error);
-                        }
-                        break;
+                    let read_buffer = buffer[..count].to_vec();
+                    if let Err(error) = log_packet("out", &read_buffer) {
+                        log::debug!("failed to log packet, error={:?}", error);
                     }
+
+                    self.handle_tun_packet(read_buffer);
+                }
+                Err(error) => {
+                    if error.kind() == ErrorKind::WouldBlock {
+                        // do nothing.
+                    } else {
+                        log::error!("failed to read from tun, error={:?}", error);
+                    }
+                    return true;
                 }
             }
+        }
+    }
 
-            log::trace!("finished handle tun event");
+    fn handle_tun_packet(&mut self, bytes: Vec<u8>) {
+        let mtu = mtu::packet_mtu(&bytes, &self.config.tun);
+        if mtu::is_too_big(&bytes, mtu) {
+            self.reject_oversized_packet(&bytes, mtu);
+            return;
+        }
+
+        let now = std::time::Instant::now();
+        let bytes = match self.reassembler.reassemble(bytes, now) {
+            Ok(Some(bytes)) => bytes,
+            Ok(None) => return,
+            Err(error) => {
+                log::debug!("failed to reassemble packet, error={:?}", error);
+                return;
+            }
+        };
+        match SessionInfo::new(&bytes) {
+            Ok(session_info) => {
+                let direction = OutgoingDirection::ToServer;
+                for bytes in self.emulator.emulate(direction, &session_info, bytes, now) {
+                    self.receive_packet(&session_info, bytes);
+                }
+            }
+            Err(Error::UnsupportedTransportProtocol(protocol)) => {
+                // neighbor discovery, multicast listener reports and pings are not forwarded.
+                log::trace!("dropping packet, protocol={:?}", protocol);
+            }
+            Err(error) => {
+                log::error!(
+                    "failed to get session for bytes, error={:?} len={:?}",
+                    error,
+                    bytes.len()
+                );
+            }
+        }
+    }
+
+    fn receive_packet(&mut self, session_info: &SessionInfo, mut bytes: Vec<u8>) {
+        let now = std::time::Instant::now();
+        if !self.sessions.contains_key(session_info) && !self.session_rate.allow(session_info, now)
+        {
+            log::debug!("session rate exceeded, session={:?}", session_info);
+            return;
+        }
+        if self.create_session(session_info) {
+            if let Err(error) = mtu::clamp_mss(&mut bytes, &self.config.tun) {
+                log::debug!("failed to clamp mss, error={:?}", error);
+            }
+            let session = self.sessions.get_mut(session_info).unwrap();
+            let Client::Tun(client) = &mut session.client else {
+                // the session of a client with a socket has the same addresses.
+                return;
+            };
+            client.device.receive(bytes);
+            session.counters.packets_from_client += 1;
+            session.last_activity = std::time::Instant::now();
+
+            self.write_to_tun(session_info);
+            self.read_from_smoltcp(session_info);
+            self.write_to_server(session_info);
+            // acknowledgements from the client may have made room for queued data.
+            self.write_to_smoltcp(session_info);
+            self.resume_server_read(session_info);
+            self.write_to_tun(session_info);
+        } else {
+            self.reject_packet(session_info, &bytes);
+        }
+    }
+
+    fn reject_packet(&mut self, session_info: &SessionInfo, bytes: &[u8]) {
+        log::debug!("rejecting packet, session={:?}", session_info);
+
+        match icmp::destination_unreachable(bytes) {
+            Ok(None) => {}
+            Ok(Some(reply)) => {
+                let now = std::time::Instant::now();
+                let direction = OutgoingDirection::ToClient;
+                for bytes in self.emulator.emulate(direction, session_info, reply, now) {
+                    self.tun.write_packet(&bytes);
+                }
+            }
+            Err(error) => {
+                log::error!("failed to build icmp reply, error={:?}", error);
+            }
+        }
+    }
+
+    //
+    // The error is written immediately, without emulation, as it would be sent
+    // by the tun device itself.
+    //
+    fn reject_oversized_packet(&mut self, bytes: &[u8], mtu: usize) {
+        log::debug!("rejecting packet larger than mtu, len={:?}", bytes.len());
+
+        match icmp::packet_too_big(bytes, mtu as u16) {
+            Ok(None) => {}
+            Ok(Some(reply)) => {
+                self.tun.write_packet(&reply);
+            }
+            Err(error) => {
+                log::error!("failed to build icmp reply, error={:?}", error);
+            }
         }
     }
 
     fn write_to_tun(&mut self, session_info: &SessionInfo) {
         if let Some(session) = self.sessions.get_mut(session_info) {
+            let Client::Tun(client) = &mut session.client else {
+                return;
+            };
             log::trace!("write to tun");
 
-            if !session
+            if !client
                 .interface
-                .poll(Instant::now(), &mut session.device, &mut session.sockets)
+                .poll(Instant::now(), &mut client.device, &mut client.sockets)
             {
                 log::error!("failed to poll interface, error={:?}", session.token);
             }
 
-            while let Some(bytes) = session.device.transmit() {
-                log_packet("in", &bytes);
-                self.file.write_all(&bytes[..]).unwrap();
+            let mtu = mtu::mtu(&self.config.tun, session_info.internet_protocol);
+            while let Some(mut bytes) = client.device.transmit() {
+                if let Err(error) = mtu::clamp_mss(&mut bytes, &self.config.tun) {
+                    log::debug!("failed to clamp mss, error={:?}", error);
+                }
+                let fragments = match self.fragmenter.fragment(bytes, mtu) {
+                    Ok(fragments) => fragments,
+                    Err(error) => {
+                        log::error!("failed to fragment packet, error={:?}", error);
+                        continue;
+                    }
+                };
+                let now = std::time::Instant::now();
+                let direction = OutgoingDirection::ToClient;
+                for bytes in fragments {
+                    for bytes in self.emulator.emulate(direction, session_info, bytes, now) {
+                        self.tun.write_packet(&bytes);
+                        session.counters.packets_to_client += 1;
+                    }
+                }
             }
 
             log::trace!("finished write to tun");
@@ -211,14 +1290,28 @@ impl<'a> Processor<'a> {
     }
 
     fn handle_server_event(&mut self, event: &Event) {
+        // sessions are drained after each batch of events instead.
+        if self.shutdown_deadline.is_some() {
+            return;
+        }
         if let Some(session_info) = self.tokens_to_sessions.get(&event.token()) {
             let session_info = *session_info;
+            self.handle_server_connect(&session_info, event);
+            if !self.is_tun_session(&session_info) {
+                if event.is_readable() || event.is_read_closed() || event.is_error() {
+                    self.read_from_server(&session_info);
+                }
+                self.flush_socket_session(&session_info);
+                return;
+            }
             if event.is_readable() {
                 log::trace!("handle server event read, session={:?}", session_info);
 
                 self.read_from_server(&session_info);
                 self.write_to_smoltcp(&session_info);
                 self.write_to_tun(&session_info);
+                // queries may have been issued on behalf of the client.
+                self.write_to_server(&session_info);
 
                 log::trace!("finished server event read, session={:?}", session_info);
             }
@@ -227,87 +1320,255 @@ impl<'a> Processor<'a> {
 
                 self.read_from_smoltcp(&session_info);
                 self.write_to_server(&session_info);
+                self.resume_client_read(&session_info);
+                self.write_to_tun(&session_info);
 
                 log::trace!("finished server event write, session={:?}", session_info);
             }
             if event.is_read_closed() || event.is_write_closed() {
                 log::trace!("handle server event closed, session={:?}", session_info);
 
-                self.destroy_session(&session_info);
+                let close_reason = self.server_close_reason(&session_info, None);
+                self.destroy_session(&session_info, close_reason);
 
                 log::trace!("finished server event closed, session={:?}", session_info);
             }
         }
     }
 
-    fn read_from_server(&mut self, session_info: &SessionInfo) {
+    //
+    // A connecting upstream socket becomes writable once the connection has
+    // been established and additionally reports an error when it has failed.
+    // Socks clients are answered then.
+    //
+    fn handle_server_connect(&mut self, session_info: &SessionInfo, event: &Event) {
+        let mut is_failed = false;
         if let Some(session) = self.sessions.get_mut(session_info) {
-            log::trace!("read from server, session={:?}", session_info);
-
-            let is_session_closed = match session.mio_socket.read() {
-                Ok((read_seqs, is_closed)) => {
-                    for bytes in read_seqs {
-                        if !bytes.is_empty() {
-                            let event = IncomingDataEvent {
-                                direction: IncomingDirection::FromServer,
-                                buffer: &bytes[..],
-                            };
-                            session.buffers.push_data(event);
+            if session.is_upstream_connecting && (event.is_writable() || event.is_error()) {
+                session.is_upstream_connecting = false;
+                if event.is_error() {
+                    log::debug!("failed to connect to server, session={:?}", session_info);
+                    METRICS.connect_failures.increment();
+                }
+                if let Client::Socket(client) = &mut session.client {
+                    if client.is_reply_pending {
+                        client.is_reply_pending = false;
+                        let (reply, is_connected) = transparent::connect_reply(&session.mio_socket);
+                        if let Err(error) = client.socket.write(&reply) {
+                            log::debug!("failed to reply to socks client, error={:?}", error);
                         }
+                        is_failed = !is_connected;
                     }
-                    is_closed
                 }
-                Err(error) => {
-                    if error.kind() == ErrorKind::WouldBlock {
-                        false
-                    } else if error.kind() == ErrorKind::ConnectionReset {
-                        true
-                    } else {
-                        log::error!("failed to read from tcp stream, errro={:?}", error);
-                        true
+            }
+        }
+        if is_failed {
+            self.destroy_session(session_info, CloseReason::Error);
+        }
+    }
+
+    fn read_from_server(&mut self, session_info: &SessionInfo) -> usize {
+        let Some(session) = self.sessions.get_mut(session_info) else {
+            return 0;
+        };
+        if session.is_upstream_closed {
+            return 0;
+        }
+        log::trace!("read from server, session={:?}", session_info);
+
+        // data delayed on the way to a client with a socket is queued as well.
+        let delayed = match &session.client {
+            Client::Socket(client) => client.to_client_delay.bytes,
+            Client::Tun(_) | Client::Association(_, _) => 0,
+        };
+        let queued = session.buffers.len(&OutgoingDirection::ToClient) + delayed;
+        let limit = self.config.memory.max_queued.saturating_sub(queued);
+        if limit == 0 {
+            log::trace!("pausing read from server, session={:?}", session_info);
+            session.is_server_read_paused = true;
+            return 0;
+        }
+
+        let is_tun = matches!(session.client, Client::Tun(_));
+        let is_dns = session_info.transport_protocol == TransportProtocol::Udp
+            && session_info.destination.port() == DNS_PORT
+            && tun_callbacks::is_connection_log_enabled();
+        // payload for clients with sockets goes through the emulator first.
+        let mut payloads = Vec::new();
+        let mut read = 0;
+        // set with the error, if any, once the server has closed the connection.
+        let closed = match session.mio_socket.read(limit) {
+            Ok((read_seqs, is_closed)) => {
+                read = read_seqs.iter().map(Vec::len).sum();
+                session.is_server_read_paused = !is_closed && read >= limit;
+                if read > 0 {
+                    session.last_activity = std::time::Instant::now();
+                }
+                for bytes in read_seqs {
+                    if !bytes.is_empty() {
+                        let (direction, bytes) = match session.dns64.as_mut() {
+                            Some(dns64) => match dns64.handle_response(bytes) {
+                                Dns64Message::ToClient(bytes) => {
+                                    (IncomingDirection::FromServer, bytes)
+                                }
+                                Dns64Message::ToServer(bytes) => {
+                                    (IncomingDirection::FromClient, bytes)
+                                }
+                            },
+                            None => (IncomingDirection::FromServer, bytes),
+                        };
+                        if is_dns && direction == IncomingDirection::FromServer {
+                            self.resolved_names.record_response(&bytes);
+                        }
+                        if !is_tun && direction == IncomingDirection::FromServer {
+                            payloads.push(bytes);
+                            continue;
+                        }
+                        let event = IncomingDataEvent {
+                            direction,
+                            buffer: &bytes[..],
+                        };
+                        session.buffers.push_data(event);
                     }
                 }
-            };
-            if is_session_closed {
-                self.destroy_session(session_info);
+                is_closed.then_some(None)
+            }
+            Err(error) => {
+                if error.kind() == ErrorKind::WouldBlock {
+                    None
+                } else if error.kind() == ErrorKind::ConnectionReset {
+                    Some(Some(error))
+                } else {
+                    log::error!("failed to read from tcp stream, errro={:?}", error);
+                    Some(Some(error))
+                }
+            }
+        };
+        for bytes in payloads {
+            self.queue_payload(session_info, OutgoingDirection::ToClient, bytes);
+        }
+        if let Some(error) = closed {
+            self.close_server(session_info, error);
+        }
+
+        log::trace!("finished read from server, session={:?}", session_info);
+        read
+    }
+
+    //
+    // The client of a tcp connection with a socket is told once the data of
+    // the server has been written to it; other sessions end with the server.
+    //
+    fn close_server(&mut self, session_info: &SessionInfo, error: Option<std::io::Error>) {
+        let Some(session) = self.sessions.get_mut(session_info) else {
+            return;
+        };
+        let is_tcp = session_info.transport_protocol == TransportProtocol::Tcp;
+        match &mut session.client {
+            Client::Socket(client) if is_tcp && error.is_none() => {
+                client.is_server_closed = true;
+                client.close_reason.get_or_insert(CloseReason::ServerFin);
+                return;
             }
+            _ => {}
+        }
+        let is_reset = error.is_some() && !matches!(session.client, Client::Tun(_));
+        let close_reason = self.server_close_reason(session_info, error);
+        if is_reset {
+            self.abort_session(session_info, close_reason);
+        } else {
+            self.destroy_session(session_info, close_reason);
+        }
+    }
+
+    //
+    // Reading from the server is paused while the queue to the client is full
+    // and resumed once data has been written to the client.
+    //
+    fn resume_server_read(&mut self, session_info: &SessionInfo) {
+        if let Some(session) = self.sessions.get(session_info) {
+            let queued = session.buffers.len(&OutgoingDirection::ToClient);
+            if session.is_server_read_paused && queued < self.config.memory.max_queued {
+                log::trace!("resuming read from server, session={:?}", session_info);
 
-            log::trace!("finished read from server, session={:?}", session_info);
+                self.read_from_server(session_info);
+                self.write_to_smoltcp(session_info);
+            }
         }
     }
 
-    fn write_to_server(&mut self, session_info: &SessionInfo) {
+    //
+    // Reading from the client stops while the queue to the server is full and
+    // resumes once data has been written to the server; reading again opens the
+    // window of the client.
+    //
+    fn resume_client_read(&mut self, session_info: &SessionInfo) {
         if let Some(session) = self.sessions.get_mut(session_info) {
-            log::trace!("write to server, session={:?}", session_info);
+            let Client::Tun(client) = &mut session.client else {
+                return;
+            };
+            let socket = client.smoltcp_socket.get(&mut client.sockets);
+            let queued = session.buffers.len(&OutgoingDirection::ToServer);
+            if socket.can_receive() && queued < self.config.memory.max_queued {
+                log::trace!("resuming read from client, session={:?}", session_info);
 
-            session
-                .buffers
-                .write_data(OutgoingDirection::ToServer, |b| {
-                    session.mio_socket.write(b).map_err(|e| e.into())
-                });
+                self.read_from_smoltcp(session_info);
+                self.write_to_server(session_info);
+            }
+        }
+    }
 
-            log::trace!("finished write to server, session={:?}", session_info);
+    fn write_to_server(&mut self, session_info: &SessionInfo) -> usize {
+        if self.is_held(session_info) {
+            return 0;
         }
+        let Some(session) = self.sessions.get_mut(session_info) else {
+            return 0;
+        };
+        if session.is_upstream_closed {
+            return 0;
+        }
+        log::trace!("write to server, session={:?}", session_info);
+
+        let direction = OutgoingDirection::ToServer;
+        let now = std::time::Instant::now();
+        let allowance = self.shaper.allowance(&mut session.shaping, &direction, now);
+        let written = session.buffers.write_data(direction, allowance, |b| {
+            session.mio_socket.write(b).map_err(|e| e.into())
+        });
+        session.counters.bytes_to_server += written as u64;
+        METRICS.bytes_to_server.add(written as u64);
+        self.shape_write(
+            session_info,
+            OutgoingDirection::ToServer,
+            allowance,
+            written,
+        );
+
+        log::trace!("finished write to server, session={:?}", session_info);
+        written
     }
 
     fn read_from_smoltcp(&mut self, session_info: &SessionInfo) {
         if let Some(session) = self.sessions.get_mut(session_info) {
             log::trace!("read from smoltcp, session={:?}", session_info);
 
+            let max_queued = self.config.memory.max_queued;
             let mut data: [u8; 65535] = [0; 65535];
             loop {
-                let mut socket = session.smoltcp_socket.get(&mut session.sockets);
+                // data is left in the socket, closing its window, while the queue is full.
+                if session.buffers.len(&OutgoingDirection::ToServer) >= max_queued {
+                    break;
+                }
+                let Client::Tun(client) = &mut session.client else {
+                    break;
+                };
+                let mut socket = client.smoltcp_socket.get(&mut client.sockets);
                 if !socket.can_receive() {
                     break;
                 }
                 match socket.receive(&mut data) {
-                    Ok(data_len) => {
-                        let event = IncomingDataEvent {
-                            direction: IncomingDirection::FromClient,
-                            buffer: &data[..data_len],
-                        };
-                        session.buffers.push_data(event);
-                    }
+                    Ok(data_len) => session.receive_from_client(session_info, &data[..data_len]),
                     Err(error) => {
                         log::error!("failed to receive from smoltcp, error={:?}", error);
                         break;
@@ -321,16 +1582,498 @@ impl<'a> Processor<'a> {
 
     fn write_to_smoltcp(&mut self, session_info: &SessionInfo) {
         if let Some(session) = self.sessions.get_mut(session_info) {
+            let Client::Tun(client) = &mut session.client else {
+                return;
+            };
             log::trace!("write to smoltcp, session={:?}", session_info);
 
-            let mut socket = session.smoltcp_socket.get(&mut session.sockets);
+            let mut socket = client.smoltcp_socket.get(&mut client.sockets);
             if socket.can_send() {
-                session
+                let direction = OutgoingDirection::ToClient;
+                let now = std::time::Instant::now();
+                let allowance = self.shaper.allowance(&mut session.shaping, &direction, now);
+                let written = session
                     .buffers
-                    .write_data(OutgoingDirection::ToClient, |b| socket.send(b));
+                    .write_data(direction, allowance, |b| socket.send(b));
+                session.counters.bytes_to_client += written as u64;
+                METRICS.bytes_to_client.add(written as u64);
+                self.shape_write(
+                    session_info,
+                    OutgoingDirection::ToClient,
+                    allowance,
+                    written,
+                );
             }
 
             log::trace!("finished write to smoltcp, session={:?}", session_info);
         }
     }
+
+    fn is_tun_session(&self, session_info: &SessionInfo) -> bool {
+        self.sessions
+            .get(session_info)
+            .is_some_and(|session| matches!(session.client, Client::Tun(_)))
+    }
+
+    //
+    // Udp sessions of clients with sockets are not ended by the client, so
+    // they expire once idle, see SOCKET_UDP_IDLE_TIMEOUT.
+    //
+    fn is_socket_udp_session(session_info: &SessionInfo, session: &Session) -> bool {
+        session_info.transport_protocol == TransportProtocol::Udp
+            && !matches!(session.client, Client::Tun(_))
+    }
+
+    fn expire_socket_sessions(&mut self, now: std::time::Instant) {
+        let expired_sessions: Vec<SessionInfo> = self
+            .sessions
+            .iter()
+            .filter(|(session_info, session)| {
+                Self::is_socket_udp_session(session_info, session)
+                    && now.saturating_duration_since(session.last_activity)
+                        >= SOCKET_UDP_IDLE_TIMEOUT
+            })
+            .map(|(session_info, _)| *session_info)
+            .collect();
+        for session_info in expired_sessions {
+            log::debug!("udp session timed out, session={:?}", session_info);
+            self.destroy_session(&session_info, CloseReason::Timeout);
+        }
+    }
+
+    fn handle_client_event(&mut self, event: &Event) {
+        // sessions are drained after each batch of events instead.
+        if self.shutdown_deadline.is_some() {
+            return;
+        }
+        if let Some(session_info) = self.tokens_to_clients.get(&event.token()) {
+            let session_info = *session_info;
+            log::trace!("handle client event, session={:?}", session_info);
+
+            if event.is_readable() || event.is_read_closed() || event.is_error() {
+                self.read_from_socket_client(&session_info);
+            }
+            self.flush_socket_session(&session_info);
+
+            log::trace!("finished client event, session={:?}", session_info);
+        }
+    }
+
+    fn handle_listener_event(&mut self, event: &Event) {
+        let arrivals =
+            self.proxy
+                .handle_event(event.token(), self.poll.registry(), &mut self.tokens);
+        self.handle_arrivals(arrivals);
+    }
+
+    fn handle_arrivals(&mut self, arrivals: Vec<Arrival>) {
+        for arrival in arrivals {
+            match arrival {
+                Arrival::Connection(connection) => self.accept_connection(connection),
+                Arrival::Datagram(datagram) => self.accept_datagram(datagram),
+                Arrival::AssociationClosed(relay_token) => self.close_association(relay_token),
+            }
+        }
+    }
+
+    //
+    // Applies the checks of sessions created from packets to a session of a
+    // client with a socket, which has no smoltcp socket.
+    //
+    fn admit_socket_session(
+        &mut self,
+        session_info: &SessionInfo,
+        process: Option<&ProcessInfo>,
+    ) -> bool {
+        let now = std::time::Instant::now();
+        if !self.session_rate.allow(session_info, now) {
+            log::debug!("session rate exceeded, session={:?}", session_info);
+            return false;
+        }
+        self.admit_session(session_info, process, 0)
+    }
+
+    fn accept_connection(&mut self, connection: Connection) {
+        let Connection {
+            session_info,
+            socket,
+            hostname,
+            payload,
+            is_socks,
+        } = connection;
+        if self.sessions.contains_key(&session_info) {
+            log::debug!("session exists, session={:?}", session_info);
+            self.refuse_connection(&session_info, socket, is_socks);
+            return;
+        }
+        let process = process_lookups::initial_process();
+        if !self.admit_socket_session(&session_info, process.as_ref()) {
+            self.refuse_connection(&session_info, socket, is_socks);
+            return;
+        }
+        let mut client = SocketClient::new(socket, self.tokens.next());
+        client.is_reply_pending = is_socks;
+        if let Err(client) = self.insert_session(&session_info, Client::Socket(client), process) {
+            if let Client::Socket(client) = client {
+                self.refuse_connection(&session_info, client.socket, is_socks);
+            }
+            return;
+        }
+
+        if let Some(session) = self.sessions.get_mut(&session_info) {
+            if hostname.is_some() {
+                session.hostname = hostname;
+            }
+        }
+        if !payload.is_empty() {
+            self.queue_payload(&session_info, OutgoingDirection::ToServer, payload);
+        }
+        self.read_from_socket_client(&session_info);
+        self.flush_socket_session(&session_info);
+    }
+
+    //
+    // Socks clients are told why their connection has been refused, others
+    // are reset.
+    //
+    fn refuse_connection(&self, session_info: &SessionInfo, mut socket: MioSocket, is_socks: bool) {
+        if is_socks {
+            let reply = transparent::refusal_reply(self.is_allowed(session_info, None));
+            if let Err(error) = socket.write(&reply) {
+                log::debug!("failed to reply to socks client, error={:?}", error);
+            }
+        } else {
+            socket.reset_on_close();
+        }
+    }
+
+    fn accept_datagram(&mut self, datagram: Datagram) {
+        let Datagram {
+            session_info,
+            origin,
+            hostname,
+            bytes,
+        } = datagram;
+        if !self.sessions.contains_key(&session_info) {
+            let process = process_lookups::initial_process();
+            if !self.admit_socket_session(&session_info, process.as_ref()) {
+                return;
+            }
+            let client = match origin {
+                DatagramOrigin::Tproxy => match transparent::reply_socket(&session_info) {
+                    Ok(socket) => Client::Socket(SocketClient::new(socket, self.tokens.next())),
+                    Err(error) => {
+                        log::error!("failed to create reply socket, error={:?}", error);
+                        return;
+                    }
+                },
+                DatagramOrigin::Association(relay_token, client) => {
+                    Client::Association(relay_token, client)
+                }
+            };
+            if self.insert_session(&session_info, client, process).is_err() {
+                return;
+            }
+            if let Some(session) = self.sessions.get_mut(&session_info) {
+                if hostname.is_some() {
+                    session.hostname = hostname;
+                }
+            }
+        }
+        if let Some(session) = self.sessions.get_mut(&session_info) {
+            session.last_activity = std::time::Instant::now();
+        }
+        self.queue_payload(&session_info, OutgoingDirection::ToServer, bytes);
+        self.flush_socket_session(&session_info);
+    }
+
+    fn close_association(&mut self, relay_token: Token) {
+        let sessions: Vec<SessionInfo> = self
+            .sessions
+            .iter()
+            .filter(|(_, session)| {
+                matches!(session.client, Client::Association(token, _) if token == relay_token)
+            })
+            .map(|(session_info, _)| *session_info)
+            .collect();
+        for session_info in sessions {
+            self.destroy_session(&session_info, CloseReason::ClientFin);
+        }
+    }
+
+    //
+    // Payload exchanged with clients with sockets is delayed by the emulator,
+    // and datagrams are also lost, duplicated or reordered, before it is
+    // queued, see deliver_payload.
+    //
+    fn queue_payload(
+        &mut self,
+        session_info: &SessionInfo,
+        direction: OutgoingDirection,
+        bytes: Vec<u8>,
+    ) {
+        let Some(session) = self.sessions.get_mut(session_info) else {
+            return;
+        };
+        let now = std::time::Instant::now();
+        let is_tcp = session_info.transport_protocol == TransportProtocol::Tcp;
+        let ready_payloads = match &mut session.client {
+            Client::Socket(client) if is_tcp => {
+                let delay = client.delay(&direction);
+                let ready =
+                    self.emulator
+                        .emulate_stream(direction, session_info, bytes, now, delay);
+                ready.into_iter().collect()
+            }
+            _ => self
+                .emulator
+                .emulate_payload(direction, session_info, bytes, now),
+        };
+        for bytes in ready_payloads {
+            Self::deliver_payload(session_info, session, direction, &bytes);
+        }
+    }
+
+    fn deliver_payload(
+        session_info: &SessionInfo,
+        session: &mut Session,
+        direction: OutgoingDirection,
+        bytes: &[u8],
+    ) {
+        match direction {
+            OutgoingDirection::ToServer => session.receive_from_client(session_info, bytes),
+            OutgoingDirection::ToClient => session.buffers.push_data(IncomingDataEvent {
+                direction: IncomingDirection::FromServer,
+                buffer: bytes,
+            }),
+        }
+    }
+
+    fn deliver_delayed_payload(
+        &mut self,
+        session_info: &SessionInfo,
+        direction: OutgoingDirection,
+        bytes: Vec<u8>,
+    ) {
+        let Some(session) = self.sessions.get_mut(session_info) else {
+            return;
+        };
+        if let Client::Socket(client) = &mut session.client {
+            let delay = client.delay(&direction);
+            delay.bytes = delay.bytes.saturating_sub(bytes.len());
+        }
+        Self::deliver_payload(session_info, session, direction, &bytes);
+        self.flush_socket_session(session_info);
+    }
+
+    fn read_from_socket_client(&mut self, session_info: &SessionInfo) -> usize {
+        let Some(session) = self.sessions.get_mut(session_info) else {
+            return 0;
+        };
+        let Client::Socket(client) = &mut session.client else {
+            return 0;
+        };
+        if client.is_closed {
+            return 0;
+        }
+        log::trace!("read from client, session={:?}", session_info);
+
+        // data delayed on the way to the server is queued as well.
+        let queued =
+            session.buffers.len(&OutgoingDirection::ToServer) + client.to_server_delay.bytes;
+        let limit = self.config.memory.max_queued.saturating_sub(queued);
+        if limit == 0 {
+            log::trace!("pausing read from client, session={:?}", session_info);
+            client.is_read_paused = true;
+            return 0;
+        }
+
+        let mut read = 0;
+        let mut payloads = Vec::new();
+        let mut reset = None;
+        match client.socket.read(limit) {
+            Ok((read_seqs, is_closed)) => {
+                read = read_seqs.iter().map(Vec::len).sum();
+                client.is_read_paused = !is_closed && read >= limit;
+                if is_closed && session_info.transport_protocol == TransportProtocol::Tcp {
+                    client.is_closed = true;
+                    client.close_reason.get_or_insert(CloseReason::ClientFin);
+                }
+                payloads.extend(read_seqs.into_iter().filter(|bytes| !bytes.is_empty()));
+            }
+            Err(error) => {
+                if error.kind() == ErrorKind::ConnectionReset {
+                    reset = Some(CloseReason::Reset);
+                } else if error.kind() != ErrorKind::WouldBlock {
+                    log::debug!("failed to read from client, error={:?}", error);
+                    reset = Some(CloseReason::Error);
+                }
+            }
+        }
+        if read > 0 {
+            session.last_activity = std::time::Instant::now();
+        }
+        self.socket_bytes_received += read as u64;
+        for bytes in payloads {
+            self.queue_payload(session_info, OutgoingDirection::ToServer, bytes);
+        }
+        if let Some(close_reason) = reset {
+            self.abort_session(session_info, close_reason);
+        }
+
+        log::trace!("finished read from client, session={:?}", session_info);
+        read
+    }
+
+    fn write_to_socket_client(&mut self, session_info: &SessionInfo) -> usize {
+        let Some(session) = self.sessions.get_mut(session_info) else {
+            return 0;
+        };
+        if matches!(session.client, Client::Tun(_)) {
+            return 0;
+        }
+        log::trace!("write to client, session={:?}", session_info);
+
+        let direction = OutgoingDirection::ToClient;
+        let now = std::time::Instant::now();
+        let allowance = self.shaper.allowance(&mut session.shaping, &direction, now);
+        let proxy = &self.proxy;
+        let mut error_kind = None;
+        let written = session.buffers.write_data(direction, allowance, |b| {
+            let result = match &mut session.client {
+                Client::Socket(client) => client.socket.write(b),
+                Client::Association(relay_token, client) => {
+                    proxy.send_datagram(*relay_token, *client, session_info.destination, b)
+                }
+                Client::Tun(_) => Ok(0),
+            };
+            result.map_err(|error| {
+                error_kind = Some(error.kind());
+                error.into()
+            })
+        });
+        session.counters.bytes_to_client += written as u64;
+        METRICS.bytes_to_client.add(written as u64);
+        self.socket_bytes_sent += written as u64;
+        self.shape_write(
+            session_info,
+            OutgoingDirection::ToClient,
+            allowance,
+            written,
+        );
+
+        let is_failed = error_kind.is_some_and(|kind| kind != ErrorKind::WouldBlock);
+        if is_failed && session_info.transport_protocol == TransportProtocol::Tcp {
+            log::debug!(
+                "failed to write to client, session={:?} error={:?}",
+                session_info,
+                error_kind
+            );
+            self.abort_session(session_info, CloseReason::Reset);
+        }
+
+        log::trace!("finished write to client, session={:?}", session_info);
+        written
+    }
+
+    //
+    // Moves data between a client with a socket and the server until neither
+    // side takes or has more, then closes what has been closed by the other
+    // side.
+    //
+    fn flush_socket_session(&mut self, session_info: &SessionInfo) {
+        if self.is_tun_session(session_info) {
+            return;
+        }
+        loop {
+            let written = self.write_to_server(session_info);
+            let is_paused = self.sessions.get(session_info).is_some_and(|session| {
+                matches!(&session.client, Client::Socket(client) if client.is_read_paused)
+            });
+            let read = if is_paused {
+                self.read_from_socket_client(session_info)
+            } else {
+                0
+            };
+            if written == 0 && read == 0 {
+                break;
+            }
+        }
+        loop {
+            let written = self.write_to_socket_client(session_info);
+            let is_paused = self
+                .sessions
+                .get(session_info)
+                .is_some_and(|session| session.is_server_read_paused);
+            let read = if is_paused {
+                self.read_from_server(session_info)
+            } else {
+                0
+            };
+            if written == 0 && read == 0 {
+                break;
+            }
+        }
+        self.close_socket_session(session_info);
+    }
+
+    //
+    // Each side of a tcp connection with a socket is shut down once the other
+    // side has closed it and its queue has been written; the session ends
+    // once both are.
+    //
+    fn close_socket_session(&mut self, session_info: &SessionInfo) {
+        let Some(session) = self.sessions.get_mut(session_info) else {
+            return;
+        };
+        let Client::Socket(client) = &mut session.client else {
+            return;
+        };
+        let is_to_server_empty = session.buffers.is_empty(&OutgoingDirection::ToServer)
+            && client.to_server_delay.bytes == 0;
+        let is_to_client_empty = session.buffers.is_empty(&OutgoingDirection::ToClient)
+            && client.to_client_delay.bytes == 0;
+        if client.is_closed && is_to_server_empty && !session.is_upstream_closed {
+            session.mio_socket.shutdown_write();
+        }
+        if client.is_server_closed && is_to_client_empty {
+            client.socket.shutdown_write();
+        }
+        if client.is_closed && client.is_server_closed && is_to_server_empty && is_to_client_empty {
+            let close_reason = client.close_reason.unwrap_or(CloseReason::ServerFin);
+            self.destroy_session(session_info, close_reason);
+        }
+    }
+
+    fn shape_write(
+        &mut self,
+        session_info: &SessionInfo,
+        direction: OutgoingDirection,
+        allowance: usize,
+        written: usize,
+    ) {
+        if let Some(session) = self.sessions.get_mut(session_info) {
+            self.shaper
+                .consume(&mut session.shaping, &direction, written);
+
+            if written >= allowance && !session.buffers.is_empty(&direction) {
+                let now = std::time::Instant::now();
+                let deadline = self
+                    .shaper
+                    .next_write_time(&mut session.shaping, &direction, now);
+
+                log::trace!(
+                    "delaying write, session={:?} direction={:?} delay={:?}",
+                    session_info,
+                    direction,
+                    deadline - now
+                );
+
+                self.delayed_writes
+                    .entry(*session_info)
+                    .and_modify(|current| *current = (*current).min(deadline))
+                    .or_insert(deadline);
+            }
+        }
+    }
 }
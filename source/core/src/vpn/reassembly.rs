@@ -0,0 +1,448 @@
+// This is free and unencumbered software released into the public domain.
+//
+// Anyone is free to copy, modify, publish, use, compile, sell, or
+// distribute this software, either in source code form or as a compiled
+// binary, for any purpose, commercial or non-commercial, and by any
+// means.
+//
+// In jurisdictions that recognize copyright laws, the author or authors
+// of this software dedicate any and all copyright interest in the
+// software to the public domain. We make this dedication for the benefit
+// of the public at large and to the detriment of our heirs and
+// successors. We intend this dedication to be an overt act of
+// relinquishment in perpetuity of all present and future rights to this
+// software under copyright law.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS BE LIABLE FOR ANY CLAIM, DAMAGES OR
+// OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE,
+// ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR
+// OTHER DEALINGS IN THE SOFTWARE.
+//
+// For more information, please refer to <https://unlicense.org>
+
+use crate::config::ReassemblyConfig;
+use crate::Error;
+use smoltcp::wire::{IpProtocol, Ipv4Packet, Ipv6Packet};
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::IpAddr,
+    time::Instant,
+};
+
+const IPV6_HEADER_LENGTH: usize = 40;
+const IPV6_FRAGMENT_HEADER_LENGTH: usize = 8;
+const IPV6_AUTHENTICATION_HEADER: IpProtocol = IpProtocol::Unknown(51);
+const MAXIMUM_PACKET_LENGTH: usize = 65535;
+
+// bookkeeping charged against the memory limit for every fragment held.
+const FRAGMENT_OVERHEAD: usize = 64;
+
+//
+// Result of walking the extension header chain of an ipv6 packet; offsets are
+// relative to the ipv6 payload.
+//
+pub(crate) struct Ipv6Headers {
+    pub(crate) protocol: IpProtocol,
+    pub(crate) offset: usize,
+    pub(crate) fragment: Option<Ipv6Fragment>,
+}
+
+pub(crate) struct Ipv6Fragment {
+    next_header: IpProtocol,
+    data_offset: usize,
+    offset: usize,
+    more_fragments: bool,
+    identification: u32,
+}
+
+//
+// Walks hop-by-hop, routing, destination options, authentication and fragment
+// headers up to the upper layer header. The walk stops at a fragment header
+// unless the fragment is atomic, as the headers following it are only
+// available once the packet has been reassembled.
+//
+pub(crate) fn parse_ipv6_headers(
+    next_header: IpProtocol,
+    payload: &[u8],
+) -> crate::Result<Ipv6Headers> {
+    let mut protocol = next_header;
+    let mut offset = 0;
+    let mut fragment = None;
+    loop {
+        let header = payload
+            .get(offset..offset + 2)
+            .ok_or(Error::Wire(smoltcp::wire::Error))?;
+        let length = match protocol {
+            IpProtocol::HopByHop | IpProtocol::Ipv6Route | IpProtocol::Ipv6Opts => {
+                (usize::from(header[1]) + 1) * 8
+            }
+            IPV6_AUTHENTICATION_HEADER => (usize::from(header[1]) + 2) * 4,
+            IpProtocol::Ipv6Frag => IPV6_FRAGMENT_HEADER_LENGTH,
+            _ => break,
+        };
+        let header = payload
+            .get(offset..offset + length)
+            .ok_or(Error::Wire(smoltcp::wire::Error))?;
+        let next_header = IpProtocol::from(header[0]);
+
+        if protocol == IpProtocol::Ipv6Frag {
+            let offset_and_flags = u16::from_be_bytes([header[2], header[3]]);
+            let ipv6_fragment = Ipv6Fragment {
+                next_header,
+                data_offset: offset + length,
+                offset: usize::from(offset_and_flags & 0xfff8),
+                more_fragments: offset_and_flags & 0x1 != 0,
+                identification: u32::from_be_bytes([header[4], header[5], header[6], header[7]]),
+            };
+            if fragment.is_some() {
+                return Err(Error::InvalidFragment);
+            }
+            let is_atomic = ipv6_fragment.offset == 0 && !ipv6_fragment.more_fragments;
+            fragment = Some(ipv6_fragment);
+            if !is_atomic {
+                protocol = next_header;
+                offset += length;
+                break;
+            }
+        }
+
+        protocol = next_header;
+        offset += length;
+    }
+    Ok(Ipv6Headers {
+        protocol,
+        offset,
+        fragment,
+    })
+}
+
+impl Ipv6Headers {
+    pub(crate) fn is_fragmented(&self) -> bool {
+        self.fragment
+            .as_ref()
+            .is_some_and(|fragment| fragment.offset != 0 || fragment.more_fragments)
+    }
+}
+
+#[derive(PartialEq, Eq, Hash, Debug, Clone, Copy)]
+struct FragmentKey {
+    source: IpAddr,
+    destination: IpAddr,
+    protocol: u8,
+    identification: u32,
+}
+
+struct Fragment<'a> {
+    offset: usize,
+    more_fragments: bool,
+    data: &'a [u8],
+}
+
+struct PartialPacket {
+    // header of the first fragment, to which the reassembled payload is appended.
+    header: Option<Vec<u8>>,
+    protocol: IpProtocol,
+    fragments: BTreeMap<usize, Vec<u8>>,
+    total_length: Option<usize>,
+    deadline: Instant,
+    memory: usize,
+}
+
+impl PartialPacket {
+    fn new(deadline: Instant) -> PartialPacket {
+        PartialPacket {
+            header: None,
+            protocol: IpProtocol::Unknown(0),
+            fragments: BTreeMap::new(),
+            total_length: None,
+            deadline,
+            memory: 0,
+        }
+    }
+
+    fn is_duplicate(&self, fragment: &Fragment) -> bool {
+        self.fragments
+            .get(&fragment.offset)
+            .is_some_and(|data| data.len() == fragment.data.len())
+    }
+
+    fn overlaps(&self, fragment: &Fragment) -> bool {
+        let end = fragment.offset + fragment.data.len();
+        let previous = self.fragments.range(..fragment.offset).next_back();
+        let next = self.fragments.range(fragment.offset..).next();
+        previous.is_some_and(|(offset, data)| offset + data.len() > fragment.offset)
+            || next.is_some_and(|(offset, _)| *offset < end)
+    }
+
+    fn is_complete(&self) -> bool {
+        let Some(total_length) = self.total_length else {
+            return false;
+        };
+        let mut expected_offset = 0;
+        for (offset, data) in self.fragments.iter() {
+            if *offset != expected_offset {
+                return false;
+            }
+            expected_offset += data.len();
+        }
+        self.header.is_some() && expected_offset == total_length
+    }
+}
+
+//
+// Reassembles fragmented packets read from the tun before they are classified
+// and handed to smoltcp. Incomplete packets are dropped after a timeout, and
+// the oldest incomplete packets are dropped when the memory limit is reached.
+//
+pub(crate) struct Reassembler {
+    config: ReassemblyConfig,
+    packets: HashMap<FragmentKey, PartialPacket>,
+    memory: usize,
+}
+
+impl Reassembler {
+    pub(crate) fn new(config: ReassemblyConfig) -> Reassembler {
+        Reassembler {
+            config,
+            packets: HashMap::new(),
+            memory: 0,
+        }
+    }
+
+    pub(crate) fn configure(&mut self, config: ReassemblyConfig) {
+        self.config = config;
+        while self.memory > self.config.max_memory && self.drop_oldest() {}
+    }
+
+    //
+    // Returns the packet once all of its fragments have been received, or None
+    // while fragments are outstanding. Extension headers are removed from ipv6
+    // packets so that the upper layer header directly follows the ipv6 header.
+    //
+    pub(crate) fn reassemble(
+        &mut self,
+        bytes: Vec<u8>,
+        now: Instant,
+    ) -> crate::Result<Option<Vec<u8>>> {
+        self.expire(now);
+        match bytes.first().map(|byte| byte >> 4) {
+            Some(4) => self.reassemble_ipv4(bytes, now),
+            Some(6) => self.reassemble_ipv6(bytes, now),
+            _ => Ok(Some(bytes)),
+        }
+    }
+
+    pub(crate) fn next_deadline(&self) -> Option<Instant> {
+        self.packets.values().map(|packet| packet.deadline).min()
+    }
+
+    pub(crate) fn expire(&mut self, now: Instant) {
+        let memory = &mut self.memory;
+        self.packets.retain(|key, packet| {
+            let is_expired = packet.deadline <= now;
+            if is_expired {
+                log::debug!("dropping incomplete packet, key={:?}", key);
+                *memory -= packet.memory;
+            }
+            !is_expired
+        });
+    }
+
+    fn reassemble_ipv4(&mut self, bytes: Vec<u8>, now: Instant) -> crate::Result<Option<Vec<u8>>> {
+        let ip_packet = Ipv4Packet::new_checked(&bytes[..])?;
+        if !ip_packet.more_frags() && ip_packet.frag_offset() == 0 {
+            return Ok(Some(bytes));
+        }
+
+        let key = FragmentKey {
+            source: IpAddr::from(ip_packet.src_addr().0),
+            destination: IpAddr::from(ip_packet.dst_addr().0),
+            protocol: u8::from(ip_packet.next_header()),
+            identification: u32::from(ip_packet.ident()),
+        };
+        let header_length = usize::from(ip_packet.header_len());
+        let fragment = Fragment {
+            offset: usize::from(ip_packet.frag_offset()),
+            more_fragments: ip_packet.more_frags(),
+            data: ip_packet.payload(),
+        };
+        let header = &bytes[..header_length];
+        let protocol = ip_packet.next_header();
+
+        let Some((mut packet, _)) = self.insert(key, header, protocol, fragment, now)? else {
+            return Ok(None);
+        };
+        let total_length = u16::try_from(packet.len()).map_err(|_| Error::InvalidFragment)?;
+        let mut ip_packet = Ipv4Packet::new_unchecked(&mut packet[..]);
+        ip_packet.set_total_len(total_length);
+        ip_packet.set_more_frags(false);
+        ip_packet.set_frag_offset(0);
+        ip_packet.fill_checksum();
+        Ok(Some(packet))
+    }
+
+    fn reassemble_ipv6(&mut self, bytes: Vec<u8>, now: Instant) -> crate::Result<Option<Vec<u8>>> {
+        let ip_packet = Ipv6Packet::new_checked(&bytes[..])?;
+        let payload = ip_packet.payload();
+        let headers = parse_ipv6_headers(ip_packet.next_header(), payload)?;
+
+        if !headers.is_fragmented() {
+            if headers.offset == 0 {
+                return Ok(Some(bytes));
+            }
+            let header = &bytes[..IPV6_HEADER_LENGTH];
+            return build_ipv6(header, headers.protocol, &payload[headers.offset..]).map(Some);
+        }
+
+        let Some(ipv6_fragment) = headers.fragment else {
+            return Err(Error::InvalidFragment);
+        };
+        let key = FragmentKey {
+            source: IpAddr::from(ip_packet.src_addr().0),
+            destination: IpAddr::from(ip_packet.dst_addr().0),
+            protocol: 0,
+            identification: ipv6_fragment.identification,
+        };
+        let fragment = Fragment {
+            offset: ipv6_fragment.offset,
+            more_fragments: ipv6_fragment.more_fragments,
+            data: &payload[ipv6_fragment.data_offset..],
+        };
+        let header = &bytes[..IPV6_HEADER_LENGTH];
+        let protocol = ipv6_fragment.next_header;
+
+        let Some((packet, protocol)) = self.insert(key, header, protocol, fragment, now)? else {
+            return Ok(None);
+        };
+
+        // headers following the fragment header are only visible after reassembly.
+        let payload = &packet[IPV6_HEADER_LENGTH..];
+        let headers = parse_ipv6_headers(protocol, payload)?;
+        if headers.fragment.is_some() {
+            return Err(Error::InvalidFragment);
+        }
+        let header = &packet[..IPV6_HEADER_LENGTH];
+        build_ipv6(header, headers.protocol, &payload[headers.offset..]).map(Some)
+    }
+
+    fn insert(
+        &mut self,
+        key: FragmentKey,
+        header: &[u8],
+        protocol: IpProtocol,
+        fragment: Fragment,
+        now: Instant,
+    ) -> crate::Result<Option<(Vec<u8>, IpProtocol)>> {
+        let end = fragment.offset + fragment.data.len();
+        let is_aligned = !fragment.more_fragments || fragment.data.len().is_multiple_of(8);
+        if fragment.data.is_empty() || !is_aligned || header.len() + end > MAXIMUM_PACKET_LENGTH {
+            self.remove(&key);
+            return Err(Error::InvalidFragment);
+        }
+
+        if let Some(packet) = self.packets.get(&key) {
+            if packet.is_duplicate(&fragment) {
+                return Ok(None);
+            }
+            // overlapping fragments invalidate the whole packet, see rfc 5722.
+            if packet.overlaps(&fragment) {
+                self.remove(&key);
+                return Err(Error::InvalidFragment);
+            }
+        }
+
+        let memory = fragment.data.len() + header.len() + FRAGMENT_OVERHEAD;
+        if memory > self.config.max_memory {
+            return Err(Error::ReassemblyLimit);
+        }
+        while self.memory + memory > self.config.max_memory && self.drop_oldest() {}
+
+        let deadline = now + self.config.timeout;
+        let packet = self
+            .packets
+            .entry(key)
+            .or_insert_with(|| PartialPacket::new(deadline));
+
+        if !fragment.more_fragments {
+            if packet.total_length.is_some_and(|length| length != end) {
+                self.remove(&key);
+                return Err(Error::InvalidFragment);
+            }
+            packet.total_length = Some(end);
+        }
+        // fragments are ordered and do not overlap, so the last one ends furthest.
+        let last_end = packet
+            .fragments
+            .iter()
+            .next_back()
+            .map_or(0, |(offset, data)| offset + data.len());
+        if packet
+            .total_length
+            .is_some_and(|length| end > length || last_end > length)
+        {
+            self.remove(&key);
+            return Err(Error::InvalidFragment);
+        }
+        if fragment.offset == 0 {
+            packet.header = Some(header.to_vec());
+            packet.protocol = protocol;
+        }
+        packet
+            .fragments
+            .insert(fragment.offset, fragment.data.to_vec());
+        packet.memory += memory;
+        self.memory += memory;
+
+        if !packet.is_complete() {
+            return Ok(None);
+        }
+
+        let Some(packet) = self.remove(&key) else {
+            return Ok(None);
+        };
+        let mut bytes = packet.header.unwrap_or_default();
+        for data in packet.fragments.into_values() {
+            bytes.extend_from_slice(&data);
+        }
+        Ok(Some((bytes, packet.protocol)))
+    }
+
+    fn remove(&mut self, key: &FragmentKey) -> Option<PartialPacket> {
+        let packet = self.packets.remove(key)?;
+        self.memory -= packet.memory;
+        Some(packet)
+    }
+
+    fn drop_oldest(&mut self) -> bool {
+        let oldest = self
+            .packets
+            .iter()
+            .min_by_key(|(_, packet)| packet.deadline)
+            .map(|(key, _)| *key);
+        match oldest {
+            Some(key) => {
+                log::debug!(
+                    "dropping incomplete packet over memory limit, key={:?}",
+                    key
+                );
+                self.remove(&key);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+fn build_ipv6(header: &[u8], protocol: IpProtocol, payload: &[u8]) -> crate::Result<Vec<u8>> {
+    let payload_length = u16::try_from(payload.len()).map_err(|_| Error::InvalidFragment)?;
+    let mut bytes = Vec::with_capacity(header.len() + payload.len());
+    bytes.extend_from_slice(header);
+    bytes.extend_from_slice(payload);
+    let mut ip_packet = Ipv6Packet::new_unchecked(&mut bytes[..]);
+    ip_packet.set_next_header(protocol);
+    ip_packet.set_payload_len(payload_length);
+    Ok(bytes)
+}
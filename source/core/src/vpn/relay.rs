@@ -0,0 +1,188 @@
+// This is free and unencumbered software released into the public domain.
+//
+// Anyone is free to copy, modify, publish, use, compile, sell, or
+// distribute this software, either in source code form or as a compiled
+// binary, for any purpose, commercial or non-commercial, and by any
+// means.
+//
+// In jurisdictions that recognize copyright laws, the author or authors
+// of this software dedicate any and all copyright interest in the
+// software to the public domain. We make this dedication for the benefit
+// of the public at large and to the detriment of our heirs and
+// successors. We intend this dedication to be an overt act of
+// relinquishment in perpetuity of all present and future rights to this
+// software under copyright law.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS BE LIABLE FOR ANY CLAIM, DAMAGES OR
+// OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE,
+// ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR
+// OTHER DEALINGS IN THE SOFTWARE.
+//
+// For more information, please refer to <https://unlicense.org>
+
+use crate::tun_callbacks::on_socket_created;
+use crate::vpn::packet_source::{PacketSource, StreamSource, TunSource};
+use mio::{event::Event, Events, Poll, Token, Waker};
+use std::{io::ErrorKind, net::SocketAddr, os::unix::io::AsRawFd, time::Duration};
+
+const EVENTS_CAPACITY: usize = 1024;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+const TOKEN_TUN: Token = Token(0);
+const TOKEN_STREAM: Token = Token(1);
+const TOKEN_WAKER: Token = Token(2);
+
+//
+// Forwards raw packets between a tun file descriptor and a stream so they can
+// be processed on a remote host, e.g. a desktop reached through `adb reverse`.
+//
+pub(crate) struct Relay {
+    file_descriptor: i32,
+    address: SocketAddr,
+    stop_waker: Option<Waker>,
+    thread_join_handle: Option<std::thread::JoinHandle<()>>,
+}
+
+impl Relay {
+    pub(crate) fn new(file_descriptor: i32, address: SocketAddr) -> Self {
+        Self {
+            file_descriptor,
+            address,
+            stop_waker: None,
+            thread_join_handle: None,
+        }
+    }
+
+    pub(crate) fn start(&mut self) -> crate::Result<()> {
+        let poll = Poll::new()?;
+        self.stop_waker = Some(Waker::new(poll.registry(), TOKEN_WAKER)?);
+        let tun = TunSource::new(self.file_descriptor);
+        let address = self.address;
+        // connecting blocks, so it is done on the relay thread rather than the
+        // caller's, e.g. a jni thread.
+        self.thread_join_handle = Some(std::thread::spawn(move || {
+            let stream = match Self::connect(address) {
+                Ok(stream) => stream,
+                Err(error) => {
+                    log::error!("failed to connect to relay, error={:?}", error);
+                    return;
+                }
+            };
+            let mut forwarder = Forwarder { tun, stream, poll };
+            forwarder.run();
+        }));
+        Ok(())
+    }
+
+    pub(crate) fn stop(&mut self) {
+        if let Some(stop_waker) = self.stop_waker.take() {
+            stop_waker.wake().unwrap();
+        }
+        if let Some(thread_join_handle) = self.thread_join_handle.take() {
+            thread_join_handle.join().unwrap();
+        }
+    }
+
+    fn connect(address: SocketAddr) -> crate::Result<StreamSource> {
+        let domain = socket2::Domain::for_address(address);
+        let socket = socket2::Socket::new(domain, socket2::Type::STREAM, None)?;
+
+        on_socket_created(socket.as_raw_fd());
+
+        log::debug!("connecting to relay, address={:?}", address);
+        socket.connect_timeout(&address.into(), CONNECT_TIMEOUT)?;
+        log::debug!("connected to relay, address={:?}", address);
+
+        Ok(StreamSource::new(socket.into())?)
+    }
+}
+
+struct Forwarder {
+    tun: TunSource,
+    stream: StreamSource,
+    poll: Poll,
+}
+
+impl Forwarder {
+    fn run(&mut self) {
+        let registry = self.poll.registry();
+        self.tun.register(registry, TOKEN_TUN).unwrap();
+        self.stream.register(registry, TOKEN_STREAM).unwrap();
+
+        let mut events = Events::with_capacity(EVENTS_CAPACITY);
+
+        'poll_loop: loop {
+            let _ = self.poll.poll(&mut events, None);
+
+            for event in events.iter() {
+                let is_open = if event.token() == TOKEN_TUN {
+                    Self::forward(&mut self.tun, &mut self.stream, event)
+                } else if event.token() == TOKEN_STREAM {
+                    Self::forward(&mut self.stream, &mut self.tun, event)
+                } else {
+                    false
+                };
+                if !is_open {
+                    break 'poll_loop;
+                }
+            }
+        }
+
+        let registry = self.poll.registry();
+        let _ = self.tun.deregister(registry);
+        let _ = self.stream.deregister(registry);
+
+        log::trace!("relay is stopping");
+    }
+
+    fn forward(from: &mut dyn PacketSource, to: &mut dyn PacketSource, event: &Event) -> bool {
+        if event.is_writable() {
+            if let Err(error) = from.flush() {
+                if error.kind() != ErrorKind::WouldBlock {
+                    log::error!("failed to flush relay, error={:?}", error);
+                    return false;
+                }
+            }
+        }
+
+        if event.is_readable() {
+            let mut buffer: [u8; 65535] = [0; 65535];
+            loop {
+                match from.read_packet(&mut buffer) {
+                    Ok(0) => {
+                        log::info!("relay source is closed");
+                        return false;
+                    }
+                    Ok(count) => {
+                        match to.write_packet(&buffer[..count]) {
+                            Ok(_) => {}
+                            Err(error) if error.kind() == ErrorKind::WouldBlock => {
+                                // packets are dropped if the destination is not ready.
+                                log::warn!("dropped relayed packet, len={:?}", count);
+                            }
+                            Err(error) => {
+                                log::error!("failed to relay packet, error={:?}", error);
+                                return false;
+                            }
+                        }
+                    }
+                    Err(error) => {
+                        if error.kind() == ErrorKind::WouldBlock {
+                            // do nothing.
+                        } else {
+                            log::error!("failed to read from relay source, error={:?}", error);
+                            return false;
+                        }
+                        break;
+                    }
+                }
+            }
+        }
+
+        true
+    }
+}
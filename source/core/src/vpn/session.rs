@@ -23,75 +23,319 @@
 //
 // For more information, please refer to <https://unlicense.org>
 
+use crate::config::Config as VpnConfig;
+use crate::connection_log::{CloseReason, ConnectionRecord};
+use crate::process::ProcessInfo;
 use crate::vpn::{
-    buffers::{Buffers, TcpBuffers, UdpBuffers},
+    buffers::{
+        Buffers, IncomingDataEvent, IncomingDirection, OutgoingDirection, TcpBuffers, UdpBuffers,
+    },
+    dns64::Dns64,
+    hostname,
     mio_socket::{
         InternetProtocol as MioInternetProtocol, Socket as MioSocket,
         TransportProtocol as MioTransportProtocol,
     },
-    session_info::{InternetProtocol, SessionInfo, TransportProtocol},
-    smoltcp_socket::{Socket as SmoltcpSocket, TransportProtocol as SmoltcpProtocol},
+    mtu, process_lookups,
+    session_info::{SessionInfo, TransportProtocol},
+    shaping::SessionShaping,
+    smoltcp_socket::{Socket as SmoltcpSocket, SocketPool, TransportProtocol as SmoltcpProtocol},
     vpn_device::VpnDevice,
 };
 use mio::{Poll, Token};
 use smoltcp::{
     iface::{Config, Interface, SocketSet},
     time::Instant,
-    wire::{HardwareAddress, IpAddress, IpCidr, Ipv4Address},
+    wire::{HardwareAddress, IpAddress, IpCidr, Ipv4Address, Ipv6Address},
 };
+use std::{net::SocketAddr, time::SystemTime};
 
-pub(crate) struct Session<'a> {
+//
+// The interface accepts packets for any destination; its own addresses are
+// only used as the gateway of the default routes.
+//
+const DEFAULT_GATEWAY_IPV4: Ipv4Address = Ipv4Address([0, 0, 0, 1]);
+const DEFAULT_GATEWAY_IPV6: Ipv6Address =
+    Ipv6Address([0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+
+const MAXIMUM_MTU: usize = 65535;
+
+//
+// Payload written to the server and to the client, and packets exchanged with
+// the client through the tun.
+//
+#[derive(Default)]
+pub(crate) struct SessionCounters {
+    pub(crate) bytes_to_server: u64,
+    pub(crate) bytes_to_client: u64,
+    pub(crate) packets_from_client: u64,
+    pub(crate) packets_to_client: u64,
+}
+
+//
+// The side of a session facing the client.
+//
+pub(crate) enum Client<'a> {
+    // packets of the client read from and written to the tun.
+    Tun(Box<TunClient<'a>>),
+    // a socket of the kernel connected to the client, see Listeners.
+    Socket(SocketClient),
+    // datagrams to the client of a socks udp association are sent with a
+    // header through the relay of the association, its token, to the address.
+    Association(Token, SocketAddr),
+}
+
+//
+// Terminates the connection of a client behind the tun with smoltcp.
+//
+pub(crate) struct TunClient<'a> {
     pub(crate) smoltcp_socket: SmoltcpSocket,
-    pub(crate) mio_socket: MioSocket,
-    pub(crate) token: Token,
-    pub(crate) buffers: Buffers,
     pub(crate) interface: Interface,
     pub(crate) sockets: SocketSet<'a>,
     pub(crate) device: VpnDevice,
+    // memory allocated for the buffers of the smoltcp socket.
+    pub(crate) socket_memory: usize,
+}
+
+//
+// Exchanges payload with a client through a socket of the kernel, which a
+// listener has accepted or which has been created to reply to the client.
+//
+pub(crate) struct SocketClient {
+    pub(crate) socket: MioSocket,
+    pub(crate) token: Token,
+    // set while reading from the client is paused as the queue to the server is full.
+    pub(crate) is_read_paused: bool,
+    // set once the client, or the server, has closed its direction of a tcp connection.
+    pub(crate) is_closed: bool,
+    pub(crate) is_server_closed: bool,
+    // the way the connection has been closed first.
+    pub(crate) close_reason: Option<CloseReason>,
+    // set until a socks client has been told whether its connection succeeded.
+    pub(crate) is_reply_pending: bool,
+    // data of a tcp connection delayed by the emulator, see Emulator::emulate_stream.
+    pub(crate) to_server_delay: StreamDelay,
+    pub(crate) to_client_delay: StreamDelay,
+}
+
+#[derive(Default)]
+pub(crate) struct StreamDelay {
+    // data delayed later is not due before this.
+    pub(crate) deadline: Option<std::time::Instant>,
+    pub(crate) bytes: usize,
+}
+
+impl SocketClient {
+    pub(crate) fn new(socket: MioSocket, token: Token) -> SocketClient {
+        SocketClient {
+            socket,
+            token,
+            is_read_paused: false,
+            is_closed: false,
+            is_server_closed: false,
+            close_reason: None,
+            is_reply_pending: false,
+            to_server_delay: StreamDelay::default(),
+            to_client_delay: StreamDelay::default(),
+        }
+    }
+
+    pub(crate) fn delay(&mut self, direction: &OutgoingDirection) -> &mut StreamDelay {
+        match direction {
+            OutgoingDirection::ToServer => &mut self.to_server_delay,
+            OutgoingDirection::ToClient => &mut self.to_client_delay,
+        }
+    }
+}
+
+pub(crate) struct Session<'a> {
+    pub(crate) client: Client<'a>,
+    pub(crate) mio_socket: MioSocket,
+    pub(crate) token: Token,
+    pub(crate) buffers: Buffers,
+    pub(crate) shaping: SessionShaping,
+    pub(crate) dns64: Option<Dns64>,
+    pub(crate) created: std::time::Instant,
+    pub(crate) last_activity: std::time::Instant,
+    // set while reading from the server is paused as the queue to the client is full.
+    pub(crate) is_server_read_paused: bool,
+    // set once the upstream socket has been shut down while draining.
+    pub(crate) is_upstream_closed: bool,
+    // set until the connection of the upstream socket has succeeded or failed.
+    pub(crate) is_upstream_connecting: bool,
+    pub(crate) upstream_address: SocketAddr,
+    // name of the destination, see ConnectionRecord.
+    pub(crate) hostname: Option<String>,
+    // set once the first payload of the client has been inspected for a hostname.
+    pub(crate) is_payload_inspected: bool,
+    // owner of the socket of the client, not set while it is looked up.
+    pub(crate) process: Option<ProcessInfo>,
+    pub(crate) start_time: SystemTime,
+    pub(crate) counters: SessionCounters,
 }
 
 impl<'a> Session<'a> {
+    //
+    // The upstream address differs from the destination of the session when
+    // the destination is translated, as with nat64. The client is handed back
+    // when the upstream socket cannot be created.
+    //
     pub(crate) fn new(
         session_info: &SessionInfo,
+        upstream_address: SocketAddr,
         poll: &mut Poll,
         token: Token,
-    ) -> Option<Session<'a>> {
-        let mut device = VpnDevice::new();
-        let interface = Self::create_interface(&mut device);
-        let mut sockets = SocketSet::new([]);
-
+        shaping: SessionShaping,
+        client: Client<'a>,
+    ) -> Result<Session<'a>, Client<'a>> {
+        let Some(mio_socket) = Self::create_mio_socket(session_info, upstream_address, poll, token)
+        else {
+            return Err(client);
+        };
         let session = Session {
-            smoltcp_socket: Self::create_smoltcp_socket(session_info, &mut sockets)?,
-            mio_socket: Self::create_mio_socket(session_info, poll, token)?,
+            client,
+            mio_socket,
             token,
             buffers: Self::create_buffer(session_info),
+            shaping,
+            dns64: None,
+            created: std::time::Instant::now(),
+            last_activity: std::time::Instant::now(),
+            is_server_read_paused: false,
+            is_upstream_closed: false,
+            // udp sockets are only polled for reading and connect without a handshake.
+            is_upstream_connecting: session_info.transport_protocol == TransportProtocol::Tcp,
+            upstream_address,
+            hostname: None,
+            is_payload_inspected: false,
+            process: None,
+            start_time: SystemTime::now(),
+            counters: SessionCounters::default(),
+        };
+
+        Ok(session)
+    }
+
+    pub(crate) fn new_tun_client(
+        session_info: &SessionInfo,
+        config: &VpnConfig,
+        socket_pool: &mut SocketPool<'a>,
+    ) -> Option<Client<'a>> {
+        let mtu = mtu::mtu(&config.tun, session_info.internet_protocol);
+        let mtu = Self::device_mtu(session_info, mtu);
+        let mut device = VpnDevice::new(mtu);
+        let interface = Self::create_interface(&mut device);
+        let mut sockets = SocketSet::new([]);
+
+        let client = TunClient {
+            smoltcp_socket: Self::create_smoltcp_socket(session_info, socket_pool, &mut sockets)?,
             interface,
             sockets,
             device,
+            socket_memory: Self::socket_memory(session_info, config),
         };
 
-        Some(session)
+        Some(Client::Tun(Box::new(client)))
     }
 
-    fn create_smoltcp_socket(
+    //
+    // The interface fragments ipv4 packets larger than its mtu in a buffer of
+    // only 1500 bytes and drops larger ones, so udp sessions keep the largest
+    // mtu and their datagrams are fragmented when written to the tun instead.
+    //
+    pub(crate) fn device_mtu(session_info: &SessionInfo, mtu: usize) -> usize {
+        match session_info.transport_protocol {
+            TransportProtocol::Tcp => mtu,
+            TransportProtocol::Udp => MAXIMUM_MTU,
+        }
+    }
+
+    //
+    // Returns the memory held by the session, its socket buffers and the data
+    // queued in both directions.
+    //
+    pub(crate) fn memory(&self) -> usize {
+        let socket_memory = match &self.client {
+            Client::Tun(client) => client.socket_memory,
+            Client::Socket(_) | Client::Association(_, _) => 0,
+        };
+        socket_memory
+            + self.buffers.len(&OutgoingDirection::ToServer)
+            + self.buffers.len(&OutgoingDirection::ToClient)
+    }
+
+    //
+    // Queues data received from the client to the server; the first payload
+    // of a tcp connection is inspected for a hostname.
+    //
+    pub(crate) fn receive_from_client(&mut self, session_info: &SessionInfo, bytes: &[u8]) {
+        if !self.is_payload_inspected && session_info.transport_protocol == TransportProtocol::Tcp {
+            self.is_payload_inspected = true;
+            if let Some(hostname) = hostname::sniff(bytes) {
+                self.hostname = Some(hostname);
+            }
+        }
+        if let Some(dns64) = self.dns64.as_mut() {
+            dns64.handle_query(bytes);
+        }
+        self.buffers.push_data(IncomingDataEvent {
+            direction: IncomingDirection::FromClient,
+            buffer: bytes,
+        });
+    }
+
+    pub(crate) fn connection_record(
+        &self,
         session_info: &SessionInfo,
-        sockets: &mut SocketSet<'_>,
-    ) -> Option<SmoltcpSocket> {
-        let transport_protocol = match session_info.transport_protocol {
+        close_reason: CloseReason,
+    ) -> ConnectionRecord {
+        ConnectionRecord {
+            source: session_info.source,
+            destination: session_info.destination,
+            protocol: session_info.transport_protocol.into(),
+            hostname: self.hostname.clone(),
+            upstream: self.upstream_address,
+            outbound: self.mio_socket.local_address().ok(),
+            process: process_lookups::known_process(&self.process),
+            start_time: self.start_time,
+            end_time: SystemTime::now(),
+            bytes_to_server: self.counters.bytes_to_server,
+            bytes_to_client: self.counters.bytes_to_client,
+            packets_from_client: self.counters.packets_from_client,
+            packets_to_client: self.counters.packets_to_client,
+            close_reason,
+        }
+    }
+
+    pub(crate) fn socket_memory(session_info: &SessionInfo, config: &VpnConfig) -> usize {
+        let transport_protocol = Self::smoltcp_protocol(session_info);
+        SmoltcpSocket::buffer_memory(&transport_protocol, &config.memory)
+    }
+
+    fn smoltcp_protocol(session_info: &SessionInfo) -> SmoltcpProtocol {
+        match session_info.transport_protocol {
             TransportProtocol::Tcp => SmoltcpProtocol::Tcp,
             TransportProtocol::Udp => SmoltcpProtocol::Udp,
-        };
+        }
+    }
 
+    fn create_smoltcp_socket(
+        session_info: &SessionInfo,
+        socket_pool: &mut SocketPool<'a>,
+        sockets: &mut SocketSet<'a>,
+    ) -> Option<SmoltcpSocket> {
         SmoltcpSocket::new(
-            transport_protocol,
+            Self::smoltcp_protocol(session_info),
             session_info.source,
             session_info.destination,
+            socket_pool,
             sockets,
         )
     }
 
     fn create_mio_socket(
         session_info: &SessionInfo,
+        upstream_address: SocketAddr,
         poll: &mut Poll,
         token: Token,
     ) -> Option<MioSocket> {
@@ -100,16 +344,14 @@ impl<'a> Session<'a> {
             TransportProtocol::Udp => MioTransportProtocol::Udp,
         };
 
-        let internet_protocol = match session_info.internet_protocol {
-            InternetProtocol::Ipv4 => MioInternetProtocol::Ipv4,
-            InternetProtocol::Ipv6 => MioInternetProtocol::Ipv6,
+        let internet_protocol = if upstream_address.is_ipv4() {
+            MioInternetProtocol::Ipv4
+        } else {
+            MioInternetProtocol::Ipv6
         };
 
-        let mut mio_socket = MioSocket::new(
-            transport_protocol,
-            internet_protocol,
-            session_info.destination,
-        )?;
+        let mut mio_socket =
+            MioSocket::new(transport_protocol, internet_protocol, upstream_address)?;
 
         if let Err(error) = mio_socket.register_poll(poll, token) {
             log::error!("failed to register poll, error={:?}", error);
@@ -123,19 +365,25 @@ impl<'a> Session<'a> {
     where
         D: ::smoltcp::phy::Device + ?Sized,
     {
-        let default_gateway_ipv4 = Ipv4Address::new(0, 0, 0, 1);
         let config = Config::new(HardwareAddress::Ip);
 
         let mut interface = Interface::new(config, device, Instant::now());
         interface.set_any_ip(true);
         interface.update_ip_addrs(|ip_addrs| {
             ip_addrs
-                .push(IpCidr::new(IpAddress::v4(0, 0, 0, 1), 0))
+                .push(IpCidr::new(IpAddress::Ipv4(DEFAULT_GATEWAY_IPV4), 0))
+                .unwrap();
+            ip_addrs
+                .push(IpCidr::new(IpAddress::Ipv6(DEFAULT_GATEWAY_IPV6), 0))
                 .unwrap();
         });
         interface
             .routes_mut()
-            .add_default_ipv4_route(default_gateway_ipv4)
+            .add_default_ipv4_route(DEFAULT_GATEWAY_IPV4)
+            .unwrap();
+        interface
+            .routes_mut()
+            .add_default_ipv6_route(DEFAULT_GATEWAY_IPV6)
             .unwrap();
 
         interface
@@ -23,8 +23,15 @@
 //
 // For more information, please refer to <https://unlicense.org>
 
+use crate::config::Protocol;
+use crate::vpn::reassembly::parse_ipv6_headers;
+use crate::Error;
 use smoltcp::wire::{IpProtocol, Ipv4Packet, Ipv6Packet, TcpPacket, UdpPacket};
-use std::{fmt, hash::Hash, net::SocketAddr};
+use std::{
+    fmt,
+    hash::Hash,
+    net::{IpAddr, SocketAddr},
+};
 
 #[derive(PartialEq, Eq, Hash, Debug, Clone, Copy)]
 pub(crate) struct SessionInfo {
@@ -40,6 +47,15 @@ pub(crate) enum TransportProtocol {
     Udp,
 }
 
+impl From<TransportProtocol> for Protocol {
+    fn from(transport_protocol: TransportProtocol) -> Protocol {
+        match transport_protocol {
+            TransportProtocol::Tcp => Protocol::Tcp,
+            TransportProtocol::Udp => Protocol::Udp,
+        }
+    }
+}
+
 #[derive(PartialEq, Eq, Hash, Debug, Clone, Copy)]
 pub(crate) enum InternetProtocol {
     Ipv4,
@@ -47,95 +63,66 @@ pub(crate) enum InternetProtocol {
 }
 
 impl SessionInfo {
-    pub(crate) fn new(bytes: &Vec<u8>) -> Option<SessionInfo> {
-        Self::new_ipv4(bytes)
-            .or_else(|| Self::new_ipv6(bytes))
-            .or_else(|| {
-                log::error!("failed to create session info, len={:?}", bytes.len(),);
-                None
-            })
+    pub(crate) fn new(bytes: &[u8]) -> crate::Result<SessionInfo> {
+        match bytes.first().map(|byte| byte >> 4) {
+            Some(4) => Self::new_ipv4(bytes),
+            Some(6) => Self::new_ipv6(bytes),
+            Some(version) => Err(Error::UnsupportedInternetProtocol(version)),
+            None => Err(Error::Wire(smoltcp::wire::Error)),
+        }
     }
 
-    fn new_ipv4(bytes: &Vec<u8>) -> Option<SessionInfo> {
-        if let Ok(ip_packet) = Ipv4Packet::new_checked(&bytes) {
-            match ip_packet.next_header() {
-                IpProtocol::Tcp => {
-                    let payload = ip_packet.payload();
-                    let packet = TcpPacket::new_checked(payload).unwrap();
-                    let source_ip: [u8; 4] = ip_packet.src_addr().as_bytes().try_into().unwrap();
-                    let destination_ip: [u8; 4] =
-                        ip_packet.dst_addr().as_bytes().try_into().unwrap();
-                    return Some(SessionInfo {
-                        source: SocketAddr::from((source_ip, packet.src_port())),
-                        destination: SocketAddr::from((destination_ip, packet.dst_port())),
-                        transport_protocol: TransportProtocol::Tcp,
-                        internet_protocol: InternetProtocol::Ipv4,
-                    });
-                }
-                IpProtocol::Udp => {
-                    let payload = ip_packet.payload();
-                    let packet = UdpPacket::new_checked(payload).unwrap();
-                    let source_ip: [u8; 4] = ip_packet.src_addr().as_bytes().try_into().unwrap();
-                    let destination_ip: [u8; 4] =
-                        ip_packet.dst_addr().as_bytes().try_into().unwrap();
-                    return Some(SessionInfo {
-                        source: SocketAddr::from((source_ip, packet.src_port())),
-                        destination: SocketAddr::from((destination_ip, packet.dst_port())),
-                        transport_protocol: TransportProtocol::Udp,
-                        internet_protocol: InternetProtocol::Ipv4,
-                    });
-                }
-                _ => {
-                    log::warn!(
-                        "unsupported transport protocol, protocol=${:?}",
-                        ip_packet.next_header()
-                    );
-                    return None;
-                }
-            }
+    fn new_ipv4(bytes: &[u8]) -> crate::Result<SessionInfo> {
+        let ip_packet = Ipv4Packet::new_checked(bytes)?;
+        if ip_packet.more_frags() || ip_packet.frag_offset() != 0 {
+            return Err(Error::Fragmented);
         }
+        let source_ip = IpAddr::from(ip_packet.src_addr().0);
+        let destination_ip = IpAddr::from(ip_packet.dst_addr().0);
+        let (source_port, destination_port, transport_protocol) =
+            Self::parse_ports(ip_packet.next_header(), ip_packet.payload())?;
+        Ok(SessionInfo {
+            source: SocketAddr::new(source_ip, source_port),
+            destination: SocketAddr::new(destination_ip, destination_port),
+            transport_protocol,
+            internet_protocol: InternetProtocol::Ipv4,
+        })
+    }
 
-        None
+    fn new_ipv6(bytes: &[u8]) -> crate::Result<SessionInfo> {
+        let ip_packet = Ipv6Packet::new_checked(bytes)?;
+        let source_ip = IpAddr::from(ip_packet.src_addr().0);
+        let destination_ip = IpAddr::from(ip_packet.dst_addr().0);
+        let headers = parse_ipv6_headers(ip_packet.next_header(), ip_packet.payload())?;
+        if headers.is_fragmented() {
+            return Err(Error::Fragmented);
+        }
+        let payload = &ip_packet.payload()[headers.offset..];
+        let (source_port, destination_port, transport_protocol) =
+            Self::parse_ports(headers.protocol, payload)?;
+        Ok(SessionInfo {
+            source: SocketAddr::new(source_ip, source_port),
+            destination: SocketAddr::new(destination_ip, destination_port),
+            transport_protocol,
+            internet_protocol: InternetProtocol::Ipv6,
+        })
     }
 
-    fn new_ipv6(bytes: &Vec<u8>) -> Option<SessionInfo> {
-        if let Ok(ip_packet) = Ipv6Packet::new_checked(&bytes) {
-            let protocol = ip_packet.next_header();
-            match protocol {
-                IpProtocol::Tcp => {
-                    let payload = ip_packet.payload();
-                    let packet = TcpPacket::new_checked(payload).unwrap();
-                    let source_ip: [u8; 16] = ip_packet.src_addr().as_bytes().try_into().unwrap();
-                    let destination_ip: [u8; 16] =
-                        ip_packet.dst_addr().as_bytes().try_into().unwrap();
-                    return Some(SessionInfo {
-                        source: SocketAddr::from((source_ip, packet.src_port())),
-                        destination: SocketAddr::from((destination_ip, packet.dst_port())),
-                        transport_protocol: TransportProtocol::Tcp,
-                        internet_protocol: InternetProtocol::Ipv6,
-                    });
-                }
-                IpProtocol::Udp => {
-                    let payload = ip_packet.payload();
-                    let packet = UdpPacket::new_checked(payload).unwrap();
-                    let source_ip: [u8; 16] = ip_packet.src_addr().as_bytes().try_into().unwrap();
-                    let destination_ip: [u8; 16] =
-                        ip_packet.dst_addr().as_bytes().try_into().unwrap();
-                    return Some(SessionInfo {
-                        source: SocketAddr::from((source_ip, packet.src_port())),
-                        destination: SocketAddr::from((destination_ip, packet.dst_port())),
-                        transport_protocol: TransportProtocol::Udp,
-                        internet_protocol: InternetProtocol::Ipv6,
-                    });
-                }
-                _ => {
-                    log::warn!("unsupported transport protocol, protocol=${:?}", protocol);
-                    return None;
-                }
+    fn parse_ports(
+        protocol: IpProtocol,
+        payload: &[u8],
+    ) -> crate::Result<(u16, u16, TransportProtocol)> {
+        match protocol {
+            IpProtocol::Tcp => {
+                let packet = TcpPacket::new_checked(payload)?;
+                Ok((packet.src_port(), packet.dst_port(), TransportProtocol::Tcp))
             }
+            IpProtocol::Udp => {
+                let packet = UdpPacket::new_checked(payload)?;
+                Ok((packet.src_port(), packet.dst_port(), TransportProtocol::Udp))
+            }
+            _ => Err(Error::UnsupportedTransportProtocol(protocol)),
         }
-
-        None
     }
 }
 
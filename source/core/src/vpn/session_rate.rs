@@ -0,0 +1,120 @@
+// This is free and unencumbered software released into the public domain.
+//
+// Anyone is free to copy, modify, publish, use, compile, sell, or
+// distribute this software, either in source code form or as a compiled
+// binary, for any purpose, commercial or non-commercial, and by any
+// means.
+//
+// In jurisdictions that recognize copyright laws, the author or authors
+// of this software dedicate any and all copyright interest in the
+// software to the public domain. We make this dedication for the benefit
+// of the public at large and to the detriment of our heirs and
+// successors. We intend this dedication to be an overt act of
+// relinquishment in perpetuity of all present and future rights to this
+// software under copyright law.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS BE LIABLE FOR ANY CLAIM, DAMAGES OR
+// OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE,
+// ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR
+// OTHER DEALINGS IN THE SOFTWARE.
+//
+// For more information, please refer to <https://unlicense.org>
+
+use crate::config::{RateLimit, SessionLimits, SessionRate};
+use crate::vpn::{session_info::SessionInfo, shaping::TokenBucket};
+use std::{collections::HashMap, net::IpAddr, time::Instant};
+
+//
+// Buckets which have refilled completely are dropped once this many addresses
+// are tracked; while all buckets are still in use, sessions for further
+// addresses are denied so that flooding from many addresses stays bounded too.
+//
+const MAXIMUM_TRACKED_ADDRESSES: usize = 4096;
+
+type Buckets = HashMap<IpAddr, TokenBucket>;
+
+//
+// Limits the rate of new sessions per destination and per source address with
+// a token bucket per address, where each new session takes one token.
+//
+pub(crate) struct SessionRateLimiter {
+    destination_rate: Option<SessionRate>,
+    source_rate: Option<SessionRate>,
+    destinations: Buckets,
+    sources: Buckets,
+}
+
+impl SessionRateLimiter {
+    pub(crate) fn new(limits: &SessionLimits) -> SessionRateLimiter {
+        SessionRateLimiter {
+            destination_rate: limits.destination_rate,
+            source_rate: limits.source_rate,
+            destinations: Buckets::new(),
+            sources: Buckets::new(),
+        }
+    }
+
+    pub(crate) fn configure(&mut self, limits: &SessionLimits) {
+        if self.destination_rate != limits.destination_rate {
+            self.destinations.clear();
+        }
+        if self.source_rate != limits.source_rate {
+            self.sources.clear();
+        }
+        self.destination_rate = limits.destination_rate;
+        self.source_rate = limits.source_rate;
+    }
+
+    //
+    // Returns whether a new session may be created, taking a token for it from
+    // the buckets of both its destination and its source.
+    //
+    pub(crate) fn allow(&mut self, session_info: &SessionInfo, now: Instant) -> bool {
+        let destination = session_info.destination.ip();
+        let source = session_info.source.ip();
+
+        let is_allowed = Self::is_available(
+            &mut self.destinations,
+            self.destination_rate,
+            destination,
+            now,
+        ) && Self::is_available(&mut self.sources, self.source_rate, source, now);
+        if is_allowed {
+            Self::consume(&mut self.destinations, destination);
+            Self::consume(&mut self.sources, source);
+        }
+        is_allowed
+    }
+
+    fn is_available(
+        buckets: &mut Buckets,
+        rate: Option<SessionRate>,
+        address: IpAddr,
+        now: Instant,
+    ) -> bool {
+        let Some(rate) = rate else {
+            return true;
+        };
+        if !buckets.contains_key(&address) && buckets.len() >= MAXIMUM_TRACKED_ADDRESSES {
+            buckets.retain(|_, bucket| !bucket.is_full(now));
+            if buckets.len() >= MAXIMUM_TRACKED_ADDRESSES {
+                return false;
+            }
+        }
+        let limit = RateLimit::new(rate.sessions_per_second, rate.burst);
+        buckets
+            .entry(address)
+            .or_insert_with(|| TokenBucket::new(limit, now))
+            .available(now)
+            > 0
+    }
+
+    fn consume(buckets: &mut Buckets, address: IpAddr) {
+        if let Some(bucket) = buckets.get_mut(&address) {
+            bucket.consume(1);
+        }
+    }
+}
@@ -0,0 +1,309 @@
+// This is free and unencumbered software released into the public domain.
+//
+// Anyone is free to copy, modify, publish, use, compile, sell, or
+// distribute this software, either in source code form or as a compiled
+// binary, for any purpose, commercial or non-commercial, and by any
+// means.
+//
+// In jurisdictions that recognize copyright laws, the author or authors
+// of this software dedicate any and all copyright interest in the
+// software to the public domain. We make this dedication for the benefit
+// of the public at large and to the detriment of our heirs and
+// successors. We intend this dedication to be an overt act of
+// relinquishment in perpetuity of all present and future rights to this
+// software under copyright law.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS BE LIABLE FOR ANY CLAIM, DAMAGES OR
+// OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE,
+// ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR
+// OTHER DEALINGS IN THE SOFTWARE.
+//
+// For more information, please refer to <https://unlicense.org>
+
+use crate::config::{RateLimit, RateLimits, ShapingConfig, ShapingRule};
+use crate::vpn::{buffers::OutgoingDirection, session_info::SessionInfo};
+use std::time::{Duration, Instant};
+
+//
+// Writes are delayed until at least this many bytes are available so that
+// throttled sessions are not woken up for every single byte.
+//
+const MINIMUM_WRITE_SIZE: u64 = 1500;
+
+pub(crate) struct Shaper {
+    config: ShapingConfig,
+    global: Buckets,
+    rules: Vec<Buckets>,
+}
+
+//
+// Shaping state owned by each session.
+//
+pub(crate) struct SessionShaping {
+    rule_index: Option<usize>,
+    buckets: Buckets,
+}
+
+impl Shaper {
+    pub(crate) fn new(config: ShapingConfig) -> Shaper {
+        let now = Instant::now();
+        Shaper {
+            global: Buckets::new(&config.global, now),
+            rules: config
+                .rules
+                .iter()
+                .map(|rule| Buckets::new(&rule.shared, now))
+                .collect(),
+            config,
+        }
+    }
+
+    //
+    // Applies a new configuration; buckets whose limit is unchanged keep their
+    // tokens so that reloading does not hand out burst credit again. Rules keep
+    // their shared buckets, wherever they have moved, only while their
+    // destination and shared limits are unchanged.
+    //
+    pub(crate) fn configure(&mut self, config: ShapingConfig) {
+        let now = Instant::now();
+        self.global.update(Buckets::new(&config.global, now));
+        let mut previous_rules: Vec<Option<(ShapingRule, Buckets)>> =
+            std::mem::take(&mut self.config.rules)
+                .into_iter()
+                .zip(std::mem::take(&mut self.rules))
+                .map(Some)
+                .collect();
+        self.rules = config
+            .rules
+            .iter()
+            .map(|rule| {
+                let previous = previous_rules
+                    .iter_mut()
+                    .find(|previous| {
+                        previous.as_ref().is_some_and(|(previous_rule, _)| {
+                            previous_rule.destination == rule.destination
+                                && previous_rule.shared == rule.shared
+                        })
+                    })
+                    .and_then(Option::take);
+                match previous {
+                    Some((_, buckets)) => buckets,
+                    None => Buckets::new(&rule.shared, now),
+                }
+            })
+            .collect();
+        self.config = config;
+    }
+
+    pub(crate) fn configure_session(
+        &self,
+        session: &mut SessionShaping,
+        session_info: &SessionInfo,
+    ) {
+        let shaping = self.new_session(session_info);
+        session.rule_index = shaping.rule_index;
+        session.buckets.update(shaping.buckets);
+    }
+
+    pub(crate) fn new_session(&self, session_info: &SessionInfo) -> SessionShaping {
+        let now = Instant::now();
+        let rule_index = self.config.rules.iter().position(|rule| {
+            rule.destination.matches(
+                &session_info.destination,
+                session_info.transport_protocol.into(),
+            )
+        });
+        let mut buckets = Buckets::new(&self.config.session, now);
+        if let Some(rule_index) = rule_index {
+            buckets.restrict(&self.config.rules[rule_index].session, now);
+        }
+        SessionShaping {
+            rule_index,
+            buckets,
+        }
+    }
+
+    //
+    // Returns how many bytes may be written in the given direction; writes may
+    // exceed the allowance by at most one datagram.
+    //
+    pub(crate) fn allowance(
+        &mut self,
+        session: &mut SessionShaping,
+        direction: &OutgoingDirection,
+        now: Instant,
+    ) -> usize {
+        let mut allowance = u64::MAX;
+        for bucket in self.buckets(session, direction) {
+            allowance = allowance.min(bucket.available(now));
+        }
+        allowance.try_into().unwrap_or(usize::MAX)
+    }
+
+    pub(crate) fn consume(
+        &mut self,
+        session: &mut SessionShaping,
+        direction: &OutgoingDirection,
+        count: usize,
+    ) {
+        for bucket in self.buckets(session, direction) {
+            bucket.consume(count as u64);
+        }
+    }
+
+    //
+    // Returns when a throttled session should attempt writing again.
+    //
+    pub(crate) fn next_write_time(
+        &mut self,
+        session: &mut SessionShaping,
+        direction: &OutgoingDirection,
+        now: Instant,
+    ) -> Instant {
+        let mut delay = Duration::ZERO;
+        for bucket in self.buckets(session, direction) {
+            delay = delay.max(bucket.delay(now));
+        }
+        now + delay
+    }
+
+    fn buckets<'a>(
+        &'a mut self,
+        session: &'a mut SessionShaping,
+        direction: &OutgoingDirection,
+    ) -> impl Iterator<Item = &'a mut TokenBucket> {
+        let rule = match session.rule_index {
+            Some(rule_index) => self.rules.get_mut(rule_index),
+            None => None,
+        };
+        [
+            self.global.get(direction),
+            rule.and_then(|buckets| buckets.get(direction)),
+            session.buckets.get(direction),
+        ]
+        .into_iter()
+        .flatten()
+    }
+}
+
+struct Buckets {
+    upload: Option<TokenBucket>,
+    download: Option<TokenBucket>,
+}
+
+impl Buckets {
+    fn new(limits: &RateLimits, now: Instant) -> Buckets {
+        Buckets {
+            upload: limits.upload.map(|limit| TokenBucket::new(limit, now)),
+            download: limits.download.map(|limit| TokenBucket::new(limit, now)),
+        }
+    }
+
+    //
+    // Replaces limits with the given ones where they are more restrictive.
+    //
+    fn restrict(&mut self, limits: &RateLimits, now: Instant) {
+        fn restrict(bucket: &mut Option<TokenBucket>, limit: Option<RateLimit>, now: Instant) {
+            if let Some(limit) = limit {
+                let is_more_restrictive = bucket
+                    .as_ref()
+                    .is_none_or(|bucket| limit.bytes_per_second < bucket.limit.bytes_per_second);
+                if is_more_restrictive {
+                    *bucket = Some(TokenBucket::new(limit, now));
+                }
+            }
+        }
+        restrict(&mut self.upload, limits.upload, now);
+        restrict(&mut self.download, limits.download, now);
+    }
+
+    //
+    // Takes over the limits of the given buckets, keeping the tokens of those
+    // whose limit is unchanged.
+    //
+    fn update(&mut self, buckets: Buckets) {
+        fn update(bucket: &mut Option<TokenBucket>, new_bucket: Option<TokenBucket>) {
+            let is_unchanged = match (bucket.as_ref(), new_bucket.as_ref()) {
+                (Some(bucket), Some(new_bucket)) => bucket.limit == new_bucket.limit,
+                _ => false,
+            };
+            if !is_unchanged {
+                *bucket = new_bucket;
+            }
+        }
+        update(&mut self.upload, buckets.upload);
+        update(&mut self.download, buckets.download);
+    }
+
+    fn get(&mut self, direction: &OutgoingDirection) -> Option<&mut TokenBucket> {
+        match direction {
+            OutgoingDirection::ToServer => self.upload.as_mut(),
+            OutgoingDirection::ToClient => self.download.as_mut(),
+        }
+    }
+}
+
+pub(crate) struct TokenBucket {
+    limit: RateLimit,
+    // may become negative when a datagram exceeds the available tokens.
+    tokens: i64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub(crate) fn new(limit: RateLimit, now: Instant) -> TokenBucket {
+        TokenBucket {
+            limit,
+            tokens: Self::burst(&limit),
+            last_refill: now,
+        }
+    }
+
+    fn burst(limit: &RateLimit) -> i64 {
+        limit.burst_bytes.max(1).min(i64::MAX as u64) as i64
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        let refill = elapsed.as_nanos() * u128::from(self.limit.bytes_per_second) / 1_000_000_000;
+        if refill > 0 {
+            let tokens = i128::from(self.tokens) + refill as i128;
+            self.tokens = tokens.min(i128::from(Self::burst(&self.limit))) as i64;
+            self.last_refill = now;
+        }
+    }
+
+    pub(crate) fn available(&mut self, now: Instant) -> u64 {
+        self.refill(now);
+        self.tokens.max(0) as u64
+    }
+
+    pub(crate) fn consume(&mut self, count: u64) {
+        self.tokens = self
+            .tokens
+            .saturating_sub(count.min(i64::MAX as u64) as i64);
+    }
+
+    pub(crate) fn is_full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= Self::burst(&self.limit)
+    }
+
+    fn delay(&mut self, now: Instant) -> Duration {
+        self.refill(now);
+        let target = MINIMUM_WRITE_SIZE.min(Self::burst(&self.limit) as u64) as i64;
+        if self.tokens >= target {
+            return Duration::ZERO;
+        }
+        if self.limit.bytes_per_second == 0 {
+            // nothing is ever refilled; check again later in case limits change.
+            return Duration::from_secs(1);
+        }
+        let missing = (target - self.tokens) as u128;
+        let nanos = missing * 1_000_000_000 / u128::from(self.limit.bytes_per_second) + 1;
+        Duration::from_nanos(nanos.min(u64::MAX as u128) as u64)
+    }
+}
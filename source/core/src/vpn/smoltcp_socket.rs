@@ -23,6 +23,8 @@
 //
 // For more information, please refer to <https://unlicense.org>
 
+use crate::config::MemoryConfig;
+use crate::connection_log::CloseReason;
 use smoltcp::{
     iface::{SocketHandle, SocketSet},
     socket::{tcp, udp},
@@ -30,6 +32,12 @@ use smoltcp::{
 };
 use std::net::SocketAddr;
 
+// udp buffers hold at least one datagram of the largest size.
+const MINIMUM_UDP_BUFFER_SIZE: usize = 1 << 16;
+
+// sockets kept for reuse, per protocol.
+const MAXIMUM_POOLED_SOCKETS: usize = 16;
+
 pub(crate) enum TransportProtocol {
     Tcp,
     Udp,
@@ -42,11 +50,12 @@ pub(crate) struct Socket {
 }
 
 impl Socket {
-    pub(crate) fn new(
+    pub(crate) fn new<'a>(
         transport_protocol: TransportProtocol,
         local_address: SocketAddr,
         remote_address: SocketAddr,
-        sockets: &mut SocketSet<'_>,
+        pool: &mut SocketPool<'a>,
+        sockets: &mut SocketSet<'a>,
     ) -> Option<Socket> {
         let local_endpoint = IpEndpoint::from(local_address);
 
@@ -54,11 +63,11 @@ impl Socket {
 
         let socket_handle = match transport_protocol {
             TransportProtocol::Tcp => {
-                let socket = Self::create_tcp_socket(remote_endpoint).unwrap();
+                let socket = Self::create_tcp_socket(remote_endpoint, pool)?;
                 sockets.add(socket)
             }
             TransportProtocol::Udp => {
-                let socket = Self::create_udp_socket(remote_endpoint).unwrap();
+                let socket = Self::create_udp_socket(remote_endpoint, pool)?;
                 sockets.add(socket)
             }
         };
@@ -72,11 +81,43 @@ impl Socket {
         Some(socket)
     }
 
-    fn create_tcp_socket<'a>(endpoint: IpEndpoint) -> Option<tcp::Socket<'a>> {
-        let mut socket = tcp::Socket::new(
-            tcp::SocketBuffer::new(vec![0; 1024 * 1024]),
-            tcp::SocketBuffer::new(vec![0; 1024 * 1024]),
-        );
+    //
+    // Removes the socket from the set and hands it to the pool for reuse.
+    //
+    pub(crate) fn release<'a>(&self, sockets: &mut SocketSet<'a>, pool: &mut SocketPool<'a>) {
+        match sockets.remove(self.socket_handle) {
+            smoltcp::socket::Socket::Tcp(socket) => pool.put_tcp(socket),
+            smoltcp::socket::Socket::Udp(socket) => pool.put_udp(socket),
+            _ => {}
+        }
+    }
+
+    //
+    // Returns the memory allocated for the send and receive buffers of a socket.
+    //
+    pub(crate) fn buffer_memory(
+        transport_protocol: &TransportProtocol,
+        memory: &MemoryConfig,
+    ) -> usize {
+        match transport_protocol {
+            TransportProtocol::Tcp => 2 * memory.tcp_buffer_size,
+            TransportProtocol::Udp => {
+                let metadata = std::mem::size_of::<udp::PacketMetadata>();
+                2 * (Self::udp_buffer_size(memory) + memory.udp_buffer_datagrams * metadata)
+            }
+        }
+    }
+
+    fn create_tcp_socket<'a>(
+        endpoint: IpEndpoint,
+        pool: &mut SocketPool<'a>,
+    ) -> Option<tcp::Socket<'a>> {
+        let mut socket = pool.tcp.pop().unwrap_or_else(|| {
+            tcp::Socket::new(
+                tcp::SocketBuffer::new(vec![0; pool.memory.tcp_buffer_size]),
+                tcp::SocketBuffer::new(vec![0; pool.memory.tcp_buffer_size]),
+            )
+        });
 
         if socket.listen(endpoint).is_err() {
             log::error!("failed to listen on socket, endpoint=[{}]", endpoint);
@@ -88,17 +129,27 @@ impl Socket {
         Some(socket)
     }
 
-    fn create_udp_socket<'a>(endpoint: IpEndpoint) -> Option<udp::Socket<'a>> {
-        let mut socket = udp::Socket::new(
-            udp::PacketBuffer::new(
-                vec![udp::PacketMetadata::EMPTY; 1024 * 1024],
-                vec![0; 1024 * 1024],
-            ),
-            udp::PacketBuffer::new(
-                vec![udp::PacketMetadata::EMPTY; 1024 * 1024],
-                vec![0; 1024 * 1024],
-            ),
-        );
+    fn udp_buffer_size(memory: &MemoryConfig) -> usize {
+        memory.udp_buffer_size.max(MINIMUM_UDP_BUFFER_SIZE)
+    }
+
+    fn create_udp_socket<'a>(
+        endpoint: IpEndpoint,
+        pool: &mut SocketPool<'a>,
+    ) -> Option<udp::Socket<'a>> {
+        let memory = &pool.memory;
+        let mut socket = pool.udp.pop().unwrap_or_else(|| {
+            udp::Socket::new(
+                udp::PacketBuffer::new(
+                    vec![udp::PacketMetadata::EMPTY; memory.udp_buffer_datagrams],
+                    vec![0; Self::udp_buffer_size(memory)],
+                ),
+                udp::PacketBuffer::new(
+                    vec![udp::PacketMetadata::EMPTY; memory.udp_buffer_datagrams],
+                    vec![0; Self::udp_buffer_size(memory)],
+                ),
+            )
+        });
 
         if socket.bind(endpoint).is_err() {
             log::error!("failed to bind socket, endpoint=[{}]", endpoint);
@@ -124,6 +175,76 @@ impl Socket {
     }
 }
 
+//
+// Sockets of closed sessions are kept for reuse by new sessions so that their
+// buffers are not allocated again for every session, e.g. for every dns
+// lookup. smoltcp cannot resize the buffers of a socket, so those of live
+// sessions keep the size of the memory configuration; trimming memory releases
+// the pooled ones.
+//
+pub(crate) struct SocketPool<'a> {
+    memory: MemoryConfig,
+    tcp: Vec<tcp::Socket<'a>>,
+    udp: Vec<udp::Socket<'a>>,
+}
+
+impl<'a> SocketPool<'a> {
+    pub(crate) fn new(memory: MemoryConfig) -> SocketPool<'a> {
+        SocketPool {
+            memory,
+            tcp: Vec::new(),
+            udp: Vec::new(),
+        }
+    }
+
+    //
+    // Pooled sockets are released when the size of their buffers changes.
+    //
+    pub(crate) fn configure(&mut self, memory: MemoryConfig) {
+        if memory.tcp_buffer_size != self.memory.tcp_buffer_size {
+            self.tcp.clear();
+        }
+        if memory.udp_buffer_size != self.memory.udp_buffer_size
+            || memory.udp_buffer_datagrams != self.memory.udp_buffer_datagrams
+        {
+            self.udp.clear();
+        }
+        self.memory = memory;
+    }
+
+    //
+    // Returns the memory held by the buffers of the pooled sockets.
+    //
+    pub(crate) fn memory(&self) -> usize {
+        self.tcp.len() * Socket::buffer_memory(&TransportProtocol::Tcp, &self.memory)
+            + self.udp.len() * Socket::buffer_memory(&TransportProtocol::Udp, &self.memory)
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.tcp.clear();
+        self.udp.clear();
+    }
+
+    fn put_tcp(&mut self, mut socket: tcp::Socket<'a>) {
+        let is_reusable = socket.recv_capacity() == self.memory.tcp_buffer_size
+            && socket.send_capacity() == self.memory.tcp_buffer_size;
+        if is_reusable && self.tcp.len() < MAXIMUM_POOLED_SOCKETS {
+            // listening again resets the connection state and the buffers.
+            socket.abort();
+            self.tcp.push(socket);
+        }
+    }
+
+    fn put_udp(&mut self, mut socket: udp::Socket<'a>) {
+        let is_reusable = socket.payload_recv_capacity() == Socket::udp_buffer_size(&self.memory)
+            && socket.packet_recv_capacity() == self.memory.udp_buffer_datagrams;
+        if is_reusable && self.udp.len() < MAXIMUM_POOLED_SOCKETS {
+            socket.close();
+            self.udp.push(socket);
+        }
+    }
+}
+
 pub(crate) struct SocketInstance<'a, 'b> {
     instance: SocketType<'a, 'b>,
 }
@@ -170,4 +291,38 @@ impl<'a, 'b> SocketInstance<'a, 'b> {
             SocketType::Udp(socket, _) => socket.close(),
         }
     }
+
+    //
+    // Tcp connections in time wait are closed as far as the client is concerned.
+    //
+    pub(crate) fn is_closed(&self) -> bool {
+        match &self.instance {
+            SocketType::Tcp(socket) => {
+                matches!(socket.state(), tcp::State::Closed | tcp::State::TimeWait)
+            }
+            SocketType::Udp(socket, _) => !socket.is_open(),
+        }
+    }
+
+    //
+    // Returns how the client closed a tcp connection while the server has not
+    // closed its side yet, if it did.
+    //
+    pub(crate) fn client_close_reason(&self) -> Option<CloseReason> {
+        match &self.instance {
+            SocketType::Tcp(socket) => match socket.state() {
+                tcp::State::CloseWait | tcp::State::LastAck => Some(CloseReason::ClientFin),
+                tcp::State::Closed => Some(CloseReason::Reset),
+                _ => None,
+            },
+            SocketType::Udp(_, _) => None,
+        }
+    }
+
+    pub(crate) fn abort(&mut self) {
+        match &mut self.instance {
+            SocketType::Tcp(socket) => socket.abort(),
+            SocketType::Udp(socket, _) => socket.close(),
+        }
+    }
 }
@@ -0,0 +1,219 @@
+// This is free and unencumbered software released into the public domain.
+//
+// Anyone is free to copy, modify, publish, use, compile, sell, or
+// distribute this software, either in source code form or as a compiled
+// binary, for any purpose, commercial or non-commercial, and by any
+// means.
+//
+// In jurisdictions that recognize copyright laws, the author or authors
+// of this software dedicate any and all copyright interest in the
+// software to the public domain. We make this dedication for the benefit
+// of the public at large and to the detriment of our heirs and
+// successors. We intend this dedication to be an overt act of
+// relinquishment in perpetuity of all present and future rights to this
+// software under copyright law.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS BE LIABLE FOR ANY CLAIM, DAMAGES OR
+// OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE,
+// ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR
+// OTHER DEALINGS IN THE SOFTWARE.
+//
+// For more information, please refer to <https://unlicense.org>
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4};
+
+//
+// Messages of socks5 as in rfc 1928, without authentication and without the
+// bind command.
+//
+
+const VERSION: u8 = 5;
+
+const METHOD_NO_AUTHENTICATION: u8 = 0;
+const METHOD_NONE_ACCEPTABLE: u8 = 0xff;
+
+const COMMAND_CONNECT: u8 = 1;
+const COMMAND_UDP_ASSOCIATE: u8 = 3;
+
+const ADDRESS_IPV4: u8 = 1;
+const ADDRESS_DOMAIN: u8 = 3;
+const ADDRESS_IPV6: u8 = 4;
+
+// bound address of replies which have none.
+pub(crate) const UNSPECIFIED: SocketAddr =
+    SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0));
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Reply {
+    Succeeded = 0,
+    GeneralFailure = 1,
+    NotAllowed = 2,
+    NetworkUnreachable = 3,
+    HostUnreachable = 4,
+    ConnectionRefused = 5,
+    CommandNotSupported = 7,
+    AddressTypeNotSupported = 8,
+}
+
+impl From<std::io::ErrorKind> for Reply {
+    fn from(kind: std::io::ErrorKind) -> Self {
+        match kind {
+            std::io::ErrorKind::ConnectionRefused => Reply::ConnectionRefused,
+            std::io::ErrorKind::NetworkUnreachable => Reply::NetworkUnreachable,
+            std::io::ErrorKind::HostUnreachable | std::io::ErrorKind::TimedOut => {
+                Reply::HostUnreachable
+            }
+            _ => Reply::GeneralFailure,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Address {
+    Ip(SocketAddr),
+    // resolved by the server.
+    Domain(String, u16),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Command {
+    Connect,
+    UdpAssociate,
+}
+
+//
+// The outcome of parsing a message from the start of the bytes received so
+// far; complete messages report how many bytes they took.
+//
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Parsed<T> {
+    Incomplete,
+    Complete(T, usize),
+    // the connection is answered with the reply, if any, and closed.
+    Invalid(Option<Reply>),
+}
+
+//
+// Parses the methods offered by the client and returns whether no
+// authentication is among them.
+//
+pub(crate) fn parse_greeting(bytes: &[u8]) -> Parsed<bool> {
+    let [version, count, ..] = *bytes else {
+        return Parsed::Incomplete;
+    };
+    if version != VERSION {
+        return Parsed::Invalid(None);
+    }
+    let length = 2 + count as usize;
+    let Some(methods) = bytes.get(2..length) else {
+        return Parsed::Incomplete;
+    };
+    Parsed::Complete(methods.contains(&METHOD_NO_AUTHENTICATION), length)
+}
+
+// the connection is closed after the reply when no method is acceptable.
+pub(crate) fn greeting_reply(is_accepted: bool) -> [u8; 2] {
+    if is_accepted {
+        [VERSION, METHOD_NO_AUTHENTICATION]
+    } else {
+        [VERSION, METHOD_NONE_ACCEPTABLE]
+    }
+}
+
+pub(crate) fn parse_request(bytes: &[u8]) -> Parsed<(Command, Address)> {
+    let [version, command, _, ..] = *bytes else {
+        return Parsed::Incomplete;
+    };
+    if version != VERSION {
+        return Parsed::Invalid(None);
+    }
+    let command = match command {
+        COMMAND_CONNECT => Command::Connect,
+        COMMAND_UDP_ASSOCIATE => Command::UdpAssociate,
+        _ => return Parsed::Invalid(Some(Reply::CommandNotSupported)),
+    };
+    match parse_address(&bytes[3..]) {
+        Parsed::Complete(address, length) => Parsed::Complete((command, address), 3 + length),
+        Parsed::Incomplete => Parsed::Incomplete,
+        Parsed::Invalid(reply) => Parsed::Invalid(reply),
+    }
+}
+
+pub(crate) fn reply(reply: Reply, bound: SocketAddr) -> Vec<u8> {
+    let mut bytes = vec![VERSION, reply as u8, 0];
+    write_address(&mut bytes, bound);
+    bytes
+}
+
+//
+// Parses the header of a datagram sent to a udp relay and returns the
+// destination and the length of the header; fragments are not supported.
+//
+pub(crate) fn parse_udp_header(bytes: &[u8]) -> Option<(Address, usize)> {
+    let [0, 0, 0, ..] = *bytes else {
+        return None;
+    };
+    match parse_address(&bytes[3..]) {
+        Parsed::Complete(address, length) => Some((address, 3 + length)),
+        _ => None,
+    }
+}
+
+pub(crate) fn udp_header(source: SocketAddr) -> Vec<u8> {
+    let mut bytes = vec![0, 0, 0];
+    write_address(&mut bytes, source);
+    bytes
+}
+
+fn parse_address(bytes: &[u8]) -> Parsed<Address> {
+    let Some(&address_type) = bytes.first() else {
+        return Parsed::Incomplete;
+    };
+    let (start, length) = match address_type {
+        ADDRESS_IPV4 => (1, 4),
+        ADDRESS_IPV6 => (1, 16),
+        ADDRESS_DOMAIN => match bytes.get(1) {
+            Some(&length) => (2, length as usize),
+            None => return Parsed::Incomplete,
+        },
+        _ => return Parsed::Invalid(Some(Reply::AddressTypeNotSupported)),
+    };
+    let end = start + length;
+    let Some(&[high, low]) = bytes.get(end..end + 2) else {
+        return Parsed::Incomplete;
+    };
+    let host = &bytes[start..end];
+    let port = u16::from_be_bytes([high, low]);
+    let address = match address_type {
+        ADDRESS_IPV4 => {
+            let octets: [u8; 4] = host.try_into().unwrap();
+            Address::Ip(SocketAddr::new(Ipv4Addr::from(octets).into(), port))
+        }
+        ADDRESS_IPV6 => {
+            let octets: [u8; 16] = host.try_into().unwrap();
+            Address::Ip(SocketAddr::new(Ipv6Addr::from(octets).into(), port))
+        }
+        _ => match std::str::from_utf8(host) {
+            Ok(name) if !name.is_empty() => Address::Domain(name.to_string(), port),
+            _ => return Parsed::Invalid(Some(Reply::GeneralFailure)),
+        },
+    };
+    Parsed::Complete(address, end + 2)
+}
+
+fn write_address(bytes: &mut Vec<u8>, address: SocketAddr) {
+    match address.ip() {
+        IpAddr::V4(ip) => {
+            bytes.push(ADDRESS_IPV4);
+            bytes.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            bytes.push(ADDRESS_IPV6);
+            bytes.extend_from_slice(&ip.octets());
+        }
+    }
+    bytes.extend_from_slice(&address.port().to_be_bytes());
+}
@@ -0,0 +1,932 @@
+// This is free and unencumbered software released into the public domain.
+//
+// Anyone is free to copy, modify, publish, use, compile, sell, or
+// distribute this software, either in source code form or as a compiled
+// binary, for any purpose, commercial or non-commercial, and by any
+// means.
+//
+// In jurisdictions that recognize copyright laws, the author or authors
+// of this software dedicate any and all copyright interest in the
+// software to the public domain. We make this dedication for the benefit
+// of the public at large and to the detriment of our heirs and
+// successors. We intend this dedication to be an overt act of
+// relinquishment in perpetuity of all present and future rights to this
+// software under copyright law.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS BE LIABLE FOR ANY CLAIM, DAMAGES OR
+// OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE,
+// ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR
+// OTHER DEALINGS IN THE SOFTWARE.
+//
+// For more information, please refer to <https://unlicense.org>
+#[cfg(target_os = "linux")]
+use crate::vpn::transparent_socket::{self, ListenerKind};
+use crate::vpn::{
+    mio_socket::Socket as MioSocket,
+    processor::{Command, Control, Tokens},
+    session_info::{InternetProtocol, SessionInfo, TransportProtocol},
+    socks::{self, Address, Parsed, Reply},
+};
+use mio::{
+    net::{TcpListener, TcpStream, UdpSocket},
+    Interest, Registry, Token,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    io::{ErrorKind, Read, Write},
+    net::{IpAddr, SocketAddr, ToSocketAddrs},
+    sync::{
+        mpsc::{sync_channel, Receiver, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+// socks clients which have not sent their request by then are disconnected.
+const SOCKS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// a greeting and a request with a domain name take at most this many bytes.
+const SOCKS_HANDSHAKE_MAX_LENGTH: usize = 2 + 255 + 4 + 1 + 255 + 2;
+// names are resolved by this many threads, as resolving blocks.
+const RESOLVER_THREADS: usize = 4;
+// names beyond this many waiting for a thread fail to resolve right away.
+const RESOLVER_QUEUE_LENGTH: usize = 64;
+
+enum Listener {
+    #[cfg(target_os = "linux")]
+    Tcp(TcpListener, ListenerKind),
+    // udp is only received through tproxy.
+    #[cfg(target_os = "linux")]
+    Udp(UdpSocket),
+    Socks(TcpListener),
+}
+
+//
+// Listeners whose connections and datagrams the vpn forwards next to, or
+// instead of, the packets of a tun, see Vpn::new_listeners. Iptables or
+// nftables redirect them to transparent listeners, keeping their original
+// destination, or clients configured to use a socks5 proxy request their
+// destination from socks listeners, along with the domain names they send as
+// hostnames. The sockets of the kernel face the clients instead of smoltcp;
+// otherwise their sessions are those of the vpn. Requires CAP_NET_ADMIN for
+// tproxy.
+//
+pub struct Listeners {
+    listeners: Vec<Listener>,
+}
+
+impl Default for Listeners {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Listeners {
+    pub fn new() -> Self {
+        Self {
+            listeners: Vec::new(),
+        }
+    }
+
+    //
+    // Listens for tcp connections redirected with REDIRECT, whose original
+    // destination is looked up with SO_ORIGINAL_DST.
+    //
+    #[cfg(target_os = "linux")]
+    pub fn listen_redirect(&mut self, address: SocketAddr) -> crate::Result<()> {
+        let listener = transparent_socket::tcp_listener(address, ListenerKind::Redirect)?;
+        self.listeners
+            .push(Listener::Tcp(listener, ListenerKind::Redirect));
+        Ok(())
+    }
+
+    //
+    // Listens for tcp connections and udp datagrams diverted with TPROXY,
+    // which keep their original destination.
+    //
+    #[cfg(target_os = "linux")]
+    pub fn listen_tproxy(&mut self, address: SocketAddr) -> crate::Result<()> {
+        let tcp_listener = transparent_socket::tcp_listener(address, ListenerKind::Tproxy)?;
+        let udp_listener = transparent_socket::udp_listener(address)?;
+        self.listeners
+            .push(Listener::Tcp(tcp_listener, ListenerKind::Tproxy));
+        self.listeners.push(Listener::Udp(udp_listener));
+        Ok(())
+    }
+
+    //
+    // Listens for socks5 clients, without authentication; returns the address
+    // listened on, whose port is chosen when the port of the address is 0.
+    //
+    pub fn listen_socks(&mut self, address: SocketAddr) -> crate::Result<SocketAddr> {
+        let listener = TcpListener::bind(address)?;
+        let address = listener.local_addr()?;
+        self.listeners.push(Listener::Socks(listener));
+        Ok(address)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.listeners.is_empty()
+    }
+}
+
+//
+// What a listener has received, for the processor to create or continue a
+// session with.
+//
+pub(crate) enum Arrival {
+    Connection(Connection),
+    Datagram(Datagram),
+    // the connection of a socks udp association, the token of its relay, has
+    // been closed, which ends the sessions of the association.
+    AssociationClosed(Token),
+}
+
+pub(crate) struct Connection {
+    pub(crate) session_info: SessionInfo,
+    pub(crate) socket: MioSocket,
+    // the domain name requested by a socks client.
+    pub(crate) hostname: Option<String>,
+    // sent by a socks client along with its request.
+    pub(crate) payload: Vec<u8>,
+    // set for socks clients, which are answered once connected, see connect_reply.
+    pub(crate) is_socks: bool,
+}
+
+pub(crate) struct Datagram {
+    pub(crate) session_info: SessionInfo,
+    pub(crate) origin: DatagramOrigin,
+    pub(crate) hostname: Option<String>,
+    pub(crate) bytes: Vec<u8>,
+}
+
+pub(crate) enum DatagramOrigin {
+    // received by a tproxy listener; the session replies from a socket of its
+    // own, see reply_socket, which later datagrams usually arrive at.
+    Tproxy,
+    // received by the relay of a socks udp association, its token, from the
+    // address.
+    Association(Token, SocketAddr),
+}
+
+//
+// A socks client whose request has not been handled yet.
+//
+struct SocksClient {
+    stream: TcpStream,
+    source: SocketAddr,
+    // bytes received which have not been parsed yet.
+    received: Vec<u8>,
+    is_greeted: bool,
+    // set while the domain name of the request is resolved.
+    is_resolving: bool,
+    created: Instant,
+}
+
+//
+// A udp relay created for a UDP ASSOCIATE request, which lasts as long as the
+// tcp connection of the request.
+//
+struct Association {
+    relay: UdpSocket,
+    connection: TcpStream,
+    connection_token: Token,
+    // datagrams are only relayed from the host which made the request.
+    client_ip: IpAddr,
+    resolved_names: HashMap<String, IpAddr>,
+    // datagrams to names which are being resolved are dropped.
+    resolving_names: HashSet<String>,
+}
+
+pub(crate) struct Resolution {
+    name: String,
+    address: Option<SocketAddr>,
+    request: ResolutionRequest,
+}
+
+struct ResolutionJob {
+    name: String,
+    port: u16,
+    request: ResolutionRequest,
+}
+
+//
+// Resolves the domain names sent by socks clients on a fixed number of
+// threads and hands the first address of each back to the processor, see
+// Command::Resolved.
+//
+struct Resolver {
+    sender: SyncSender<ResolutionJob>,
+}
+
+impl Resolver {
+    fn new(control: Control) -> Resolver {
+        let (sender, receiver) = sync_channel(RESOLVER_QUEUE_LENGTH);
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..RESOLVER_THREADS {
+            let receiver = receiver.clone();
+            let control = control.clone();
+            std::thread::spawn(move || Self::run(&receiver, &control));
+        }
+        Resolver { sender }
+    }
+
+    // threads end once the resolver has been dropped.
+    fn run(receiver: &Mutex<Receiver<ResolutionJob>>, control: &Control) {
+        loop {
+            let job = receiver.lock().unwrap().recv();
+            let Ok(ResolutionJob {
+                name,
+                port,
+                request,
+            }) = job
+            else {
+                return;
+            };
+            let address = match (name.as_str(), port).to_socket_addrs() {
+                Ok(mut addresses) => addresses.next(),
+                Err(error) => {
+                    log::debug!("failed to resolve {:?}, error={:?}", name, error);
+                    None
+                }
+            };
+            control.send(Command::Resolved(Resolution {
+                name,
+                address,
+                request,
+            }));
+        }
+    }
+
+    //
+    // Hands the resolution back as failed when the queue is full.
+    //
+    fn resolve(&self, job: ResolutionJob) -> Result<(), Resolution> {
+        match self.sender.try_send(job) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(job)) | Err(TrySendError::Disconnected(job)) => {
+                log::warn!("failed to resolve {:?}, too many names queued", job.name);
+                Err(Resolution {
+                    name: job.name,
+                    address: None,
+                    request: job.request,
+                })
+            }
+        }
+    }
+}
+
+enum ResolutionRequest {
+    // the token of the socks client.
+    Connect(Token),
+    Datagram {
+        relay_token: Token,
+        source: SocketAddr,
+        bytes: Vec<u8>,
+    },
+}
+
+//
+// Accepts clients on the listeners, handles the handshakes of socks clients
+// and relays the datagrams of socks udp associations; the sessions of what
+// arrives are created by the processor, see Arrival.
+//
+pub(crate) struct Proxy {
+    listeners: HashMap<Token, Listener>,
+    socks_clients: HashMap<Token, SocksClient>,
+    // associations by the tokens of their relays.
+    associations: HashMap<Token, Association>,
+    // the tokens of relays and of the connections of associations.
+    tokens_to_associations: HashMap<Token, Token>,
+    // only created for socks listeners.
+    resolver: Option<Resolver>,
+}
+
+impl Proxy {
+    pub(crate) fn new() -> Proxy {
+        Proxy {
+            listeners: HashMap::new(),
+            socks_clients: HashMap::new(),
+            associations: HashMap::new(),
+            tokens_to_associations: HashMap::new(),
+            resolver: None,
+        }
+    }
+
+    pub(crate) fn listen(
+        &mut self,
+        listeners: Listeners,
+        registry: &Registry,
+        tokens: &mut Tokens,
+        control: Control,
+    ) -> crate::Result<()> {
+        for mut listener in listeners.listeners {
+            let token = tokens.next();
+            match &mut listener {
+                #[cfg(target_os = "linux")]
+                Listener::Tcp(listener, _) => {
+                    registry.register(listener, token, Interest::READABLE)?
+                }
+                #[cfg(target_os = "linux")]
+                Listener::Udp(socket) => registry.register(socket, token, Interest::READABLE)?,
+                Listener::Socks(listener) => {
+                    registry.register(listener, token, Interest::READABLE)?
+                }
+            }
+            self.listeners.insert(token, listener);
+        }
+        let has_socks = self
+            .listeners
+            .values()
+            .any(|listener| matches!(listener, Listener::Socks(_)));
+        if has_socks {
+            self.resolver = Some(Resolver::new(control));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn owns(&self, token: Token) -> bool {
+        self.listeners.contains_key(&token)
+            || self.socks_clients.contains_key(&token)
+            || self.tokens_to_associations.contains_key(&token)
+    }
+
+    pub(crate) fn next_deadline(&self) -> Option<Instant> {
+        self.socks_clients
+            .values()
+            .map(|client| client.created + SOCKS_HANDSHAKE_TIMEOUT)
+            .min()
+    }
+
+    pub(crate) fn handle_event(
+        &mut self,
+        token: Token,
+        registry: &Registry,
+        tokens: &mut Tokens,
+    ) -> Vec<Arrival> {
+        let mut arrivals = Vec::new();
+        if self.listeners.contains_key(&token) {
+            self.accept(token, registry, tokens, &mut arrivals);
+        } else if self.socks_clients.contains_key(&token) {
+            self.handle_socks_client(token, registry, tokens, &mut arrivals);
+        } else if let Some(relay_token) = self.tokens_to_associations.get(&token).copied() {
+            if token == relay_token {
+                self.relay_datagrams(relay_token, registry, &mut arrivals);
+            } else {
+                self.handle_association_connection(relay_token, registry, &mut arrivals);
+            }
+        }
+        arrivals
+    }
+
+    fn accept(
+        &mut self,
+        token: Token,
+        registry: &Registry,
+        tokens: &mut Tokens,
+        arrivals: &mut Vec<Arrival>,
+    ) {
+        match &self.listeners[&token] {
+            #[cfg(target_os = "linux")]
+            Listener::Tcp(listener, kind) => Self::accept_connections(listener, *kind, arrivals),
+            #[cfg(target_os = "linux")]
+            Listener::Udp(socket) => Self::receive_datagrams(socket, arrivals),
+            Listener::Socks(_) => self.accept_socks_clients(token, registry, tokens),
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn accept_connections(listener: &TcpListener, kind: ListenerKind, arrivals: &mut Vec<Arrival>) {
+        loop {
+            match listener.accept() {
+                Ok((stream, source)) => {
+                    let destination = match transparent_socket::original_destination(&stream, kind)
+                    {
+                        Ok(destination) => destination,
+                        Err(error) => {
+                            log::error!(
+                                "failed to get original destination, source={:?} error={:?}",
+                                source,
+                                error
+                            );
+                            continue;
+                        }
+                    };
+                    arrivals.push(Arrival::Connection(Connection {
+                        session_info: session_info(source, destination, TransportProtocol::Tcp),
+                        socket: MioSocket::from_tcp_stream(stream),
+                        hostname: None,
+                        payload: Vec::new(),
+                        is_socks: false,
+                    }));
+                }
+                Err(error) if error.kind() == ErrorKind::WouldBlock => return,
+                Err(error) => {
+                    log::error!("failed to accept connection, error={:?}", error);
+                    return;
+                }
+            }
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn receive_datagrams(socket: &UdpSocket, arrivals: &mut Vec<Arrival>) {
+        let mut buffer = [0; 1 << 16];
+        loop {
+            match transparent_socket::receive_datagram(socket, &mut buffer) {
+                Ok((count, source, destination)) => {
+                    arrivals.push(Arrival::Datagram(Datagram {
+                        session_info: session_info(source, destination, TransportProtocol::Udp),
+                        origin: DatagramOrigin::Tproxy,
+                        hostname: None,
+                        bytes: buffer[..count].to_vec(),
+                    }));
+                }
+                Err(error) if error.kind() == ErrorKind::WouldBlock => return,
+                Err(error) => {
+                    log::error!("failed to receive datagram, error={:?}", error);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn accept_socks_clients(&mut self, token: Token, registry: &Registry, tokens: &mut Tokens) {
+        let Some(Listener::Socks(listener)) = self.listeners.get(&token) else {
+            return;
+        };
+        loop {
+            match listener.accept() {
+                Ok((mut stream, source)) => {
+                    let token = tokens.next();
+                    if let Err(error) = registry.register(&mut stream, token, Interest::READABLE) {
+                        log::error!("failed to register poll, error={:?}", error);
+                        continue;
+                    }
+                    let client = SocksClient {
+                        stream,
+                        source,
+                        received: Vec::new(),
+                        is_greeted: false,
+                        is_resolving: false,
+                        created: Instant::now(),
+                    };
+                    self.socks_clients.insert(token, client);
+                }
+                Err(error) if error.kind() == ErrorKind::WouldBlock => return,
+                Err(error) => {
+                    log::error!("failed to accept socks client, error={:?}", error);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn handle_socks_client(
+        &mut self,
+        token: Token,
+        registry: &Registry,
+        tokens: &mut Tokens,
+        arrivals: &mut Vec<Arrival>,
+    ) {
+        let Some(client) = self.socks_clients.get_mut(&token) else {
+            return;
+        };
+        let mut buffer = [0; SOCKS_HANDSHAKE_MAX_LENGTH];
+        // bytes beyond the handshake are read once the session is created.
+        while client.received.len() < SOCKS_HANDSHAKE_MAX_LENGTH {
+            match client.stream.read(&mut buffer) {
+                Ok(0) => {
+                    self.remove_socks_client(token, registry);
+                    return;
+                }
+                Ok(count) => client.received.extend_from_slice(&buffer[..count]),
+                Err(error) if error.kind() == ErrorKind::WouldBlock => break,
+                Err(error) => {
+                    log::debug!("failed to read socks handshake, error={:?}", error);
+                    self.remove_socks_client(token, registry);
+                    return;
+                }
+            }
+        }
+        if client.is_resolving {
+            return;
+        }
+
+        if !client.is_greeted {
+            match socks::parse_greeting(&client.received) {
+                Parsed::Incomplete => return,
+                Parsed::Invalid(_) => {
+                    self.remove_socks_client(token, registry);
+                    return;
+                }
+                Parsed::Complete(is_accepted, length) => {
+                    client.received.drain(..length);
+                    let _ = client.stream.write(&socks::greeting_reply(is_accepted));
+                    if !is_accepted {
+                        self.remove_socks_client(token, registry);
+                        return;
+                    }
+                    client.is_greeted = true;
+                }
+            }
+        }
+
+        match socks::parse_request(&client.received) {
+            Parsed::Incomplete => {
+                if client.received.len() >= SOCKS_HANDSHAKE_MAX_LENGTH {
+                    self.remove_socks_client(token, registry);
+                }
+            }
+            Parsed::Invalid(reply) => {
+                if let Some(reply) = reply {
+                    let _ = client
+                        .stream
+                        .write(&socks::reply(reply, socks::UNSPECIFIED));
+                }
+                self.remove_socks_client(token, registry);
+            }
+            Parsed::Complete((command, address), length) => {
+                client.received.drain(..length);
+                match (command, address) {
+                    (socks::Command::Connect, Address::Ip(destination)) => {
+                        if let Some(connection) =
+                            self.connect_socks_client(token, destination, None, registry)
+                        {
+                            arrivals.push(Arrival::Connection(connection));
+                        }
+                    }
+                    (socks::Command::Connect, Address::Domain(name, port)) => {
+                        client.is_resolving = true;
+                        let request = ResolutionRequest::Connect(token);
+                        self.resolve(name, port, request, registry, arrivals);
+                    }
+                    (socks::Command::UdpAssociate, _) => self.associate(token, registry, tokens),
+                }
+            }
+        }
+    }
+
+    fn remove_socks_client(&mut self, token: Token, registry: &Registry) -> Option<SocksClient> {
+        let mut client = self.socks_clients.remove(&token)?;
+        let _ = registry.deregister(&mut client.stream);
+        Some(client)
+    }
+
+    pub(crate) fn expire(&mut self, registry: &Registry, now: Instant) {
+        let expired_clients: Vec<Token> = self
+            .socks_clients
+            .iter()
+            .filter(|(_, client)| now.duration_since(client.created) >= SOCKS_HANDSHAKE_TIMEOUT)
+            .map(|(token, _)| *token)
+            .collect();
+        for token in expired_clients {
+            log::debug!("socks handshake timed out, token={:?}", token);
+            self.remove_socks_client(token, registry);
+        }
+    }
+
+    //
+    // Hands the client of a CONNECT request over to a session; the client is
+    // answered once the connection to the destination has succeeded or
+    // failed, see connect_reply.
+    //
+    fn connect_socks_client(
+        &mut self,
+        token: Token,
+        destination: SocketAddr,
+        hostname: Option<String>,
+        registry: &Registry,
+    ) -> Option<Connection> {
+        let client = self.remove_socks_client(token, registry)?;
+        Some(Connection {
+            session_info: session_info(client.source, destination, TransportProtocol::Tcp),
+            socket: MioSocket::from_tcp_stream(client.stream),
+            hostname,
+            // clients may send their first payload without waiting for the reply.
+            payload: client.received,
+            is_socks: true,
+        })
+    }
+
+    //
+    // Answers a UDP ASSOCIATE request with a relay bound to the address the
+    // client has connected to, so that the client can reach it the same way.
+    //
+    fn associate(&mut self, token: Token, registry: &Registry, tokens: &mut Tokens) {
+        let Some(client) = self.socks_clients.remove(&token) else {
+            return;
+        };
+        let mut connection = client.stream;
+        let relay = connection
+            .local_addr()
+            .and_then(|address| UdpSocket::bind(SocketAddr::new(address.ip(), 0)));
+        let mut relay = match relay {
+            Ok(relay) => relay,
+            Err(error) => {
+                log::error!("failed to create udp relay, error={:?}", error);
+                let _ = connection.write(&socks::reply(Reply::GeneralFailure, socks::UNSPECIFIED));
+                let _ = registry.deregister(&mut connection);
+                return;
+            }
+        };
+        let relay_token = tokens.next();
+        let bound = match registry
+            .register(&mut relay, relay_token, Interest::READABLE)
+            .and_then(|_| relay.local_addr())
+        {
+            Ok(bound) => bound,
+            Err(error) => {
+                log::error!("failed to register poll, error={:?}", error);
+                let _ = connection.write(&socks::reply(Reply::GeneralFailure, socks::UNSPECIFIED));
+                let _ = registry.deregister(&mut connection);
+                return;
+            }
+        };
+        let _ = connection.write(&socks::reply(Reply::Succeeded, bound));
+        log::debug!(
+            "created udp association, client={:?} relay={:?}",
+            client.source,
+            bound
+        );
+
+        // the connection keeps its token, now for the association.
+        self.tokens_to_associations.insert(token, relay_token);
+        self.tokens_to_associations.insert(relay_token, relay_token);
+        let association = Association {
+            relay,
+            connection,
+            connection_token: token,
+            client_ip: client.source.ip(),
+            resolved_names: HashMap::new(),
+            resolving_names: HashSet::new(),
+        };
+        self.associations.insert(relay_token, association);
+    }
+
+    // anything sent over the connection of an association is ignored.
+    fn handle_association_connection(
+        &mut self,
+        relay_token: Token,
+        registry: &Registry,
+        arrivals: &mut Vec<Arrival>,
+    ) {
+        let Some(association) = self.associations.get_mut(&relay_token) else {
+            return;
+        };
+        let mut buffer = [0; 1024];
+        loop {
+            match association.connection.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(_) => continue,
+                Err(error) if error.kind() == ErrorKind::WouldBlock => return,
+                Err(error) => {
+                    log::debug!("failed to read association, error={:?}", error);
+                    break;
+                }
+            }
+        }
+        self.close_association(relay_token, registry);
+        arrivals.push(Arrival::AssociationClosed(relay_token));
+    }
+
+    fn close_association(&mut self, relay_token: Token, registry: &Registry) {
+        let Some(mut association) = self.associations.remove(&relay_token) else {
+            return;
+        };
+        let _ = registry.deregister(&mut association.relay);
+        let _ = registry.deregister(&mut association.connection);
+        self.tokens_to_associations.remove(&relay_token);
+        self.tokens_to_associations
+            .remove(&association.connection_token);
+        log::debug!("closed udp association, relay={:?}", relay_token);
+    }
+
+    fn relay_datagrams(
+        &mut self,
+        relay_token: Token,
+        registry: &Registry,
+        arrivals: &mut Vec<Arrival>,
+    ) {
+        let mut buffer = [0; 1 << 16];
+        loop {
+            let Some(association) = self.associations.get_mut(&relay_token) else {
+                return;
+            };
+            let (count, source) = match association.relay.recv_from(&mut buffer) {
+                Ok(received) => received,
+                Err(error) if error.kind() == ErrorKind::WouldBlock => return,
+                Err(error) => {
+                    log::error!("failed to receive datagram, error={:?}", error);
+                    return;
+                }
+            };
+            if source.ip() != association.client_ip {
+                continue;
+            }
+            let Some((address, length)) = socks::parse_udp_header(&buffer[..count]) else {
+                continue;
+            };
+            let bytes = buffer[length..count].to_vec();
+            match address {
+                Address::Ip(destination) => {
+                    arrivals.push(relayed_datagram(
+                        relay_token,
+                        source,
+                        destination,
+                        None,
+                        bytes,
+                    ));
+                }
+                Address::Domain(name, port) => {
+                    if let Some(ip) = association.resolved_names.get(&name) {
+                        let destination = SocketAddr::new(*ip, port);
+                        let hostname = Some(name.to_ascii_lowercase());
+                        arrivals.push(relayed_datagram(
+                            relay_token,
+                            source,
+                            destination,
+                            hostname,
+                            bytes,
+                        ));
+                    } else if association.resolving_names.insert(name.clone()) {
+                        let request = ResolutionRequest::Datagram {
+                            relay_token,
+                            source,
+                            bytes,
+                        };
+                        self.resolve(name, port, request, registry, arrivals);
+                    }
+                }
+            }
+        }
+    }
+
+    //
+    // Sends a datagram from the destination to the client of an association
+    // through its relay.
+    //
+    pub(crate) fn send_datagram(
+        &self,
+        relay_token: Token,
+        client: SocketAddr,
+        destination: SocketAddr,
+        bytes: &[u8],
+    ) -> std::io::Result<usize> {
+        let Some(association) = self.associations.get(&relay_token) else {
+            return Err(ErrorKind::NotConnected.into());
+        };
+        let mut datagram = socks::udp_header(destination);
+        datagram.extend_from_slice(bytes);
+        association
+            .relay
+            .send_to(&datagram, client)
+            .map(|_| bytes.len())
+    }
+
+    fn resolve(
+        &mut self,
+        name: String,
+        port: u16,
+        request: ResolutionRequest,
+        registry: &Registry,
+        arrivals: &mut Vec<Arrival>,
+    ) {
+        let Some(resolver) = &self.resolver else {
+            return;
+        };
+        let job = ResolutionJob {
+            name,
+            port,
+            request,
+        };
+        if let Err(resolution) = resolver.resolve(job) {
+            arrivals.extend(self.handle_resolution(resolution, registry));
+        }
+    }
+
+    pub(crate) fn handle_resolution(
+        &mut self,
+        resolution: Resolution,
+        registry: &Registry,
+    ) -> Vec<Arrival> {
+        let Resolution {
+            name,
+            address,
+            request,
+        } = resolution;
+        let hostname = Some(name.to_ascii_lowercase());
+        let arrival = match request {
+            ResolutionRequest::Connect(token) => match address {
+                Some(destination) => self
+                    .connect_socks_client(token, destination, hostname, registry)
+                    .map(Arrival::Connection),
+                None => {
+                    if let Some(mut client) = self.remove_socks_client(token, registry) {
+                        let reply = socks::reply(Reply::HostUnreachable, socks::UNSPECIFIED);
+                        let _ = client.stream.write(&reply);
+                    }
+                    None
+                }
+            },
+            ResolutionRequest::Datagram {
+                relay_token,
+                source,
+                bytes,
+            } => {
+                let association = self.associations.get_mut(&relay_token);
+                association.and_then(|association| {
+                    association.resolving_names.remove(&name);
+                    let destination = address?;
+                    association.resolved_names.insert(name, destination.ip());
+                    Some(relayed_datagram(
+                        relay_token,
+                        source,
+                        destination,
+                        hostname,
+                        bytes,
+                    ))
+                })
+            }
+        };
+        arrival.into_iter().collect()
+    }
+}
+
+fn relayed_datagram(
+    relay_token: Token,
+    source: SocketAddr,
+    destination: SocketAddr,
+    hostname: Option<String>,
+    bytes: Vec<u8>,
+) -> Arrival {
+    Arrival::Datagram(Datagram {
+        session_info: session_info(source, destination, TransportProtocol::Udp),
+        origin: DatagramOrigin::Association(relay_token, source),
+        hostname,
+        bytes,
+    })
+}
+
+fn session_info(
+    source: SocketAddr,
+    destination: SocketAddr,
+    transport_protocol: TransportProtocol,
+) -> SessionInfo {
+    let internet_protocol = match destination {
+        SocketAddr::V4(_) => InternetProtocol::Ipv4,
+        SocketAddr::V6(_) => InternetProtocol::Ipv6,
+    };
+    SessionInfo {
+        source,
+        destination,
+        transport_protocol,
+        internet_protocol,
+    }
+}
+
+//
+// Answers the CONNECT request of a socks client with the address connected
+// from, or with the reason the connection has failed; returns the reply and
+// whether the connection has succeeded.
+//
+pub(crate) fn connect_reply(upstream: &MioSocket) -> (Vec<u8>, bool) {
+    match upstream.take_error().unwrap_or_else(Some) {
+        None => {
+            let bound = upstream.local_address().unwrap_or(socks::UNSPECIFIED);
+            (socks::reply(Reply::Succeeded, bound), true)
+        }
+        Some(error) => (socks::reply(error.kind().into(), socks::UNSPECIFIED), false),
+    }
+}
+
+//
+// Answers the CONNECT request of a socks client for which no session has been
+// created.
+//
+pub(crate) fn refusal_reply(is_allowed: bool) -> Vec<u8> {
+    let reply = if is_allowed {
+        Reply::GeneralFailure
+    } else {
+        Reply::NotAllowed
+    };
+    socks::reply(reply, socks::UNSPECIFIED)
+}
+
+//
+// Replies to a tproxy client have to come from the address the client sent
+// its datagrams to, see transparent_socket::udp_reply_socket.
+//
+#[cfg(target_os = "linux")]
+pub(crate) fn reply_socket(session_info: &SessionInfo) -> std::io::Result<MioSocket> {
+    let socket =
+        transparent_socket::udp_reply_socket(session_info.destination, session_info.source)?;
+    Ok(MioSocket::from_udp_socket(socket))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn reply_socket(_session_info: &SessionInfo) -> std::io::Result<MioSocket> {
+    Err(ErrorKind::Unsupported.into())
+}
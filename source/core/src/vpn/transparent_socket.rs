@@ -0,0 +1,369 @@
+// This is free and unencumbered software released into the public domain.
+//
+// Anyone is free to copy, modify, publish, use, compile, sell, or
+// distribute this software, either in source code form or as a compiled
+// binary, for any purpose, commercial or non-commercial, and by any
+// means.
+//
+// In jurisdictions that recognize copyright laws, the author or authors
+// of this software dedicate any and all copyright interest in the
+// software to the public domain. We make this dedication for the benefit
+// of the public at large and to the detriment of our heirs and
+// successors. We intend this dedication to be an overt act of
+// relinquishment in perpetuity of all present and future rights to this
+// software under copyright law.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS BE LIABLE FOR ANY CLAIM, DAMAGES OR
+// OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE,
+// ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR
+// OTHER DEALINGS IN THE SOFTWARE.
+//
+// For more information, please refer to <https://unlicense.org>
+
+use mio::net::{TcpListener, TcpStream, UdpSocket};
+use socket2::{Domain, SockAddr, Socket, Type};
+use std::{
+    io::{Error, ErrorKind, Result},
+    mem::{size_of, MaybeUninit},
+    net::SocketAddr,
+    os::unix::io::AsRawFd,
+};
+
+const LISTEN_BACKLOG: i32 = 1024;
+
+// room for a single ipv4 or ipv6 address in the ancillary data of a datagram.
+const CONTROL_LENGTH: usize = 64;
+
+//
+// How connections reach a listener: REDIRECT rewrites their destination to
+// the listener and keeps the original one in conntrack, TPROXY delivers them
+// with their destination unchanged to a listener which accepts any address.
+//
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ListenerKind {
+    Redirect,
+    Tproxy,
+}
+
+pub(crate) fn tcp_listener(address: SocketAddr, kind: ListenerKind) -> Result<TcpListener> {
+    let socket = new_socket(address, Type::STREAM)?;
+    if kind == ListenerKind::Tproxy {
+        set_transparent(&socket, address)?;
+    }
+    socket.bind(&address.into())?;
+    socket.listen(LISTEN_BACKLOG)?;
+    Ok(TcpListener::from_std(socket.into()))
+}
+
+//
+// Datagrams carry their original destination as ancillary data, see
+// receive_datagram.
+//
+pub(crate) fn udp_listener(address: SocketAddr) -> Result<UdpSocket> {
+    let socket = new_socket(address, Type::DGRAM)?;
+    set_transparent(&socket, address)?;
+    set_receive_original_destination(&socket, address)?;
+    socket.bind(&address.into())?;
+    Ok(UdpSocket::from_std(socket.into()))
+}
+
+//
+// Replies to a client have to come from the address the client sent its
+// datagrams to, so every udp session gets a socket bound to that address and
+// connected to the client.
+//
+pub(crate) fn udp_reply_socket(destination: SocketAddr, client: SocketAddr) -> Result<UdpSocket> {
+    let socket = new_socket(destination, Type::DGRAM)?;
+    set_transparent(&socket, destination)?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&destination.into())?;
+    socket.connect(&client.into())?;
+    Ok(UdpSocket::from_std(socket.into()))
+}
+
+pub(crate) fn original_destination(stream: &TcpStream, kind: ListenerKind) -> Result<SocketAddr> {
+    let local_address = stream.local_addr()?;
+    if kind == ListenerKind::Tproxy {
+        return Ok(local_address);
+    }
+    let level = match local_address {
+        SocketAddr::V4(_) => libc::SOL_IP,
+        SocketAddr::V6(_) => libc::SOL_IPV6,
+    };
+    // SO_ORIGINAL_DST and IP6T_SO_ORIGINAL_DST share their value.
+    let mut storage = MaybeUninit::<libc::sockaddr_storage>::zeroed();
+    let mut length = size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            level,
+            libc::SO_ORIGINAL_DST,
+            storage.as_mut_ptr() as *mut libc::c_void,
+            &mut length,
+        )
+    };
+    if result == -1 {
+        return Err(Error::last_os_error());
+    }
+    let address = unsafe { SockAddr::new(storage.assume_init(), length) };
+    address
+        .as_socket()
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "unsupported original destination"))
+}
+
+//
+// Receives a datagram along with its source and original destination.
+//
+pub(crate) fn receive_datagram(
+    socket: &UdpSocket,
+    buffer: &mut [u8],
+) -> Result<(usize, SocketAddr, SocketAddr)> {
+    let mut source = MaybeUninit::<libc::sockaddr_storage>::zeroed();
+    let mut control = [0u8; CONTROL_LENGTH];
+    let mut iovec = libc::iovec {
+        iov_base: buffer.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buffer.len(),
+    };
+    let mut message: libc::msghdr = unsafe { std::mem::zeroed() };
+    message.msg_name = source.as_mut_ptr() as *mut libc::c_void;
+    message.msg_namelen = size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    message.msg_iov = &mut iovec;
+    message.msg_iovlen = 1;
+    message.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    message.msg_controllen = control.len() as _;
+
+    let count = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut message, 0) };
+    if count == -1 {
+        return Err(Error::last_os_error());
+    }
+    let source = unsafe { SockAddr::new(source.assume_init(), message.msg_namelen) };
+    let source = source
+        .as_socket()
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "unsupported source"))?;
+    let destination = unsafe { original_destination_from_control(&message) }
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "missing original destination"))?;
+    Ok((count as usize, source, destination))
+}
+
+unsafe fn original_destination_from_control(message: &libc::msghdr) -> Option<SocketAddr> {
+    let mut header = libc::CMSG_FIRSTHDR(message);
+    while !header.is_null() {
+        let is_original_destination = matches!(
+            ((*header).cmsg_level, (*header).cmsg_type),
+            (libc::SOL_IP, libc::IP_ORIGDSTADDR) | (libc::SOL_IPV6, libc::IPV6_ORIGDSTADDR)
+        );
+        if is_original_destination {
+            let length = (*header).cmsg_len as usize - libc::CMSG_LEN(0) as usize;
+            let length = length.min(size_of::<libc::sockaddr_storage>());
+            let mut storage = MaybeUninit::<libc::sockaddr_storage>::zeroed();
+            std::ptr::copy_nonoverlapping(
+                libc::CMSG_DATA(header),
+                storage.as_mut_ptr() as *mut u8,
+                length,
+            );
+            let address = SockAddr::new(storage.assume_init(), length as libc::socklen_t);
+            return address.as_socket();
+        }
+        header = libc::CMSG_NXTHDR(message, header);
+    }
+    None
+}
+
+fn new_socket(address: SocketAddr, socket_type: Type) -> Result<Socket> {
+    let socket = Socket::new(Domain::for_address(address), socket_type, None)?;
+    // ipv4 connections would otherwise reach ipv6 listeners with mapped addresses.
+    if address.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+//
+// Allows binding to and receiving for addresses which are not local; requires
+// CAP_NET_ADMIN.
+//
+fn set_transparent(socket: &Socket, address: SocketAddr) -> Result<()> {
+    match address {
+        SocketAddr::V4(_) => set_option(socket, libc::SOL_IP, libc::IP_TRANSPARENT),
+        SocketAddr::V6(_) => set_option(socket, libc::SOL_IPV6, libc::IPV6_TRANSPARENT),
+    }
+}
+
+fn set_receive_original_destination(socket: &Socket, address: SocketAddr) -> Result<()> {
+    match address {
+        SocketAddr::V4(_) => set_option(socket, libc::SOL_IP, libc::IP_RECVORIGDSTADDR),
+        SocketAddr::V6(_) => set_option(socket, libc::SOL_IPV6, libc::IPV6_RECVORIGDSTADDR),
+    }
+}
+
+fn set_option(socket: &Socket, level: libc::c_int, name: libc::c_int) -> Result<()> {
+    let value: libc::c_int = 1;
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result == -1 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        net::{
+            TcpListener as StdTcpListener, TcpStream as StdTcpStream, UdpSocket as StdUdpSocket,
+        },
+        thread,
+        time::{Duration, Instant},
+    };
+
+    // the original destination is only set by netfilter, which tests do
+    // without, so datagrams to a plain socket carry its own address.
+    fn original_destination_socket(address: &str) -> UdpSocket {
+        let address: SocketAddr = address.parse().unwrap();
+        let socket = new_socket(address, Type::DGRAM).unwrap();
+        set_receive_original_destination(&socket, address).unwrap();
+        socket.bind(&address.into()).unwrap();
+        UdpSocket::from_std(socket.into())
+    }
+
+    fn receive(socket: &UdpSocket, buffer: &mut [u8]) -> (usize, SocketAddr, SocketAddr) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            match receive_datagram(socket, buffer) {
+                Err(error)
+                    if error.kind() == ErrorKind::WouldBlock && Instant::now() < deadline =>
+                {
+                    thread::sleep(Duration::from_millis(10));
+                }
+                result => return result.unwrap(),
+            }
+        }
+    }
+
+    fn connect(address: SocketAddr) -> (StdTcpStream, TcpStream) {
+        let listener = StdTcpListener::bind(address).unwrap();
+        let client = StdTcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (accepted, _) = listener.accept().unwrap();
+        (client, TcpStream::from_std(accepted))
+    }
+
+    // CMSG_SPACE sized buffer aligned for struct cmsghdr.
+    fn control_message(
+        level: libc::c_int,
+        message_type: libc::c_int,
+        address: SocketAddr,
+    ) -> Vec<u64> {
+        let address = SockAddr::from(address);
+        let mut control = vec![0u64; CONTROL_LENGTH / size_of::<u64>()];
+        unsafe {
+            let mut message: libc::msghdr = std::mem::zeroed();
+            message.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+            message.msg_controllen = CONTROL_LENGTH as _;
+            let header = libc::CMSG_FIRSTHDR(&message);
+            (*header).cmsg_level = level;
+            (*header).cmsg_type = message_type;
+            (*header).cmsg_len = libc::CMSG_LEN(address.len()) as _;
+            std::ptr::copy_nonoverlapping(
+                address.as_ptr() as *const u8,
+                libc::CMSG_DATA(header),
+                address.len() as usize,
+            );
+        }
+        control
+    }
+
+    fn destination_from_control(control: &mut [u64]) -> Option<SocketAddr> {
+        unsafe {
+            let mut message: libc::msghdr = std::mem::zeroed();
+            message.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+            message.msg_controllen = CONTROL_LENGTH as _;
+            original_destination_from_control(&message)
+        }
+    }
+
+    #[test]
+    fn tproxy_destination_is_local_address() {
+        let (client, stream) = connect("127.0.0.1:0".parse().unwrap());
+
+        let destination = original_destination(&stream, ListenerKind::Tproxy).unwrap();
+
+        assert_eq!(destination, client.peer_addr().unwrap());
+    }
+
+    #[test]
+    fn redirect_destination_of_plain_connection_is_not_translated() {
+        let (client, stream) = connect("127.0.0.1:0".parse().unwrap());
+
+        // without conntrack there is no original destination at all.
+        if let Ok(destination) = original_destination(&stream, ListenerKind::Redirect) {
+            assert_eq!(destination, client.peer_addr().unwrap());
+        }
+    }
+
+    #[test]
+    fn datagrams_are_received_with_destination() {
+        for address in ["127.0.0.1:0", "[::1]:0"] {
+            let socket = original_destination_socket(address);
+            let local_address = socket.local_addr().unwrap();
+            let client = StdUdpSocket::bind(address).unwrap();
+            client.send_to(b"hello", local_address).unwrap();
+
+            let mut buffer = [0; 64];
+            let (count, source, destination) = receive(&socket, &mut buffer);
+
+            assert_eq!(&buffer[..count], b"hello");
+            assert_eq!(source, client.local_addr().unwrap());
+            assert_eq!(destination, local_address);
+        }
+    }
+
+    #[test]
+    fn datagrams_without_destination_are_invalid() {
+        let socket = UdpSocket::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let client = StdUdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .send_to(b"hello", socket.local_addr().unwrap())
+            .unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut buffer = [0; 64];
+        let error = loop {
+            match receive_datagram(&socket, &mut buffer) {
+                Err(error)
+                    if error.kind() == ErrorKind::WouldBlock && Instant::now() < deadline =>
+                {
+                    thread::sleep(Duration::from_millis(10));
+                }
+                result => break result.unwrap_err(),
+            }
+        };
+
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn destination_is_parsed_from_control_message() {
+        let ipv4: SocketAddr = "192.0.2.1:443".parse().unwrap();
+        let ipv6: SocketAddr = "[2001:db8::1]:53".parse().unwrap();
+
+        let mut control = control_message(libc::SOL_IP, libc::IP_ORIGDSTADDR, ipv4);
+        assert_eq!(destination_from_control(&mut control), Some(ipv4));
+        let mut control = control_message(libc::SOL_IPV6, libc::IPV6_ORIGDSTADDR, ipv6);
+        assert_eq!(destination_from_control(&mut control), Some(ipv6));
+        let mut control = control_message(libc::SOL_IP, libc::IP_TTL, ipv4);
+        assert_eq!(destination_from_control(&mut control), None);
+    }
+}
@@ -0,0 +1,157 @@
+// This is free and unencumbered software released into the public domain.
+//
+// Anyone is free to copy, modify, publish, use, compile, sell, or
+// distribute this software, either in source code form or as a compiled
+// binary, for any purpose, commercial or non-commercial, and by any
+// means.
+//
+// In jurisdictions that recognize copyright laws, the author or authors
+// of this software dedicate any and all copyright interest in the
+// software to the public domain. We make this dedication for the benefit
+// of the public at large and to the detriment of our heirs and
+// successors. We intend this dedication to be an overt act of
+// relinquishment in perpetuity of all present and future rights to this
+// software under copyright law.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS BE LIABLE FOR ANY CLAIM, DAMAGES OR
+// OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE,
+// ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR
+// OTHER DEALINGS IN THE SOFTWARE.
+//
+// For more information, please refer to <https://unlicense.org>
+
+use crate::metrics::METRICS;
+use crate::vpn::{capture::Capture, packet_source::PacketSource, utils::log_packet};
+use mio::{Registry, Token};
+use std::io::{ErrorKind, Result};
+
+#[derive(Default)]
+pub(crate) struct TunCounters {
+    pub(crate) packets_received: u64,
+    pub(crate) packets_sent: u64,
+    pub(crate) bytes_received: u64,
+    pub(crate) bytes_sent: u64,
+    pub(crate) errors: u64,
+}
+
+//
+// Exchanges packets with the client through the packet source, counting and
+// optionally capturing them.
+//
+pub(crate) struct Tun {
+    packet_source: Box<dyn PacketSource>,
+    capture: Option<Capture>,
+    pub(crate) counters: TunCounters,
+}
+
+impl Tun {
+    pub(crate) fn new(packet_source: Box<dyn PacketSource>) -> Tun {
+        Tun {
+            packet_source,
+            capture: None,
+            counters: TunCounters::default(),
+        }
+    }
+
+    pub(crate) fn register(&mut self, registry: &Registry, token: Token) -> Result<()> {
+        self.packet_source.register(registry, token)
+    }
+
+    pub(crate) fn deregister(&mut self, registry: &Registry) -> Result<()> {
+        self.packet_source.deregister(registry)
+    }
+
+    pub(crate) fn flush(&mut self) -> Result<()> {
+        self.packet_source.flush()
+    }
+
+    pub(crate) fn replace(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        packet_source: Box<dyn PacketSource>,
+    ) {
+        if let Err(error) = self.packet_source.flush() {
+            log::error!("failed to flush packet source, error={:?}", error);
+        }
+        if let Err(error) = self.packet_source.deregister(registry) {
+            log::error!("failed to deregister packet source, error={:?}", error);
+        }
+        self.packet_source = packet_source;
+        if let Err(error) = self.packet_source.register(registry, token) {
+            log::error!("failed to register packet source, error={:?}", error);
+        }
+    }
+
+    pub(crate) fn read_packet(&mut self, buffer: &mut [u8]) -> Result<usize> {
+        match self.packet_source.read_packet(buffer) {
+            Ok(count) => {
+                if count > 0 {
+                    self.counters.packets_received += 1;
+                    self.counters.bytes_received += count as u64;
+                    self.capture(&buffer[..count]);
+                }
+                Ok(count)
+            }
+            Err(error) => {
+                if error.kind() != ErrorKind::WouldBlock {
+                    self.counters.errors += 1;
+                    METRICS.tun_read_errors.increment();
+                }
+                Err(error)
+            }
+        }
+    }
+
+    pub(crate) fn write_packet(&mut self, bytes: &[u8]) {
+        if let Err(error) = log_packet("in", bytes) {
+            log::debug!("failed to log packet, error={:?}", error);
+        }
+        self.capture(bytes);
+        match self.packet_source.write_packet(bytes) {
+            Ok(()) => {
+                self.counters.packets_sent += 1;
+                self.counters.bytes_sent += bytes.len() as u64;
+            }
+            Err(error) if error.kind() == ErrorKind::WouldBlock => {
+                // packets are dropped if the destination is not ready.
+                self.counters.errors += 1;
+                METRICS.tun_write_errors.increment();
+                log::debug!("dropped packet written to tun, len={:?}", bytes.len());
+            }
+            Err(error) => {
+                self.counters.errors += 1;
+                METRICS.tun_write_errors.increment();
+                log::error!("failed to write to tun, error={:?}", error);
+            }
+        }
+    }
+
+    pub(crate) fn start_capture(&mut self, capture: Capture) {
+        self.stop_capture();
+        self.capture = Some(capture);
+    }
+
+    pub(crate) fn stop_capture(&mut self) {
+        if let Some(mut capture) = self.capture.take() {
+            if let Err(error) = capture.flush() {
+                log::error!("failed to flush capture, error={:?}", error);
+            }
+        }
+    }
+
+    fn capture(&mut self, bytes: &[u8]) {
+        if let Some(capture) = self.capture.as_mut() {
+            if let Err(error) = capture.write(bytes) {
+                log::error!(
+                    "failed to capture packet, stopping capture, error={:?}",
+                    error
+                );
+                self.capture = None;
+            }
+        }
+    }
+}
@@ -23,42 +23,69 @@
 //
 // For more information, please refer to <https://unlicense.org>
 
-use smoltcp::wire::{IpProtocol, Ipv4Packet, TcpPacket, UdpPacket};
+use smoltcp::wire::{IpProtocol, Ipv4Packet, Ipv6Packet, TcpPacket, UdpPacket};
+use std::fmt::Display;
 
-pub fn log_packet(message: &str, bytes: &Vec<u8>) {
-    let result = Ipv4Packet::new_checked(&bytes);
-    match result {
-        Ok(ip_packet) => match ip_packet.next_header() {
-            IpProtocol::Tcp => {
-                let tcp_bytes = ip_packet.payload();
-                let tcp_packet = TcpPacket::new_checked(tcp_bytes).unwrap();
-                log::debug!(
-                    "[{:?}] len={:?} tcp=[{}] tcp_len={:?} ip=[{}]",
-                    message,
-                    bytes.len(),
-                    tcp_packet,
-                    tcp_bytes.len(),
-                    ip_packet
-                );
-            }
-            IpProtocol::Udp => {
-                let udp_bytes = ip_packet.payload();
-                let udp_packet = UdpPacket::new_checked(udp_bytes).unwrap();
-                log::debug!(
-                    "[{:?}] len={:?} udp=[{}] udp_len={:?} ip=[{}]",
-                    message,
-                    bytes.len(),
-                    udp_packet,
-                    udp_bytes.len(),
-                    ip_packet
-                );
-            }
-            _ => {
-                log::debug!("[{:?}] len={:?} ip=[{}]", message, bytes.len(), ip_packet);
-            }
-        },
-        Err(error) => {
-            log::error!("[{:?}] failed to log packet, error={:?}", message, error);
+pub(crate) fn log_packet(message: &str, bytes: &[u8]) -> crate::Result<()> {
+    match bytes.first().map(|byte| byte >> 4) {
+        Some(4) => {
+            let ip_packet = Ipv4Packet::new_checked(bytes)?;
+            log_ip_packet(
+                message,
+                bytes,
+                ip_packet.next_header(),
+                ip_packet.payload(),
+                &ip_packet,
+            )
+        }
+        Some(6) => {
+            let ip_packet = Ipv6Packet::new_checked(bytes)?;
+            log_ip_packet(
+                message,
+                bytes,
+                ip_packet.next_header(),
+                ip_packet.payload(),
+                &ip_packet,
+            )
+        }
+        Some(version) => Err(crate::Error::UnsupportedInternetProtocol(version)),
+        None => Err(crate::Error::Wire(smoltcp::wire::Error)),
+    }
+}
+
+fn log_ip_packet(
+    message: &str,
+    bytes: &[u8],
+    protocol: IpProtocol,
+    payload: &[u8],
+    ip_packet: &dyn Display,
+) -> crate::Result<()> {
+    match protocol {
+        IpProtocol::Tcp => {
+            let tcp_packet = TcpPacket::new_checked(payload)?;
+            log::debug!(
+                "[{:?}] len={:?} tcp=[{}] tcp_len={:?} ip=[{}]",
+                message,
+                bytes.len(),
+                tcp_packet,
+                payload.len(),
+                ip_packet
+            );
+        }
+        IpProtocol::Udp => {
+            let udp_packet = UdpPacket::new_checked(payload)?;
+            log::debug!(
+                "[{:?}] len={:?} udp=[{}] udp_len={:?} ip=[{}]",
+                message,
+                bytes.len(),
+                udp_packet,
+                payload.len(),
+                ip_packet
+            );
+        }
+        _ => {
+            log::debug!("[{:?}] len={:?} ip=[{}]", message, bytes.len(), ip_packet);
         }
     }
+    Ok(())
 }
@@ -33,16 +33,22 @@ use std::collections::VecDeque;
 pub(crate) struct VpnDevice {
     rx_queue: VecDeque<Vec<u8>>,
     tx_queue: VecDeque<Vec<u8>>,
+    mtu: usize,
 }
 
 impl VpnDevice {
-    pub(crate) fn new() -> VpnDevice {
+    pub(crate) fn new(mtu: usize) -> VpnDevice {
         VpnDevice {
             rx_queue: VecDeque::new(),
             tx_queue: VecDeque::new(),
+            mtu,
         }
     }
 
+    pub(crate) fn set_mtu(&mut self, mtu: usize) {
+        self.mtu = mtu;
+    }
+
     pub(crate) fn receive(&mut self, bytes: Vec<u8>) {
         self.rx_queue.push_back(bytes);
     }
@@ -58,7 +64,7 @@ impl ::smoltcp::phy::Device for VpnDevice {
 
     fn capabilities(&self) -> DeviceCapabilities {
         let mut default = DeviceCapabilities::default();
-        default.max_transmission_unit = 65535;
+        default.max_transmission_unit = self.mtu;
         default.medium = Medium::Ip;
         default
     }
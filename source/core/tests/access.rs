@@ -0,0 +1,99 @@
+// This is free and unencumbered software released into the public domain.
+//
+// Anyone is free to copy, modify, publish, use, compile, sell, or
+// distribute this software, either in source code form or as a compiled
+// binary, for any purpose, commercial or non-commercial, and by any
+// means.
+//
+// In jurisdictions that recognize copyright laws, the author or authors
+// of this software dedicate any and all copyright interest in the
+// software to the public domain. We make this dedication for the benefit
+// of the public at large and to the detriment of our heirs and
+// successors. We intend this dedication to be an overt act of
+// relinquishment in perpetuity of all present and future rights to this
+// software under copyright law.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS BE LIABLE FOR ANY CLAIM, DAMAGES OR
+// OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE,
+// ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR
+// OTHER DEALINGS IN THE SOFTWARE.
+//
+// For more information, please refer to <https://unlicense.org>
+
+mod common;
+
+use common::{
+    client, echo_tcp_server, port_udp_server, udp_packet, upstream_port, Harness, TcpClient,
+    TIMEOUT,
+};
+use core::config::{AccessConfig, AccessRule, Action, Config, Destination, Network};
+
+fn deny_config(network: &str) -> Config {
+    Config {
+        access: AccessConfig {
+            default: Action::Allow,
+            rules: vec![AccessRule {
+                destination: Destination {
+                    network: Some(network.parse::<Network>().unwrap()),
+                    ..Destination::default()
+                },
+                action: Action::Deny,
+                ..AccessRule::default()
+            }],
+        },
+        ..Config::default()
+    }
+}
+
+#[test]
+fn denied_session_is_rejected() {
+    let harness = Harness::start_with_config(deny_config("127.0.0.2"));
+    let denied_server = port_udp_server("127.0.0.2:0");
+    let server = port_udp_server("127.0.0.1:0");
+
+    harness.send(&udp_packet(client(51000), denied_server, b"port"));
+    let message = harness.receive_icmp(TIMEOUT).expect("no icmp received");
+    assert_eq!(message.message_type, 3);
+
+    upstream_port(&harness, client(51000), server);
+}
+
+#[test]
+fn configure_keeps_existing_sessions() {
+    let harness = Harness::start();
+    let server = port_udp_server("127.0.0.1:0");
+
+    let port = upstream_port(&harness, client(51001), server);
+
+    harness.configure(deny_config("127.0.0.1"));
+
+    assert_eq!(upstream_port(&harness, client(51001), server), port);
+    harness.send(&udp_packet(client(51002), server, b"port"));
+    let message = harness.receive_icmp(TIMEOUT).expect("no icmp received");
+    assert_eq!(message.message_type, 3);
+}
+
+#[test]
+fn reload_closes_denied_sessions() {
+    let harness = Harness::start();
+    let server = echo_tcp_server("127.0.0.1:0");
+    let allowed_server = port_udp_server("127.0.0.2:0");
+
+    let allowed_port = upstream_port(&harness, client(51003), allowed_server);
+    let mut connection = TcpClient::connect(&harness, client(51004), server);
+    connection.send(b"ping");
+    assert_eq!(connection.receive(4), b"ping");
+
+    harness.vpn().reload(deny_config("127.0.0.1"));
+
+    let segment = harness.receive_tcp(TIMEOUT).expect("no reset received");
+    assert!(segment.rst);
+    assert_eq!(segment.destination, client(51004));
+    assert_eq!(
+        upstream_port(&harness, client(51003), allowed_server),
+        allowed_port
+    );
+}
@@ -0,0 +1,160 @@
+// This is free and unencumbered software released into the public domain.
+//
+// Anyone is free to copy, modify, publish, use, compile, sell, or
+// distribute this software, either in source code form or as a compiled
+// binary, for any purpose, commercial or non-commercial, and by any
+// means.
+//
+// In jurisdictions that recognize copyright laws, the author or authors
+// of this software dedicate any and all copyright interest in the
+// software to the public domain. We make this dedication for the benefit
+// of the public at large and to the detriment of our heirs and
+// successors. We intend this dedication to be an overt act of
+// relinquishment in perpetuity of all present and future rights to this
+// software under copyright law.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS BE LIABLE FOR ANY CLAIM, DAMAGES OR
+// OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE,
+// ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR
+// OTHER DEALINGS IN THE SOFTWARE.
+//
+// For more information, please refer to <https://unlicense.org>
+
+mod common;
+
+use common::{
+    client, port_udp_server, record_connections, udp_packet, upstream_port, wait_for_record,
+    Harness, TIMEOUT,
+};
+use core::config::{
+    AccessConfig, AccessRule, Action, Config, Destination, ProcessMatcher, Protocol,
+};
+use core::connection_log::CloseReason;
+use core::process::{AppProcessLookup, ConnectionOwner, ProcessInfo};
+use core::statistics::SessionSummary;
+use core::tun_callbacks;
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Once,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+static PACKAGE_NAME_CALLS: AtomicUsize = AtomicUsize::new(0);
+static CALLBACKS: Once = Once::new();
+
+const BROWSER_UID: u32 = 10123;
+const TRACKER_UID: u32 = 10456;
+
+//
+// Plays the part of android, which owns connections by the port of their
+// client.
+//
+struct FakeConnectionOwner;
+
+impl ConnectionOwner for FakeConnectionOwner {
+    fn owner_uid(&self, protocol: Protocol, local: SocketAddr, _remote: SocketAddr) -> Option<u32> {
+        assert_eq!(protocol, Protocol::Udp);
+        match local.port() {
+            53000 | 53001 => Some(BROWSER_UID),
+            53002 => Some(TRACKER_UID),
+            _ => None,
+        }
+    }
+
+    fn package_name(&self, uid: u32) -> Option<String> {
+        match uid {
+            BROWSER_UID => {
+                PACKAGE_NAME_CALLS.fetch_add(1, Ordering::SeqCst);
+                Some("com.example.browser".to_string())
+            }
+            TRACKER_UID => Some("com.example.tracker".to_string()),
+            _ => None,
+        }
+    }
+}
+
+// the lookup and its cache are shared by the tests like the callbacks.
+fn set_callbacks() {
+    CALLBACKS.call_once(|| {
+        record_connections();
+        let lookup = AppProcessLookup::new(FakeConnectionOwner);
+        tun_callbacks::set_process_lookup(Some(Arc::new(lookup)));
+    });
+}
+
+fn wait_for_process(harness: &Harness, source: SocketAddr) -> SessionSummary {
+    let deadline = Instant::now() + TIMEOUT;
+    loop {
+        let sessions = harness.vpn().sessions();
+        if let Some(session) = sessions
+            .into_iter()
+            .find(|session| session.source == source && session.process.is_some())
+        {
+            return session;
+        }
+        assert!(Instant::now() < deadline, "process not looked up");
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn sessions_are_attributed_to_apps() {
+    set_callbacks();
+    let harness = Harness::start();
+    let server = port_udp_server("127.0.0.1:0");
+
+    upstream_port(&harness, client(53000), server);
+    upstream_port(&harness, client(53001), server);
+
+    let expected = ProcessInfo {
+        pid: None,
+        uid: Some(BROWSER_UID),
+        name: Some("com.example.browser".to_string()),
+    };
+    assert_eq!(
+        wait_for_process(&harness, client(53000)).process,
+        Some(expected.clone())
+    );
+    assert_eq!(
+        wait_for_process(&harness, client(53001)).process,
+        Some(expected)
+    );
+    assert_eq!(PACKAGE_NAME_CALLS.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn sessions_of_denied_apps_are_closed() {
+    set_callbacks();
+    let harness = Harness::start_with_config(Config {
+        access: AccessConfig {
+            default: Action::Allow,
+            rules: vec![AccessRule {
+                destination: Destination::default(),
+                process: Some(ProcessMatcher {
+                    uid: Some(TRACKER_UID),
+                    ..ProcessMatcher::default()
+                }),
+                action: Action::Deny,
+            }],
+        },
+        ..Config::default()
+    });
+    let server = port_udp_server("127.0.0.1:0");
+
+    // the session is created and closed again once the app is known.
+    harness.send(&udp_packet(client(53002), server, b"port"));
+
+    let record = wait_for_record(client(53002));
+    assert_eq!(record.close_reason, CloseReason::Denied);
+    assert_eq!(
+        record.process.and_then(|process| process.name).as_deref(),
+        Some("com.example.tracker")
+    );
+}
@@ -0,0 +1,749 @@
+// This is free and unencumbered software released into the public domain.
+//
+// Anyone is free to copy, modify, publish, use, compile, sell, or
+// distribute this software, either in source code form or as a compiled
+// binary, for any purpose, commercial or non-commercial, and by any
+// means.
+//
+// In jurisdictions that recognize copyright laws, the author or authors
+// of this software dedicate any and all copyright interest in the
+// software to the public domain. We make this dedication for the benefit
+// of the public at large and to the detriment of our heirs and
+// successors. We intend this dedication to be an overt act of
+// relinquishment in perpetuity of all present and future rights to this
+// software under copyright law.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS BE LIABLE FOR ANY CLAIM, DAMAGES OR
+// OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE,
+// ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR
+// OTHER DEALINGS IN THE SOFTWARE.
+//
+// For more information, please refer to <https://unlicense.org>
+
+#![allow(dead_code)]
+
+use core::{config::Config, connection_log::ConnectionRecord, tun_callbacks, Vpn};
+use smoltcp::{
+    phy::ChecksumCapabilities,
+    wire::{
+        IpAddress, IpProtocol, IpRepr, Ipv4Packet, Ipv6Packet, TcpControl, TcpPacket, TcpRepr,
+        TcpSeqNumber, UdpPacket, UdpRepr,
+    },
+};
+use std::{
+    collections::BTreeMap,
+    io::{Read, Write},
+    net::{IpAddr, SocketAddr, TcpListener, UdpSocket},
+    os::unix::net::UnixDatagram,
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+pub const TIMEOUT: Duration = Duration::from_secs(5);
+
+static RECORDS: Mutex<Vec<ConnectionRecord>> = Mutex::new(Vec::new());
+
+const IPV6_HEADER_LENGTH: usize = 40;
+
+//
+// Runs a vpn over one end of a socketpair; the other end plays the role of
+// the client side of the tun.
+//
+pub struct Harness {
+    vpn: Vpn,
+    socket: UnixDatagram,
+}
+
+impl Harness {
+    pub fn start() -> Harness {
+        Self::start_with_config(Config::default())
+    }
+
+    pub fn start_with_config(config: Config) -> Harness {
+        let (socket, vpn_socket) = UnixDatagram::pair().unwrap();
+        let mut vpn = Vpn::new_datagram(vpn_socket).unwrap();
+        vpn.start(config).unwrap();
+        Harness { vpn, socket }
+    }
+
+    //
+    // Hands the vpn over to a new socketpair, as when the tun is re-established.
+    //
+    pub fn replace_socket(&mut self) {
+        let (socket, vpn_socket) = UnixDatagram::pair().unwrap();
+        self.vpn.replace_datagram(vpn_socket).unwrap();
+        self.socket = socket;
+    }
+
+    pub fn vpn(&self) -> &Vpn {
+        &self.vpn
+    }
+
+    //
+    // Applies the configuration and waits until the processor has handled it;
+    // commands are handled in order, so a reply to a later one means it has.
+    //
+    pub fn configure(&self, config: Config) {
+        self.vpn.configure(config);
+        self.vpn.statistics();
+    }
+
+    pub fn send(&self, packet: &[u8]) {
+        self.socket.send(packet).unwrap();
+    }
+
+    pub fn receive(&self, timeout: Duration) -> Option<Vec<u8>> {
+        self.socket.set_read_timeout(Some(timeout)).unwrap();
+        let mut buffer = vec![0; 65535];
+        match self.socket.recv(&mut buffer) {
+            Ok(count) => {
+                buffer.truncate(count);
+                Some(buffer)
+            }
+            Err(_) => None,
+        }
+    }
+
+    //
+    // Datagrams larger than the mtu are received as fragments, which are
+    // reassembled before parsing.
+    //
+    pub fn receive_udp(&self, timeout: Duration) -> Option<Datagram> {
+        let deadline = Instant::now() + timeout;
+        let mut defragmenter = Defragmenter::default();
+        while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+            let packet = self.receive(remaining)?;
+            let Some(packet) = defragmenter.add(packet) else {
+                continue;
+            };
+            if let Some(datagram) = Datagram::parse(&packet) {
+                return Some(datagram);
+            }
+        }
+        None
+    }
+
+    pub fn receive_icmp(&self, timeout: Duration) -> Option<IcmpMessage> {
+        let deadline = Instant::now() + timeout;
+        while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+            let packet = self.receive(remaining)?;
+            if let Some(message) = IcmpMessage::parse(&packet) {
+                return Some(message);
+            }
+        }
+        None
+    }
+
+    pub fn receive_tcp(&self, timeout: Duration) -> Option<Segment> {
+        let deadline = Instant::now() + timeout;
+        while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+            let packet = self.receive(remaining)?;
+            if let Some(segment) = Segment::parse(&packet) {
+                return Some(segment);
+            }
+        }
+        None
+    }
+}
+
+impl Drop for Harness {
+    fn drop(&mut self) {
+        self.vpn.stop();
+    }
+}
+
+pub fn client(port: u16) -> SocketAddr {
+    SocketAddr::new("10.0.0.2".parse().unwrap(), port)
+}
+
+pub fn payload(length: usize) -> Vec<u8> {
+    (0..length).map(|value| value as u8).collect()
+}
+
+//
+// Collects the records of closed connections; the callback is global, so the
+// records of all tests of a file are collected together.
+//
+pub fn record_connections() {
+    tun_callbacks::set_connection_closed_callback(Some(on_connection_closed));
+}
+
+fn on_connection_closed(record: &ConnectionRecord) {
+    RECORDS.lock().unwrap().push(record.clone());
+}
+
+pub fn wait_for_record(source: SocketAddr) -> ConnectionRecord {
+    let deadline = Instant::now() + TIMEOUT;
+    while Instant::now() < deadline {
+        let records = RECORDS.lock().unwrap();
+        if let Some(record) = records.iter().find(|record| record.source == source) {
+            return record.clone();
+        }
+        drop(records);
+        thread::sleep(Duration::from_millis(10));
+    }
+    panic!("no record for {}", source);
+}
+
+//
+// Waits until the condition holds, e.g. for metrics, which are shared by
+// concurrent tests, or for sessions to end; returns whether it did.
+//
+pub fn wait_until(condition: impl Fn() -> bool) -> bool {
+    let deadline = Instant::now() + TIMEOUT;
+    while Instant::now() < deadline {
+        if condition() {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    false
+}
+
+pub fn echo_udp_server(address: &str) -> SocketAddr {
+    let socket = UdpSocket::bind(address).unwrap();
+    let address = socket.local_addr().unwrap();
+    thread::spawn(move || {
+        let mut buffer = [0; 65535];
+        while let Ok((count, peer)) = socket.recv_from(&mut buffer) {
+            let _ = socket.send_to(&buffer[..count], peer);
+        }
+    });
+    address
+}
+
+pub fn echo_tcp_server(address: &str) -> SocketAddr {
+    let listener = TcpListener::bind(address).unwrap();
+    let address = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            thread::spawn(move || {
+                let mut buffer = [0; 65535];
+                while let Ok(count) = stream.read(&mut buffer) {
+                    if count == 0 || stream.write_all(&buffer[..count]).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+    address
+}
+
+//
+// Replies to every datagram with the port it was sent from, which changes
+// whenever the session is created again.
+//
+pub fn port_udp_server(address: &str) -> SocketAddr {
+    let socket = UdpSocket::bind(address).unwrap();
+    let address = socket.local_addr().unwrap();
+    thread::spawn(move || {
+        let mut buffer = [0; 65535];
+        while let Ok((_, peer)) = socket.recv_from(&mut buffer) {
+            let _ = socket.send_to(&peer.port().to_be_bytes(), peer);
+        }
+    });
+    address
+}
+
+pub fn upstream_port(harness: &Harness, source: SocketAddr, server: SocketAddr) -> u16 {
+    harness.send(&udp_packet(source, server, b"port"));
+    let datagram = harness.receive_udp(TIMEOUT).expect("no reply received");
+    u16::from_be_bytes(datagram.payload[..2].try_into().unwrap())
+}
+
+pub fn udp_packet(source: SocketAddr, destination: SocketAddr, payload: &[u8]) -> Vec<u8> {
+    let udp_repr = UdpRepr {
+        src_port: source.port(),
+        dst_port: destination.port(),
+    };
+    let udp_length = 8 + payload.len();
+    let (ip_repr, mut buffer) = ip_packet(source, destination, IpProtocol::Udp, udp_length);
+    let header_length = ip_repr.header_len();
+    let (source, destination) = (ip_repr.src_addr(), ip_repr.dst_addr());
+    let mut packet = UdpPacket::new_unchecked(&mut buffer[header_length..]);
+    udp_repr.emit(
+        &mut packet,
+        &source,
+        &destination,
+        payload.len(),
+        |buffer| buffer.copy_from_slice(payload),
+        &ChecksumCapabilities::default(),
+    );
+    buffer
+}
+
+pub fn tcp_packet(
+    source: SocketAddr,
+    destination: SocketAddr,
+    control: TcpControl,
+    seq_number: u32,
+    ack_number: Option<u32>,
+    payload: &[u8],
+) -> Vec<u8> {
+    let tcp_repr = TcpRepr {
+        src_port: source.port(),
+        dst_port: destination.port(),
+        control,
+        seq_number: TcpSeqNumber(seq_number as i32),
+        ack_number: ack_number.map(|ack_number| TcpSeqNumber(ack_number as i32)),
+        window_len: 65535,
+        window_scale: None,
+        max_seg_size: if control == TcpControl::Syn {
+            Some(1460)
+        } else {
+            None
+        },
+        sack_permitted: false,
+        sack_ranges: [None, None, None],
+        payload,
+    };
+    let (ip_repr, mut buffer) =
+        ip_packet(source, destination, IpProtocol::Tcp, tcp_repr.buffer_len());
+    let header_length = ip_repr.header_len();
+    let (source, destination) = (ip_repr.src_addr(), ip_repr.dst_addr());
+    let mut packet = TcpPacket::new_unchecked(&mut buffer[header_length..]);
+    tcp_repr.emit(
+        &mut packet,
+        &source,
+        &destination,
+        &ChecksumCapabilities::default(),
+    );
+    buffer
+}
+
+//
+// Splits an ipv4 packet into fragments carrying at most the given number of
+// payload bytes, which is rounded down to a multiple of eight.
+//
+pub fn fragment_ipv4(packet: &[u8], identification: u16, fragment_length: usize) -> Vec<Vec<u8>> {
+    let ip_packet = Ipv4Packet::new_checked(packet).unwrap();
+    let header_length = usize::from(ip_packet.header_len());
+    let payload = ip_packet.payload();
+    let fragment_length = fragment_length / 8 * 8;
+    let count = payload.chunks(fragment_length).count();
+    payload
+        .chunks(fragment_length)
+        .enumerate()
+        .map(|(index, data)| {
+            let mut fragment = packet[..header_length].to_vec();
+            fragment.extend_from_slice(data);
+            let mut fragment_packet = Ipv4Packet::new_unchecked(&mut fragment[..]);
+            fragment_packet.set_total_len((header_length + data.len()) as u16);
+            fragment_packet.set_ident(identification);
+            fragment_packet.set_more_frags(index + 1 < count);
+            fragment_packet.set_frag_offset((index * fragment_length) as u16);
+            fragment_packet.fill_checksum();
+            fragment
+        })
+        .collect()
+}
+
+//
+// Splits an ipv6 packet without extension headers into fragments carrying at
+// most the given number of payload bytes, rounded down to a multiple of eight.
+//
+pub fn fragment_ipv6(packet: &[u8], identification: u32, fragment_length: usize) -> Vec<Vec<u8>> {
+    let ip_packet = Ipv6Packet::new_checked(packet).unwrap();
+    let next_header = u8::from(ip_packet.next_header());
+    let payload = ip_packet.payload();
+    let fragment_length = fragment_length / 8 * 8;
+    let count = payload.chunks(fragment_length).count();
+    payload
+        .chunks(fragment_length)
+        .enumerate()
+        .map(|(index, data)| {
+            let offset_and_flags = (index * fragment_length) as u16 | u16::from(index + 1 < count);
+            let mut fragment = packet[..IPV6_HEADER_LENGTH].to_vec();
+            fragment.extend_from_slice(&[next_header, 0]);
+            fragment.extend_from_slice(&offset_and_flags.to_be_bytes());
+            fragment.extend_from_slice(&identification.to_be_bytes());
+            fragment.extend_from_slice(data);
+            let mut fragment_packet = Ipv6Packet::new_unchecked(&mut fragment[..]);
+            fragment_packet.set_next_header(IpProtocol::Ipv6Frag);
+            fragment_packet.set_payload_len((8 + data.len()) as u16);
+            fragment
+        })
+        .collect()
+}
+
+//
+// Inserts an empty destination options header after the ipv6 header.
+//
+pub fn with_destination_options(packet: &[u8]) -> Vec<u8> {
+    let ip_packet = Ipv6Packet::new_checked(packet).unwrap();
+    let next_header = u8::from(ip_packet.next_header());
+    let mut bytes = packet[..IPV6_HEADER_LENGTH].to_vec();
+    // padn option filling the remainder of the header.
+    bytes.extend_from_slice(&[next_header, 0, 1, 4, 0, 0, 0, 0]);
+    bytes.extend_from_slice(ip_packet.payload());
+    let mut ip_packet = Ipv6Packet::new_unchecked(&mut bytes[..]);
+    ip_packet.set_next_header(IpProtocol::Ipv6Opts);
+    ip_packet.set_payload_len(ip_packet.payload_len() + 8);
+    bytes
+}
+
+fn ip_packet(
+    source: SocketAddr,
+    destination: SocketAddr,
+    protocol: IpProtocol,
+    payload_length: usize,
+) -> (IpRepr, Vec<u8>) {
+    let ip_repr = IpRepr::new(
+        IpAddress::from(source.ip()),
+        IpAddress::from(destination.ip()),
+        protocol,
+        payload_length,
+        64,
+    );
+    let mut buffer = vec![0; ip_repr.buffer_len()];
+    ip_repr.emit(&mut buffer[..], &ChecksumCapabilities::default());
+    (ip_repr, buffer)
+}
+
+fn parse_ip(packet: &[u8]) -> Option<(IpAddress, IpAddress, IpProtocol, Vec<u8>)> {
+    match packet.first()? >> 4 {
+        4 => {
+            let packet = Ipv4Packet::new_checked(packet).ok()?;
+            Some((
+                IpAddress::Ipv4(packet.src_addr()),
+                IpAddress::Ipv4(packet.dst_addr()),
+                packet.next_header(),
+                packet.payload().to_vec(),
+            ))
+        }
+        6 => {
+            let packet = Ipv6Packet::new_checked(packet).ok()?;
+            Some((
+                IpAddress::Ipv6(packet.src_addr()),
+                IpAddress::Ipv6(packet.dst_addr()),
+                packet.next_header(),
+                packet.payload().to_vec(),
+            ))
+        }
+        _ => None,
+    }
+}
+
+fn socket_address(address: IpAddress, port: u16) -> SocketAddr {
+    match address {
+        IpAddress::Ipv4(address) => SocketAddr::from((address.0, port)),
+        IpAddress::Ipv6(address) => SocketAddr::from((address.0, port)),
+    }
+}
+
+#[derive(Debug)]
+//
+// Joins the fragments of one packet at a time; packets which are not fragments
+// are returned as they are.
+//
+#[derive(Default)]
+pub struct Defragmenter {
+    header: Option<Vec<u8>>,
+    data: BTreeMap<usize, Vec<u8>>,
+    length: Option<usize>,
+}
+
+impl Defragmenter {
+    pub fn add(&mut self, packet: Vec<u8>) -> Option<Vec<u8>> {
+        let (header, offset, more_fragments, data) = match packet.first()? >> 4 {
+            4 => {
+                let ip_packet = Ipv4Packet::new_checked(&packet[..]).ok()?;
+                if !ip_packet.more_frags() && ip_packet.frag_offset() == 0 {
+                    return Some(packet);
+                }
+                let header_length = usize::from(ip_packet.header_len());
+                (
+                    packet[..header_length].to_vec(),
+                    usize::from(ip_packet.frag_offset()),
+                    ip_packet.more_frags(),
+                    ip_packet.payload().to_vec(),
+                )
+            }
+            6 => {
+                let ip_packet = Ipv6Packet::new_checked(&packet[..]).ok()?;
+                if ip_packet.next_header() != IpProtocol::Ipv6Frag {
+                    return Some(packet);
+                }
+                let payload = ip_packet.payload();
+                let offset_and_flags = u16::from_be_bytes([payload[2], payload[3]]);
+                let mut header = packet[..40].to_vec();
+                header[6] = payload[0];
+                (
+                    header,
+                    usize::from(offset_and_flags & 0xfff8),
+                    offset_and_flags & 0x1 != 0,
+                    payload[8..].to_vec(),
+                )
+            }
+            _ => return Some(packet),
+        };
+
+        if offset == 0 {
+            self.header = Some(header);
+        }
+        if !more_fragments {
+            self.length = Some(offset + data.len());
+        }
+        self.data.insert(offset, data);
+
+        let length = self.length?;
+        let received: usize = self.data.values().map(Vec::len).sum();
+        if self.header.is_none() || received != length {
+            return None;
+        }
+
+        let mut packet = self.header.take().unwrap();
+        let header_length = packet.len();
+        for data in std::mem::take(&mut self.data).into_values() {
+            packet.extend_from_slice(&data);
+        }
+        self.length = None;
+        if packet[0] >> 4 == 4 {
+            let mut ip_packet = Ipv4Packet::new_unchecked(&mut packet[..]);
+            ip_packet.set_total_len((header_length + length) as u16);
+            ip_packet.set_more_frags(false);
+            ip_packet.set_frag_offset(0);
+            ip_packet.fill_checksum();
+        } else {
+            Ipv6Packet::new_unchecked(&mut packet[..]).set_payload_len(length as u16);
+        }
+        Some(packet)
+    }
+}
+
+pub struct IcmpMessage {
+    pub source: IpAddr,
+    pub destination: IpAddr,
+    pub message_type: u8,
+    pub code: u8,
+    // second half of the header, e.g. the mtu of packet too big errors.
+    pub parameter: u32,
+    // quoted packet which caused the error.
+    pub data: Vec<u8>,
+}
+
+impl IcmpMessage {
+    pub fn parse(packet: &[u8]) -> Option<IcmpMessage> {
+        let (source, destination, protocol, payload) = parse_ip(packet)?;
+        if protocol != IpProtocol::Icmp && protocol != IpProtocol::Icmpv6 {
+            return None;
+        }
+        Some(IcmpMessage {
+            source: socket_address(source, 0).ip(),
+            destination: socket_address(destination, 0).ip(),
+            message_type: *payload.first()?,
+            code: *payload.get(1)?,
+            parameter: u32::from_be_bytes(payload.get(4..8)?.try_into().ok()?),
+            data: payload.get(8..)?.to_vec(),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct Datagram {
+    pub source: SocketAddr,
+    pub destination: SocketAddr,
+    pub payload: Vec<u8>,
+}
+
+impl Datagram {
+    pub fn parse(packet: &[u8]) -> Option<Datagram> {
+        let (source, destination, protocol, payload) = parse_ip(packet)?;
+        if protocol != IpProtocol::Udp {
+            return None;
+        }
+        let udp_packet = UdpPacket::new_checked(&payload[..]).ok()?;
+        Some(Datagram {
+            source: socket_address(source, udp_packet.src_port()),
+            destination: socket_address(destination, udp_packet.dst_port()),
+            payload: udp_packet.payload().to_vec(),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct Segment {
+    pub source: SocketAddr,
+    pub destination: SocketAddr,
+    pub seq_number: u32,
+    pub ack_number: u32,
+    pub syn: bool,
+    pub ack: bool,
+    pub fin: bool,
+    pub rst: bool,
+    pub max_seg_size: Option<u16>,
+    pub payload: Vec<u8>,
+}
+
+impl Segment {
+    pub fn parse(packet: &[u8]) -> Option<Segment> {
+        let (source, destination, protocol, payload) = parse_ip(packet)?;
+        if protocol != IpProtocol::Tcp {
+            return None;
+        }
+        let tcp_packet = TcpPacket::new_checked(&payload[..]).ok()?;
+        let tcp_repr = TcpRepr::parse(
+            &tcp_packet,
+            &source,
+            &destination,
+            &ChecksumCapabilities::default(),
+        )
+        .ok()?;
+        Some(Segment {
+            source: socket_address(source, tcp_packet.src_port()),
+            destination: socket_address(destination, tcp_packet.dst_port()),
+            seq_number: tcp_packet.seq_number().0 as u32,
+            ack_number: tcp_packet.ack_number().0 as u32,
+            syn: tcp_packet.syn(),
+            ack: tcp_packet.ack(),
+            fin: tcp_packet.fin(),
+            rst: tcp_packet.rst(),
+            max_seg_size: tcp_repr.max_seg_size,
+            payload: tcp_packet.payload().to_vec(),
+        })
+    }
+}
+
+//
+// Minimal client side of a tcp connection driven through the harness.
+//
+pub struct TcpClient<'a> {
+    harness: &'a Harness,
+    source: SocketAddr,
+    destination: SocketAddr,
+    seq_number: u32,
+    ack_number: u32,
+    // mss option of the syn-ack.
+    pub max_seg_size: Option<u16>,
+}
+
+impl<'a> TcpClient<'a> {
+    pub fn connect(
+        harness: &'a Harness,
+        source: SocketAddr,
+        destination: SocketAddr,
+    ) -> TcpClient<'a> {
+        let initial_seq_number = 1000;
+        harness.send(&tcp_packet(
+            source,
+            destination,
+            TcpControl::Syn,
+            initial_seq_number,
+            None,
+            &[],
+        ));
+
+        let syn_ack = harness.receive_tcp(TIMEOUT).expect("no syn-ack received");
+        assert!(
+            syn_ack.syn && syn_ack.ack,
+            "unexpected segment {:?}",
+            syn_ack
+        );
+        assert_eq!(syn_ack.source, destination);
+        assert_eq!(syn_ack.destination, source);
+        assert_eq!(syn_ack.ack_number, initial_seq_number + 1);
+
+        let client = TcpClient {
+            harness,
+            source,
+            destination,
+            seq_number: initial_seq_number + 1,
+            ack_number: syn_ack.seq_number.wrapping_add(1),
+            max_seg_size: syn_ack.max_seg_size,
+        };
+        client.send_control(TcpControl::None, &[]);
+        client
+    }
+
+    //
+    // Continues a connection established earlier, e.g. over another harness
+    // socket, from the position returned by position.
+    //
+    pub fn resume(
+        harness: &'a Harness,
+        source: SocketAddr,
+        destination: SocketAddr,
+        seq_number: u32,
+        ack_number: u32,
+    ) -> TcpClient<'a> {
+        TcpClient {
+            harness,
+            source,
+            destination,
+            seq_number,
+            ack_number,
+            max_seg_size: None,
+        }
+    }
+
+    pub fn position(&self) -> (u32, u32) {
+        (self.seq_number, self.ack_number)
+    }
+
+    pub fn send(&mut self, payload: &[u8]) {
+        self.send_control(TcpControl::Psh, payload);
+        self.seq_number = self.seq_number.wrapping_add(payload.len() as u32);
+    }
+
+    pub fn receive(&mut self, length: usize) -> Vec<u8> {
+        self.receive_segments(length)
+            .into_iter()
+            .flat_map(|segment| segment.payload)
+            .collect()
+    }
+
+    //
+    // Receives segments up to the fin of the server and acknowledges it.
+    //
+    pub fn receive_fin(&mut self) {
+        loop {
+            let segment = self.harness.receive_tcp(TIMEOUT).expect("no fin received");
+            assert!(!segment.rst, "connection reset");
+            if segment.fin {
+                self.ack_number = segment
+                    .seq_number
+                    .wrapping_add(segment.payload.len() as u32 + 1);
+                self.send_control(TcpControl::None, &[]);
+                return;
+            }
+        }
+    }
+
+    pub fn close(&mut self) {
+        self.send_control(TcpControl::Fin, &[]);
+        self.seq_number = self.seq_number.wrapping_add(1);
+    }
+
+    pub fn receive_segments(&mut self, length: usize) -> Vec<Segment> {
+        let mut received = 0;
+        let mut segments = Vec::new();
+        while received < length {
+            let segment = self.harness.receive_tcp(TIMEOUT).expect("no data received");
+            assert!(!segment.rst, "connection reset");
+            if segment.payload.is_empty() || segment.seq_number != self.ack_number {
+                continue;
+            }
+            self.ack_number = self.ack_number.wrapping_add(segment.payload.len() as u32);
+            received += segment.payload.len();
+            segments.push(segment);
+            self.send_control(TcpControl::None, &[]);
+        }
+        segments
+    }
+
+    fn send_control(&self, control: TcpControl, payload: &[u8]) {
+        self.harness.send(&tcp_packet(
+            self.source,
+            self.destination,
+            control,
+            self.seq_number,
+            Some(self.ack_number),
+            payload,
+        ));
+    }
+}
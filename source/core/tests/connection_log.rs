@@ -0,0 +1,138 @@
+// This is free and unencumbered software released into the public domain.
+//
+// Anyone is free to copy, modify, publish, use, compile, sell, or
+// distribute this software, either in source code form or as a compiled
+// binary, for any purpose, commercial or non-commercial, and by any
+// means.
+//
+// In jurisdictions that recognize copyright laws, the author or authors
+// of this software dedicate any and all copyright interest in the
+// software to the public domain. We make this dedication for the benefit
+// of the public at large and to the detriment of our heirs and
+// successors. We intend this dedication to be an overt act of
+// relinquishment in perpetuity of all present and future rights to this
+// software under copyright law.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS BE LIABLE FOR ANY CLAIM, DAMAGES OR
+// OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE,
+// ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR
+// OTHER DEALINGS IN THE SOFTWARE.
+//
+// For more information, please refer to <https://unlicense.org>
+
+mod common;
+
+use common::{client, record_connections, wait_for_record, Harness, TcpClient};
+use core::config::{AccessConfig, AccessRule, Action, Config, Destination, Protocol};
+use core::connection_log::CloseReason;
+use std::{
+    io::{Read, Write},
+    net::{SocketAddr, TcpListener},
+    thread,
+};
+
+//
+// Answers the first read with the reply and closes the connection.
+//
+fn reply_tcp_server(reply: &'static [u8]) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            let mut buffer = [0; 65535];
+            if stream.read(&mut buffer).unwrap_or(0) > 0 {
+                let _ = stream.write_all(reply);
+            }
+        }
+    });
+    address
+}
+
+fn client_hello(server_name: &str) -> Vec<u8> {
+    let name = server_name.as_bytes();
+    let mut extension = vec![0, 0];
+    extension.extend_from_slice(&(name.len() as u16 + 5).to_be_bytes());
+    extension.extend_from_slice(&(name.len() as u16 + 3).to_be_bytes());
+    extension.push(0);
+    extension.extend_from_slice(&(name.len() as u16).to_be_bytes());
+    extension.extend_from_slice(name);
+
+    let mut body = vec![3, 3];
+    body.extend_from_slice(&[0; 32]);
+    // no session id, one cipher suite and the null compression method.
+    body.extend_from_slice(&[0, 0, 2, 0x13, 0x01, 1, 0]);
+    body.extend_from_slice(&(extension.len() as u16).to_be_bytes());
+    body.extend_from_slice(&extension);
+
+    let mut handshake = vec![1, 0];
+    handshake.extend_from_slice(&(body.len() as u16).to_be_bytes());
+    handshake.extend_from_slice(&body);
+
+    let mut record = vec![0x16, 3, 1];
+    record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+    record.extend_from_slice(&handshake);
+    record
+}
+
+#[test]
+fn closed_tcp_session_is_logged_with_server_name() {
+    record_connections();
+    let harness = Harness::start();
+    let server = reply_tcp_server(b"reply");
+    let hello = client_hello("Example.com");
+
+    let mut connection = TcpClient::connect(&harness, client(54000), server);
+    connection.send(&hello);
+    assert_eq!(connection.receive(5), b"reply");
+
+    let record = wait_for_record(client(54000));
+    assert_eq!(record.destination, server);
+    assert_eq!(record.upstream, server);
+    assert_eq!(record.protocol, Protocol::Tcp);
+    assert_eq!(record.hostname.as_deref(), Some("example.com"));
+    assert_eq!(record.close_reason, CloseReason::ServerFin);
+    assert_eq!(record.bytes_to_server, hello.len() as u64);
+    assert_eq!(record.bytes_to_client, 5);
+    assert!(record.packets_from_client >= 3);
+    assert!(record.packets_to_client >= 2);
+    assert!(record.end_time >= record.start_time);
+    assert_eq!(
+        record.outbound.map(|outbound| outbound.ip()),
+        Some(server.ip())
+    );
+}
+
+#[test]
+fn denied_session_is_logged_with_http_host() {
+    record_connections();
+    let harness = Harness::start();
+    let server = common::echo_tcp_server("127.0.0.1:0");
+    let request = b"GET / HTTP/1.1\r\nAccept: */*\r\nhost: example.org:8080\r\n\r\n";
+
+    let mut connection = TcpClient::connect(&harness, client(54001), server);
+    connection.send(request);
+    connection.receive(request.len());
+    harness.vpn().reload(Config {
+        access: AccessConfig {
+            default: Action::Allow,
+            rules: vec![AccessRule {
+                destination: Destination {
+                    ports: Some(server.port()..=server.port()),
+                    ..Destination::default()
+                },
+                action: Action::Deny,
+                ..AccessRule::default()
+            }],
+        },
+        ..Config::default()
+    });
+
+    let record = wait_for_record(client(54001));
+    assert_eq!(record.hostname.as_deref(), Some("example.org"));
+    assert_eq!(record.close_reason, CloseReason::Denied);
+    assert_eq!(record.bytes_to_server, request.len() as u64);
+    assert_eq!(record.bytes_to_client, request.len() as u64);
+}
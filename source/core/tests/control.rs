@@ -0,0 +1,107 @@
+// This is free and unencumbered software released into the public domain.
+//
+// Anyone is free to copy, modify, publish, use, compile, sell, or
+// distribute this software, either in source code form or as a compiled
+// binary, for any purpose, commercial or non-commercial, and by any
+// means.
+//
+// In jurisdictions that recognize copyright laws, the author or authors
+// of this software dedicate any and all copyright interest in the
+// software to the public domain. We make this dedication for the benefit
+// of the public at large and to the detriment of our heirs and
+// successors. We intend this dedication to be an overt act of
+// relinquishment in perpetuity of all present and future rights to this
+// software under copyright law.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS BE LIABLE FOR ANY CLAIM, DAMAGES OR
+// OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE,
+// ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR
+// OTHER DEALINGS IN THE SOFTWARE.
+//
+// For more information, please refer to <https://unlicense.org>
+
+mod common;
+
+use common::{client, echo_tcp_server, port_udp_server, upstream_port, Harness, TcpClient};
+use core::config::Protocol;
+use std::{fs, net::SocketAddr, process};
+
+const PCAP_HEADER_LENGTH: usize = 24;
+const PCAP_RECORD_HEADER_LENGTH: usize = 16;
+
+#[test]
+fn sessions_are_listed() {
+    let harness = Harness::start();
+    let udp_server = port_udp_server("127.0.0.1:0");
+    let tcp_server = echo_tcp_server("127.0.0.1:0");
+
+    upstream_port(&harness, client(52000), udp_server);
+    let _connection = TcpClient::connect(&harness, client(52001), tcp_server);
+
+    let mut sessions: Vec<(SocketAddr, SocketAddr, Protocol)> = harness
+        .vpn()
+        .sessions()
+        .into_iter()
+        .map(|session| (session.source, session.destination, session.protocol))
+        .collect();
+    sessions.sort_by_key(|(source, _, _)| source.port());
+
+    assert_eq!(
+        sessions,
+        vec![
+            (client(52000), udp_server, Protocol::Udp),
+            (client(52001), tcp_server, Protocol::Tcp),
+        ]
+    );
+}
+
+#[test]
+fn statistics_count_sessions_and_packets() {
+    let harness = Harness::start();
+    let server = port_udp_server("127.0.0.1:0");
+
+    upstream_port(&harness, client(52002), server);
+    upstream_port(&harness, client(52002), server);
+
+    let statistics = harness.vpn().statistics();
+    assert_eq!((statistics.tcp_sessions, statistics.udp_sessions), (0, 1));
+    assert_eq!(
+        (statistics.packets_received, statistics.packets_sent),
+        (2, 2)
+    );
+    assert!(statistics.bytes_received > statistics.bytes_sent);
+    assert_eq!(statistics.tun_errors, 0);
+}
+
+#[test]
+fn capture_writes_pcap() {
+    let harness = Harness::start();
+    let server = port_udp_server("127.0.0.1:0");
+    let path = std::env::temp_dir().join(format!("capture-{}.pcap", process::id()));
+
+    harness
+        .vpn()
+        .start_capture(fs::File::create(&path).unwrap());
+    upstream_port(&harness, client(52003), server);
+    harness.vpn().stop_capture();
+    // the capture has been stopped once the statistics are answered.
+    harness.vpn().statistics();
+
+    let capture = fs::read(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+
+    assert_eq!(capture[..4], 0xa1b2c3d4u32.to_ne_bytes());
+    assert_eq!(capture[20..24], 101u32.to_ne_bytes());
+    let mut offset = PCAP_HEADER_LENGTH;
+    let mut records = 0;
+    while offset < capture.len() {
+        let length = u32::from_ne_bytes(capture[offset + 8..offset + 12].try_into().unwrap());
+        offset += PCAP_RECORD_HEADER_LENGTH + length as usize;
+        records += 1;
+    }
+    assert_eq!(offset, capture.len());
+    assert_eq!(records, 2);
+}
@@ -0,0 +1,91 @@
+// This is free and unencumbered software released into the public domain.
+//
+// Anyone is free to copy, modify, publish, use, compile, sell, or
+// distribute this software, either in source code form or as a compiled
+// binary, for any purpose, commercial or non-commercial, and by any
+// means.
+//
+// In jurisdictions that recognize copyright laws, the author or authors
+// of this software dedicate any and all copyright interest in the
+// software to the public domain. We make this dedication for the benefit
+// of the public at large and to the detriment of our heirs and
+// successors. We intend this dedication to be an overt act of
+// relinquishment in perpetuity of all present and future rights to this
+// software under copyright law.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS BE LIABLE FOR ANY CLAIM, DAMAGES OR
+// OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE,
+// ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR
+// OTHER DEALINGS IN THE SOFTWARE.
+//
+// For more information, please refer to <https://unlicense.org>
+
+mod common;
+
+use common::{client, echo_tcp_server, port_udp_server, upstream_port, Harness, TcpClient};
+use core::config::Config;
+use core::{Error, Vpn};
+use std::os::unix::net::UnixDatagram;
+
+#[test]
+fn tcp_connection_survives_handover() {
+    let mut harness = Harness::start();
+    let server = echo_tcp_server("127.0.0.1:0");
+
+    let (seq_number, ack_number) = {
+        let mut connection = TcpClient::connect(&harness, client(50000), server);
+        connection.send(b"before");
+        assert_eq!(connection.receive(6), b"before");
+        connection.position()
+    };
+
+    harness.replace_socket();
+
+    let mut connection = TcpClient::resume(&harness, client(50000), server, seq_number, ack_number);
+    connection.send(b"after");
+    assert_eq!(connection.receive(5), b"after");
+}
+
+#[test]
+fn udp_session_survives_handover() {
+    let mut harness = Harness::start();
+    let server = port_udp_server("127.0.0.1:0");
+
+    let port = upstream_port(&harness, client(50001), server);
+
+    harness.replace_socket();
+
+    assert_eq!(upstream_port(&harness, client(50001), server), port);
+}
+
+#[test]
+fn second_start_fails() {
+    let (_socket, vpn_socket) = UnixDatagram::pair().unwrap();
+    let mut vpn = Vpn::new_datagram(vpn_socket).unwrap();
+    vpn.start(Config::default()).unwrap();
+
+    assert!(matches!(
+        vpn.start(Config::default()),
+        Err(Error::AlreadyStarted)
+    ));
+    assert!(vpn.is_running());
+    vpn.stop();
+}
+
+#[test]
+fn handover_to_stopped_vpn_fails() {
+    let (_socket, vpn_socket) = UnixDatagram::pair().unwrap();
+    let mut vpn = Vpn::new_datagram(vpn_socket).unwrap();
+    vpn.start(Config::default()).unwrap();
+    vpn.stop();
+
+    assert!(!vpn.is_running());
+    let (_socket, vpn_socket) = UnixDatagram::pair().unwrap();
+    assert!(matches!(
+        vpn.replace_datagram(vpn_socket),
+        Err(Error::Stopped)
+    ));
+}
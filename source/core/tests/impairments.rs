@@ -0,0 +1,157 @@
+// This is free and unencumbered software released into the public domain.
+//
+// Anyone is free to copy, modify, publish, use, compile, sell, or
+// distribute this software, either in source code form or as a compiled
+// binary, for any purpose, commercial or non-commercial, and by any
+// means.
+//
+// In jurisdictions that recognize copyright laws, the author or authors
+// of this software dedicate any and all copyright interest in the
+// software to the public domain. We make this dedication for the benefit
+// of the public at large and to the detriment of our heirs and
+// successors. We intend this dedication to be an overt act of
+// relinquishment in perpetuity of all present and future rights to this
+// software under copyright law.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS BE LIABLE FOR ANY CLAIM, DAMAGES OR
+// OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE,
+// ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR
+// OTHER DEALINGS IN THE SOFTWARE.
+//
+// For more information, please refer to <https://unlicense.org>
+
+mod common;
+
+use common::{client, echo_tcp_server, echo_udp_server, udp_packet, Harness, TcpClient, TIMEOUT};
+use core::config::{Config, Delay, Impairments, RateLimit};
+use std::{
+    net::SocketAddr,
+    ops::Range,
+    time::{Duration, Instant},
+};
+
+//
+// Sends a datagram with each value and returns the values echoed.
+//
+fn echoed_values(
+    harness: &Harness,
+    source: SocketAddr,
+    server: SocketAddr,
+    values: Range<u8>,
+) -> Vec<u8> {
+    for value in values {
+        harness.send(&udp_packet(source, server, &[value]));
+    }
+    let mut echoed = Vec::new();
+    while let Some(datagram) = harness.receive_udp(Duration::from_millis(300)) {
+        echoed.extend(datagram.payload);
+    }
+    echoed.sort();
+    echoed
+}
+
+#[test]
+fn lost_upload_packets_never_reach_server() {
+    let mut config = Config::default();
+    config.emulation.upload.loss = 1.0;
+    let harness = Harness::start_with_config(config);
+    let server = echo_udp_server("127.0.0.1:0");
+
+    harness.send(&udp_packet(client(42000), server, b"lost"));
+
+    assert!(harness.receive_udp(Duration::from_millis(300)).is_none());
+}
+
+#[test]
+fn duplicated_upload_packets_are_echoed_twice() {
+    let mut config = Config::default();
+    config.emulation.upload.duplicate = 1.0;
+    let harness = Harness::start_with_config(config);
+    let server = echo_udp_server("127.0.0.1:0");
+
+    harness.send(&udp_packet(client(42001), server, b"twice"));
+
+    for _ in 0..2 {
+        let datagram = harness.receive_udp(TIMEOUT).expect("no reply received");
+        assert_eq!(datagram.payload, b"twice");
+    }
+}
+
+#[test]
+fn download_latency_delays_replies() {
+    let latency = Duration::from_millis(200);
+    let mut config = Config::default();
+    config.emulation.download = Impairments {
+        delay: Delay {
+            latency,
+            ..Delay::default()
+        },
+        ..Impairments::default()
+    };
+    let harness = Harness::start_with_config(config);
+    let server = echo_udp_server("127.0.0.1:0");
+
+    let start = Instant::now();
+    harness.send(&udp_packet(client(42002), server, b"late"));
+
+    let datagram = harness.receive_udp(TIMEOUT).expect("no reply received");
+    assert_eq!(datagram.payload, b"late");
+    assert!(start.elapsed() >= latency);
+}
+
+#[test]
+fn session_download_rate_limits_throughput() {
+    let mut config = Config::default();
+    config.shaping.session.download = Some(RateLimit::new(20_000, 1500));
+    let harness = Harness::start_with_config(config);
+    let server = echo_tcp_server("127.0.0.1:0");
+    let payload = vec![7u8; 10_000];
+
+    let start = Instant::now();
+    let mut connection = TcpClient::connect(&harness, client(42003), server);
+    for chunk in payload.chunks(1000) {
+        connection.send(chunk);
+    }
+
+    assert_eq!(connection.receive(payload.len()), payload);
+    // 1500 bytes of burst plus 20000 bytes per second cannot deliver 10000 bytes sooner.
+    assert!(start.elapsed() >= Duration::from_millis(400));
+}
+
+#[test]
+fn reload_with_unchanged_seed_continues_random_sequence() {
+    let mut config = Config::default();
+    config.emulation.seed = 7;
+    config.emulation.upload.loss = 0.5;
+    let server = echo_udp_server("127.0.0.1:0");
+
+    let harness = Harness::start_with_config(config.clone());
+    let expected = echoed_values(&harness, client(42010), server, 0..16);
+    drop(harness);
+
+    let harness = Harness::start_with_config(config.clone());
+    let mut echoed = echoed_values(&harness, client(42011), server, 0..8);
+    harness.configure(config);
+    echoed.extend(echoed_values(&harness, client(42011), server, 8..16));
+
+    assert_eq!(echoed, expected);
+}
+
+#[test]
+fn excessive_latency_does_not_stop_processor() {
+    let mut config = Config::default();
+    config.emulation.download.delay.latency = Duration::MAX;
+    let harness = Harness::start_with_config(config);
+    let server = echo_udp_server("127.0.0.1:0");
+
+    harness.send(&udp_packet(client(42012), server, b"late"));
+    assert!(harness.receive_udp(Duration::from_millis(300)).is_none());
+
+    harness.configure(Config::default());
+    harness.send(&udp_packet(client(42013), server, b"early"));
+    let datagram = harness.receive_udp(TIMEOUT).expect("no reply received");
+    assert_eq!(datagram.payload, b"early");
+}
@@ -0,0 +1,108 @@
+// This is free and unencumbered software released into the public domain.
+//
+// Anyone is free to copy, modify, publish, use, compile, sell, or
+// distribute this software, either in source code form or as a compiled
+// binary, for any purpose, commercial or non-commercial, and by any
+// means.
+//
+// In jurisdictions that recognize copyright laws, the author or authors
+// of this software dedicate any and all copyright interest in the
+// software to the public domain. We make this dedication for the benefit
+// of the public at large and to the detriment of our heirs and
+// successors. We intend this dedication to be an overt act of
+// relinquishment in perpetuity of all present and future rights to this
+// software under copyright law.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS BE LIABLE FOR ANY CLAIM, DAMAGES OR
+// OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE,
+// ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR
+// OTHER DEALINGS IN THE SOFTWARE.
+//
+// For more information, please refer to <https://unlicense.org>
+
+mod common;
+
+use common::{
+    echo_tcp_server, echo_udp_server, fragment_ipv6, tcp_packet, udp_packet,
+    with_destination_options, Harness, TcpClient, TIMEOUT,
+};
+use smoltcp::wire::TcpControl;
+use std::net::SocketAddr;
+
+fn client(port: u16) -> SocketAddr {
+    SocketAddr::new("fd00:a::2".parse().unwrap(), port)
+}
+
+#[test]
+fn udp_datagram_is_echoed_back_to_client() {
+    let harness = Harness::start();
+    let server = echo_udp_server("[::1]:0");
+
+    harness.send(&udp_packet(client(44000), server, b"hello udp"));
+
+    let datagram = harness.receive_udp(TIMEOUT).expect("no reply received");
+    assert_eq!(datagram.source, server);
+    assert_eq!(datagram.destination, client(44000));
+    assert_eq!(datagram.payload, b"hello udp");
+}
+
+#[test]
+fn tcp_stream_is_echoed_back_to_client() {
+    let harness = Harness::start();
+    let server = echo_tcp_server("[::1]:0");
+    let payload: Vec<u8> = (0..4000u32).map(|value| value as u8).collect();
+
+    let mut connection = TcpClient::connect(&harness, client(44001), server);
+    for chunk in payload.chunks(1000) {
+        connection.send(chunk);
+    }
+
+    assert_eq!(connection.receive(payload.len()), payload);
+}
+
+#[test]
+fn extension_headers_are_skipped() {
+    let harness = Harness::start();
+    let server = echo_udp_server("[::1]:0");
+
+    let packet = udp_packet(client(44002), server, b"options");
+    harness.send(&with_destination_options(&packet));
+
+    let datagram = harness.receive_udp(TIMEOUT).expect("no reply received");
+    assert_eq!(datagram.destination, client(44002));
+    assert_eq!(datagram.payload, b"options");
+}
+
+#[test]
+fn fragmented_udp_datagram_is_reassembled() {
+    let harness = Harness::start();
+    let server = echo_udp_server("[::1]:0");
+    let payload: Vec<u8> = (0..3000u32).map(|value| value as u8).collect();
+
+    let fragments = fragment_ipv6(&udp_packet(client(44003), server, &payload), 1, 1000);
+    for fragment in fragments.iter().rev() {
+        harness.send(fragment);
+    }
+
+    let datagram = harness.receive_udp(TIMEOUT).expect("no reply received");
+    assert_eq!(datagram.payload, payload);
+}
+
+#[test]
+fn unreachable_destination_is_reported_with_icmpv6() {
+    let harness = Harness::start();
+    // no session can be created for port zero.
+    let destination: SocketAddr = "[::1]:0".parse().unwrap();
+
+    let packet = tcp_packet(client(44004), destination, TcpControl::Syn, 1, None, &[]);
+    harness.send(&packet);
+
+    let message = harness.receive_icmp(TIMEOUT).expect("no icmp received");
+    assert_eq!(message.source, destination.ip());
+    assert_eq!(message.destination, client(44004).ip());
+    assert_eq!((message.message_type, message.code), (1, 3));
+    assert_eq!(message.data, packet);
+}
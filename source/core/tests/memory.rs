@@ -0,0 +1,157 @@
+// This is free and unencumbered software released into the public domain.
+//
+// Anyone is free to copy, modify, publish, use, compile, sell, or
+// distribute this software, either in source code form or as a compiled
+// binary, for any purpose, commercial or non-commercial, and by any
+// means.
+//
+// In jurisdictions that recognize copyright laws, the author or authors
+// of this software dedicate any and all copyright interest in the
+// software to the public domain. We make this dedication for the benefit
+// of the public at large and to the detriment of our heirs and
+// successors. We intend this dedication to be an overt act of
+// relinquishment in perpetuity of all present and future rights to this
+// software under copyright law.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS BE LIABLE FOR ANY CLAIM, DAMAGES OR
+// OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE,
+// ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR
+// OTHER DEALINGS IN THE SOFTWARE.
+//
+// For more information, please refer to <https://unlicense.org>
+
+mod common;
+
+use common::{
+    client, echo_tcp_server, port_udp_server, tcp_packet, upstream_port, Harness, TcpClient,
+    TIMEOUT,
+};
+use core::config::{Action, Config, MemoryConfig};
+use smoltcp::wire::TcpControl;
+use std::{io::Write, net::TcpListener, thread, time::Duration};
+
+const UDP_SESSION_MEMORY: usize = 2 * 64 * 1024;
+
+fn memory_config(memory: MemoryConfig) -> Config {
+    Config {
+        memory,
+        ..Config::default()
+    }
+}
+
+#[test]
+fn large_transfer_completes_with_small_buffers() {
+    let harness = Harness::start_with_config(memory_config(MemoryConfig {
+        tcp_buffer_size: 4 * 1024,
+        max_queued: 8 * 1024,
+        ..MemoryConfig::default()
+    }));
+    let payload: Vec<u8> = (0..1024 * 1024u32).map(|value| value as u8).collect();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let server = listener.local_addr().unwrap();
+    let data = payload.clone();
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        stream.write_all(&data).unwrap();
+        thread::sleep(TIMEOUT);
+    });
+
+    let mut connection = TcpClient::connect(&harness, client(47000), server);
+
+    assert_eq!(connection.receive(payload.len()), payload);
+}
+
+#[test]
+fn session_over_memory_budget_is_rejected() {
+    let tcp_buffer_size = 16 * 1024;
+    let harness = Harness::start_with_config(memory_config(MemoryConfig {
+        tcp_buffer_size,
+        max_memory: 3 * tcp_buffer_size,
+        ..MemoryConfig::default()
+    }));
+    let server = echo_tcp_server("127.0.0.1:0");
+
+    let mut connection = TcpClient::connect(&harness, client(47001), server);
+    connection.send(b"first");
+    assert_eq!(connection.receive(5), b"first");
+
+    let syn = tcp_packet(client(47002), server, TcpControl::Syn, 1, None, &[]);
+    harness.send(&syn);
+
+    let message = harness.receive_icmp(TIMEOUT).expect("no icmp received");
+    assert_eq!((message.message_type, message.code), (3, 1));
+}
+
+#[test]
+fn idle_udp_session_is_closed_for_new_session() {
+    let harness = Harness::start_with_config(memory_config(MemoryConfig {
+        udp_buffer_datagrams: 1,
+        max_memory: UDP_SESSION_MEMORY + 1024,
+        ..MemoryConfig::default()
+    }));
+    let server = port_udp_server("127.0.0.1:0");
+
+    let first_port = upstream_port(&harness, client(47003), server);
+    upstream_port(&harness, client(47004), server);
+
+    assert_ne!(upstream_port(&harness, client(47003), server), first_port);
+}
+
+#[test]
+fn trim_memory_closes_idle_udp_sessions() {
+    let harness = Harness::start_with_config(memory_config(MemoryConfig {
+        trim_idle_time: Duration::ZERO,
+        ..MemoryConfig::default()
+    }));
+    let server = port_udp_server("127.0.0.1:0");
+
+    let first_port = upstream_port(&harness, client(47005), server);
+    assert_eq!(upstream_port(&harness, client(47005), server), first_port);
+
+    harness.vpn().trim_memory();
+
+    assert_ne!(upstream_port(&harness, client(47005), server), first_port);
+}
+
+#[test]
+fn sockets_of_closed_sessions_are_reused_until_trimmed() {
+    let tcp_buffer_size = 16 * 1024;
+    let mut config = memory_config(MemoryConfig {
+        tcp_buffer_size,
+        ..MemoryConfig::default()
+    });
+    let harness = Harness::start_with_config(config.clone());
+    let server = echo_tcp_server("127.0.0.1:0");
+
+    let close_sessions = |harness: &Harness, config: &mut Config| {
+        config.access.default = Action::Deny;
+        harness.vpn().reload(config.clone());
+        let segment = harness.receive_tcp(TIMEOUT).expect("no reset received");
+        assert!(segment.rst);
+        config.access.default = Action::Allow;
+        harness.vpn().reload(config.clone());
+    };
+
+    let mut connection = TcpClient::connect(&harness, client(47006), server);
+    connection.send(b"first");
+    assert_eq!(connection.receive(5), b"first");
+    close_sessions(&harness, &mut config);
+    let statistics = harness.vpn().statistics();
+    assert_eq!(statistics.tcp_sessions, 0);
+    assert_eq!(statistics.memory, 2 * tcp_buffer_size);
+
+    // the next session takes over the socket of the closed one.
+    let mut connection = TcpClient::connect(&harness, client(47007), server);
+    connection.send(b"second");
+    assert_eq!(connection.receive(6), b"second");
+    assert_eq!(harness.vpn().statistics().memory, 2 * tcp_buffer_size);
+    close_sessions(&harness, &mut config);
+
+    harness.vpn().trim_memory();
+
+    assert_eq!(harness.vpn().statistics().memory, 0);
+}
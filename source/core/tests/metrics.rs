@@ -0,0 +1,104 @@
+// This is free and unencumbered software released into the public domain.
+//
+// Anyone is free to copy, modify, publish, use, compile, sell, or
+// distribute this software, either in source code form or as a compiled
+// binary, for any purpose, commercial or non-commercial, and by any
+// means.
+//
+// In jurisdictions that recognize copyright laws, the author or authors
+// of this software dedicate any and all copyright interest in the
+// software to the public domain. We make this dedication for the benefit
+// of the public at large and to the detriment of our heirs and
+// successors. We intend this dedication to be an overt act of
+// relinquishment in perpetuity of all present and future rights to this
+// software under copyright law.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS BE LIABLE FOR ANY CLAIM, DAMAGES OR
+// OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE,
+// ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR
+// OTHER DEALINGS IN THE SOFTWARE.
+//
+// For more information, please refer to <https://unlicense.org>
+
+mod common;
+
+use common::{
+    client, echo_tcp_server, echo_udp_server, tcp_packet, udp_packet, wait_until, Harness,
+    TcpClient, TIMEOUT,
+};
+use core::metrics::METRICS;
+use smoltcp::wire::TcpControl;
+use std::net::TcpListener;
+
+#[test]
+fn sessions_and_bytes_are_counted() {
+    let harness = Harness::start();
+    let server = echo_tcp_server("127.0.0.1:0");
+    let created = METRICS.tcp_sessions_created.get();
+    let to_server = METRICS.bytes_to_server.get();
+    let to_client = METRICS.bytes_to_client.get();
+
+    let mut connection = TcpClient::connect(&harness, client(53000), server);
+    connection.send(b"metrics");
+    assert_eq!(connection.receive(7), b"metrics");
+
+    assert!(METRICS.tcp_sessions_created.get() > created);
+    assert!(METRICS.tcp_sessions.get() > 0);
+    assert!(METRICS.bytes_to_server.get() >= to_server + 7);
+    assert!(METRICS.bytes_to_client.get() >= to_client + 7);
+    assert!(METRICS.loop_latency.count() > 0);
+}
+
+#[test]
+fn connect_failures_are_counted() {
+    let harness = Harness::start();
+    let closed = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let failures = METRICS.connect_failures.get();
+    let destroyed = METRICS.tcp_sessions_destroyed.get();
+
+    harness.send(&tcp_packet(
+        client(53001),
+        closed,
+        TcpControl::Syn,
+        1,
+        None,
+        &[],
+    ));
+
+    assert!(wait_until(|| METRICS.connect_failures.get() > failures));
+    assert!(wait_until(
+        || METRICS.tcp_sessions_destroyed.get() > destroyed
+    ));
+}
+
+#[test]
+fn sessions_left_when_stopping_are_counted_as_destroyed() {
+    let harness = Harness::start();
+    let server = echo_udp_server("127.0.0.1:0");
+
+    harness.send(&udp_packet(client(53002), server, b"metrics"));
+    assert!(harness.receive_udp(TIMEOUT).is_some());
+    let destroyed = METRICS.udp_sessions_destroyed.get();
+
+    drop(harness);
+
+    assert!(METRICS.udp_sessions_destroyed.get() > destroyed);
+}
+
+#[test]
+fn metrics_are_rendered() {
+    let text = METRICS.render();
+
+    assert!(text.contains("# TYPE vpn_sessions gauge\n"));
+    assert!(text.contains("vpn_sessions{protocol=\"udp\"} "));
+    assert!(text.contains("# TYPE vpn_bytes_total counter\n"));
+    assert!(text.contains("vpn_connect_failures_total "));
+    assert!(text.contains("# TYPE vpn_loop_duration_seconds histogram\n"));
+    assert!(text.contains("vpn_loop_duration_seconds_bucket{le=\"+Inf\"} "));
+}
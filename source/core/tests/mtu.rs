@@ -0,0 +1,178 @@
+// This is free and unencumbered software released into the public domain.
+//
+// Anyone is free to copy, modify, publish, use, compile, sell, or
+// distribute this software, either in source code form or as a compiled
+// binary, for any purpose, commercial or non-commercial, and by any
+// means.
+//
+// In jurisdictions that recognize copyright laws, the author or authors
+// of this software dedicate any and all copyright interest in the
+// software to the public domain. We make this dedication for the benefit
+// of the public at large and to the detriment of our heirs and
+// successors. We intend this dedication to be an overt act of
+// relinquishment in perpetuity of all present and future rights to this
+// software under copyright law.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS BE LIABLE FOR ANY CLAIM, DAMAGES OR
+// OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE,
+// ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR
+// OTHER DEALINGS IN THE SOFTWARE.
+//
+// For more information, please refer to <https://unlicense.org>
+
+mod common;
+
+use common::{
+    client, echo_tcp_server, echo_udp_server, fragment_ipv4, fragment_ipv6, payload, udp_packet,
+    Datagram, Defragmenter, Harness, TcpClient, TIMEOUT,
+};
+use core::config::{Config, TunConfig};
+use std::net::SocketAddr;
+
+fn client_ipv6(port: u16) -> SocketAddr {
+    SocketAddr::new("fd00::2".parse().unwrap(), port)
+}
+
+fn tun_config(mtu: usize, max_segment_size: Option<u16>) -> Config {
+    Config {
+        tun: TunConfig {
+            mtu,
+            max_segment_size,
+        },
+        ..Config::default()
+    }
+}
+
+#[test]
+fn syn_ack_mss_fits_mtu() {
+    let harness = Harness::start_with_config(tun_config(1280, None));
+    let server = echo_tcp_server("127.0.0.1:0");
+
+    let connection = TcpClient::connect(&harness, client(46000), server);
+
+    assert_eq!(connection.max_seg_size, Some(1280 - 40));
+}
+
+#[test]
+fn mss_is_clamped_in_both_directions() {
+    let harness = Harness::start_with_config(tun_config(1500, Some(1000)));
+    let server = echo_tcp_server("127.0.0.1:0");
+    let payload = payload(3000);
+
+    let mut connection = TcpClient::connect(&harness, client(46001), server);
+    assert_eq!(connection.max_seg_size, Some(1000));
+    for chunk in payload.chunks(1000) {
+        connection.send(chunk);
+    }
+
+    // the client announced an mss of 1460 in its syn.
+    let segments = connection.receive_segments(payload.len());
+    assert!(segments.iter().all(|segment| segment.payload.len() <= 1000));
+    let received: Vec<u8> = segments.into_iter().flat_map(|s| s.payload).collect();
+    assert_eq!(received, payload);
+}
+
+#[test]
+fn udp_datagram_larger_than_mtu_is_fragmented() {
+    let harness = Harness::start();
+    let server = echo_udp_server("127.0.0.1:0");
+    let payload = payload(3000);
+
+    for fragment in fragment_ipv4(&udp_packet(client(46002), server, &payload), 1, 1000) {
+        harness.send(&fragment);
+    }
+
+    assert_eq!(receive_fragmented_datagram(&harness, 1500), payload);
+}
+
+#[test]
+fn ipv6_udp_datagram_larger_than_mtu_is_fragmented() {
+    let harness = Harness::start();
+    let server = echo_udp_server("[::1]:0");
+    let payload = payload(3000);
+
+    for fragment in fragment_ipv6(&udp_packet(client_ipv6(46003), server, &payload), 1, 1000) {
+        harness.send(&fragment);
+    }
+
+    assert_eq!(receive_fragmented_datagram(&harness, 1500), payload);
+}
+
+#[test]
+fn oversized_packet_is_rejected_with_fragmentation_needed() {
+    let harness = Harness::start();
+    let server = echo_udp_server("127.0.0.1:0");
+
+    // packets are sent with the don't fragment flag set.
+    let packet = udp_packet(client(46004), server, &payload(2000));
+    harness.send(&packet);
+
+    let message = harness.receive_icmp(TIMEOUT).expect("no icmp received");
+    assert_eq!(message.source, server.ip());
+    assert_eq!(message.destination, client(46004).ip());
+    assert_eq!((message.message_type, message.code), (3, 4));
+    assert_eq!(message.parameter, 1500);
+    assert_eq!(message.data, packet[..message.data.len()]);
+}
+
+#[test]
+fn oversized_ipv6_packet_is_rejected_with_packet_too_big() {
+    let harness = Harness::start_with_config(tun_config(1400, None));
+    let server = echo_udp_server("[::1]:0");
+
+    let packet = udp_packet(client_ipv6(46005), server, &payload(1400));
+    harness.send(&packet);
+
+    let message = harness.receive_icmp(TIMEOUT).expect("no icmp received");
+    assert_eq!(message.source, server.ip());
+    assert_eq!((message.message_type, message.code), (2, 0));
+    assert_eq!(message.parameter, 1400);
+    assert_eq!(message.data, packet[..message.data.len()]);
+}
+
+#[test]
+fn ipv4_mtu_may_be_below_the_ipv6_minimum() {
+    let harness = Harness::start_with_config(tun_config(576, None));
+    let server = echo_udp_server("127.0.0.1:0");
+
+    let packet = udp_packet(client(46006), server, &payload(1000));
+    harness.send(&packet);
+
+    let message = harness.receive_icmp(TIMEOUT).expect("no icmp received");
+    assert_eq!((message.message_type, message.code), (3, 4));
+    assert_eq!(message.parameter, 576);
+}
+
+#[test]
+fn ipv6_mtu_is_raised_to_the_minimum() {
+    let harness = Harness::start_with_config(tun_config(576, None));
+    let server = echo_udp_server("[::1]:0");
+
+    let packet = udp_packet(client_ipv6(46007), server, &payload(1300));
+    harness.send(&packet);
+
+    let message = harness.receive_icmp(TIMEOUT).expect("no icmp received");
+    assert_eq!((message.message_type, message.code), (2, 0));
+    assert_eq!(message.parameter, 1280);
+}
+
+fn receive_fragmented_datagram(harness: &Harness, mtu: usize) -> Vec<u8> {
+    let mut defragmenter = Defragmenter::default();
+    let mut fragments = 0;
+    loop {
+        let packet = harness.receive(TIMEOUT).expect("no fragment received");
+        assert!(
+            packet.len() <= mtu,
+            "packet larger than mtu, len={}",
+            packet.len()
+        );
+        fragments += 1;
+        if let Some(packet) = defragmenter.add(packet) {
+            assert!(fragments > 1);
+            return Datagram::parse(&packet).expect("no datagram").payload;
+        }
+    }
+}
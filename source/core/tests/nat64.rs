@@ -0,0 +1,247 @@
+// This is free and unencumbered software released into the public domain.
+//
+// Anyone is free to copy, modify, publish, use, compile, sell, or
+// distribute this software, either in source code form or as a compiled
+// binary, for any purpose, commercial or non-commercial, and by any
+// means.
+//
+// In jurisdictions that recognize copyright laws, the author or authors
+// of this software dedicate any and all copyright interest in the
+// software to the public domain. We make this dedication for the benefit
+// of the public at large and to the detriment of our heirs and
+// successors. We intend this dedication to be an overt act of
+// relinquishment in perpetuity of all present and future rights to this
+// software under copyright law.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS BE LIABLE FOR ANY CLAIM, DAMAGES OR
+// OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE,
+// ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR
+// OTHER DEALINGS IN THE SOFTWARE.
+//
+// For more information, please refer to <https://unlicense.org>
+
+mod common;
+
+use common::{client, echo_tcp_server, echo_udp_server, udp_packet, Harness, TcpClient, TIMEOUT};
+use core::config::{Config, Nat64Config, Network};
+use std::{
+    net::{Ipv6Addr, SocketAddr, UdpSocket},
+    sync::OnceLock,
+    thread,
+};
+
+const DNS_ADDRESS: &str = "[::1]:53";
+
+fn nat64_config(prefix: &str, dns64: bool) -> Config {
+    nat64_config_with_prefix(prefix.parse().unwrap(), dns64)
+}
+
+fn nat64_config_with_prefix(prefix: Network, dns64: bool) -> Config {
+    Config {
+        nat64: Some(Nat64Config::new(prefix, dns64)),
+        ..Config::default()
+    }
+}
+
+//
+// With the prefix ::/96, the ipv4 address 0.0.0.1 is translated to ::1 so the
+// echo servers on the ipv6 loopback are only reachable through translation.
+//
+fn translated_destination(server: SocketAddr) -> SocketAddr {
+    SocketAddr::new("0.0.0.1".parse().unwrap(), server.port())
+}
+
+#[test]
+fn ipv4_udp_session_connects_over_ipv6() {
+    let harness = Harness::start_with_config(nat64_config("::/96", false));
+    let server = echo_udp_server("[::1]:0");
+    let destination = translated_destination(server);
+
+    harness.send(&udp_packet(client(45000), destination, b"translated"));
+
+    let datagram = harness.receive_udp(TIMEOUT).expect("no reply received");
+    assert_eq!(datagram.source, destination);
+    assert_eq!(datagram.destination, client(45000));
+    assert_eq!(datagram.payload, b"translated");
+}
+
+#[test]
+fn ipv4_tcp_session_connects_over_ipv6() {
+    let harness = Harness::start_with_config(nat64_config("::/96", false));
+    let server = echo_tcp_server("[::1]:0");
+
+    let mut connection =
+        TcpClient::connect(&harness, client(45001), translated_destination(server));
+    connection.send(b"translated");
+
+    assert_eq!(connection.receive(10), b"translated");
+}
+
+#[test]
+fn well_known_prefix_is_not_used_for_non_global_addresses() {
+    let harness = Harness::start_with_config(nat64_config_with_prefix(
+        Nat64Config::WELL_KNOWN_PREFIX,
+        false,
+    ));
+    let server = echo_udp_server("127.0.0.1:0");
+
+    harness.send(&udp_packet(client(45002), server, b"direct"));
+
+    let datagram = harness.receive_udp(TIMEOUT).expect("no reply received");
+    assert_eq!(datagram.payload, b"direct");
+}
+
+#[test]
+fn dns64_synthesizes_aaaa_answers_from_a_records() {
+    let Some(dns_server) = fake_dns_server() else {
+        return;
+    };
+    let harness = Harness::start_with_config(nat64_config("64:ff9b::/96", true));
+    let client = SocketAddr::new("fd00:a::2".parse().unwrap(), 45003);
+
+    harness.send(&udp_packet(
+        client,
+        dns_server,
+        &query(0x1234, "v4only.example", 28),
+    ));
+
+    let datagram = harness.receive_udp(TIMEOUT).expect("no answer received");
+    let answers = aaaa_answers(&datagram.payload, 0x1234);
+    assert_eq!(
+        answers,
+        vec!["64:ff9b::c000:201".parse::<Ipv6Addr>().unwrap()]
+    );
+}
+
+#[test]
+fn dns64_keeps_existing_aaaa_answers() {
+    let Some(dns_server) = fake_dns_server() else {
+        return;
+    };
+    let harness = Harness::start_with_config(nat64_config("64:ff9b::/96", true));
+    let client = SocketAddr::new("fd00:a::2".parse().unwrap(), 45004);
+
+    harness.send(&udp_packet(
+        client,
+        dns_server,
+        &query(0x4321, "dual.example", 28),
+    ));
+
+    let datagram = harness.receive_udp(TIMEOUT).expect("no answer received");
+    let answers = aaaa_answers(&datagram.payload, 0x4321);
+    assert_eq!(answers, vec!["2001:db8::1".parse::<Ipv6Addr>().unwrap()]);
+}
+
+//
+// Answers A queries with 192.0.2.1 for any name, and AAAA queries with
+// 2001:db8::1 for dual.example only. Returns None where the dns port cannot
+// be bound, in which case the test is skipped. The server is shared by all
+// tests since there is only one dns port.
+//
+fn fake_dns_server() -> Option<SocketAddr> {
+    static SERVER: OnceLock<Option<SocketAddr>> = OnceLock::new();
+    *SERVER.get_or_init(start_fake_dns_server)
+}
+
+fn start_fake_dns_server() -> Option<SocketAddr> {
+    let socket = match UdpSocket::bind(DNS_ADDRESS) {
+        Ok(socket) => socket,
+        Err(error) => {
+            eprintln!(
+                "skipping dns64 test, failed to bind dns port, error={:?}",
+                error
+            );
+            return None;
+        }
+    };
+    let address = socket.local_addr().unwrap();
+    thread::spawn(move || {
+        let mut buffer = [0; 1500];
+        while let Ok((count, peer)) = socket.recv_from(&mut buffer) {
+            let query = &buffer[..count];
+            let name_end = 12 + query[12..].iter().position(|byte| *byte == 0).unwrap() + 1;
+            let record_type = u16::from_be_bytes([query[name_end], query[name_end + 1]]);
+            let is_dual = query[12..name_end].starts_with(b"\x04dual");
+
+            let data: Option<&[u8]> = match record_type {
+                1 => Some(&[192, 0, 2, 1]),
+                28 if is_dual => {
+                    Some(&[0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1])
+                }
+                _ => None,
+            };
+            let mut response = query[..2].to_vec();
+            response.extend_from_slice(&[
+                0x81,
+                0x80,
+                0,
+                1,
+                0,
+                u8::from(data.is_some()),
+                0,
+                0,
+                0,
+                0,
+            ]);
+            response.extend_from_slice(&query[12..name_end + 4]);
+            if let Some(data) = data {
+                // name compressed as a pointer to the question.
+                response.extend_from_slice(&[0xc0, 0x0c]);
+                response.extend_from_slice(&record_type.to_be_bytes());
+                response.extend_from_slice(&[0, 1, 0, 0, 1, 44]);
+                response.extend_from_slice(&(data.len() as u16).to_be_bytes());
+                response.extend_from_slice(data);
+            }
+            let _ = socket.send_to(&response, peer);
+        }
+    });
+    Some(address)
+}
+
+fn query(id: u16, name: &str, record_type: u16) -> Vec<u8> {
+    let mut query = id.to_be_bytes().to_vec();
+    query.extend_from_slice(&[1, 0, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.split('.') {
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&record_type.to_be_bytes());
+    query.extend_from_slice(&[0, 1]);
+    query
+}
+
+fn aaaa_answers(response: &[u8], id: u16) -> Vec<Ipv6Addr> {
+    assert_eq!(response[..2], id.to_be_bytes());
+    let answer_count = u16::from_be_bytes([response[6], response[7]]);
+    let question_end = 12 + response[12..].iter().position(|byte| *byte == 0).unwrap() + 5;
+    assert_eq!(response[question_end - 4..question_end - 2], [0, 28]);
+
+    let mut offset = question_end;
+    let mut answers = Vec::new();
+    for _ in 0..answer_count {
+        offset += if response[offset] & 0xc0 == 0xc0 {
+            2
+        } else {
+            response[offset..]
+                .iter()
+                .position(|byte| *byte == 0)
+                .unwrap()
+                + 1
+        };
+        let record_type = u16::from_be_bytes([response[offset], response[offset + 1]]);
+        let length = usize::from(u16::from_be_bytes([
+            response[offset + 8],
+            response[offset + 9],
+        ]));
+        let data = &response[offset + 10..offset + 10 + length];
+        if record_type == 28 {
+            answers.push(Ipv6Addr::from(<[u8; 16]>::try_from(data).unwrap()));
+        }
+        offset += 10 + length;
+    }
+    answers
+}
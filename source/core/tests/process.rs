@@ -0,0 +1,189 @@
+// This is free and unencumbered software released into the public domain.
+//
+// Anyone is free to copy, modify, publish, use, compile, sell, or
+// distribute this software, either in source code form or as a compiled
+// binary, for any purpose, commercial or non-commercial, and by any
+// means.
+//
+// In jurisdictions that recognize copyright laws, the author or authors
+// of this software dedicate any and all copyright interest in the
+// software to the public domain. We make this dedication for the benefit
+// of the public at large and to the detriment of our heirs and
+// successors. We intend this dedication to be an overt act of
+// relinquishment in perpetuity of all present and future rights to this
+// software under copyright law.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS BE LIABLE FOR ANY CLAIM, DAMAGES OR
+// OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE,
+// ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR
+// OTHER DEALINGS IN THE SOFTWARE.
+//
+// For more information, please refer to <https://unlicense.org>
+
+mod common;
+
+use common::{
+    client, echo_tcp_server, record_connections, wait_for_record, Harness, TcpClient, TIMEOUT,
+};
+use core::config::{
+    AccessConfig, AccessRule, Action, Config, Destination, ProcessMatcher, Protocol,
+};
+use core::connection_log::CloseReason;
+use core::process::{ProcessInfo, ProcessLookup};
+use core::tun_callbacks;
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+static LOOKUPS: Mutex<Vec<(Protocol, SocketAddr, SocketAddr)>> = Mutex::new(Vec::new());
+
+const ALLOWED_PORT: u16 = 52000;
+const DENIED_PORT: u16 = 52001;
+
+//
+// Attributes sessions to processes by the port of their client.
+//
+struct FakeLookup;
+
+impl ProcessLookup for FakeLookup {
+    fn lookup(
+        &self,
+        protocol: Protocol,
+        local: SocketAddr,
+        remote: SocketAddr,
+    ) -> Option<ProcessInfo> {
+        LOOKUPS.lock().unwrap().push((protocol, local, remote));
+        let name = match local.port() {
+            ALLOWED_PORT => "browser",
+            DENIED_PORT => "blocked",
+            _ => return None,
+        };
+        Some(ProcessInfo {
+            pid: Some(u32::from(local.port())),
+            uid: Some(1000),
+            name: Some(name.to_string()),
+        })
+    }
+}
+
+// lookups, records and the callbacks are global to the tests.
+fn set_callbacks() {
+    record_connections();
+    tun_callbacks::set_process_lookup(Some(Arc::new(FakeLookup)));
+}
+
+fn deny_process_config(name: &str) -> Config {
+    Config {
+        access: AccessConfig {
+            default: Action::Allow,
+            rules: vec![AccessRule {
+                destination: Destination::default(),
+                process: Some(ProcessMatcher {
+                    name: Some(name.to_string()),
+                    ..ProcessMatcher::default()
+                }),
+                action: Action::Deny,
+            }],
+        },
+        ..Config::default()
+    }
+}
+
+#[test]
+fn session_is_attributed_to_its_process() {
+    set_callbacks();
+    let harness = Harness::start_with_config(deny_process_config("blocked"));
+    let server = echo_tcp_server("127.0.0.1:0");
+
+    let expected = ProcessInfo {
+        pid: Some(u32::from(ALLOWED_PORT)),
+        uid: Some(1000),
+        name: Some("browser".to_string()),
+    };
+    {
+        let mut connection = TcpClient::connect(&harness, client(ALLOWED_PORT), server);
+        connection.send(b"echo");
+        assert_eq!(connection.receive(4), b"echo");
+
+        let deadline = Instant::now() + TIMEOUT;
+        loop {
+            let sessions = harness.vpn().sessions();
+            let session = sessions
+                .iter()
+                .find(|session| session.source == client(ALLOWED_PORT))
+                .expect("no session");
+            if session.process.is_some() {
+                assert_eq!(session.process.as_ref(), Some(&expected));
+                break;
+            }
+            assert!(Instant::now() < deadline, "process not looked up");
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(LOOKUPS
+            .lock()
+            .unwrap()
+            .contains(&(Protocol::Tcp, client(ALLOWED_PORT), server)));
+    }
+
+    // sessions left are logged once the vpn is stopped.
+    drop(harness);
+    let record = wait_for_record(client(ALLOWED_PORT));
+    assert_eq!(record.process, Some(expected));
+}
+
+#[test]
+fn session_of_denied_process_is_reset() {
+    set_callbacks();
+    let harness = Harness::start_with_config(deny_process_config("blocked"));
+    let server = echo_tcp_server("127.0.0.1:0");
+
+    // data is held until its process is known.
+    let mut connection = TcpClient::connect(&harness, client(DENIED_PORT), server);
+    connection.send(b"echo");
+
+    let deadline = Instant::now() + TIMEOUT;
+    loop {
+        let segment = harness.receive_tcp(TIMEOUT).expect("no reset received");
+        assert!(segment.payload.is_empty(), "data passed");
+        if segment.rst {
+            break;
+        }
+        assert!(Instant::now() < deadline, "no reset received");
+    }
+    let record = wait_for_record(client(DENIED_PORT));
+    assert_eq!(record.close_reason, CloseReason::Denied);
+    assert_eq!(
+        record.process.and_then(|process| process.name).as_deref(),
+        Some("blocked")
+    );
+    assert_eq!(record.bytes_to_server, 0);
+}
+
+#[test]
+fn unknown_process_is_decided_by_the_rules_it_could_match() {
+    let destination = "127.0.0.1:443".parse().unwrap();
+    let mut access = deny_process_config("blocked").access;
+    assert!(access.allows_process(&destination, Protocol::Tcp, None));
+    assert!(access.may_deny_process(&destination, Protocol::Tcp));
+
+    // no process could be allowed.
+    access.default = Action::Deny;
+    assert!(!access.allows_process(&destination, Protocol::Tcp, None));
+
+    // a rule which does not match processes decides before it is known.
+    access.rules.insert(
+        0,
+        AccessRule {
+            action: Action::Allow,
+            ..AccessRule::default()
+        },
+    );
+    assert!(access.allows_process(&destination, Protocol::Tcp, None));
+    assert!(!access.may_deny_process(&destination, Protocol::Tcp));
+}
@@ -0,0 +1,123 @@
+// This is free and unencumbered software released into the public domain.
+//
+// Anyone is free to copy, modify, publish, use, compile, sell, or
+// distribute this software, either in source code form or as a compiled
+// binary, for any purpose, commercial or non-commercial, and by any
+// means.
+//
+// In jurisdictions that recognize copyright laws, the author or authors
+// of this software dedicate any and all copyright interest in the
+// software to the public domain. We make this dedication for the benefit
+// of the public at large and to the detriment of our heirs and
+// successors. We intend this dedication to be an overt act of
+// relinquishment in perpetuity of all present and future rights to this
+// software under copyright law.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS BE LIABLE FOR ANY CLAIM, DAMAGES OR
+// OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE,
+// ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR
+// OTHER DEALINGS IN THE SOFTWARE.
+//
+// For more information, please refer to <https://unlicense.org>
+
+mod common;
+
+use common::{
+    client, echo_tcp_server, echo_udp_server, tcp_packet, udp_packet, Harness, TcpClient, TIMEOUT,
+};
+use smoltcp::wire::TcpControl;
+use std::net::SocketAddr;
+
+#[test]
+fn udp_datagram_is_echoed_back_to_client() {
+    let harness = Harness::start();
+    let server = echo_udp_server("127.0.0.1:0");
+
+    harness.send(&udp_packet(client(40000), server, b"hello udp"));
+
+    let datagram = harness.receive_udp(TIMEOUT).expect("no reply received");
+    assert_eq!(datagram.source, server);
+    assert_eq!(datagram.destination, client(40000));
+    assert_eq!(datagram.payload, b"hello udp");
+}
+
+#[test]
+fn udp_sessions_are_kept_apart() {
+    let harness = Harness::start();
+    let server = echo_udp_server("127.0.0.1:0");
+
+    harness.send(&udp_packet(client(40001), server, b"first"));
+    let first = harness.receive_udp(TIMEOUT).expect("no reply received");
+
+    harness.send(&udp_packet(client(40002), server, b"second"));
+    let second = harness.receive_udp(TIMEOUT).expect("no reply received");
+
+    assert_eq!(first.destination, client(40001));
+    assert_eq!(first.payload, b"first");
+    assert_eq!(second.destination, client(40002));
+    assert_eq!(second.payload, b"second");
+}
+
+#[test]
+fn tcp_stream_is_echoed_back_to_client() {
+    let harness = Harness::start();
+    let server = echo_tcp_server("127.0.0.1:0");
+
+    let mut connection = TcpClient::connect(&harness, client(41000), server);
+    connection.send(b"hello tcp");
+
+    assert_eq!(connection.receive(9), b"hello tcp");
+}
+
+#[test]
+fn tcp_stream_larger_than_a_segment_is_echoed_back_to_client() {
+    let harness = Harness::start();
+    let server = echo_tcp_server("127.0.0.1:0");
+    let payload: Vec<u8> = (0..4000u32).map(|value| value as u8).collect();
+
+    let mut connection = TcpClient::connect(&harness, client(41001), server);
+    for chunk in payload.chunks(1000) {
+        connection.send(chunk);
+    }
+
+    assert_eq!(connection.receive(payload.len()), payload);
+}
+
+#[test]
+fn malformed_packets_do_not_stop_processor() {
+    let harness = Harness::start();
+    let server = echo_udp_server("127.0.0.1:0");
+    let packet = udp_packet(client(40003), server, b"after");
+
+    // truncated transport headers, unknown versions and a zero length destination port.
+    harness.send(&packet[..22]);
+    harness.send(&[0x70, 0, 0, 0]);
+    harness.send(&udp_packet(
+        client(40003),
+        "127.0.0.1:0".parse().unwrap(),
+        b"",
+    ));
+    harness.send(&packet);
+
+    let datagram = harness.receive_udp(TIMEOUT).expect("no reply received");
+    assert_eq!(datagram.payload, b"after");
+}
+
+#[test]
+fn unreachable_destination_is_reported_with_icmp() {
+    let harness = Harness::start();
+    // no session can be created for port zero.
+    let destination: SocketAddr = "127.0.0.1:0".parse().unwrap();
+
+    let packet = tcp_packet(client(40004), destination, TcpControl::Syn, 1, None, &[]);
+    harness.send(&packet);
+
+    let message = harness.receive_icmp(TIMEOUT).expect("no icmp received");
+    assert_eq!(message.source, destination.ip());
+    assert_eq!(message.destination, client(40004).ip());
+    assert_eq!((message.message_type, message.code), (3, 1));
+    assert_eq!(message.data, packet);
+}
@@ -0,0 +1,115 @@
+// This is free and unencumbered software released into the public domain.
+//
+// Anyone is free to copy, modify, publish, use, compile, sell, or
+// distribute this software, either in source code form or as a compiled
+// binary, for any purpose, commercial or non-commercial, and by any
+// means.
+//
+// In jurisdictions that recognize copyright laws, the author or authors
+// of this software dedicate any and all copyright interest in the
+// software to the public domain. We make this dedication for the benefit
+// of the public at large and to the detriment of our heirs and
+// successors. We intend this dedication to be an overt act of
+// relinquishment in perpetuity of all present and future rights to this
+// software under copyright law.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS BE LIABLE FOR ANY CLAIM, DAMAGES OR
+// OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE,
+// ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR
+// OTHER DEALINGS IN THE SOFTWARE.
+//
+// For more information, please refer to <https://unlicense.org>
+
+mod common;
+
+use common::{client, echo_udp_server, fragment_ipv4, payload, udp_packet, Harness, TIMEOUT};
+use core::config::Config;
+use std::{thread, time::Duration};
+
+#[test]
+fn fragmented_udp_datagram_is_reassembled() {
+    let harness = Harness::start();
+    let server = echo_udp_server("127.0.0.1:0");
+    let payload = payload(3000);
+
+    let fragments = fragment_ipv4(&udp_packet(client(43000), server, &payload), 1, 1000);
+    assert_eq!(fragments.len(), 4);
+    for fragment in fragments.iter().rev() {
+        harness.send(fragment);
+    }
+
+    let datagram = harness.receive_udp(TIMEOUT).expect("no reply received");
+    assert_eq!(datagram.destination, client(43000));
+    assert_eq!(datagram.payload, payload);
+}
+
+#[test]
+fn incomplete_datagram_is_not_forwarded() {
+    let harness = Harness::start();
+    let server = echo_udp_server("127.0.0.1:0");
+
+    let fragments = fragment_ipv4(&udp_packet(client(43001), server, &payload(3000)), 2, 1000);
+    for fragment in &fragments[..3] {
+        harness.send(fragment);
+    }
+
+    assert!(harness.receive_udp(Duration::from_millis(300)).is_none());
+}
+
+#[test]
+fn overlapping_fragments_drop_datagram() {
+    let harness = Harness::start();
+    let server = echo_udp_server("127.0.0.1:0");
+    let packet = udp_packet(client(43002), server, &payload(3000));
+
+    let fragments = fragment_ipv4(&packet, 3, 1000);
+    let overlapping = fragment_ipv4(&packet, 3, 800);
+    harness.send(&fragments[0]);
+    harness.send(&overlapping[1]);
+    for fragment in &fragments[1..] {
+        harness.send(fragment);
+    }
+
+    assert!(harness.receive_udp(Duration::from_millis(300)).is_none());
+}
+
+#[test]
+fn expired_fragments_are_dropped() {
+    let mut config = Config::default();
+    config.reassembly.timeout = Duration::from_millis(100);
+    let harness = Harness::start_with_config(config);
+    let server = echo_udp_server("127.0.0.1:0");
+
+    let fragments = fragment_ipv4(&udp_packet(client(43003), server, &payload(3000)), 4, 1000);
+    harness.send(&fragments[0]);
+    thread::sleep(Duration::from_millis(300));
+    for fragment in &fragments[1..] {
+        harness.send(fragment);
+    }
+
+    assert!(harness.receive_udp(Duration::from_millis(300)).is_none());
+}
+
+#[test]
+fn fragments_over_memory_limit_are_dropped() {
+    let mut config = Config::default();
+    config.reassembly.max_memory = 2000;
+    let harness = Harness::start_with_config(config);
+    let server = echo_udp_server("127.0.0.1:0");
+
+    let fragments = fragment_ipv4(&udp_packet(client(43004), server, &payload(3000)), 5, 1000);
+    for fragment in &fragments {
+        harness.send(fragment);
+    }
+    assert!(harness.receive_udp(Duration::from_millis(300)).is_none());
+
+    let fragments = fragment_ipv4(&udp_packet(client(43004), server, &payload(1500)), 6, 1000);
+    for fragment in &fragments {
+        harness.send(fragment);
+    }
+    let datagram = harness.receive_udp(TIMEOUT).expect("no reply received");
+    assert_eq!(datagram.payload, payload(1500));
+}
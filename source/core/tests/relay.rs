@@ -0,0 +1,114 @@
+// This is free and unencumbered software released into the public domain.
+//
+// Anyone is free to copy, modify, publish, use, compile, sell, or
+// distribute this software, either in source code form or as a compiled
+// binary, for any purpose, commercial or non-commercial, and by any
+// means.
+//
+// In jurisdictions that recognize copyright laws, the author or authors
+// of this software dedicate any and all copyright interest in the
+// software to the public domain. We make this dedication for the benefit
+// of the public at large and to the detriment of our heirs and
+// successors. We intend this dedication to be an overt act of
+// relinquishment in perpetuity of all present and future rights to this
+// software under copyright law.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS BE LIABLE FOR ANY CLAIM, DAMAGES OR
+// OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE,
+// ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR
+// OTHER DEALINGS IN THE SOFTWARE.
+//
+// For more information, please refer to <https://unlicense.org>
+
+mod common;
+
+use common::{client, echo_udp_server, udp_packet, Datagram, TIMEOUT};
+use core::{config::Config, Vpn};
+use std::{
+    io::{Read, Write},
+    net::{TcpListener, TcpStream},
+    thread,
+    time::Duration,
+};
+
+//
+// Runs a vpn over one end of a tcp connection, as the host does for packets
+// relayed from a device; the other end frames packets the way the relay does.
+//
+struct RelayHarness {
+    vpn: Vpn,
+    stream: TcpStream,
+}
+
+impl RelayHarness {
+    fn start() -> RelayHarness {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (vpn_stream, _) = listener.accept().unwrap();
+        stream.set_nodelay(true).unwrap();
+        stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+        let mut vpn = Vpn::new_relay(vpn_stream).unwrap();
+        vpn.start(Config::default()).unwrap();
+        RelayHarness { vpn, stream }
+    }
+
+    fn receive_udp(&mut self) -> Datagram {
+        let mut length = [0; 2];
+        self.stream.read_exact(&mut length).unwrap();
+        let mut packet = vec![0; usize::from(u16::from_be_bytes(length))];
+        self.stream.read_exact(&mut packet).unwrap();
+        Datagram::parse(&packet).expect("no datagram received")
+    }
+}
+
+impl Drop for RelayHarness {
+    fn drop(&mut self) {
+        self.vpn.stop();
+    }
+}
+
+fn frame(packet: &[u8]) -> Vec<u8> {
+    let mut frame = (packet.len() as u16).to_be_bytes().to_vec();
+    frame.extend_from_slice(packet);
+    frame
+}
+
+#[test]
+fn frames_split_across_reads_are_decoded() {
+    let mut harness = RelayHarness::start();
+    let server = echo_udp_server("127.0.0.1:0");
+
+    let mut bytes = frame(&udp_packet(client(62000), server, b"first"));
+    bytes.extend(frame(&udp_packet(client(62001), server, b"second")));
+    for chunk in bytes.chunks(3) {
+        harness.stream.write_all(chunk).unwrap();
+        thread::sleep(Duration::from_millis(1));
+    }
+
+    let mut replies = [harness.receive_udp(), harness.receive_udp()];
+    replies.sort_by_key(|datagram| datagram.destination.port());
+    assert_eq!(replies[0].destination, client(62000));
+    assert_eq!(replies[0].payload, b"first");
+    assert_eq!(replies[1].destination, client(62001));
+    assert_eq!(replies[1].payload, b"second");
+}
+
+#[test]
+fn frames_in_a_single_read_are_decoded_and_empty_frames_skipped() {
+    let mut harness = RelayHarness::start();
+    let server = echo_udp_server("127.0.0.1:0");
+
+    let mut bytes = frame(&[]);
+    bytes.extend(frame(&udp_packet(client(62002), server, b"first")));
+    bytes.extend(frame(&[]));
+    bytes.extend(frame(&udp_packet(client(62003), server, b"second")));
+    harness.stream.write_all(&bytes).unwrap();
+
+    let mut replies = [harness.receive_udp(), harness.receive_udp()];
+    replies.sort_by_key(|datagram| datagram.destination.port());
+    assert_eq!(replies[0].payload, b"first");
+    assert_eq!(replies[1].payload, b"second");
+}
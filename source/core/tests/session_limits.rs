@@ -0,0 +1,148 @@
+// This is free and unencumbered software released into the public domain.
+//
+// Anyone is free to copy, modify, publish, use, compile, sell, or
+// distribute this software, either in source code form or as a compiled
+// binary, for any purpose, commercial or non-commercial, and by any
+// means.
+//
+// In jurisdictions that recognize copyright laws, the author or authors
+// of this software dedicate any and all copyright interest in the
+// software to the public domain. We make this dedication for the benefit
+// of the public at large and to the detriment of our heirs and
+// successors. We intend this dedication to be an overt act of
+// relinquishment in perpetuity of all present and future rights to this
+// software under copyright law.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS BE LIABLE FOR ANY CLAIM, DAMAGES OR
+// OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE,
+// ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR
+// OTHER DEALINGS IN THE SOFTWARE.
+//
+// For more information, please refer to <https://unlicense.org>
+
+mod common;
+
+use common::{
+    client, echo_tcp_server, port_udp_server, udp_packet, upstream_port, Harness, TcpClient,
+    TIMEOUT,
+};
+use core::config::{Config, MemoryConfig, SessionLimits, SessionRate};
+use std::{net::SocketAddr, time::Duration};
+
+const NO_REPLY_TIMEOUT: Duration = Duration::from_millis(500);
+
+fn limits_config(sessions: SessionLimits) -> Config {
+    Config {
+        sessions,
+        ..Config::default()
+    }
+}
+
+fn assert_no_reply(harness: &Harness, source: SocketAddr, server: SocketAddr) {
+    harness.send(&udp_packet(source, server, b"port"));
+    assert!(harness.receive_udp(NO_REPLY_TIMEOUT).is_none());
+}
+
+#[test]
+fn least_recently_active_udp_session_is_closed() {
+    let harness = Harness::start_with_config(limits_config(SessionLimits {
+        max_udp_sessions: Some(2),
+        ..SessionLimits::default()
+    }));
+    let server = port_udp_server("127.0.0.1:0");
+
+    let first_port = upstream_port(&harness, client(48000), server);
+    let second_port = upstream_port(&harness, client(48001), server);
+    upstream_port(&harness, client(48002), server);
+
+    assert_eq!(upstream_port(&harness, client(48001), server), second_port);
+    assert_ne!(upstream_port(&harness, client(48000), server), first_port);
+}
+
+#[test]
+fn closed_tcp_session_is_reset() {
+    let harness = Harness::start_with_config(limits_config(SessionLimits {
+        max_sessions: Some(1),
+        ..SessionLimits::default()
+    }));
+    let tcp_server = echo_tcp_server("127.0.0.1:0");
+    let udp_server = port_udp_server("127.0.0.1:0");
+
+    let mut connection = TcpClient::connect(&harness, client(48003), tcp_server);
+    connection.send(b"ping");
+    assert_eq!(connection.receive(4), b"ping");
+
+    harness.send(&udp_packet(client(48004), udp_server, b"port"));
+
+    let segment = harness.receive_tcp(TIMEOUT).expect("no reset received");
+    assert!(segment.rst);
+    assert_eq!(segment.destination, client(48003));
+    assert!(harness.receive_udp(TIMEOUT).is_some());
+}
+
+#[test]
+fn sessions_are_kept_when_memory_budget_rejects_new_session() {
+    let harness = Harness::start_with_config(Config {
+        sessions: SessionLimits {
+            max_sessions: Some(1),
+            ..SessionLimits::default()
+        },
+        memory: MemoryConfig {
+            tcp_buffer_size: 16 * 1024,
+            udp_buffer_datagrams: 1,
+            max_memory: 64 * 1024,
+            ..MemoryConfig::default()
+        },
+        ..Config::default()
+    });
+    let tcp_server = echo_tcp_server("127.0.0.1:0");
+    let udp_server = port_udp_server("127.0.0.1:0");
+
+    let mut connection = TcpClient::connect(&harness, client(48011), tcp_server);
+    connection.send(b"ping");
+    assert_eq!(connection.receive(4), b"ping");
+
+    // the buffers of the udp session alone exceed the budget.
+    assert_no_reply(&harness, client(48012), udp_server);
+
+    connection.send(b"pong");
+    assert_eq!(connection.receive(4), b"pong");
+}
+
+#[test]
+fn new_sessions_to_destination_are_rate_limited() {
+    let harness = Harness::start_with_config(limits_config(SessionLimits {
+        destination_rate: Some(SessionRate::new(0, 2)),
+        ..SessionLimits::default()
+    }));
+    let server = port_udp_server("127.0.0.1:0");
+    let other_server = port_udp_server("127.0.0.2:0");
+
+    upstream_port(&harness, client(48005), server);
+    upstream_port(&harness, client(48006), server);
+    assert_no_reply(&harness, client(48007), server);
+
+    // existing sessions are not limited.
+    upstream_port(&harness, client(48005), server);
+    upstream_port(&harness, client(48007), other_server);
+}
+
+#[test]
+fn new_sessions_from_source_are_rate_limited() {
+    let harness = Harness::start_with_config(limits_config(SessionLimits {
+        source_rate: Some(SessionRate::new(0, 2)),
+        ..SessionLimits::default()
+    }));
+    let server = port_udp_server("127.0.0.1:0");
+    let other_source = SocketAddr::new("10.0.0.3".parse().unwrap(), 48010);
+
+    upstream_port(&harness, client(48008), server);
+    upstream_port(&harness, client(48009), server);
+    assert_no_reply(&harness, client(48010), server);
+
+    upstream_port(&harness, client(48008), server);
+    upstream_port(&harness, other_source, server);
+}
@@ -0,0 +1,162 @@
+// This is free and unencumbered software released into the public domain.
+//
+// Anyone is free to copy, modify, publish, use, compile, sell, or
+// distribute this software, either in source code form or as a compiled
+// binary, for any purpose, commercial or non-commercial, and by any
+// means.
+//
+// In jurisdictions that recognize copyright laws, the author or authors
+// of this software dedicate any and all copyright interest in the
+// software to the public domain. We make this dedication for the benefit
+// of the public at large and to the detriment of our heirs and
+// successors. We intend this dedication to be an overt act of
+// relinquishment in perpetuity of all present and future rights to this
+// software under copyright law.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS BE LIABLE FOR ANY CLAIM, DAMAGES OR
+// OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE,
+// ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR
+// OTHER DEALINGS IN THE SOFTWARE.
+//
+// For more information, please refer to <https://unlicense.org>
+
+mod common;
+
+use common::{client, echo_udp_server, udp_packet, Harness, TIMEOUT};
+use core::config::{Config, Destination, RateLimit, RateLimits, ShapingRule};
+use std::{net::SocketAddr, time::Duration};
+
+const DATAGRAM_SIZE: usize = 1000;
+
+// a bucket which is never refilled lets exactly one datagram through.
+const ONE_DATAGRAM: RateLimit = RateLimit {
+    bytes_per_second: 0,
+    burst_bytes: DATAGRAM_SIZE as u64,
+};
+
+fn session_download_config(limit: RateLimit) -> Config {
+    let mut config = Config::default();
+    config.shaping.session.download = Some(limit);
+    config
+}
+
+fn shared_rule(network: &str) -> ShapingRule {
+    ShapingRule {
+        destination: Destination {
+            network: Some(network.parse().unwrap()),
+            ..Destination::default()
+        },
+        shared: RateLimits {
+            download: Some(ONE_DATAGRAM),
+            ..RateLimits::default()
+        },
+        ..ShapingRule::default()
+    }
+}
+
+fn is_echoed(harness: &Harness, source: SocketAddr, server: SocketAddr) -> bool {
+    harness.send(&udp_packet(source, server, &[7; DATAGRAM_SIZE]));
+    harness.receive_udp(Duration::from_millis(300)).is_some()
+}
+
+#[test]
+fn session_is_throttled_once_burst_is_used() {
+    let harness = Harness::start_with_config(session_download_config(ONE_DATAGRAM));
+    let server = echo_udp_server("127.0.0.1:0");
+
+    assert!(is_echoed(&harness, client(63000), server));
+    assert!(!is_echoed(&harness, client(63000), server));
+    // other sessions have buckets of their own.
+    assert!(is_echoed(&harness, client(63001), server));
+}
+
+#[test]
+fn global_limit_is_shared_by_sessions() {
+    let mut config = Config::default();
+    config.shaping.global.download = Some(ONE_DATAGRAM);
+    let harness = Harness::start_with_config(config);
+    let server = echo_udp_server("127.0.0.1:0");
+
+    assert!(is_echoed(&harness, client(63002), server));
+    assert!(!is_echoed(&harness, client(63003), server));
+}
+
+#[test]
+fn rule_limits_apply_to_matching_destinations_only() {
+    let mut config = Config::default();
+    config.shaping.rules = vec![ShapingRule {
+        destination: Destination {
+            network: Some("127.0.0.2/32".parse().unwrap()),
+            ..Destination::default()
+        },
+        session: RateLimits {
+            download: Some(ONE_DATAGRAM),
+            ..RateLimits::default()
+        },
+        ..ShapingRule::default()
+    }];
+    let harness = Harness::start_with_config(config);
+    let limited_server = echo_udp_server("127.0.0.2:0");
+    let server = echo_udp_server("127.0.0.1:0");
+
+    assert!(is_echoed(&harness, client(63004), limited_server));
+    assert!(!is_echoed(&harness, client(63004), limited_server));
+    assert!(is_echoed(&harness, client(63005), server));
+    assert!(is_echoed(&harness, client(63005), server));
+}
+
+#[test]
+fn reload_keeps_buckets_with_unchanged_limits() {
+    let harness = Harness::start_with_config(session_download_config(ONE_DATAGRAM));
+    let server = echo_udp_server("127.0.0.1:0");
+
+    assert!(is_echoed(&harness, client(63006), server));
+    harness.configure(session_download_config(ONE_DATAGRAM));
+
+    assert!(!is_echoed(&harness, client(63006), server));
+}
+
+#[test]
+fn reload_replaces_buckets_with_changed_limits() {
+    let harness = Harness::start_with_config(session_download_config(ONE_DATAGRAM));
+    let server = echo_udp_server("127.0.0.1:0");
+
+    assert!(is_echoed(&harness, client(63007), server));
+    harness.configure(session_download_config(RateLimit::new(
+        1_000_000, 1_000_000,
+    )));
+
+    harness.send(&udp_packet(client(63007), server, &[7; DATAGRAM_SIZE]));
+    assert!(harness.receive_udp(TIMEOUT).is_some());
+}
+
+#[test]
+fn reload_keeps_rule_buckets_of_moved_rules() {
+    let mut config = Config::default();
+    config.shaping.rules = vec![shared_rule("127.0.0.2/32")];
+    let harness = Harness::start_with_config(config.clone());
+    let server = echo_udp_server("127.0.0.2:0");
+
+    assert!(is_echoed(&harness, client(63008), server));
+    config.shaping.rules.insert(0, shared_rule("127.0.0.3/32"));
+    harness.configure(config);
+
+    assert!(!is_echoed(&harness, client(63008), server));
+}
+
+#[test]
+fn reload_replaces_rule_buckets_of_changed_rules() {
+    let mut config = Config::default();
+    config.shaping.rules = vec![shared_rule("127.0.0.2/32")];
+    let harness = Harness::start_with_config(config.clone());
+    let server = echo_udp_server("127.0.0.2:0");
+
+    assert!(is_echoed(&harness, client(63009), server));
+    config.shaping.rules = vec![shared_rule("127.0.0.0/30")];
+    harness.configure(config);
+
+    assert!(is_echoed(&harness, client(63009), server));
+}
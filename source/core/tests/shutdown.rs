@@ -0,0 +1,119 @@
+// This is free and unencumbered software released into the public domain.
+//
+// Anyone is free to copy, modify, publish, use, compile, sell, or
+// distribute this software, either in source code form or as a compiled
+// binary, for any purpose, commercial or non-commercial, and by any
+// means.
+//
+// In jurisdictions that recognize copyright laws, the author or authors
+// of this software dedicate any and all copyright interest in the
+// software to the public domain. We make this dedication for the benefit
+// of the public at large and to the detriment of our heirs and
+// successors. We intend this dedication to be an overt act of
+// relinquishment in perpetuity of all present and future rights to this
+// software under copyright law.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS BE LIABLE FOR ANY CLAIM, DAMAGES OR
+// OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE,
+// ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR
+// OTHER DEALINGS IN THE SOFTWARE.
+//
+// For more information, please refer to <https://unlicense.org>
+
+mod common;
+
+use common::{client, echo_tcp_server, Harness, TcpClient, TIMEOUT};
+use core::config::{Config, ShutdownConfig, ShutdownMode};
+use std::{
+    io::{Read, Write},
+    net::{SocketAddr, TcpListener},
+    sync::mpsc::{channel, Receiver},
+    thread,
+    time::{Duration, Instant},
+};
+
+fn shutdown_config(mode: ShutdownMode, deadline: Duration) -> Config {
+    Config {
+        shutdown: ShutdownConfig { mode, deadline },
+        ..Config::default()
+    }
+}
+
+//
+// Greets every connection and reports everything received until the
+// connection is closed.
+//
+fn greeting_tcp_server() -> (SocketAddr, Receiver<Vec<u8>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let (sender, receiver) = channel();
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        stream.write_all(b"hello").unwrap();
+        let mut received = Vec::new();
+        let _ = stream.read_to_end(&mut received);
+        let _ = sender.send(received);
+    });
+    (address, receiver)
+}
+
+#[test]
+fn drain_closes_connections_with_fin() {
+    let harness = Harness::start_with_config(shutdown_config(
+        ShutdownMode::Drain,
+        Duration::from_secs(10),
+    ));
+    let (server, received) = greeting_tcp_server();
+
+    let mut connection = TcpClient::connect(&harness, client(49000), server);
+    assert_eq!(connection.receive(5), b"hello");
+    connection.send(b"request");
+
+    harness.vpn().request_stop();
+
+    connection.receive_fin();
+    assert_eq!(received.recv_timeout(TIMEOUT).unwrap(), b"request");
+    connection.close();
+
+    let start = Instant::now();
+    drop(harness);
+    assert!(start.elapsed() < TIMEOUT);
+}
+
+#[test]
+fn drain_resets_connections_open_at_deadline() {
+    let harness = Harness::start_with_config(shutdown_config(
+        ShutdownMode::Drain,
+        Duration::from_millis(500),
+    ));
+    let server = echo_tcp_server("127.0.0.1:0");
+
+    let mut connection = TcpClient::connect(&harness, client(49001), server);
+    harness.vpn().request_stop();
+    connection.receive_fin();
+
+    let segment = harness.receive_tcp(TIMEOUT).expect("no reset received");
+    assert!(segment.rst);
+}
+
+#[test]
+fn reset_mode_resets_connections() {
+    let harness = Harness::start_with_config(shutdown_config(
+        ShutdownMode::Reset,
+        Duration::from_secs(10),
+    ));
+    let server = echo_tcp_server("127.0.0.1:0");
+
+    let mut connection = TcpClient::connect(&harness, client(49002), server);
+    connection.send(b"ping");
+    assert_eq!(connection.receive(4), b"ping");
+
+    harness.vpn().request_stop();
+
+    let segment = harness.receive_tcp(TIMEOUT).expect("no reset received");
+    assert!(segment.rst);
+    assert_eq!(segment.destination, client(49002));
+}
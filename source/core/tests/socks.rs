@@ -0,0 +1,294 @@
+// This is free and unencumbered software released into the public domain.
+//
+// Anyone is free to copy, modify, publish, use, compile, sell, or
+// distribute this software, either in source code form or as a compiled
+// binary, for any purpose, commercial or non-commercial, and by any
+// means.
+//
+// In jurisdictions that recognize copyright laws, the author or authors
+// of this software dedicate any and all copyright interest in the
+// software to the public domain. We make this dedication for the benefit
+// of the public at large and to the detriment of our heirs and
+// successors. We intend this dedication to be an overt act of
+// relinquishment in perpetuity of all present and future rights to this
+// software under copyright law.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS BE LIABLE FOR ANY CLAIM, DAMAGES OR
+// OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE,
+// ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR
+// OTHER DEALINGS IN THE SOFTWARE.
+//
+// For more information, please refer to <https://unlicense.org>
+
+mod common;
+
+use common::TIMEOUT;
+use core::config::{
+    AccessConfig, Action, Config, Delay, Impairments, Protocol, SessionLimits, SessionRate,
+};
+use core::{Listeners, Vpn};
+use std::{
+    io::{Read, Write},
+    net::{IpAddr, SocketAddr, TcpStream, UdpSocket},
+    thread,
+    time::{Duration, Instant},
+};
+
+fn start_proxy(config: Config) -> (Vpn, SocketAddr) {
+    let mut listeners = Listeners::new();
+    let address = listeners
+        .listen_socks("127.0.0.1:0".parse().unwrap())
+        .unwrap();
+    let mut proxy = Vpn::new_listeners(listeners);
+    proxy.start(config).unwrap();
+    (proxy, address)
+}
+
+fn address_bytes(address: SocketAddr) -> Vec<u8> {
+    let mut bytes = match address.ip() {
+        IpAddr::V4(ip) => [&[1][..], &ip.octets()].concat(),
+        IpAddr::V6(ip) => [&[4][..], &ip.octets()].concat(),
+    };
+    bytes.extend_from_slice(&address.port().to_be_bytes());
+    bytes
+}
+
+//
+// Greets the proxy and sends the request, whose destination is given as the
+// address type followed by the address and the port; returns the connection
+// and the reply.
+//
+fn request(proxy: SocketAddr, command: u8, destination: &[u8]) -> (TcpStream, Vec<u8>) {
+    let mut stream = TcpStream::connect(proxy).unwrap();
+    stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+    stream.write_all(&[5, 1, 0]).unwrap();
+    let mut method = [0; 2];
+    stream.read_exact(&mut method).unwrap();
+    assert_eq!(method, [5, 0]);
+
+    stream
+        .write_all(&[&[5, command, 0][..], destination].concat())
+        .unwrap();
+    let mut reply = vec![0; 4];
+    stream.read_exact(&mut reply).unwrap();
+    let length = if reply[3] == 4 { 16 + 2 } else { 4 + 2 };
+    let mut bound = vec![0; length];
+    stream.read_exact(&mut bound).unwrap();
+    reply.extend(bound);
+    (stream, reply)
+}
+
+fn reply_address(reply: &[u8]) -> SocketAddr {
+    let port = u16::from_be_bytes([reply[8], reply[9]]);
+    SocketAddr::new([reply[4], reply[5], reply[6], reply[7]].into(), port)
+}
+
+#[test]
+fn connect_relays_tcp() {
+    let (mut proxy, address) = start_proxy(Config::default());
+    let server = common::echo_tcp_server("127.0.0.1:0");
+
+    let (mut stream, reply) = request(address, 1, &address_bytes(server));
+    assert_eq!(reply[..4], [5, 0, 0, 1]);
+    assert_eq!(reply_address(&reply).ip(), server.ip());
+    stream.write_all(b"hello").unwrap();
+    let mut echo = [0; 5];
+    stream.read_exact(&mut echo).unwrap();
+    assert_eq!(&echo, b"hello");
+
+    proxy.stop();
+}
+
+#[test]
+fn connect_resolves_domain_names() {
+    let (mut proxy, address) = start_proxy(Config::default());
+    let server = common::echo_tcp_server("127.0.0.1:0");
+    let name = b"127.0.0.1";
+    let destination = [
+        &[3, name.len() as u8][..],
+        name,
+        &server.port().to_be_bytes(),
+    ]
+    .concat();
+
+    let (mut stream, reply) = request(address, 1, &destination);
+    assert_eq!(reply[..2], [5, 0]);
+    stream.write_all(b"hello").unwrap();
+    let mut echo = [0; 5];
+    stream.read_exact(&mut echo).unwrap();
+    assert_eq!(&echo, b"hello");
+
+    proxy.stop();
+}
+
+#[test]
+fn connects_beyond_resolver_threads_resolve_domain_names() {
+    let (mut proxy, address) = start_proxy(Config::default());
+    let server = common::echo_tcp_server("127.0.0.1:0");
+    let name = b"127.0.0.1";
+    let destination = [
+        &[3, name.len() as u8][..],
+        name,
+        &server.port().to_be_bytes(),
+    ]
+    .concat();
+
+    let clients: Vec<_> = (0..16)
+        .map(|_| {
+            let destination = destination.clone();
+            thread::spawn(move || request(address, 1, &destination).1)
+        })
+        .collect();
+    for client in clients {
+        let reply = client.join().unwrap();
+        assert_eq!(reply[..2], [5, 0]);
+    }
+
+    proxy.stop();
+}
+
+#[test]
+fn denied_connect_is_refused() {
+    let config = Config {
+        access: AccessConfig {
+            default: Action::Deny,
+            rules: Vec::new(),
+        },
+        ..Config::default()
+    };
+    let (mut proxy, address) = start_proxy(config);
+    let server = common::echo_tcp_server("127.0.0.1:0");
+
+    let (_, reply) = request(address, 1, &address_bytes(server));
+    assert_eq!(reply[..2], [5, 2]);
+
+    proxy.stop();
+}
+
+#[test]
+fn udp_associate_relays_datagrams() {
+    let (mut proxy, address) = start_proxy(Config::default());
+    let server = common::echo_udp_server("127.0.0.1:0");
+
+    let (_connection, reply) = request(address, 3, &address_bytes("0.0.0.0:0".parse().unwrap()));
+    assert_eq!(reply[..2], [5, 0]);
+    let relay = reply_address(&reply);
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.set_read_timeout(Some(TIMEOUT)).unwrap();
+    let header = [&[0, 0, 0][..], &address_bytes(server)].concat();
+    socket
+        .send_to(&[&header[..], b"hello"].concat(), relay)
+        .unwrap();
+
+    let mut buffer = [0; 1024];
+    let (count, source) = socket.recv_from(&mut buffer).unwrap();
+    assert_eq!(source, relay);
+    assert_eq!(&buffer[..header.len()], &header[..]);
+    assert_eq!(&buffer[header.len()..count], b"hello");
+
+    proxy.stop();
+}
+
+#[test]
+fn connect_is_a_session() {
+    let (mut proxy, address) = start_proxy(Config::default());
+    let server = common::echo_tcp_server("127.0.0.1:0");
+
+    let (mut stream, _) = request(address, 1, &address_bytes(server));
+    stream.write_all(b"hello").unwrap();
+    let mut echo = [0; 5];
+    stream.read_exact(&mut echo).unwrap();
+
+    let sessions = proxy.sessions();
+    assert_eq!(sessions.len(), 1);
+    assert_eq!(sessions[0].source, stream.local_addr().unwrap());
+    assert_eq!(sessions[0].destination, server);
+    assert_eq!(sessions[0].protocol, Protocol::Tcp);
+    let statistics = proxy.statistics();
+    assert_eq!(statistics.tcp_sessions, 1);
+    assert_eq!(statistics.bytes_received, 5);
+    assert_eq!(statistics.bytes_sent, 5);
+
+    drop(stream);
+    assert!(common::wait_until(|| proxy.sessions().is_empty()));
+
+    proxy.stop();
+}
+
+#[test]
+fn connect_beyond_session_rate_is_refused() {
+    let config = Config {
+        sessions: SessionLimits {
+            destination_rate: Some(SessionRate::new(1, 1)),
+            ..SessionLimits::default()
+        },
+        ..Config::default()
+    };
+    let (mut proxy, address) = start_proxy(config);
+    let server = common::echo_tcp_server("127.0.0.1:0");
+
+    let (_stream, reply) = request(address, 1, &address_bytes(server));
+    assert_eq!(reply[..2], [5, 0]);
+    let (_, reply) = request(address, 1, &address_bytes(server));
+    assert_eq!(reply[..2], [5, 1]);
+
+    proxy.stop();
+}
+
+#[test]
+fn udp_associate_datagrams_are_sessions() {
+    let (mut proxy, address) = start_proxy(Config::default());
+    let server = common::echo_udp_server("127.0.0.1:0");
+
+    let (connection, reply) = request(address, 3, &address_bytes("0.0.0.0:0".parse().unwrap()));
+    let relay = reply_address(&reply);
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.set_read_timeout(Some(TIMEOUT)).unwrap();
+    let header = [&[0, 0, 0][..], &address_bytes(server)].concat();
+    socket
+        .send_to(&[&header[..], b"hello"].concat(), relay)
+        .unwrap();
+    let mut buffer = [0; 1024];
+    socket.recv_from(&mut buffer).unwrap();
+
+    let sessions = proxy.sessions();
+    assert_eq!(sessions.len(), 1);
+    assert_eq!(sessions[0].source, socket.local_addr().unwrap());
+    assert_eq!(sessions[0].destination, server);
+    assert_eq!(sessions[0].protocol, Protocol::Udp);
+
+    // closing the connection of the association ends its sessions.
+    drop(connection);
+    assert!(common::wait_until(|| proxy.sessions().is_empty()));
+
+    proxy.stop();
+}
+
+#[test]
+fn upload_latency_delays_connect_payload() {
+    let latency = Duration::from_millis(200);
+    let mut config = Config::default();
+    config.emulation.upload = Impairments {
+        delay: Delay {
+            latency,
+            ..Delay::default()
+        },
+        ..Impairments::default()
+    };
+    let (mut proxy, address) = start_proxy(config);
+    let server = common::echo_tcp_server("127.0.0.1:0");
+
+    let (mut stream, _) = request(address, 1, &address_bytes(server));
+    let start = Instant::now();
+    stream.write_all(b"hel").unwrap();
+    stream.write_all(b"lo").unwrap();
+    let mut echo = [0; 5];
+    stream.read_exact(&mut echo).unwrap();
+    assert_eq!(&echo, b"hello");
+    assert!(start.elapsed() >= latency);
+
+    proxy.stop();
+}
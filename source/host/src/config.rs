@@ -0,0 +1,61 @@
+// This is free and unencumbered software released into the public domain.
+//
+// Anyone is free to copy, modify, publish, use, compile, sell, or
+// distribute this software, either in source code form or as a compiled
+// binary, for any purpose, commercial or non-commercial, and by any
+// means.
+//
+// In jurisdictions that recognize copyright laws, the author or authors
+// of this software dedicate any and all copyright interest in the
+// software to the public domain. We make this dedication for the benefit
+// of the public at large and to the detriment of our heirs and
+// successors. We intend this dedication to be an overt act of
+// relinquishment in perpetuity of all present and future rights to this
+// software under copyright law.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS BE LIABLE FOR ANY CLAIM, DAMAGES OR
+// OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE,
+// ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR
+// OTHER DEALINGS IN THE SOFTWARE.
+//
+// For more information, please refer to <https://unlicense.org>
+
+use std::{fs, path::PathBuf};
+use vpn_core::config::{Config, ShutdownConfig, TunConfig};
+
+//
+// Builds the configuration from the json file given with --config, if any;
+// the tun mtu and the shutdown options of the command line take precedence.
+// Reloading reads the file again.
+//
+#[derive(Clone)]
+pub(crate) struct ConfigSource {
+    pub(crate) path: Option<PathBuf>,
+    pub(crate) mtu: usize,
+    pub(crate) shutdown: ShutdownConfig,
+}
+
+impl ConfigSource {
+    pub(crate) fn load(&self) -> Result<Config, String> {
+        let config: Config = match &self.path {
+            Some(path) => {
+                let contents = fs::read_to_string(path)
+                    .map_err(|error| format!("failed to read {:?}, error={:?}", path, error))?;
+                serde_json::from_str(&contents)
+                    .map_err(|error| format!("failed to parse {:?}, error={}", path, error))?
+            }
+            None => Config::default(),
+        };
+        Ok(Config {
+            tun: TunConfig {
+                mtu: self.mtu,
+                ..config.tun
+            },
+            shutdown: self.shutdown,
+            ..config
+        })
+    }
+}
@@ -0,0 +1,144 @@
+// This is free and unencumbered software released into the public domain.
+//
+// Anyone is free to copy, modify, publish, use, compile, sell, or
+// distribute this software, either in source code form or as a compiled
+// binary, for any purpose, commercial or non-commercial, and by any
+// means.
+//
+// In jurisdictions that recognize copyright laws, the author or authors
+// of this software dedicate any and all copyright interest in the
+// software to the public domain. We make this dedication for the benefit
+// of the public at large and to the detriment of our heirs and
+// successors. We intend this dedication to be an overt act of
+// relinquishment in perpetuity of all present and future rights to this
+// software under copyright law.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS BE LIABLE FOR ANY CLAIM, DAMAGES OR
+// OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE,
+// ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR
+// OTHER DEALINGS IN THE SOFTWARE.
+//
+// For more information, please refer to <https://unlicense.org>
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{channel, Receiver, Sender},
+        Mutex,
+    },
+    thread::{self, JoinHandle},
+};
+use vpn_core::{connection_log::ConnectionRecord, tun_callbacks};
+
+static SENDER: Mutex<Option<Sender<String>>> = Mutex::new(None);
+
+//
+// Writes a json line for every closed session; once the file would grow beyond
+// the maximum size it is renamed to `<path>.1`, shifting older files up to
+// `<path>.<max_files>`, and a new file is started.
+//
+pub(crate) struct ConnectionLog {
+    writer: Option<JoinHandle<()>>,
+}
+
+impl ConnectionLog {
+    pub(crate) fn start(path: &Path, max_size: u64, max_files: usize) -> io::Result<ConnectionLog> {
+        let file = RotatingFile::open(path.to_path_buf(), max_size, max_files)?;
+        let (sender, receiver) = channel();
+        *SENDER.lock().unwrap() = Some(sender);
+        let writer = thread::spawn(move || write_records(file, receiver));
+        tun_callbacks::set_connection_closed_callback(Some(on_connection_closed));
+        Ok(ConnectionLog {
+            writer: Some(writer),
+        })
+    }
+}
+
+impl Drop for ConnectionLog {
+    // pending records, e.g. those of sessions closed on shutdown, are written before returning.
+    fn drop(&mut self) {
+        tun_callbacks::set_connection_closed_callback(None);
+        SENDER.lock().unwrap().take();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+fn on_connection_closed(record: &ConnectionRecord) {
+    if let Some(sender) = SENDER.lock().unwrap().as_ref() {
+        let _ = sender.send(serde_json::to_string(record).unwrap());
+    }
+}
+
+fn write_records(mut file: RotatingFile, receiver: Receiver<String>) {
+    for record in receiver {
+        if let Err(error) = file.write_line(&record) {
+            log::error!("failed to write connection record, error={:?}", error);
+        }
+    }
+}
+
+struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    max_files: usize,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, max_size: u64, max_files: usize) -> io::Result<RotatingFile> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path,
+            max_size,
+            max_files,
+            file,
+            size,
+        })
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let length = line.len() as u64 + 1;
+        if self.size > 0 && self.size + length > self.max_size {
+            self.rotate()?;
+        }
+        // a single write keeps lines whole for readers tailing the file.
+        self.file.write_all(format!("{}\n", line).as_bytes())?;
+        self.size += length;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.max_files == 0 {
+            self.file.set_len(0)?;
+        } else {
+            for index in (1..self.max_files).rev() {
+                let from = self.rotated_path(index);
+                if from.exists() {
+                    fs::rename(from, self.rotated_path(index + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+            self.file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?;
+        }
+        self.size = 0;
+        Ok(())
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        PathBuf::from(path)
+    }
+}
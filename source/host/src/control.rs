@@ -0,0 +1,363 @@
+// This is free and unencumbered software released into the public domain.
+//
+// Anyone is free to copy, modify, publish, use, compile, sell, or
+// distribute this software, either in source code form or as a compiled
+// binary, for any purpose, commercial or non-commercial, and by any
+// means.
+//
+// In jurisdictions that recognize copyright laws, the author or authors
+// of this software dedicate any and all copyright interest in the
+// software to the public domain. We make this dedication for the benefit
+// of the public at large and to the detriment of our heirs and
+// successors. We intend this dedication to be an overt act of
+// relinquishment in perpetuity of all present and future rights to this
+// software under copyright law.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS BE LIABLE FOR ANY CLAIM, DAMAGES OR
+// OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE,
+// ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR
+// OTHER DEALINGS IN THE SOFTWARE.
+//
+// For more information, please refer to <https://unlicense.org>
+
+use crate::config::ConfigSource;
+use clap::Subcommand;
+use log::LevelFilter;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    fs,
+    io::{BufRead, BufReader, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    path::{Path, PathBuf},
+    sync::{mpsc::Sender, Arc},
+    thread,
+};
+use vpn_core::config::Config;
+use vpn_core::tun;
+
+//
+// Requests and responses are exchanged as json objects, one per line.
+//
+#[derive(Subcommand, Serialize, Deserialize, Debug)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub(crate) enum Request {
+    /// List the active sessions.
+    Sessions,
+    /// Show statistics of the vpn.
+    Statistics,
+    /// Capture packets exchanged with the tun to a pcap file.
+    CaptureStart {
+        /// Path of the pcap file, written by the running instance.
+        path: PathBuf,
+    },
+    /// Stop capturing packets.
+    CaptureStop,
+    /// Change the maximum log level, e.g. to debug, within what RUST_LOG enables.
+    LogLevel { level: String },
+    /// Read the configuration file again and apply it.
+    Reload,
+    /// Stop the vpn and exit.
+    Shutdown,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct Response {
+    pub(crate) ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) result: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) error: Option<String>,
+}
+
+impl From<Result<Value, String>> for Response {
+    fn from(result: Result<Value, String>) -> Self {
+        match result {
+            Ok(value) => Response {
+                ok: true,
+                result: Some(value).filter(|value| !value.is_null()),
+                error: None,
+            },
+            Err(error) => Response {
+                ok: false,
+                result: None,
+                error: Some(error),
+            },
+        }
+    }
+}
+
+struct Handler {
+    config_source: ConfigSource,
+    // applies a reloaded configuration, tun::reload outside of tests.
+    reload: Box<dyn Fn(Config) + Send + Sync>,
+    exit: Sender<()>,
+}
+
+impl Handler {
+    fn handle(&self, request: Request) -> Result<Value, String> {
+        match request {
+            Request::Sessions => to_value(tun::sessions()),
+            Request::Statistics => to_value(tun::statistics()),
+            Request::CaptureStart { path } => {
+                tun::start_capture(&path)
+                    .map_err(|error| format!("failed to start capture, error={:?}", error))?;
+                Ok(Value::Null)
+            }
+            Request::CaptureStop => {
+                tun::stop_capture();
+                Ok(Value::Null)
+            }
+            Request::LogLevel { level } => {
+                let level: LevelFilter = level
+                    .parse()
+                    .map_err(|_| format!("invalid log level {:?}", level))?;
+                log::set_max_level(level);
+                Ok(Value::Null)
+            }
+            Request::Reload => {
+                (self.reload)(self.config_source.load()?);
+                Ok(Value::Null)
+            }
+            Request::Shutdown => {
+                let _ = self.exit.send(());
+                Ok(Value::Null)
+            }
+        }
+    }
+}
+
+fn to_value<T: Serialize>(value: T) -> Result<Value, String> {
+    serde_json::to_value(value).map_err(|error| error.to_string())
+}
+
+//
+// Listens on a unix socket, serving each connection on its own thread; the
+// socket file is removed when the server is dropped.
+//
+pub(crate) struct Server {
+    path: PathBuf,
+}
+
+impl Server {
+    pub(crate) fn start(
+        path: &Path,
+        config_source: ConfigSource,
+        reload: Box<dyn Fn(Config) + Send + Sync>,
+        exit: Sender<()>,
+    ) -> std::io::Result<Server> {
+        // a socket left behind by a previous run would fail the bind.
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)?;
+        let handler = Arc::new(Handler {
+            config_source,
+            reload,
+            exit,
+        });
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let handler = handler.clone();
+                        thread::spawn(move || serve(stream, &handler));
+                    }
+                    Err(error) => {
+                        log::error!("failed to accept control connection, error={:?}", error);
+                    }
+                }
+            }
+        });
+        Ok(Server {
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        if let Err(error) = fs::remove_file(&self.path) {
+            log::error!("failed to remove control socket, error={:?}", error);
+        }
+    }
+}
+
+fn serve(stream: UnixStream, handler: &Handler) {
+    let mut writer = match stream.try_clone() {
+        Ok(writer) => writer,
+        Err(error) => {
+            log::error!("failed to clone control connection, error={:?}", error);
+            return;
+        }
+    };
+    for line in BufReader::new(stream).lines() {
+        let Ok(line) = line else {
+            break;
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response: Response = match serde_json::from_str::<Request>(&line) {
+            Ok(request) => {
+                log::debug!("handling control request, request={:?}", request);
+                handler.handle(request).into()
+            }
+            Err(error) => Err(format!("invalid request, error={}", error)).into(),
+        };
+        if write_line(&mut writer, &response).is_err() {
+            break;
+        }
+    }
+}
+
+fn write_line<T: Serialize>(writer: &mut impl Write, value: &T) -> std::io::Result<()> {
+    let mut line = serde_json::to_vec(value)?;
+    line.push(b'\n');
+    writer.write_all(&line)
+}
+
+//
+// Sends a single request and returns its response, for `host ctl`.
+//
+pub(crate) fn request(path: &Path, request: &Request) -> Result<Response, String> {
+    let mut stream = UnixStream::connect(path)
+        .map_err(|error| format!("failed to connect to {:?}, error={:?}", path, error))?;
+    write_line(&mut stream, request)
+        .map_err(|error| format!("failed to send request, error={:?}", error))?;
+    let mut line = String::new();
+    BufReader::new(stream)
+        .read_line(&mut line)
+        .map_err(|error| format!("failed to read response, error={:?}", error))?;
+    serde_json::from_str(&line).map_err(|error| format!("invalid response, error={}", error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::{channel, Receiver};
+    use vpn_core::config::ShutdownConfig;
+
+    const MTU: usize = 1400;
+
+    struct TestServer {
+        path: PathBuf,
+        reloads: Receiver<Config>,
+        exits: Receiver<()>,
+        _server: Server,
+    }
+
+    fn start(name: &str) -> TestServer {
+        let path =
+            std::env::temp_dir().join(format!("control-{}-{}.sock", std::process::id(), name));
+        let config_source = ConfigSource {
+            path: None,
+            mtu: MTU,
+            shutdown: ShutdownConfig::default(),
+        };
+        let (reload_sender, reloads) = channel();
+        let reload = Box::new(move |config| {
+            let _ = reload_sender.send(config);
+        });
+        let (exit, exits) = channel();
+        let server = Server::start(&path, config_source, reload, exit).unwrap();
+        TestServer {
+            path,
+            reloads,
+            exits,
+            _server: server,
+        }
+    }
+
+    fn send_line(path: &Path, line: &str) -> Response {
+        let mut stream = UnixStream::connect(path).unwrap();
+        stream.write_all(line.as_bytes()).unwrap();
+        let mut response = String::new();
+        BufReader::new(stream).read_line(&mut response).unwrap();
+        serde_json::from_str(&response).unwrap()
+    }
+
+    #[test]
+    fn requests_are_tagged_with_their_command() {
+        let request = Request::LogLevel {
+            level: "debug".to_string(),
+        };
+        assert_eq!(
+            serde_json::to_string(&request).unwrap(),
+            r#"{"command":"log_level","level":"debug"}"#
+        );
+        let request: Request = serde_json::from_str(r#"{"command":"capture_stop"}"#).unwrap();
+        assert!(matches!(request, Request::CaptureStop));
+    }
+
+    #[test]
+    fn responses_carry_either_a_result_or_an_error() {
+        let response: Response = Ok(Value::Null).into();
+        assert_eq!(serde_json::to_string(&response).unwrap(), r#"{"ok":true}"#);
+        let response: Response = Err("failed".to_string()).into();
+        assert_eq!(
+            serde_json::to_string(&response).unwrap(),
+            r#"{"ok":false,"error":"failed"}"#
+        );
+    }
+
+    #[test]
+    fn statistics_are_returned_without_a_running_vpn() {
+        let server = start("statistics");
+
+        let response = request(&server.path, &Request::Statistics).unwrap();
+
+        assert!(response.ok);
+        assert_eq!(response.result.unwrap()["tcp_sessions"], 0);
+    }
+
+    #[test]
+    fn reload_applies_the_loaded_configuration() {
+        let server = start("reload");
+
+        let response = request(&server.path, &Request::Reload).unwrap();
+
+        assert!(response.ok);
+        let config = server.reloads.try_recv().unwrap();
+        assert_eq!(config.tun.mtu, MTU);
+    }
+
+    #[test]
+    fn shutdown_requests_exit() {
+        let server = start("shutdown");
+
+        assert!(request(&server.path, &Request::Shutdown).unwrap().ok);
+
+        assert!(server.exits.try_recv().is_ok());
+    }
+
+    #[test]
+    fn invalid_requests_are_answered_with_errors() {
+        let server = start("invalid");
+
+        let response = send_line(&server.path, "{\"command\":\"restart\"}\n");
+        assert!(!response.ok);
+        assert!(response.error.unwrap().starts_with("invalid request"));
+
+        let response = send_line(
+            &server.path,
+            "{\"command\":\"log_level\",\"level\":\"loud\"}\n",
+        );
+        assert!(!response.ok);
+        assert_eq!(response.error.unwrap(), "invalid log level \"loud\"");
+    }
+
+    #[test]
+    fn socket_is_removed_with_the_server() {
+        let server = start("removed");
+        let path = server.path.clone();
+        assert!(path.exists());
+
+        drop(server);
+
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,122 @@
+// This is free and unencumbered software released into the public domain.
+//
+// Anyone is free to copy, modify, publish, use, compile, sell, or
+// distribute this software, either in source code form or as a compiled
+// binary, for any purpose, commercial or non-commercial, and by any
+// means.
+//
+// In jurisdictions that recognize copyright laws, the author or authors
+// of this software dedicate any and all copyright interest in the
+// software to the public domain. We make this dedication for the benefit
+// of the public at large and to the detriment of our heirs and
+// successors. We intend this dedication to be an overt act of
+// relinquishment in perpetuity of all present and future rights to this
+// software under copyright law.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS BE LIABLE FOR ANY CLAIM, DAMAGES OR
+// OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE,
+// ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR
+// OTHER DEALINGS IN THE SOFTWARE.
+//
+// For more information, please refer to <https://unlicense.org>
+
+use std::fs::{self, File};
+use std::io::{Error, Read, Result, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::{Path, PathBuf};
+
+//
+// Runs the rest of the program in a child process detached from the terminal.
+// The parent stays until the child reports whether it started, and exits with
+// the code reported, so that failures are visible to whoever started it; it
+// exits with `failure` when the child ends without reporting.
+//
+// Has to be called before any thread is spawned, as only the calling thread
+// survives the fork.
+//
+pub(crate) fn daemonize(failure: i32) -> Result<Readiness> {
+    let mut pipe = [0; 2];
+    if unsafe { libc::pipe2(pipe.as_mut_ptr(), libc::O_CLOEXEC) } == -1 {
+        return Err(Error::last_os_error());
+    }
+    let (reader, writer) =
+        unsafe { (OwnedFd::from_raw_fd(pipe[0]), OwnedFd::from_raw_fd(pipe[1])) };
+
+    match unsafe { libc::fork() } {
+        -1 => Err(Error::last_os_error()),
+        0 => {
+            drop(reader);
+            if unsafe { libc::setsid() } == -1 {
+                return Err(Error::last_os_error());
+            }
+            // stderr is kept so that log records can still be redirected.
+            let null = File::options().read(true).write(true).open("/dev/null")?;
+            for descriptor in [libc::STDIN_FILENO, libc::STDOUT_FILENO] {
+                if unsafe { libc::dup2(null.as_raw_fd(), descriptor) } == -1 {
+                    return Err(Error::last_os_error());
+                }
+            }
+            Ok(Readiness {
+                writer: Some(File::from(writer)),
+            })
+        }
+        _ => {
+            drop(writer);
+            let mut code = [0u8; 1];
+            let code = match File::from(reader).read_exact(&mut code) {
+                Ok(_) => code[0] as i32,
+                Err(_) => failure,
+            };
+            std::process::exit(code);
+        }
+    }
+}
+
+//
+// Reports the exit code of startup to the parent of a daemon, once; running in
+// the foreground there is nobody to report to.
+//
+pub(crate) struct Readiness {
+    writer: Option<File>,
+}
+
+impl Readiness {
+    pub(crate) fn foreground() -> Readiness {
+        Readiness { writer: None }
+    }
+
+    pub(crate) fn notify(&mut self, code: i32) {
+        if let Some(mut writer) = self.writer.take() {
+            if let Err(error) = writer.write_all(&[code as u8]) {
+                log::error!("failed to report readiness, error={:?}", error);
+            }
+        }
+    }
+}
+
+//
+// Holds the pid of the process in a file, which is removed when dropped.
+//
+pub(crate) struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    pub(crate) fn create(path: &Path) -> Result<PidFile> {
+        fs::write(path, format!("{}\n", std::process::id()))?;
+        Ok(PidFile {
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if let Err(error) = fs::remove_file(&self.path) {
+            log::error!("failed to remove pid file, error={:?}", error);
+        }
+    }
+}
@@ -0,0 +1,260 @@
+// This is free and unencumbered software released into the public domain.
+//
+// Anyone is free to copy, modify, publish, use, compile, sell, or
+// distribute this software, either in source code form or as a compiled
+// binary, for any purpose, commercial or non-commercial, and by any
+// means.
+//
+// In jurisdictions that recognize copyright laws, the author or authors
+// of this software dedicate any and all copyright interest in the
+// software to the public domain. We make this dedication for the benefit
+// of the public at large and to the detriment of our heirs and
+// successors. We intend this dedication to be an overt act of
+// relinquishment in perpetuity of all present and future rights to this
+// software under copyright law.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS BE LIABLE FOR ANY CLAIM, DAMAGES OR
+// OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE,
+// ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR
+// OTHER DEALINGS IN THE SOFTWARE.
+//
+// For more information, please refer to <https://unlicense.org>
+
+use crate::netlink::{Netlink, Route};
+use std::{io::Result, net::IpAddr};
+use vpn_core::config::Network;
+
+//
+// Configures the tun through netlink: sets its mtu, assigns addresses, brings
+// it up and routes networks through it; everything added is removed again when
+// dropped. The routes of the uplink are excluded: networks within a subnet
+// connected to the uplink are not routed through the tun, and a host route
+// through the uplink is added for each of its gateways which a tun route would
+// cover otherwise. Upstream sockets are bound to the uplink and bypass the tun
+// regardless; the exclusion keeps the uplink reachable for everything else.
+//
+pub(crate) struct InterfaceSetup {
+    name: String,
+    netlink: Netlink,
+    index: u32,
+    addresses: Vec<Network>,
+    routes: Vec<Network>,
+    uplink: u32,
+    exclusions: Vec<Network>,
+}
+
+impl InterfaceSetup {
+    pub(crate) fn apply(
+        name: &str,
+        uplink: &str,
+        mtu: usize,
+        addresses: &[Network],
+        routes: &[Network],
+    ) -> Result<InterfaceSetup> {
+        let mut setup = InterfaceSetup {
+            name: name.to_string(),
+            netlink: Netlink::new()?,
+            index: Netlink::link_index(name)?,
+            addresses: Vec::new(),
+            routes: Vec::new(),
+            uplink: Netlink::link_index(uplink)?,
+            exclusions: Vec::new(),
+        };
+        let uplink_routes: Vec<Route> = setup
+            .netlink
+            .routes()?
+            .into_iter()
+            .filter(|route| route.interface == setup.uplink)
+            .collect();
+        let (routes, exclusions) = exclude_uplink(routes, &uplink_routes);
+
+        // whatever has been added is removed by drop when a step fails.
+        setup
+            .netlink
+            .set_link(setup.index, Some(mtu as u32), true)?;
+        for address in addresses {
+            setup.netlink.add_address(setup.index, address)?;
+            setup.addresses.push(*address);
+        }
+        for exclusion in exclusions {
+            setup.netlink.add_route(setup.uplink, &exclusion)?;
+            setup.exclusions.push(exclusion);
+        }
+        for route in routes {
+            setup.netlink.add_route(setup.index, &route)?;
+            setup.routes.push(route);
+        }
+        Ok(setup)
+    }
+}
+
+impl Drop for InterfaceSetup {
+    fn drop(&mut self) {
+        for exclusion in self.exclusions.iter().rev() {
+            if let Err(error) = self.netlink.delete_route(self.uplink, exclusion) {
+                log::error!("failed to delete route {}, error={:?}", exclusion, error);
+            }
+        }
+        // a tun which is not persistent is removed along with its routes once closed.
+        if Netlink::link_index(&self.name).ok() != Some(self.index) {
+            return;
+        }
+        for route in self.routes.iter().rev() {
+            if let Err(error) = self.netlink.delete_route(self.index, route) {
+                log::error!("failed to delete route {}, error={:?}", route, error);
+            }
+        }
+        for address in self.addresses.iter().rev() {
+            if let Err(error) = self.netlink.delete_address(self.index, address) {
+                log::error!("failed to delete address {}, error={:?}", address, error);
+            }
+        }
+        if let Err(error) = self.netlink.set_link(self.index, None, false) {
+            log::error!("failed to set link down, error={:?}", error);
+        }
+    }
+}
+
+//
+// Returns the routes to add to the tun and the host routes to add to the
+// uplink for its gateways. A route of the tun wins over the uplink's routes
+// which are less specific, so it is dropped when it lies within a subnet
+// connected to the uplink.
+//
+fn exclude_uplink(routes: &[Network], uplink_routes: &[Route]) -> (Vec<Network>, Vec<Network>) {
+    let connected: Vec<&Network> = uplink_routes
+        .iter()
+        .filter(|route| route.gateway.is_none())
+        .map(|route| &route.network)
+        .collect();
+    let within_connected = |network: &Network| {
+        connected.iter().any(|subnet| {
+            subnet.prefix_length <= network.prefix_length && subnet.contains(&network.address)
+        })
+    };
+    let routes: Vec<Network> = routes
+        .iter()
+        .filter(|route| {
+            let excluded = within_connected(route);
+            if excluded {
+                log::info!("not routing {} within a subnet of the uplink", route);
+            }
+            !excluded
+        })
+        .copied()
+        .collect();
+
+    let mut exclusions: Vec<Network> = Vec::new();
+    for gateway in uplink_routes.iter().filter_map(|route| route.gateway) {
+        let host = Network::new(gateway, host_prefix_length(&gateway));
+        // gateways within a connected subnet stay reachable through it, as
+        // the routes of the tun within such a subnet have been dropped.
+        if exclusions.contains(&host) || within_connected(&host) {
+            continue;
+        }
+        if routes.iter().any(|route| route.contains(&gateway)) {
+            exclusions.push(host);
+        }
+    }
+    (routes, exclusions)
+}
+
+fn host_prefix_length(address: &IpAddr) -> u8 {
+    match address {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UPLINK: u32 = 2;
+
+    fn network(network: &str) -> Network {
+        network.parse().unwrap()
+    }
+
+    fn networks(networks: &[&str]) -> Vec<Network> {
+        networks.iter().map(|value| network(value)).collect()
+    }
+
+    fn connected(subnet: &str) -> Route {
+        Route {
+            network: network(subnet),
+            gateway: None,
+            interface: UPLINK,
+        }
+    }
+
+    fn via(destination: &str, gateway: &str) -> Route {
+        Route {
+            network: network(destination),
+            gateway: Some(gateway.parse().unwrap()),
+            interface: UPLINK,
+        }
+    }
+
+    #[test]
+    fn halves_of_the_address_space_leave_uplink_routes_in_place() {
+        let routes = networks(&["0.0.0.0/1", "128.0.0.0/1", "::/1", "8000::/1"]);
+        let uplink = [
+            connected("192.168.1.0/24"),
+            via("0.0.0.0/0", "192.168.1.1"),
+            connected("fe80::/64"),
+            via("::/0", "fe80::1"),
+        ];
+
+        let (tun_routes, exclusions) = exclude_uplink(&routes, &uplink);
+
+        assert_eq!(tun_routes, routes);
+        assert!(exclusions.is_empty());
+    }
+
+    #[test]
+    fn routes_within_connected_subnets_are_dropped() {
+        let routes = networks(&[
+            "192.168.1.0/25",
+            "192.168.1.0/24",
+            "10.0.0.0/8",
+            "192.168.0.0/16",
+        ]);
+        let uplink = [connected("192.168.1.0/24"), via("0.0.0.0/0", "192.168.1.1")];
+
+        let (tun_routes, exclusions) = exclude_uplink(&routes, &uplink);
+
+        assert_eq!(tun_routes, networks(&["10.0.0.0/8", "192.168.0.0/16"]));
+        assert!(exclusions.is_empty());
+    }
+
+    #[test]
+    fn gateways_outside_connected_subnets_are_excluded() {
+        let routes = networks(&["0.0.0.0/1", "128.0.0.0/1", "::/1", "8000::/1"]);
+        let uplink = [
+            connected("203.0.113.10/32"),
+            via("0.0.0.0/0", "172.31.1.1"),
+            via("198.51.100.0/24", "172.31.1.1"),
+            via("::/0", "2001:db8::1"),
+        ];
+
+        let (tun_routes, exclusions) = exclude_uplink(&routes, &uplink);
+
+        assert_eq!(tun_routes, routes);
+        assert_eq!(exclusions, networks(&["172.31.1.1/32", "2001:db8::1/128"]));
+    }
+
+    #[test]
+    fn gateways_not_covered_by_tun_routes_are_not_excluded() {
+        let routes = networks(&["10.0.0.0/8"]);
+        let uplink = [via("0.0.0.0/0", "172.31.1.1")];
+
+        let (tun_routes, exclusions) = exclude_uplink(&routes, &uplink);
+
+        assert_eq!(tun_routes, routes);
+        assert!(exclusions.is_empty());
+    }
+}
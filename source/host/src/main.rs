@@ -23,61 +23,1025 @@
 //
 // For more information, please refer to <https://unlicense.org>
 
-use clap::Parser;
-use core::tun;
-use core::tun_callbacks;
+mod config;
+mod connection_log;
+mod control;
+mod daemon;
+mod interface;
+mod metrics;
+mod namespace;
+mod netlink;
+mod privileges;
+mod process_lookup;
+mod signals;
+
+use clap::{Parser, Subcommand, ValueEnum};
+use config::ConfigSource;
+use connection_log::ConnectionLog;
+use daemon::{PidFile, Readiness};
 use env_logger::Env;
-use smoltcp::phy::{Medium, TunTapInterface};
+use interface::InterfaceSetup;
+use log::LevelFilter;
+use namespace::Namespace;
+use privileges::{Credentials, CAP_NET_ADMIN, CAP_NET_RAW};
+use process_lookup::HostProcessLookup;
+use signals::{Signal, Signals};
+use smoltcp::phy::{Device, Medium, TunTapInterface};
 use std::ffi::CString;
-use std::os::unix::io::AsRawFd;
+use std::net::{SocketAddr, TcpListener};
+use std::os::unix::io::{AsRawFd, IntoRawFd};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use vpn_core::config::{Network, ShutdownConfig, ShutdownMode, TunConfig};
+use vpn_core::tun;
+use vpn_core::tun_callbacks;
+use vpn_core::Listeners;
+
+// exit codes of the tun mode; clap exits with 2 on invalid arguments.
+const EXIT_FAILURE: i32 = 1;
+const EXIT_TUN: i32 = 3;
+const EXIT_CONFIG: i32 = 4;
+const EXIT_START: i32 = 5;
+
+// the tun of a namespace created for `run`, addressed and routed like on android.
+const NAMESPACE_TUN: &str = "tun0";
+const NAMESPACE_ADDRESSES: [&str; 2] = ["10.0.0.2/32", "fd00::2/128"];
+const NAMESPACE_ROUTES: [&str; 2] = ["0.0.0.0/0", "::/0"];
 
 static OUT_INTERFACE: std::sync::OnceLock<CString> = std::sync::OnceLock::new();
 
 /// Tunnel traffic through sockets.
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Name of the tun interface.
-    #[arg(short, long)]
-    tun: String,
+    #[arg(short, long, required = true)]
+    tun: Option<String>,
 
     /// Name of the output interface.
+    #[arg(short, long, required = true)]
+    out: Option<String>,
+
+    /// Address assigned to the tun interface, may be repeated.
+    #[arg(long = "address", default_values = ["10.0.0.2/32", "fd00::2/128"])]
+    addresses: Vec<Network>,
+
+    /// Network routed through the tun interface unless within a subnet of the output interface, may be repeated.
+    #[arg(long = "route", default_values = ["0.0.0.0/1", "128.0.0.0/1", "::/1", "8000::/1"])]
+    routes: Vec<Network>,
+
+    /// Maximum transmission unit of the tun interface.
+    #[arg(long, default_value_t = 1500)]
+    mtu: usize,
+
+    /// Use the tun interface as configured elsewhere, without assigning addresses and routes.
+    #[arg(long)]
+    skip_setup: bool,
+
+    /// How sessions are closed when exiting.
+    #[arg(long, value_enum, default_value_t = Shutdown::Immediate)]
+    shutdown: Shutdown,
+
+    /// Seconds to wait for drained sessions to close before resetting them.
+    #[arg(long, default_value_t = 5)]
+    drain_timeout: u64,
+
+    /// Json file with the configuration, read again on reload.
     #[arg(short, long)]
-    out: String,
+    config: Option<PathBuf>,
+
+    /// Path of a unix socket on which to accept control commands.
+    #[arg(long)]
+    control: Option<PathBuf>,
+
+    /// Detach from the terminal once started; log records are still written to stderr.
+    #[arg(short, long)]
+    daemon: bool,
+
+    /// File to write the process id to, removed on exit.
+    #[arg(long)]
+    pid_file: Option<PathBuf>,
+
+    /// Address on which to serve prometheus metrics over http, e.g. 127.0.0.1:9100.
+    #[arg(long)]
+    metrics: Option<SocketAddr>,
+
+    /// Address on which to accept socks5 clients next to the tun, may be repeated.
+    #[arg(long = "socks")]
+    socks: Vec<SocketAddr>,
+
+    /// Attribute sessions to the local processes owning their sockets, for the access rules, the
+    /// connection log and the session list.
+    #[arg(long)]
+    lookup_processes: bool,
+
+    #[command(flatten)]
+    connection_log: ConnectionLogArgs,
+
+    #[command(flatten)]
+    privileges: PrivilegeArgs,
+}
+
+#[derive(clap::Args, Debug)]
+struct ConnectionLogArgs {
+    /// File to append a json record to for every closed session.
+    #[arg(long = "connection-log")]
+    path: Option<PathBuf>,
+
+    /// Size in bytes after which the connection log is rotated.
+    #[arg(long = "connection-log-size", default_value_t = 10 * 1024 * 1024)]
+    max_size: u64,
+
+    /// Number of rotated connection logs to keep.
+    #[arg(long = "connection-log-files", default_value_t = 5)]
+    max_files: usize,
+}
+
+impl ConnectionLogArgs {
+    fn start(&self) -> Option<std::io::Result<ConnectionLog>> {
+        self.path
+            .as_ref()
+            .map(|path| ConnectionLog::start(path, self.max_size, self.max_files))
+    }
+}
+
+#[derive(clap::Args, Debug)]
+struct PrivilegeArgs {
+    /// User to switch to once set up, by name or id; only CAP_NET_RAW is kept, for binding
+    /// upstream sockets, and CAP_NET_ADMIN while addresses and routes are to be removed on
+    /// exit. Files such as the configuration and the pid file are accessed as the user.
+    #[arg(long)]
+    user: Option<String>,
+
+    /// Group to switch to along with the user, by name or id; defaults to the primary group of
+    /// the user.
+    #[arg(long, requires = "user")]
+    group: Option<String>,
+
+    /// Fail the system calls which the vpn does not make once set up with EPERM.
+    #[arg(long)]
+    seccomp: bool,
+}
+
+impl PrivilegeArgs {
+    fn credentials(&self) -> Option<std::io::Result<Credentials>> {
+        self.user
+            .as_ref()
+            .map(|user| Credentials::lookup(user, self.group.as_deref()))
+    }
+
+    //
+    // Switches to the user, if any, and restricts the system calls; called once
+    // everything which needs root has been set up and before any thread is
+    // spawned. Returns the exit code on failure.
+    //
+    fn drop_privileges(
+        &self,
+        credentials: Option<&Credentials>,
+        capabilities: &[u32],
+    ) -> Result<(), i32> {
+        if let Some(credentials) = credentials {
+            if let Err(error) = credentials.switch(capabilities) {
+                eprintln!("failed to drop privileges, error={:?}", error);
+                return Err(EXIT_FAILURE);
+            }
+        }
+        if self.seccomp {
+            if let Err(error) = privileges::restrict_system_calls() {
+                eprintln!("failed to install seccomp filter, error={:?}", error);
+                return Err(EXIT_FAILURE);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Shutdown {
+    /// Drop sessions without notifying clients.
+    Immediate,
+    /// Flush pending data and close tcp connections with a fin.
+    Drain,
+    /// Reset tcp connections.
+    Reset,
+}
+
+impl From<Shutdown> for ShutdownMode {
+    fn from(shutdown: Shutdown) -> Self {
+        match shutdown {
+            Shutdown::Immediate => ShutdownMode::Immediate,
+            Shutdown::Drain => ShutdownMode::Drain,
+            Shutdown::Reset => ShutdownMode::Reset,
+        }
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Process packets relayed over a stream, e.g. from a device through `adb reverse`.
+    Relay {
+        /// Address to listen on for relay connections.
+        #[arg(short, long, default_value = "127.0.0.1:31416")]
+        listen: SocketAddr,
+
+        /// Name of the output interface.
+        #[arg(short, long)]
+        out: Option<String>,
+
+        /// Address on which to serve prometheus metrics over http, e.g. 127.0.0.1:9100.
+        #[arg(long)]
+        metrics: Option<SocketAddr>,
+
+        #[command(flatten)]
+        connection_log: ConnectionLogArgs,
+    },
+    /// Run a command in a network namespace of its own whose traffic goes through the vpn,
+    /// until the command exits; the exit code is that of the command.
+    Run {
+        /// Name of the output interface.
+        #[arg(short, long)]
+        out: Option<String>,
+
+        /// Maximum transmission unit of the tun interface.
+        #[arg(long, default_value_t = 1500)]
+        mtu: usize,
+
+        /// Json file with the configuration, read again on reload.
+        #[arg(short, long)]
+        config: Option<PathBuf>,
+
+        /// Address on which to serve prometheus metrics over http, e.g. 127.0.0.1:9100.
+        #[arg(long)]
+        metrics: Option<SocketAddr>,
+
+        /// Attribute sessions to the local processes owning their sockets, for the access rules, the
+        /// connection log and the session list.
+        #[arg(long)]
+        lookup_processes: bool,
+
+        #[command(flatten)]
+        connection_log: ConnectionLogArgs,
+
+        /// The command to run and its arguments, e.g. `run -- curl example.com`.
+        #[arg(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+        command: Vec<String>,
+    },
+    /// Forward connections which iptables or nftables redirect to local listeners, or which
+    /// socks5 clients request, without a tun.
+    Transparent {
+        /// Address on which to accept tcp connections redirected with REDIRECT, may be repeated.
+        #[arg(long = "redirect", required_unless_present_any = ["tproxy", "socks"])]
+        redirect: Vec<SocketAddr>,
+
+        /// Address on which to accept tcp connections and udp datagrams diverted with TPROXY,
+        /// may be repeated.
+        #[arg(long = "tproxy")]
+        tproxy: Vec<SocketAddr>,
+
+        /// Address on which to accept socks5 clients, may be repeated.
+        #[arg(long = "socks")]
+        socks: Vec<SocketAddr>,
+
+        /// Name of the output interface; upstream connections have to bypass the redirection.
+        #[arg(short, long)]
+        out: Option<String>,
+
+        /// Json file with the configuration, read again on reload.
+        #[arg(short, long)]
+        config: Option<PathBuf>,
+
+        /// Path of a unix socket on which to accept control commands.
+        #[arg(long)]
+        control: Option<PathBuf>,
+
+        /// Address on which to serve prometheus metrics over http, e.g. 127.0.0.1:9100.
+        #[arg(long)]
+        metrics: Option<SocketAddr>,
+
+        /// Attribute sessions to the local processes owning their sockets, for the access rules, the
+        /// connection log and the session list.
+        #[arg(long)]
+        lookup_processes: bool,
+
+        #[command(flatten)]
+        connection_log: ConnectionLogArgs,
+
+        #[command(flatten)]
+        privileges: PrivilegeArgs,
+    },
+    /// Send a command to a running instance through its control socket.
+    Ctl {
+        /// Path of the control socket.
+        #[arg(short, long)]
+        socket: PathBuf,
+
+        #[command(subcommand)]
+        request: control::Request,
+    },
 }
 
 fn main() {
-    let environment = Env::default().default_filter_or("info");
-    env_logger::Builder::from_env(environment).init();
+    init_logger();
 
     let args = Args::parse();
 
-    OUT_INTERFACE.set(CString::new(args.out).unwrap()).unwrap();
-
-    tun_callbacks::set_socket_created_callback(Some(on_socket_created));
+    match args.command {
+        Some(Command::Relay {
+            listen,
+            out,
+            metrics,
+            connection_log,
+        }) => run_relay(listen, out, metrics, connection_log),
+        Some(Command::Run {
+            out,
+            mtu,
+            config,
+            metrics,
+            lookup_processes,
+            connection_log,
+            command,
+        }) => {
+            let config_source = ConfigSource {
+                path: config,
+                mtu,
+                shutdown: ShutdownConfig::default(),
+            };
+            let options = RunOptions {
+                out,
+                mtu,
+                config_source,
+                metrics,
+                lookup_processes,
+                connection_log,
+            };
+            std::process::exit(run_command(options, &command));
+        }
+        Some(Command::Transparent {
+            redirect,
+            tproxy,
+            socks,
+            out,
+            config,
+            control,
+            metrics,
+            lookup_processes,
+            connection_log,
+            privileges,
+        }) => {
+            let config_source = ConfigSource {
+                path: config,
+                mtu: TunConfig::default().mtu,
+                shutdown: ShutdownConfig::default(),
+            };
+            let options = TransparentOptions {
+                redirect,
+                tproxy,
+                socks,
+                out,
+                config_source,
+                control,
+                metrics,
+                lookup_processes,
+                connection_log,
+                privileges,
+            };
+            std::process::exit(run_transparent(options));
+        }
+        Some(Command::Ctl { socket, request }) => run_ctl(socket, request),
+        None => {
+            // the mtu is replaced by that of the tun once it is opened.
+            let config_source = ConfigSource {
+                path: args.config,
+                mtu: TunConfig::default().mtu,
+                shutdown: ShutdownConfig {
+                    mode: args.shutdown.into(),
+                    deadline: Duration::from_secs(args.drain_timeout),
+                },
+            };
+            let interface = (!args.skip_setup).then_some(Interface {
+                addresses: args.addresses,
+                routes: args.routes,
+                mtu: args.mtu,
+            });
+            let mut readiness = if args.daemon {
+                match daemon::daemonize(EXIT_START) {
+                    Ok(readiness) => readiness,
+                    Err(error) => {
+                        eprintln!("failed to daemonize, error={:?}", error);
+                        std::process::exit(EXIT_FAILURE);
+                    }
+                }
+            } else {
+                Readiness::foreground()
+            };
+            let options = TunOptions {
+                tun_name: args.tun.unwrap(),
+                out: args.out.unwrap(),
+                interface,
+                config_source,
+                control: args.control,
+                pid_file: args.pid_file,
+                metrics: args.metrics,
+                socks: args.socks,
+                lookup_processes: args.lookup_processes,
+                connection_log: args.connection_log,
+                privileges: args.privileges,
+            };
+            let code = run_tun(options, &mut readiness);
+            readiness.notify(code);
+            std::process::exit(code);
+        }
+    }
+}
 
-    let tun_name = &args.tun;
-    match TunTapInterface::new(tun_name, Medium::Ip) {
-        Ok(tun) => {
-            set_panic_handler();
+//
+// RUST_LOG takes the usual env_logger directives, e.g. core=debug, and bounds
+// what `ctl log-level` can enable; without it, records of any level reach the
+// logger and the maximum level starts at info so that it can be raised at
+// runtime.
+//
+fn init_logger() {
+    env_logger::Builder::from_env(Env::default().default_filter_or("trace")).init();
+    if std::env::var_os("RUST_LOG").is_none() {
+        log::set_max_level(LevelFilter::Info);
+    }
+}
 
-            tun::create();
-            tun::start(tun.as_raw_fd());
+//
+// How the tun interface is set up, unless it has been configured elsewhere.
+//
+struct Interface {
+    addresses: Vec<Network>,
+    routes: Vec<Network>,
+    mtu: usize,
+}
 
-            println!("Press any key to exit");
-            std::io::stdin().read_line(&mut String::new()).unwrap();
+struct TunOptions {
+    tun_name: String,
+    out: String,
+    interface: Option<Interface>,
+    config_source: ConfigSource,
+    control: Option<PathBuf>,
+    pid_file: Option<PathBuf>,
+    metrics: Option<SocketAddr>,
+    socks: Vec<SocketAddr>,
+    lookup_processes: bool,
+    connection_log: ConnectionLogArgs,
+    privileges: PrivilegeArgs,
+}
 
-            tun::stop();
-            tun::destroy();
+//
+// Runs until SIGINT or SIGTERM and returns the exit code; SIGHUP reloads the
+// configuration and SIGUSR1 logs the active sessions, also those of the socks
+// listeners next to the tun.
+//
+fn run_tun(options: TunOptions, readiness: &mut Readiness) -> i32 {
+    let TunOptions {
+        tun_name,
+        out,
+        interface,
+        mut config_source,
+        control,
+        pid_file,
+        metrics,
+        socks,
+        lookup_processes,
+        connection_log,
+        privileges,
+    } = options;
 
-            remove_panic_handler();
+    let signals = match Signals::block() {
+        Ok(signals) => signals,
+        Err(error) => {
+            eprintln!("failed to block signals, error={:?}", error);
+            return EXIT_FAILURE;
         }
+    };
+    let credentials = match privileges.credentials().transpose() {
+        Ok(credentials) => credentials,
+        Err(error) => {
+            eprintln!("failed to look up user, error={:?}", error);
+            return EXIT_FAILURE;
+        }
+    };
+
+    set_out_interface(Some(out.clone()));
+
+    // smoltcp panics on names which do not fit into an ifreq.
+    if tun_name.len() >= libc::IFNAMSIZ {
+        eprintln!("failed to attach to tun {:?}; name is too long", tun_name);
+        return EXIT_TUN;
+    }
+    let tun = match TunTapInterface::new(&tun_name, Medium::Ip) {
+        Ok(tun) => tun,
         Err(error) if error.kind() == std::io::ErrorKind::PermissionDenied => {
             eprintln!("failed to attach to tun {:?}; permission denied", tun_name);
+            return EXIT_TUN;
         }
         Err(_) => {
             eprintln!("failed to attach to tun {:?}", tun_name);
+            return EXIT_TUN;
+        }
+    };
+    let setup = match interface {
+        Some(interface) => {
+            match InterfaceSetup::apply(
+                &tun_name,
+                &out,
+                interface.mtu,
+                &interface.addresses,
+                &interface.routes,
+            ) {
+                Ok(setup) => {
+                    config_source.mtu = interface.mtu;
+                    Some(setup)
+                }
+                Err(error) => {
+                    eprintln!("failed to set up {}, error={:?}", tun_name, error);
+                    return EXIT_TUN;
+                }
+            }
+        }
+        None => {
+            config_source.mtu = tun.capabilities().max_transmission_unit;
+            None
         }
+    };
+    let config = match config_source.load() {
+        Ok(config) => config,
+        Err(error) => {
+            eprintln!("{}", error);
+            return EXIT_CONFIG;
+        }
+    };
+    let mut listeners = Listeners::new();
+    for address in socks.iter() {
+        match listeners.listen_socks(*address) {
+            Ok(address) => log::info!("accepting socks clients on {}", address),
+            Err(error) => {
+                eprintln!("failed to listen on {}, error={:?}", address, error);
+                return EXIT_START;
+            }
+        }
+    }
+    if lookup_processes {
+        tun_callbacks::set_process_lookup(Some(Arc::new(HostProcessLookup::new())));
+    }
+    // upstream sockets are bound to the output interface, and the addresses
+    // and routes which have been set up are removed on exit.
+    let capabilities: &[u32] = if setup.is_some() {
+        &[CAP_NET_RAW, CAP_NET_ADMIN]
+    } else {
+        &[CAP_NET_RAW]
+    };
+    if let Err(code) = privileges.drop_privileges(credentials.as_ref(), capabilities) {
+        return code;
+    }
+    // created as the user, who has to remove it on exit.
+    let _pid_file = match pid_file.map(|path| PidFile::create(&path)).transpose() {
+        Ok(pid_file) => pid_file,
+        Err(error) => {
+            eprintln!("failed to write pid file, error={:?}", error);
+            return EXIT_FAILURE;
+        }
+    };
+    // dropped after the vpn is destroyed, once the records of the last sessions are written.
+    let _connection_log = match connection_log.start().transpose() {
+        Ok(connection_log) => connection_log,
+        Err(error) => {
+            eprintln!("failed to open connection log, error={:?}", error);
+            return EXIT_FAILURE;
+        }
+    };
+
+    set_panic_handler();
+
+    tun::create();
+    tun::configure(config);
+    if let Err(error) = tun::start_with_listeners(tun.as_raw_fd(), listeners) {
+        eprintln!("failed to start vpn, error={:?}", error);
+        tun::destroy();
+        remove_panic_handler();
+        return EXIT_START;
+    }
+
+    if let Some(address) = metrics {
+        if let Err(error) = metrics::serve(address) {
+            eprintln!("failed to listen on {}, error={:?}", address, error);
+        }
+    }
+    let (exit_sender, exit_receiver) = channel();
+    let _control_server = serve_control(control, &config_source, &exit_sender);
+    thread::spawn(move || {
+        handle_signals(signals, config_source, move || {
+            let _ = exit_sender.send(());
+        })
+    });
+
+    log::info!("started vpn on {}", tun_name);
+    readiness.notify(0);
+    let _ = exit_receiver.recv();
+
+    tun::stop();
+    tun::destroy();
+
+    remove_panic_handler();
+    0
+}
+
+fn serve_control(
+    path: Option<PathBuf>,
+    config_source: &ConfigSource,
+    exit_sender: &Sender<()>,
+) -> Option<control::Server> {
+    let path = path?;
+    let reload = Box::new(tun::reload);
+    control::Server::start(&path, config_source.clone(), reload, exit_sender.clone())
+        .map_err(|error| {
+            eprintln!("failed to listen on {:?}, error={:?}", path, error);
+        })
+        .ok()
+}
+
+fn handle_signals(signals: Signals, config_source: ConfigSource, stop: impl Fn()) {
+    loop {
+        match signals.wait() {
+            Signal::Stop => {
+                log::info!("stopping vpn");
+                stop();
+            }
+            Signal::Reload => match config_source.load() {
+                Ok(config) => {
+                    log::info!("reloading configuration");
+                    tun::reload(config);
+                }
+                Err(error) => {
+                    log::error!("failed to reload configuration, error={}", error);
+                }
+            },
+            Signal::DumpSessions => {
+                let sessions = tun::sessions();
+                log::info!("sessions, count={}", sessions.len());
+                for session in sessions {
+                    log::info!("session={}", serde_json::to_string(&session).unwrap());
+                }
+            }
+        }
+    }
+}
+
+struct RunOptions {
+    out: Option<String>,
+    mtu: usize,
+    config_source: ConfigSource,
+    metrics: Option<SocketAddr>,
+    lookup_processes: bool,
+    connection_log: ConnectionLogArgs,
+}
+
+//
+// Runs the command inside a new network namespace routed through a tun, while
+// upstream sockets are created in the namespace of the host; stopping the vpn
+// with SIGINT or SIGTERM terminates the command. Returns the exit code of the
+// command, or 128 plus the signal which killed it like shells do.
+//
+fn run_command(options: RunOptions, command: &[String]) -> i32 {
+    let RunOptions {
+        out,
+        mtu,
+        config_source,
+        metrics,
+        lookup_processes,
+        connection_log,
+    } = options;
+
+    let signals = match Signals::block() {
+        Ok(signals) => signals,
+        Err(error) => {
+            eprintln!("failed to block signals, error={:?}", error);
+            return EXIT_FAILURE;
+        }
+    };
+
+    set_out_interface(out);
+
+    let addresses = NAMESPACE_ADDRESSES.map(|address| address.parse().unwrap());
+    let routes = NAMESPACE_ROUTES.map(|route| route.parse().unwrap());
+    let (namespace, tun) = match Namespace::create(NAMESPACE_TUN, mtu, &addresses, &routes) {
+        Ok(created) => created,
+        Err(error) => {
+            eprintln!("failed to create network namespace, error={:?}", error);
+            return EXIT_TUN;
+        }
+    };
+    if lookup_processes {
+        // the sockets of the namespace are only listed through sock_diag.
+        let sock_diag = match namespace.sock_diag() {
+            Ok(sock_diag) => sock_diag,
+            Err(error) => {
+                eprintln!("failed to open sock_diag, error={:?}", error);
+                return EXIT_FAILURE;
+            }
+        };
+        let lookup = HostProcessLookup::with_sock_diag(Some(sock_diag), None);
+        tun_callbacks::set_process_lookup(Some(Arc::new(lookup)));
+    }
+    let config = match config_source.load() {
+        Ok(config) => config,
+        Err(error) => {
+            eprintln!("{}", error);
+            return EXIT_CONFIG;
+        }
+    };
+    let _connection_log = match connection_log.start().transpose() {
+        Ok(connection_log) => connection_log,
+        Err(error) => {
+            eprintln!("failed to open connection log, error={:?}", error);
+            return EXIT_FAILURE;
+        }
+    };
+    if let Some(address) = metrics {
+        if let Err(error) = metrics::serve(address) {
+            eprintln!("failed to listen on {}, error={:?}", address, error);
+        }
+    }
+
+    set_panic_handler();
+
+    tun::create();
+    tun::configure(config);
+    // the vpn owns the tun from here on and closes it when stopped.
+    if let Err(error) = tun::start(tun.into_raw_fd()) {
+        eprintln!("failed to start vpn, error={:?}", error);
+        tun::destroy();
+        remove_panic_handler();
+        return EXIT_START;
+    }
+
+    let mut child = namespace.command(&command[0], &command[1..]);
+    unsafe {
+        child.pre_exec(Signals::unblock);
+    }
+    let code = match child.spawn() {
+        Ok(mut child) => {
+            let pid = child.id() as libc::pid_t;
+            thread::spawn(move || {
+                handle_signals(signals, config_source, move || unsafe {
+                    libc::kill(pid, libc::SIGTERM);
+                })
+            });
+            match child.wait() {
+                Ok(status) => status
+                    .code()
+                    .unwrap_or_else(|| 128 + status.signal().unwrap_or(0)),
+                Err(error) => {
+                    eprintln!("failed to wait for {:?}, error={:?}", command[0], error);
+                    EXIT_FAILURE
+                }
+            }
+        }
+        Err(error) => {
+            eprintln!("failed to run {:?}, error={:?}", command[0], error);
+            EXIT_START
+        }
+    };
+
+    tun::stop();
+    tun::destroy();
+
+    remove_panic_handler();
+    code
+}
+
+struct TransparentOptions {
+    redirect: Vec<SocketAddr>,
+    tproxy: Vec<SocketAddr>,
+    socks: Vec<SocketAddr>,
+    out: Option<String>,
+    config_source: ConfigSource,
+    control: Option<PathBuf>,
+    metrics: Option<SocketAddr>,
+    lookup_processes: bool,
+    connection_log: ConnectionLogArgs,
+    privileges: PrivilegeArgs,
+}
+
+//
+// Runs the transparent proxy until SIGINT or SIGTERM and returns the exit
+// code; SIGHUP reloads the configuration and SIGUSR1 logs the active sessions,
+// as do requests on the control socket.
+//
+fn run_transparent(options: TransparentOptions) -> i32 {
+    let TransparentOptions {
+        redirect,
+        tproxy,
+        socks,
+        out,
+        config_source,
+        control,
+        metrics,
+        lookup_processes,
+        connection_log,
+        privileges,
+    } = options;
+
+    let signals = match Signals::block() {
+        Ok(signals) => signals,
+        Err(error) => {
+            eprintln!("failed to block signals, error={:?}", error);
+            return EXIT_FAILURE;
+        }
+    };
+    let credentials = match privileges.credentials().transpose() {
+        Ok(credentials) => credentials,
+        Err(error) => {
+            eprintln!("failed to look up user, error={:?}", error);
+            return EXIT_FAILURE;
+        }
+    };
+    // upstream sockets bound to the output interface and reply sockets of tproxy need it.
+    let capabilities: &[u32] = if out.is_some() || !tproxy.is_empty() {
+        &[CAP_NET_RAW]
+    } else {
+        &[]
+    };
+
+    set_out_interface(out);
+
+    let config = match config_source.load() {
+        Ok(config) => config,
+        Err(error) => {
+            eprintln!("{}", error);
+            return EXIT_CONFIG;
+        }
+    };
+    let mut listeners = Listeners::new();
+    for address in redirect.iter() {
+        if let Err(error) = listeners.listen_redirect(*address) {
+            eprintln!("failed to listen on {}, error={:?}", address, error);
+            return EXIT_START;
+        }
+    }
+    for address in tproxy.iter() {
+        if let Err(error) = listeners.listen_tproxy(*address) {
+            eprintln!("failed to listen on {}, error={:?}", address, error);
+            return EXIT_START;
+        }
+    }
+    for address in socks.iter() {
+        match listeners.listen_socks(*address) {
+            Ok(address) => log::info!("accepting socks clients on {}", address),
+            Err(error) => {
+                eprintln!("failed to listen on {}, error={:?}", address, error);
+                return EXIT_START;
+            }
+        }
+    }
+    if lookup_processes {
+        tun_callbacks::set_process_lookup(Some(Arc::new(HostProcessLookup::new())));
+    }
+    if let Err(code) = privileges.drop_privileges(credentials.as_ref(), capabilities) {
+        return code;
+    }
+    let _connection_log = match connection_log.start().transpose() {
+        Ok(connection_log) => connection_log,
+        Err(error) => {
+            eprintln!("failed to open connection log, error={:?}", error);
+            return EXIT_FAILURE;
+        }
+    };
+    if let Some(address) = metrics {
+        if let Err(error) = metrics::serve(address) {
+            eprintln!("failed to listen on {}, error={:?}", address, error);
+        }
+    }
+
+    set_panic_handler();
+
+    tun::create();
+    tun::configure(config);
+    if let Err(error) = tun::start_listeners(listeners) {
+        eprintln!("failed to start transparent proxy, error={:?}", error);
+        tun::destroy();
+        remove_panic_handler();
+        return EXIT_START;
+    }
+
+    let (exit_sender, exit_receiver) = channel();
+    let _control_server = serve_control(control, &config_source, &exit_sender);
+    thread::spawn(move || {
+        handle_signals(signals, config_source, move || {
+            let _ = exit_sender.send(());
+        })
+    });
+
+    log::info!("started transparent proxy");
+    let _ = exit_receiver.recv();
+
+    tun::stop();
+    tun::destroy();
+
+    remove_panic_handler();
+    0
+}
+
+fn run_ctl(socket: PathBuf, request: control::Request) {
+    // paths are resolved by the running instance, which may run elsewhere.
+    let request = match request {
+        control::Request::CaptureStart { path } => control::Request::CaptureStart {
+            path: std::path::absolute(&path).unwrap_or(path),
+        },
+        request => request,
+    };
+    match control::request(&socket, &request) {
+        Ok(response) if response.ok => {
+            if let Some(result) = response.result {
+                println!("{}", serde_json::to_string_pretty(&result).unwrap());
+            }
+        }
+        Ok(response) => {
+            eprintln!("{}", response.error.unwrap_or_default());
+            std::process::exit(1);
+        }
+        Err(error) => {
+            eprintln!("{}", error);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn run_relay(
+    listen: SocketAddr,
+    out: Option<String>,
+    metrics: Option<SocketAddr>,
+    connection_log: ConnectionLogArgs,
+) {
+    set_out_interface(out);
+
+    let _connection_log = match connection_log.start().transpose() {
+        Ok(connection_log) => connection_log,
+        Err(error) => {
+            eprintln!("failed to open connection log, error={:?}", error);
+            return;
+        }
+    };
+
+    if let Some(address) = metrics {
+        if let Err(error) = metrics::serve(address) {
+            eprintln!("failed to listen on {}, error={:?}", address, error);
+        }
+    }
+
+    let listener = match TcpListener::bind(listen) {
+        Ok(listener) => listener,
+        Err(error) => {
+            eprintln!("failed to listen on {:?}, error={:?}", listen, error);
+            return;
+        }
+    };
+
+    println!("Waiting for relay connections on {}", listen);
+
+    set_panic_handler();
+    tun::create();
+
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                println!("Relaying packets from {:?}", stream.peer_addr());
+                match tun::start_relay(stream) {
+                    Ok(_) => {
+                        tun::wait();
+                        tun::stop();
+                        println!("Relay connection closed");
+                    }
+                    Err(error) => {
+                        eprintln!("failed to start relay, error={:?}", error);
+                    }
+                }
+            }
+            Err(error) => {
+                eprintln!("failed to accept relay connection, error={:?}", error);
+            }
+        }
+    }
+
+    tun::destroy();
+    remove_panic_handler();
+}
+
+fn set_out_interface(out: Option<String>) {
+    if let Some(out) = out {
+        OUT_INTERFACE.set(CString::new(out).unwrap()).unwrap();
+        tun_callbacks::set_socket_created_callback(Some(on_socket_created));
     }
 }
 
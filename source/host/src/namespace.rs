@@ -0,0 +1,121 @@
+// This is free and unencumbered software released into the public domain.
+//
+// Anyone is free to copy, modify, publish, use, compile, sell, or
+// distribute this software, either in source code form or as a compiled
+// binary, for any purpose, commercial or non-commercial, and by any
+// means.
+//
+// In jurisdictions that recognize copyright laws, the author or authors
+// of this software dedicate any and all copyright interest in the
+// software to the public domain. We make this dedication for the benefit
+// of the public at large and to the detriment of our heirs and
+// successors. We intend this dedication to be an overt act of
+// relinquishment in perpetuity of all present and future rights to this
+// software under copyright law.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS BE LIABLE FOR ANY CLAIM, DAMAGES OR
+// OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE,
+// ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR
+// OTHER DEALINGS IN THE SOFTWARE.
+//
+// For more information, please refer to <https://unlicense.org>
+
+use crate::netlink::Netlink;
+use smoltcp::phy::{Medium, TunTapInterface};
+use std::fs::File;
+use std::io::{Error, Result};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+use std::thread;
+use vpn_core::config::Network;
+
+//
+// A network namespace of its own whose only route is a tun. Namespaces are a
+// property of threads, so the namespace is created and set up on a thread which
+// then ends; the rest of the process, including the processor and therefore
+// its upstream sockets, stays in the parent namespace. The namespace, along with
+// the tun, goes away once the tun is closed and no process is left in it.
+//
+pub(crate) struct Namespace {
+    namespace: File,
+}
+
+impl Namespace {
+    pub(crate) fn create(
+        tun_name: &str,
+        mtu: usize,
+        addresses: &[Network],
+        routes: &[Network],
+    ) -> Result<(Namespace, OwnedFd)> {
+        let tun_name = tun_name.to_string();
+        let addresses = addresses.to_vec();
+        let routes = routes.to_vec();
+        thread::spawn(move || {
+            if unsafe { libc::unshare(libc::CLONE_NEWNET) } == -1 {
+                return Err(Error::last_os_error());
+            }
+            let namespace = File::open("/proc/thread-self/ns/net")?;
+            let tun = TunTapInterface::new(&tun_name, Medium::Ip)?;
+            // the interface of smoltcp cannot leave this thread, the descriptor can.
+            let tun = match unsafe { libc::fcntl(tun.as_raw_fd(), libc::F_DUPFD_CLOEXEC, 0) } {
+                -1 => return Err(Error::last_os_error()),
+                tun => unsafe { OwnedFd::from_raw_fd(tun) },
+            };
+
+            let mut netlink = Netlink::new()?;
+            netlink.set_link(Netlink::link_index("lo")?, None, true)?;
+            let index = Netlink::link_index(&tun_name)?;
+            netlink.set_link(index, Some(mtu as u32), true)?;
+            for address in &addresses {
+                netlink.add_address(index, address)?;
+            }
+            for route in &routes {
+                netlink.add_route(index, route)?;
+            }
+            Ok((Namespace { namespace }, tun))
+        })
+        .join()
+        .unwrap()
+    }
+
+    //
+    // Opens a sock_diag socket listing the sockets of the namespace, on a thread
+    // which enters it and then ends.
+    //
+    pub(crate) fn sock_diag(&self) -> Result<Netlink> {
+        let namespace = self.namespace.as_raw_fd();
+        thread::scope(|scope| {
+            scope
+                .spawn(|| {
+                    if unsafe { libc::setns(namespace, libc::CLONE_NEWNET) } == -1 {
+                        return Err(Error::last_os_error());
+                    }
+                    Netlink::new_sock_diag()
+                })
+                .join()
+                .unwrap()
+        })
+    }
+
+    //
+    // The command is moved into the namespace between fork and exec.
+    //
+    pub(crate) fn command(&self, program: &str, arguments: &[String]) -> Command {
+        let namespace = self.namespace.as_raw_fd();
+        let mut command = Command::new(program);
+        command.args(arguments);
+        unsafe {
+            command.pre_exec(move || {
+                if libc::setns(namespace, libc::CLONE_NEWNET) == -1 {
+                    return Err(Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        command
+    }
+}
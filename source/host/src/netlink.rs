@@ -0,0 +1,625 @@
+// This is free and unencumbered software released into the public domain.
+//
+// Anyone is free to copy, modify, publish, use, compile, sell, or
+// distribute this software, either in source code form or as a compiled
+// binary, for any purpose, commercial or non-commercial, and by any
+// means.
+//
+// In jurisdictions that recognize copyright laws, the author or authors
+// of this software dedicate any and all copyright interest in the
+// software to the public domain. We make this dedication for the benefit
+// of the public at large and to the detriment of our heirs and
+// successors. We intend this dedication to be an overt act of
+// relinquishment in perpetuity of all present and future rights to this
+// software under copyright law.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS BE LIABLE FOR ANY CLAIM, DAMAGES OR
+// OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE,
+// ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR
+// OTHER DEALINGS IN THE SOFTWARE.
+//
+// For more information, please refer to <https://unlicense.org>
+
+use std::{
+    ffi::CString,
+    io::{Error, ErrorKind, Result},
+    mem::size_of,
+    net::IpAddr,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+};
+use vpn_core::config::Network;
+
+const NLMSG_HEADER_LENGTH: usize = 16;
+const NLMSG_ERROR_LENGTH: usize = NLMSG_HEADER_LENGTH + 4;
+const RTMSG_LENGTH: usize = 12;
+const RECEIVE_BUFFER_LENGTH: usize = 8192;
+// dumps are sent in messages of up to a page or so, which must not be truncated.
+const DUMP_BUFFER_LENGTH: usize = 32768;
+
+// rtnetlink constants not exported by libc for every target.
+const IFA_F_NODAD: u8 = 0x02;
+
+//
+// Minimal rtnetlink client issuing one request at a time and waiting for its
+// acknowledgement; sock_diag sockets are dumped instead.
+//
+pub(crate) struct Netlink {
+    socket: OwnedFd,
+    sequence: u32,
+}
+
+impl Netlink {
+    pub(crate) fn new() -> Result<Netlink> {
+        Self::open(libc::NETLINK_ROUTE)
+    }
+
+    //
+    // The sockets listed are those of the network namespace of the thread
+    // opening the socket.
+    //
+    pub(crate) fn new_sock_diag() -> Result<Netlink> {
+        Self::open(libc::NETLINK_SOCK_DIAG)
+    }
+
+    fn open(protocol: libc::c_int) -> Result<Netlink> {
+        let socket = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                protocol,
+            )
+        };
+        if socket == -1 {
+            return Err(Error::last_os_error());
+        }
+        let socket = unsafe { OwnedFd::from_raw_fd(socket) };
+
+        let mut address: libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+        address.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        let result = unsafe {
+            libc::bind(
+                socket.as_raw_fd(),
+                &address as *const libc::sockaddr_nl as *const libc::sockaddr,
+                size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        };
+        if result == -1 {
+            return Err(Error::last_os_error());
+        }
+        Ok(Netlink {
+            socket,
+            sequence: 0,
+        })
+    }
+
+    pub(crate) fn link_index(name: &str) -> Result<u32> {
+        let name =
+            CString::new(name).map_err(|error| Error::new(ErrorKind::InvalidInput, error))?;
+        match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+            0 => Err(Error::last_os_error()),
+            index => Ok(index),
+        }
+    }
+
+    pub(crate) fn set_link(&mut self, index: u32, mtu: Option<u32>, is_up: bool) -> Result<()> {
+        self.request(libc::RTM_NEWLINK, 0, Self::link_message(index, mtu, is_up))
+    }
+
+    fn link_message(index: u32, mtu: Option<u32>, is_up: bool) -> Message {
+        let mut message = Message::new();
+        // struct ifinfomsg.
+        message.push_u8(libc::AF_UNSPEC as u8);
+        message.push_u8(0);
+        message.push_u16(0);
+        message.push_u32(index);
+        message.push_u32(if is_up { libc::IFF_UP as u32 } else { 0 });
+        message.push_u32(libc::IFF_UP as u32);
+        if let Some(mtu) = mtu {
+            message.push_attribute(libc::IFLA_MTU, &mtu.to_ne_bytes());
+        }
+        message
+    }
+
+    pub(crate) fn add_address(&mut self, index: u32, network: &Network) -> Result<()> {
+        let flags = (libc::NLM_F_CREATE | libc::NLM_F_EXCL) as u16;
+        self.request(
+            libc::RTM_NEWADDR,
+            flags,
+            Self::address_message(index, network),
+        )
+    }
+
+    pub(crate) fn delete_address(&mut self, index: u32, network: &Network) -> Result<()> {
+        self.request(libc::RTM_DELADDR, 0, Self::address_message(index, network))
+    }
+
+    pub(crate) fn add_route(&mut self, index: u32, network: &Network) -> Result<()> {
+        let flags = (libc::NLM_F_CREATE | libc::NLM_F_EXCL) as u16;
+        self.request(
+            libc::RTM_NEWROUTE,
+            flags,
+            Self::route_message(index, network),
+        )
+    }
+
+    pub(crate) fn delete_route(&mut self, index: u32, network: &Network) -> Result<()> {
+        self.request(libc::RTM_DELROUTE, 0, Self::route_message(index, network))
+    }
+
+    //
+    // Lists the unicast routes of the main table.
+    //
+    pub(crate) fn routes(&mut self) -> Result<Vec<Route>> {
+        let mut message = Message::new();
+        message.push_u8(libc::AF_UNSPEC as u8);
+        message.push_bytes(&[0; RTMSG_LENGTH - 1]);
+        let payloads = self.dump(libc::RTM_GETROUTE, message)?;
+        Ok(payloads
+            .iter()
+            .filter_map(|payload| route(payload))
+            .collect())
+    }
+
+    fn address_message(index: u32, network: &Network) -> Message {
+        let mut message = Message::new();
+        // struct ifaddrmsg; duplicate address detection would delay using ipv6 addresses.
+        message.push_u8(family(&network.address));
+        message.push_u8(network.prefix_length);
+        message.push_u8(IFA_F_NODAD);
+        message.push_u8(libc::RT_SCOPE_UNIVERSE);
+        message.push_u32(index);
+        let address = address_bytes(&network.address);
+        if network.address.is_ipv4() {
+            message.push_attribute(libc::IFA_LOCAL, &address);
+        }
+        message.push_attribute(libc::IFA_ADDRESS, &address);
+        message
+    }
+
+    fn route_message(index: u32, network: &Network) -> Message {
+        let mut message = Message::new();
+        // struct rtmsg.
+        message.push_u8(family(&network.address));
+        message.push_u8(network.prefix_length);
+        message.push_u8(0);
+        message.push_u8(0);
+        message.push_u8(libc::RT_TABLE_MAIN);
+        message.push_u8(libc::RTPROT_BOOT);
+        message.push_u8(libc::RT_SCOPE_LINK);
+        message.push_u8(libc::RTN_UNICAST);
+        message.push_u32(0);
+        message.push_attribute(libc::RTA_DST, &address_bytes(&network.address));
+        message.push_attribute(libc::RTA_OIF, &index.to_ne_bytes());
+        message
+    }
+
+    fn request(&mut self, message_type: u16, flags: u16, message: Message) -> Result<()> {
+        let flags = flags | (libc::NLM_F_REQUEST | libc::NLM_F_ACK) as u16;
+        self.send(message_type, flags, message)?;
+        self.receive_acknowledgement()
+    }
+
+    //
+    // Returns the payloads of the messages answering a dump request.
+    //
+    pub(crate) fn dump(&mut self, message_type: u16, message: Message) -> Result<Vec<Vec<u8>>> {
+        let flags = (libc::NLM_F_REQUEST | libc::NLM_F_DUMP) as u16;
+        self.send(message_type, flags, message)?;
+
+        let mut buffer = vec![0u8; DUMP_BUFFER_LENGTH];
+        let mut payloads = Vec::new();
+        loop {
+            let count = self.receive(&mut buffer)?;
+            let mut messages = &buffer[..count];
+            while messages.len() >= NLMSG_HEADER_LENGTH {
+                let (length, message_type, sequence) = header(messages)?;
+                if sequence == self.sequence {
+                    if message_type == libc::NLMSG_DONE as u16 {
+                        return Ok(payloads);
+                    }
+                    if message_type == libc::NLMSG_ERROR as u16 && length >= NLMSG_ERROR_LENGTH {
+                        let code = i32::from_ne_bytes(messages[16..20].try_into().unwrap());
+                        return Err(Error::from_raw_os_error(-code));
+                    }
+                    payloads.push(messages[NLMSG_HEADER_LENGTH..length].to_vec());
+                }
+                messages = &messages[align(length).min(messages.len())..];
+            }
+        }
+    }
+
+    fn send(&mut self, message_type: u16, flags: u16, message: Message) -> Result<()> {
+        self.sequence = self.sequence.wrapping_add(1);
+        let bytes = message.encode(message_type, flags, self.sequence);
+        let result = unsafe {
+            libc::send(
+                self.socket.as_raw_fd(),
+                bytes.as_ptr() as *const libc::c_void,
+                bytes.len(),
+                0,
+            )
+        };
+        if result == -1 {
+            return Err(Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn receive(&mut self, buffer: &mut [u8]) -> Result<usize> {
+        let count = unsafe {
+            libc::recv(
+                self.socket.as_raw_fd(),
+                buffer.as_mut_ptr() as *mut libc::c_void,
+                buffer.len(),
+                0,
+            )
+        };
+        if count == -1 {
+            return Err(Error::last_os_error());
+        }
+        Ok(count as usize)
+    }
+
+    fn receive_acknowledgement(&mut self) -> Result<()> {
+        let mut buffer = vec![0u8; RECEIVE_BUFFER_LENGTH];
+        loop {
+            let count = self.receive(&mut buffer)?;
+            let mut messages = &buffer[..count];
+            while messages.len() >= NLMSG_HEADER_LENGTH {
+                let (length, message_type, sequence) = header(messages)?;
+                if sequence == self.sequence
+                    && message_type == libc::NLMSG_ERROR as u16
+                    && length >= NLMSG_ERROR_LENGTH
+                {
+                    let code = i32::from_ne_bytes(messages[16..20].try_into().unwrap());
+                    return match code {
+                        0 => Ok(()),
+                        code => Err(Error::from_raw_os_error(-code)),
+                    };
+                }
+                messages = &messages[align(length).min(messages.len())..];
+            }
+        }
+    }
+}
+
+pub(crate) struct Route {
+    pub(crate) network: Network,
+    pub(crate) gateway: Option<IpAddr>,
+    pub(crate) interface: u32,
+}
+
+// struct rtmsg followed by its attributes.
+fn route(payload: &[u8]) -> Option<Route> {
+    if payload.len() < RTMSG_LENGTH || payload[7] != libc::RTN_UNICAST {
+        return None;
+    }
+    let family = i32::from(payload[0]);
+    let address = |value: &[u8]| match (family, value.len()) {
+        (libc::AF_INET, 4) => Some(IpAddr::from(<[u8; 4]>::try_from(value).unwrap())),
+        (libc::AF_INET6, 16) => Some(IpAddr::from(<[u8; 16]>::try_from(value).unwrap())),
+        _ => None,
+    };
+    let mut destination = match family {
+        libc::AF_INET => IpAddr::from([0u8; 4]),
+        libc::AF_INET6 => IpAddr::from([0u8; 16]),
+        _ => return None,
+    };
+    let mut table = u32::from(payload[4]);
+    let mut gateway = None;
+    let mut interface = None;
+    let mut attributes = &payload[RTMSG_LENGTH..];
+    while attributes.len() >= 4 {
+        let length = usize::from(u16::from_ne_bytes(attributes[0..2].try_into().unwrap()));
+        let attribute_type = u16::from_ne_bytes(attributes[2..4].try_into().unwrap());
+        if length < 4 || length > attributes.len() {
+            return None;
+        }
+        let value = &attributes[4..length];
+        match attribute_type {
+            libc::RTA_DST => destination = address(value)?,
+            libc::RTA_GATEWAY => gateway = address(value),
+            libc::RTA_OIF => interface = Some(u32::from_ne_bytes(value.try_into().ok()?)),
+            libc::RTA_TABLE => table = u32::from_ne_bytes(value.try_into().ok()?),
+            _ => {}
+        }
+        attributes = &attributes[align(length).min(attributes.len())..];
+    }
+    if table != u32::from(libc::RT_TABLE_MAIN) {
+        return None;
+    }
+    Some(Route {
+        network: Network::new(destination, payload[1]),
+        gateway,
+        interface: interface?,
+    })
+}
+
+pub(crate) struct Message {
+    bytes: Vec<u8>,
+}
+
+impl Message {
+    pub(crate) fn new() -> Message {
+        Message { bytes: Vec::new() }
+    }
+
+    pub(crate) fn push_u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    fn push_u16(&mut self, value: u16) {
+        self.bytes.extend_from_slice(&value.to_ne_bytes());
+    }
+
+    pub(crate) fn push_u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_ne_bytes());
+    }
+
+    pub(crate) fn push_bytes(&mut self, value: &[u8]) {
+        self.bytes.extend_from_slice(value);
+    }
+
+    // struct nlmsghdr followed by the message; the kernel fills in the port id.
+    fn encode(&self, message_type: u16, flags: u16, sequence: u32) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(NLMSG_HEADER_LENGTH + self.bytes.len());
+        bytes.extend_from_slice(&((NLMSG_HEADER_LENGTH + self.bytes.len()) as u32).to_ne_bytes());
+        bytes.extend_from_slice(&message_type.to_ne_bytes());
+        bytes.extend_from_slice(&flags.to_ne_bytes());
+        bytes.extend_from_slice(&sequence.to_ne_bytes());
+        bytes.extend_from_slice(&0u32.to_ne_bytes());
+        bytes.extend_from_slice(&self.bytes);
+        bytes
+    }
+
+    // struct rtattr followed by the value, padded to four bytes.
+    fn push_attribute(&mut self, attribute_type: u16, value: &[u8]) {
+        let length = 4 + value.len();
+        self.push_u16(length as u16);
+        self.push_u16(attribute_type);
+        self.bytes.extend_from_slice(value);
+        self.bytes.resize(align(self.bytes.len()), 0);
+    }
+}
+
+// returns the length, type and sequence of the first message.
+fn header(messages: &[u8]) -> Result<(usize, u16, u32)> {
+    let length = u32::from_ne_bytes(messages[0..4].try_into().unwrap()) as usize;
+    let message_type = u16::from_ne_bytes(messages[4..6].try_into().unwrap());
+    let sequence = u32::from_ne_bytes(messages[8..12].try_into().unwrap());
+    if length < NLMSG_HEADER_LENGTH || length > messages.len() {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "invalid netlink message",
+        ));
+    }
+    Ok((length, message_type, sequence))
+}
+
+fn align(length: usize) -> usize {
+    (length + 3) & !3
+}
+
+fn family(address: &IpAddr) -> u8 {
+    match address {
+        IpAddr::V4(_) => libc::AF_INET as u8,
+        IpAddr::V6(_) => libc::AF_INET6 as u8,
+    }
+}
+
+fn address_bytes(address: &IpAddr) -> Vec<u8> {
+    match address {
+        IpAddr::V4(address) => address.octets().to_vec(),
+        IpAddr::V6(address) => address.octets().to_vec(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn network(network: &str) -> Network {
+        network.parse().unwrap()
+    }
+
+    // struct rtattr in native byte order followed by the padded value.
+    fn attribute(attribute_type: u16, value: &[u8]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&((4 + value.len()) as u16).to_ne_bytes());
+        bytes.extend_from_slice(&attribute_type.to_ne_bytes());
+        bytes.extend_from_slice(value);
+        bytes.resize(align(bytes.len()), 0);
+        bytes
+    }
+
+    fn rtmsg(family: i32, prefix_length: u8, table: u8, route_type: u8) -> Vec<u8> {
+        let mut bytes = vec![family as u8, prefix_length, 0, 0, table, 0, 0, route_type];
+        bytes.extend_from_slice(&0u32.to_ne_bytes());
+        bytes
+    }
+
+    #[test]
+    fn attributes_are_padded_to_four_bytes() {
+        let mut message = Message::new();
+        message.push_attribute(1, &[1, 2, 3, 4, 5]);
+        message.push_attribute(2, &[6]);
+
+        let mut expected = attribute(1, &[1, 2, 3, 4, 5]);
+        expected.extend(attribute(2, &[6]));
+        assert_eq!(expected.len(), 20);
+        assert_eq!(message.bytes, expected);
+    }
+
+    #[test]
+    fn header_precedes_the_message() {
+        let mut message = Message::new();
+        message.push_u32(7);
+
+        let bytes = message.encode(libc::RTM_NEWROUTE, 0x305, 42);
+
+        assert_eq!(bytes.len(), 20);
+        assert_eq!(
+            header(&bytes).unwrap(),
+            (20, libc::RTM_NEWROUTE, 42),
+            "length, type and sequence"
+        );
+        assert_eq!(u16::from_ne_bytes(bytes[6..8].try_into().unwrap()), 0x305);
+        assert_eq!(&bytes[12..16], &[0; 4]);
+        assert_eq!(&bytes[16..20], &7u32.to_ne_bytes());
+    }
+
+    #[test]
+    fn truncated_headers_are_rejected() {
+        let mut message = Message::new();
+        message.push_u32(7);
+        let bytes = message.encode(libc::RTM_NEWROUTE, 0, 1);
+
+        assert!(header(&bytes[..18]).is_err());
+        let mut short = bytes.clone();
+        short[0..4].copy_from_slice(&8u32.to_ne_bytes());
+        assert!(header(&short).is_err());
+    }
+
+    #[test]
+    fn link_message_sets_mtu_and_state() {
+        let message = Netlink::link_message(3, Some(1400), true);
+
+        let mut expected = vec![libc::AF_UNSPEC as u8, 0, 0, 0];
+        expected.extend_from_slice(&3u32.to_ne_bytes());
+        expected.extend_from_slice(&(libc::IFF_UP as u32).to_ne_bytes());
+        expected.extend_from_slice(&(libc::IFF_UP as u32).to_ne_bytes());
+        expected.extend(attribute(libc::IFLA_MTU, &1400u32.to_ne_bytes()));
+        assert_eq!(message.bytes, expected);
+
+        let message = Netlink::link_message(3, None, false);
+        assert_eq!(message.bytes.len(), 16);
+        assert_eq!(&message.bytes[8..12], &0u32.to_ne_bytes());
+    }
+
+    #[test]
+    fn ipv4_address_message_has_local_address() {
+        let message = Netlink::address_message(5, &network("10.0.0.2/24"));
+
+        let mut expected = vec![
+            libc::AF_INET as u8,
+            24,
+            IFA_F_NODAD,
+            libc::RT_SCOPE_UNIVERSE,
+        ];
+        expected.extend_from_slice(&5u32.to_ne_bytes());
+        expected.extend(attribute(libc::IFA_LOCAL, &[10, 0, 0, 2]));
+        expected.extend(attribute(libc::IFA_ADDRESS, &[10, 0, 0, 2]));
+        assert_eq!(message.bytes, expected);
+    }
+
+    #[test]
+    fn ipv6_address_message_has_no_local_address() {
+        let address = network("fd00::2/128");
+        let message = Netlink::address_message(5, &address);
+
+        let mut expected = vec![
+            libc::AF_INET6 as u8,
+            128,
+            IFA_F_NODAD,
+            libc::RT_SCOPE_UNIVERSE,
+        ];
+        expected.extend_from_slice(&5u32.to_ne_bytes());
+        expected.extend(attribute(
+            libc::IFA_ADDRESS,
+            &address_bytes(&address.address),
+        ));
+        assert_eq!(message.bytes, expected);
+    }
+
+    #[test]
+    fn route_message_routes_network_through_interface() {
+        let message = Netlink::route_message(7, &network("128.0.0.0/1"));
+
+        let mut expected = vec![
+            libc::AF_INET as u8,
+            1,
+            0,
+            0,
+            libc::RT_TABLE_MAIN,
+            libc::RTPROT_BOOT,
+            libc::RT_SCOPE_LINK,
+            libc::RTN_UNICAST,
+        ];
+        expected.extend_from_slice(&0u32.to_ne_bytes());
+        expected.extend(attribute(libc::RTA_DST, &[128, 0, 0, 0]));
+        expected.extend(attribute(libc::RTA_OIF, &7u32.to_ne_bytes()));
+        assert_eq!(message.bytes, expected);
+    }
+
+    #[test]
+    fn default_route_is_parsed() {
+        let mut payload = rtmsg(libc::AF_INET, 0, libc::RT_TABLE_MAIN, libc::RTN_UNICAST);
+        payload.extend(attribute(libc::RTA_GATEWAY, &[192, 168, 1, 1]));
+        payload.extend(attribute(libc::RTA_OIF, &2u32.to_ne_bytes()));
+
+        let route = route(&payload).unwrap();
+
+        assert_eq!(route.network, network("0.0.0.0/0"));
+        assert_eq!(route.gateway, Some("192.168.1.1".parse().unwrap()));
+        assert_eq!(route.interface, 2);
+    }
+
+    #[test]
+    fn connected_route_is_parsed() {
+        let mut payload = rtmsg(libc::AF_INET6, 64, libc::RT_TABLE_MAIN, libc::RTN_UNICAST);
+        payload.extend(attribute(libc::RTA_TABLE, &254u32.to_ne_bytes()));
+        payload.extend(attribute(
+            libc::RTA_DST,
+            &address_bytes(&"2001:db8::".parse().unwrap()),
+        ));
+        payload.extend(attribute(libc::RTA_OIF, &3u32.to_ne_bytes()));
+
+        let route = route(&payload).unwrap();
+
+        assert_eq!(route.network, network("2001:db8::/64"));
+        assert_eq!(route.gateway, None);
+        assert_eq!(route.interface, 3);
+    }
+
+    #[test]
+    fn routes_of_other_tables_and_types_are_skipped() {
+        let oif = attribute(libc::RTA_OIF, &2u32.to_ne_bytes());
+
+        let mut local = rtmsg(libc::AF_INET, 32, libc::RT_TABLE_LOCAL, libc::RTN_LOCAL);
+        local.extend(&oif);
+        assert!(route(&local).is_none());
+
+        let mut table = rtmsg(libc::AF_INET, 0, libc::RT_TABLE_COMPAT, libc::RTN_UNICAST);
+        table.extend(attribute(libc::RTA_TABLE, &1000u32.to_ne_bytes()));
+        table.extend(&oif);
+        assert!(route(&table).is_none());
+
+        let mut unreachable = rtmsg(
+            libc::AF_INET6,
+            0,
+            libc::RT_TABLE_MAIN,
+            libc::RTN_UNREACHABLE,
+        );
+        unreachable.extend(&oif);
+        assert!(route(&unreachable).is_none());
+    }
+
+    #[test]
+    fn malformed_routes_are_skipped() {
+        let mut payload = rtmsg(libc::AF_INET, 0, libc::RT_TABLE_MAIN, libc::RTN_UNICAST);
+        // attribute longer than the payload.
+        payload.extend_from_slice(&32u16.to_ne_bytes());
+        payload.extend_from_slice(&libc::RTA_OIF.to_ne_bytes());
+        payload.extend_from_slice(&2u32.to_ne_bytes());
+        assert!(route(&payload).is_none());
+
+        assert!(route(&payload[..8]).is_none());
+
+        // without an output interface.
+        let payload = rtmsg(libc::AF_INET, 0, libc::RT_TABLE_MAIN, libc::RTN_UNICAST);
+        assert!(route(&payload).is_none());
+    }
+}
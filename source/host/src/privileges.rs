@@ -0,0 +1,513 @@
+// This is free and unencumbered software released into the public domain.
+//
+// Anyone is free to copy, modify, publish, use, compile, sell, or
+// distribute this software, either in source code form or as a compiled
+// binary, for any purpose, commercial or non-commercial, and by any
+// means.
+//
+// In jurisdictions that recognize copyright laws, the author or authors
+// of this software dedicate any and all copyright interest in the
+// software to the public domain. We make this dedication for the benefit
+// of the public at large and to the detriment of our heirs and
+// successors. We intend this dedication to be an overt act of
+// relinquishment in perpetuity of all present and future rights to this
+// software under copyright law.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS BE LIABLE FOR ANY CLAIM, DAMAGES OR
+// OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE,
+// ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR
+// OTHER DEALINGS IN THE SOFTWARE.
+//
+// For more information, please refer to <https://unlicense.org>
+
+use std::ffi::CString;
+use std::io::{Error, ErrorKind, Result};
+use std::mem::MaybeUninit;
+
+pub(crate) const CAP_NET_ADMIN: u32 = 12;
+pub(crate) const CAP_NET_RAW: u32 = 13;
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+// offsets into struct seccomp_data.
+const SECCOMP_DATA_NR: u32 = 0;
+const SECCOMP_DATA_ARCH: u32 = 4;
+
+//
+// The system calls made once the vpn runs: by the processor and its sockets,
+// by the threads serving control connections, metrics and the connection log,
+// by resolving the names sent by socks clients, and by panics and exiting.
+//
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+const SYSTEM_CALLS: &[libc::c_long] = &[
+    libc::SYS_read,
+    libc::SYS_write,
+    libc::SYS_readv,
+    libc::SYS_writev,
+    libc::SYS_pread64,
+    libc::SYS_pwrite64,
+    libc::SYS_openat,
+    libc::SYS_close,
+    libc::SYS_lseek,
+    libc::SYS_fstat,
+    libc::SYS_newfstatat,
+    libc::SYS_statx,
+    libc::SYS_fcntl,
+    libc::SYS_ioctl,
+    libc::SYS_dup,
+    libc::SYS_dup3,
+    libc::SYS_pipe2,
+    libc::SYS_ftruncate,
+    libc::SYS_fsync,
+    libc::SYS_fdatasync,
+    libc::SYS_renameat,
+    libc::SYS_renameat2,
+    libc::SYS_unlinkat,
+    libc::SYS_faccessat,
+    libc::SYS_faccessat2,
+    libc::SYS_readlinkat,
+    libc::SYS_getdents64,
+    libc::SYS_mmap,
+    libc::SYS_munmap,
+    libc::SYS_mprotect,
+    libc::SYS_mremap,
+    libc::SYS_madvise,
+    libc::SYS_brk,
+    libc::SYS_futex,
+    libc::SYS_set_robust_list,
+    libc::SYS_rseq,
+    libc::SYS_clone,
+    libc::SYS_clone3,
+    libc::SYS_exit,
+    libc::SYS_exit_group,
+    libc::SYS_getpid,
+    libc::SYS_gettid,
+    libc::SYS_tgkill,
+    libc::SYS_getuid,
+    libc::SYS_geteuid,
+    libc::SYS_getgid,
+    libc::SYS_getegid,
+    libc::SYS_uname,
+    libc::SYS_prctl,
+    libc::SYS_prlimit64,
+    libc::SYS_rt_sigaction,
+    libc::SYS_rt_sigprocmask,
+    libc::SYS_rt_sigreturn,
+    libc::SYS_rt_sigtimedwait,
+    libc::SYS_sigaltstack,
+    libc::SYS_restart_syscall,
+    libc::SYS_sched_yield,
+    libc::SYS_sched_getaffinity,
+    libc::SYS_getrandom,
+    libc::SYS_clock_gettime,
+    libc::SYS_clock_nanosleep,
+    libc::SYS_nanosleep,
+    libc::SYS_ppoll,
+    libc::SYS_epoll_create1,
+    libc::SYS_epoll_ctl,
+    libc::SYS_epoll_pwait,
+    libc::SYS_eventfd2,
+    libc::SYS_socket,
+    libc::SYS_socketpair,
+    libc::SYS_connect,
+    libc::SYS_bind,
+    libc::SYS_listen,
+    libc::SYS_accept4,
+    libc::SYS_getsockname,
+    libc::SYS_getpeername,
+    libc::SYS_setsockopt,
+    libc::SYS_getsockopt,
+    libc::SYS_shutdown,
+    libc::SYS_sendto,
+    libc::SYS_recvfrom,
+    libc::SYS_sendmsg,
+    libc::SYS_recvmsg,
+    libc::SYS_sendmmsg,
+    libc::SYS_recvmmsg,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_open,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_stat,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_lstat,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_access,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_rename,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_unlink,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_readlink,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_poll,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_epoll_wait,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_arch_prctl,
+];
+
+#[repr(C)]
+struct CapabilityHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct CapabilityData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+//
+// The user and group to run as once everything which needs root has been set
+// up. Files are accessed as them from then on: the configuration on reload,
+// the connection log, captures, and the pid file and control socket, which
+// are created as them so that they can be removed on exit.
+//
+pub(crate) struct Credentials {
+    uid: libc::uid_t,
+    gid: libc::gid_t,
+}
+
+impl Credentials {
+    //
+    // Users and groups are given by name or by id; the group defaults to the
+    // primary group of the user.
+    //
+    pub(crate) fn lookup(user: &str, group: Option<&str>) -> Result<Credentials> {
+        let (uid, primary_gid) = match (lookup_user(user)?, user.parse()) {
+            (Some(ids), _) => ids,
+            (None, Ok(uid)) => (uid, uid),
+            (None, Err(_)) => {
+                return Err(Error::new(
+                    ErrorKind::NotFound,
+                    format!("no user {:?}", user),
+                ))
+            }
+        };
+        let gid = match group {
+            Some(group) => match (lookup_group(group)?, group.parse()) {
+                (Some(gid), _) | (None, Ok(gid)) => gid,
+                (None, Err(_)) => {
+                    return Err(Error::new(
+                        ErrorKind::NotFound,
+                        format!("no group {:?}", group),
+                    ))
+                }
+            },
+            None => primary_gid,
+        };
+        Ok(Credentials { uid, gid })
+    }
+
+    //
+    // Switches to the user and group, keeping only the given capabilities,
+    // e.g. CAP_NET_RAW, which SO_BINDTODEVICE and IP_TRANSPARENT need on the
+    // sockets created later, or CAP_NET_ADMIN to remove addresses and routes
+    // on exit. All other capabilities are also dropped from the bounding set
+    // and no new privileges can be gained. Capabilities belong to threads and
+    // are inherited by the threads they spawn, so this is called before any
+    // thread is spawned.
+    //
+    pub(crate) fn switch(&self, capabilities: &[u32]) -> Result<()> {
+        prctl(libc::PR_SET_KEEPCAPS, 1)?;
+        for capability in 0.. {
+            if capabilities.contains(&capability) {
+                continue;
+            }
+            if let Err(error) = prctl(libc::PR_CAPBSET_DROP, capability) {
+                // past the last capability which the kernel knows.
+                if error.raw_os_error() == Some(libc::EINVAL) {
+                    break;
+                }
+                return Err(error);
+            }
+        }
+        check(unsafe { libc::setgroups(1, &self.gid) })?;
+        check(unsafe { libc::setresgid(self.gid, self.gid, self.gid) })?;
+        check(unsafe { libc::setresuid(self.uid, self.uid, self.uid) })?;
+
+        let header = CapabilityHeader {
+            version: LINUX_CAPABILITY_VERSION_3,
+            pid: 0,
+        };
+        let data = capability_sets(capabilities);
+        check(unsafe { libc::syscall(libc::SYS_capset, &header, data.as_ptr()) } as libc::c_int)?;
+        prctl(libc::PR_SET_KEEPCAPS, 0)?;
+        prctl(libc::PR_SET_NO_NEW_PRIVS, 1)
+    }
+}
+
+// capabilities are bits of two sets of 32 each.
+fn capability_sets(capabilities: &[u32]) -> [CapabilityData; 2] {
+    let mut data = [CapabilityData::default(); 2];
+    for capability in capabilities {
+        let set = &mut data[(capability / 32) as usize];
+        set.effective |= 1 << (capability % 32);
+        set.permitted |= 1 << (capability % 32);
+    }
+    data
+}
+
+//
+// Installs a seccomp filter on all threads, including those spawned later,
+// under which any system call missing from SYSTEM_CALLS fails with EPERM;
+// system calls of other architectures kill the process.
+//
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+pub(crate) fn restrict_system_calls() -> Result<()> {
+    install_filter(&mut system_call_filter())
+}
+
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+fn system_call_filter() -> Vec<libc::sock_filter> {
+    let statement = |code: u32, k: u32| libc::sock_filter {
+        code: code as u16,
+        jt: 0,
+        jf: 0,
+        k,
+    };
+    let jump_if_equal = |k: u32, jt: usize| libc::sock_filter {
+        code: (libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K) as u16,
+        jt: jt as u8,
+        jf: 0,
+        k,
+    };
+    let load = libc::BPF_LD | libc::BPF_W | libc::BPF_ABS;
+    let ret = libc::BPF_RET | libc::BPF_K;
+
+    let mut filter = vec![
+        statement(load, SECCOMP_DATA_ARCH),
+        jump_if_equal(AUDIT_ARCH, 1),
+        statement(ret, libc::SECCOMP_RET_KILL_PROCESS),
+        statement(load, SECCOMP_DATA_NR),
+    ];
+    // each match jumps over the remaining ones and the denial to the allowance.
+    for (index, system_call) in SYSTEM_CALLS.iter().enumerate() {
+        filter.push(jump_if_equal(
+            *system_call as u32,
+            SYSTEM_CALLS.len() - index,
+        ));
+    }
+    filter.push(statement(
+        ret,
+        libc::SECCOMP_RET_ERRNO | (libc::EPERM as u32 & libc::SECCOMP_RET_DATA),
+    ));
+    filter.push(statement(ret, libc::SECCOMP_RET_ALLOW));
+    filter
+}
+
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+fn install_filter(filter: &mut [libc::sock_filter]) -> Result<()> {
+    let program = libc::sock_fprog {
+        len: filter.len() as u16,
+        filter: filter.as_mut_ptr(),
+    };
+    prctl(libc::PR_SET_NO_NEW_PRIVS, 1)?;
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_seccomp,
+            libc::SECCOMP_SET_MODE_FILTER,
+            libc::SECCOMP_FILTER_FLAG_TSYNC,
+            &program,
+        )
+    };
+    match result {
+        0 => Ok(()),
+        -1 => Err(Error::last_os_error()),
+        // the id of a thread which could not be synchronized.
+        thread => Err(Error::other(format!(
+            "failed to synchronize thread {}",
+            thread
+        ))),
+    }
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+pub(crate) fn restrict_system_calls() -> Result<()> {
+    Err(Error::new(
+        ErrorKind::Unsupported,
+        "no system calls are known for this architecture",
+    ))
+}
+
+// the arguments of prctl are unsigned longs, which a variadic call does not convert to.
+fn prctl(option: libc::c_int, argument: u32) -> Result<()> {
+    let zero: libc::c_ulong = 0;
+    check(unsafe { libc::prctl(option, argument as libc::c_ulong, zero, zero, zero) })
+}
+
+fn check(result: libc::c_int) -> Result<()> {
+    if result == -1 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+// returns the uid and the primary gid of the user, if there is such a user.
+fn lookup_user(name: &str) -> Result<Option<(libc::uid_t, libc::gid_t)>> {
+    let name = CString::new(name).map_err(|error| Error::new(ErrorKind::InvalidInput, error))?;
+    let mut buffer = vec![0; 16384];
+    let mut passwd = MaybeUninit::<libc::passwd>::uninit();
+    let mut result = std::ptr::null_mut();
+    let error = unsafe {
+        libc::getpwnam_r(
+            name.as_ptr(),
+            passwd.as_mut_ptr(),
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut result,
+        )
+    };
+    if error != 0 {
+        return Err(Error::from_raw_os_error(error));
+    }
+    if result.is_null() {
+        return Ok(None);
+    }
+    let passwd = unsafe { passwd.assume_init() };
+    Ok(Some((passwd.pw_uid, passwd.pw_gid)))
+}
+
+fn lookup_group(name: &str) -> Result<Option<libc::gid_t>> {
+    let name = CString::new(name).map_err(|error| Error::new(ErrorKind::InvalidInput, error))?;
+    let mut buffer = vec![0; 16384];
+    let mut group = MaybeUninit::<libc::group>::uninit();
+    let mut result = std::ptr::null_mut();
+    let error = unsafe {
+        libc::getgrnam_r(
+            name.as_ptr(),
+            group.as_mut_ptr(),
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut result,
+        )
+    };
+    if error != 0 {
+        return Err(Error::from_raw_os_error(error));
+    }
+    if result.is_null() {
+        return Ok(None);
+    }
+    Ok(Some(unsafe { group.assume_init() }.gr_gid))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // runs the check in a child process, as it changes the process for good.
+    fn in_child(check: impl FnOnce() -> bool) -> bool {
+        match unsafe { libc::fork() } {
+            -1 => panic!("failed to fork, error={:?}", Error::last_os_error()),
+            0 => unsafe { libc::_exit(if check() { 0 } else { 1 }) },
+            pid => {
+                let mut status = 0;
+                unsafe { libc::waitpid(pid, &mut status, 0) };
+                libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0
+            }
+        }
+    }
+
+    fn current_capabilities() -> [CapabilityData; 2] {
+        let header = CapabilityHeader {
+            version: LINUX_CAPABILITY_VERSION_3,
+            pid: 0,
+        };
+        let mut data = [CapabilityData::default(); 2];
+        unsafe { libc::syscall(libc::SYS_capget, &header, data.as_mut_ptr()) };
+        data
+    }
+
+    #[test]
+    fn capabilities_are_set_in_their_sets() {
+        let data = capability_sets(&[CAP_NET_RAW, CAP_NET_ADMIN, 38]);
+
+        assert_eq!(data[0].effective, 1 << 13 | 1 << 12);
+        assert_eq!(data[0].permitted, 1 << 13 | 1 << 12);
+        assert_eq!(data[1].effective, 1 << 6);
+        assert_eq!(data[1].permitted, 1 << 6);
+        assert_eq!(data[0].inheritable | data[1].inheritable, 0);
+        assert_eq!(capability_sets(&[])[0].effective, 0);
+    }
+
+    #[test]
+    fn switch_keeps_only_the_given_capabilities() {
+        // switching users requires root.
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+        let credentials = Credentials {
+            uid: 65534,
+            gid: 65534,
+        };
+
+        assert!(in_child(|| {
+            if credentials.switch(&[CAP_NET_RAW]).is_err() {
+                return false;
+            }
+            let data = current_capabilities();
+            let ids = unsafe { (libc::getuid(), libc::geteuid(), libc::getgid()) };
+            let is_bounded = unsafe {
+                libc::prctl(
+                    libc::PR_CAPBSET_READ,
+                    CAP_NET_ADMIN as libc::c_ulong,
+                    0,
+                    0,
+                    0,
+                )
+            } == 0;
+            data[0].effective == 1 << CAP_NET_RAW
+                && data[0].permitted == 1 << CAP_NET_RAW
+                && data[1].effective == 0
+                && ids == (65534, 65534, 65534)
+                && is_bounded
+        }));
+    }
+
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    #[test]
+    fn filter_jumps_to_allowance_for_listed_system_calls() {
+        let filter = system_call_filter();
+        let allowance = filter.len() - 1;
+
+        assert_eq!(filter[allowance].k, libc::SECCOMP_RET_ALLOW);
+        assert_eq!(
+            filter[allowance - 1].k,
+            libc::SECCOMP_RET_ERRNO | libc::EPERM as u32
+        );
+        assert_eq!(filter[1].k, AUDIT_ARCH);
+        let jumps = &filter[4..allowance - 1];
+        assert_eq!(jumps.len(), SYSTEM_CALLS.len());
+        for (index, (jump, system_call)) in jumps.iter().zip(SYSTEM_CALLS).enumerate() {
+            assert_eq!(jump.k, *system_call as u32);
+            assert_eq!(4 + index + 1 + jump.jt as usize, allowance);
+        }
+    }
+
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    #[test]
+    fn filter_fails_unlisted_system_calls() {
+        // built before forking, as the child must not allocate.
+        let mut filter = system_call_filter();
+
+        assert!(in_child(move || {
+            if install_filter(&mut filter).is_err() {
+                return false;
+            }
+            let unlisted = unsafe { libc::syscall(libc::SYS_getppid) };
+            let error = Error::last_os_error().raw_os_error();
+            let listed = unsafe { libc::syscall(libc::SYS_getpid) };
+            unlisted == -1 && error == Some(libc::EPERM) && listed > 0
+        }));
+    }
+}
@@ -0,0 +1,509 @@
+// This is free and unencumbered software released into the public domain.
+//
+// Anyone is free to copy, modify, publish, use, compile, sell, or
+// distribute this software, either in source code form or as a compiled
+// binary, for any purpose, commercial or non-commercial, and by any
+// means.
+//
+// In jurisdictions that recognize copyright laws, the author or authors
+// of this software dedicate any and all copyright interest in the
+// software to the public domain. We make this dedication for the benefit
+// of the public at large and to the detriment of our heirs and
+// successors. We intend this dedication to be an overt act of
+// relinquishment in perpetuity of all present and future rights to this
+// software under copyright law.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS BE LIABLE FOR ANY CLAIM, DAMAGES OR
+// OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE,
+// ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR
+// OTHER DEALINGS IN THE SOFTWARE.
+//
+// For more information, please refer to <https://unlicense.org>
+
+use crate::netlink::{Message, Netlink};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use vpn_core::config::Protocol;
+use vpn_core::process::{ProcessInfo, ProcessLookup};
+
+// sock_diag constants not exported by libc for every target.
+const SOCK_DIAG_BY_FAMILY: u16 = 20;
+const INET_DIAG_SOCKID_LENGTH: usize = 48;
+const INET_DIAG_MSG_LENGTH: usize = 72;
+const ALL_STATES: u32 = u32::MAX;
+
+struct Socket {
+    local: SocketAddr,
+    // unspecified for listening and unconnected sockets.
+    remote: SocketAddr,
+    uid: u32,
+    // zero for sockets without an owner, e.g. in TIME_WAIT.
+    inode: u64,
+}
+
+//
+// Finds the socket of a session among those of the host with sock_diag, or
+// in /proc/net where sock_diag is not available, and its process among the
+// file descriptors in /proc. The descriptors of processes of other users are
+// only readable with CAP_SYS_PTRACE, so that only the uid is known of those
+// once privileges have been dropped.
+//
+pub(crate) struct HostProcessLookup {
+    sock_diag: Option<Mutex<Netlink>>,
+    // not set for the namespace of `run`, which is only reachable through
+    // its processes.
+    proc_net: Option<PathBuf>,
+    // pids by the inodes of the sockets they held when last scanned.
+    pids: Mutex<HashMap<u64, u32>>,
+}
+
+impl HostProcessLookup {
+    pub(crate) fn new() -> HostProcessLookup {
+        let sock_diag = Netlink::new_sock_diag().map_err(|error| {
+            log::warn!(
+                "failed to open sock_diag, using /proc/net, error={:?}",
+                error
+            );
+        });
+        Self::with_sock_diag(sock_diag.ok(), Some(PathBuf::from("/proc/net")))
+    }
+
+    pub(crate) fn with_sock_diag(
+        sock_diag: Option<Netlink>,
+        proc_net: Option<PathBuf>,
+    ) -> HostProcessLookup {
+        HostProcessLookup {
+            sock_diag: sock_diag.map(Mutex::new),
+            proc_net,
+            pids: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn sockets(&self, protocol: Protocol) -> Result<Vec<Socket>> {
+        if let Some(sock_diag) = &self.sock_diag {
+            match diag_sockets(&mut sock_diag.lock().unwrap(), protocol) {
+                Ok(sockets) => return Ok(sockets),
+                Err(error) => log::debug!("failed to dump sockets, error={:?}", error),
+            }
+        }
+        match &self.proc_net {
+            Some(proc_net) => proc_sockets(proc_net, protocol),
+            None => Err(Error::new(ErrorKind::Unsupported, "no sockets to look up")),
+        }
+    }
+
+    //
+    // The descriptors are scanned again when the inode is not known yet or
+    // its process no longer holds it, e.g. once it has exited; the scan
+    // replaces the inodes of sockets which have been closed since.
+    //
+    fn pid(&self, inode: u64) -> Option<u32> {
+        let mut pids = self.pids.lock().unwrap();
+        if let Some(pid) = pids.get(&inode).copied() {
+            let process = PathBuf::from(format!("/proc/{}", pid));
+            if process_sockets(&process).any(|held| held == inode) {
+                return Some(pid);
+            }
+        }
+        *pids = socket_pids();
+        pids.get(&inode).copied()
+    }
+}
+
+impl ProcessLookup for HostProcessLookup {
+    fn lookup(
+        &self,
+        protocol: Protocol,
+        local: SocketAddr,
+        remote: SocketAddr,
+    ) -> Option<ProcessInfo> {
+        let sockets = match self.sockets(protocol) {
+            Ok(sockets) => sockets,
+            Err(error) => {
+                log::error!("failed to list sockets, error={:?}", error);
+                return None;
+            }
+        };
+        let socket = find_socket(&sockets, protocol, local, remote)?;
+        let pid = self.pid(socket.inode);
+        Some(ProcessInfo {
+            pid,
+            uid: Some(socket.uid),
+            name: pid.and_then(process_name),
+        })
+    }
+}
+
+//
+// Prefers the socket connected to the remote address; udp datagrams may also
+// be sent from unconnected sockets bound to the local address or to any
+// address on its port, while tcp sockets bound to those listen for
+// connections rather than make them.
+//
+fn find_socket(
+    sockets: &[Socket],
+    protocol: Protocol,
+    local: SocketAddr,
+    remote: SocketAddr,
+) -> Option<&Socket> {
+    let (local, remote) = (canonical(local), canonical(remote));
+    let owned = || sockets.iter().filter(|socket| socket.inode != 0);
+    let connected = owned().find(|socket| socket.local == local && socket.remote == remote);
+    if protocol == Protocol::Tcp {
+        return connected;
+    }
+    let unconnected = || owned().filter(|socket| socket.remote.ip().is_unspecified());
+    connected
+        .or_else(|| unconnected().find(|socket| socket.local == local))
+        .or_else(|| {
+            unconnected().find(|socket| {
+                socket.local.port() == local.port() && socket.local.ip().is_unspecified()
+            })
+        })
+}
+
+// ipv4 addresses of dual stack sockets are listed as mapped ipv6 addresses.
+fn canonical(address: SocketAddr) -> SocketAddr {
+    match address {
+        SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+            Some(ip) => SocketAddr::new(IpAddr::V4(ip), v6.port()),
+            None => address,
+        },
+        SocketAddr::V4(_) => address,
+    }
+}
+
+fn ip_protocol(protocol: Protocol) -> u8 {
+    match protocol {
+        Protocol::Tcp => libc::IPPROTO_TCP as u8,
+        Protocol::Udp => libc::IPPROTO_UDP as u8,
+    }
+}
+
+//
+// Dumps the sockets of both families with inet_diag_req_v2 requests.
+//
+fn diag_sockets(sock_diag: &mut Netlink, protocol: Protocol) -> Result<Vec<Socket>> {
+    let mut sockets = Vec::new();
+    for family in [libc::AF_INET, libc::AF_INET6] {
+        let mut message = Message::new();
+        message.push_u8(family as u8);
+        message.push_u8(ip_protocol(protocol));
+        // no extensions and padding.
+        message.push_u8(0);
+        message.push_u8(0);
+        message.push_u32(ALL_STATES);
+        message.push_bytes(&[0; INET_DIAG_SOCKID_LENGTH]);
+        for payload in sock_diag.dump(SOCK_DIAG_BY_FAMILY, message)? {
+            sockets.extend(diag_socket(&payload));
+        }
+    }
+    Ok(sockets)
+}
+
+// struct inet_diag_msg, whose ports and addresses are in network byte order.
+fn diag_socket(payload: &[u8]) -> Option<Socket> {
+    if payload.len() < INET_DIAG_MSG_LENGTH {
+        return None;
+    }
+    let address = |bytes: &[u8], port: &[u8]| {
+        let port = u16::from_be_bytes(port.try_into().unwrap());
+        let ip = if i32::from(payload[0]) == libc::AF_INET {
+            IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(&bytes[..4]).unwrap()))
+        } else {
+            IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(bytes).unwrap()))
+        };
+        canonical(SocketAddr::new(ip, port))
+    };
+    Some(Socket {
+        local: address(&payload[8..24], &payload[4..6]),
+        remote: address(&payload[24..40], &payload[6..8]),
+        uid: u32::from_ne_bytes(payload[64..68].try_into().unwrap()),
+        inode: u64::from(u32::from_ne_bytes(payload[68..72].try_into().unwrap())),
+    })
+}
+
+//
+// Reads the sockets of both families from the tables in /proc/net, which
+// lack those of families which are not enabled.
+//
+fn proc_sockets(proc_net: &Path, protocol: Protocol) -> Result<Vec<Socket>> {
+    let name = match protocol {
+        Protocol::Tcp => "tcp",
+        Protocol::Udp => "udp",
+    };
+    let mut sockets = Vec::new();
+    for file in [name.to_string(), format!("{}6", name)] {
+        let Ok(table) = fs::read_to_string(proc_net.join(&file)) else {
+            continue;
+        };
+        sockets.extend(table.lines().skip(1).filter_map(proc_socket));
+    }
+    Ok(sockets)
+}
+
+// sl local_address rem_address st tx_queue:rx_queue tr:tm->when retrnsmt uid timeout inode
+fn proc_socket(line: &str) -> Option<Socket> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    if fields.len() < 10 {
+        return None;
+    }
+    Some(Socket {
+        local: proc_address(fields[1])?,
+        remote: proc_address(fields[2])?,
+        uid: fields[7].parse().ok()?,
+        inode: fields[9].parse().ok()?,
+    })
+}
+
+//
+// Addresses are written as words of 32 bits in host byte order, followed by
+// the port, all in hex.
+//
+fn proc_address(field: &str) -> Option<SocketAddr> {
+    let (address, port) = field.split_once(':')?;
+    let port = u16::from_str_radix(port, 16).ok()?;
+    let mut bytes = Vec::with_capacity(16);
+    for index in (0..address.len()).step_by(8) {
+        let word = u32::from_str_radix(address.get(index..index + 8)?, 16).ok()?;
+        bytes.extend_from_slice(&word.to_ne_bytes());
+    }
+    let ip = match bytes.len() {
+        4 => IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(bytes).ok()?)),
+        16 => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(bytes).ok()?)),
+        _ => return None,
+    };
+    Some(canonical(SocketAddr::new(ip, port)))
+}
+
+//
+// Maps the inodes of the sockets held by processes to their pids, as far as
+// their descriptors are readable.
+//
+fn socket_pids() -> HashMap<u64, u32> {
+    let mut pids = HashMap::new();
+    let Ok(processes) = fs::read_dir("/proc") else {
+        return pids;
+    };
+    for process in processes.flatten() {
+        let Some(pid) = process
+            .file_name()
+            .to_str()
+            .and_then(|name| name.parse().ok())
+        else {
+            continue;
+        };
+        for inode in process_sockets(&process.path()) {
+            pids.insert(inode, pid);
+        }
+    }
+    pids
+}
+
+// the inodes of the sockets among the readable descriptors of a process.
+fn process_sockets(process: &Path) -> impl Iterator<Item = u64> {
+    fs::read_dir(process.join("fd"))
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter_map(|descriptor| fs::read_link(descriptor.path()).ok())
+        .filter_map(|target| socket_inode(target.to_str()?))
+}
+
+fn socket_inode(target: &str) -> Option<u64> {
+    target
+        .strip_prefix("socket:[")?
+        .strip_suffix(']')?
+        .parse()
+        .ok()
+}
+
+// the name of the executable, or the command name where it is not readable.
+fn process_name(pid: u32) -> Option<String> {
+    let process = PathBuf::from(format!("/proc/{}", pid));
+    if let Ok(executable) = fs::read_link(process.join("exe")) {
+        if let Some(name) = executable.file_name().and_then(|name| name.to_str()) {
+            return Some(name.to_string());
+        }
+    }
+    fs::read_to_string(process.join("comm"))
+        .ok()
+        .map(|name| name.trim_end().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(address: &str) -> SocketAddr {
+        address.parse().unwrap()
+    }
+
+    fn socket(local: &str, remote: &str, inode: u64) -> Socket {
+        Socket {
+            local: address(local),
+            remote: address(remote),
+            uid: 1000,
+            inode,
+        }
+    }
+
+    // struct inet_diag_msg of an established socket.
+    fn inet_diag_msg(local: SocketAddr, remote: SocketAddr, uid: u32, inode: u32) -> Vec<u8> {
+        let family = match local {
+            SocketAddr::V4(_) => libc::AF_INET,
+            SocketAddr::V6(_) => libc::AF_INET6,
+        };
+        let ip = |address: SocketAddr| {
+            let mut bytes = match address.ip() {
+                IpAddr::V4(ip) => ip.octets().to_vec(),
+                IpAddr::V6(ip) => ip.octets().to_vec(),
+            };
+            bytes.resize(16, 0);
+            bytes
+        };
+        let mut bytes = vec![family as u8, 1, 0, 0];
+        bytes.extend_from_slice(&local.port().to_be_bytes());
+        bytes.extend_from_slice(&remote.port().to_be_bytes());
+        bytes.extend_from_slice(&ip(local));
+        bytes.extend_from_slice(&ip(remote));
+        // interface, cookie, expires and queues.
+        bytes.resize(64, 0);
+        bytes.extend_from_slice(&uid.to_ne_bytes());
+        bytes.extend_from_slice(&inode.to_ne_bytes());
+        bytes
+    }
+
+    #[test]
+    fn diag_socket_reads_inet_diag_msg() {
+        let local = address("10.0.0.2:40000");
+        let remote = address("93.184.216.34:443");
+        let socket = diag_socket(&inet_diag_msg(local, remote, 1000, 12345)).unwrap();
+        assert_eq!(socket.local, local);
+        assert_eq!(socket.remote, remote);
+        assert_eq!(socket.uid, 1000);
+        assert_eq!(socket.inode, 12345);
+
+        let local = address("[2001:db8::2]:40000");
+        let remote = address("[2001:db8::1]:443");
+        let socket = diag_socket(&inet_diag_msg(local, remote, 0, 1)).unwrap();
+        assert_eq!(socket.local, local);
+        assert_eq!(socket.remote, remote);
+    }
+
+    #[test]
+    fn diag_socket_maps_ipv4_of_dual_stack_sockets() {
+        let local = address("[::ffff:10.0.0.2]:40000");
+        let remote = address("[::ffff:93.184.216.34]:443");
+        let socket = diag_socket(&inet_diag_msg(local, remote, 1000, 1)).unwrap();
+        assert_eq!(socket.local, address("10.0.0.2:40000"));
+        assert_eq!(socket.remote, address("93.184.216.34:443"));
+    }
+
+    #[test]
+    fn diag_socket_skips_short_payloads() {
+        let payload = inet_diag_msg(address("10.0.0.2:1"), address("10.0.0.1:2"), 0, 1);
+        assert!(diag_socket(&payload[..INET_DIAG_MSG_LENGTH - 1]).is_none());
+    }
+
+    // the words of addresses in /proc/net are in the byte order of the host.
+    #[cfg(target_endian = "little")]
+    #[test]
+    fn proc_socket_reads_tables() {
+        let line = "   0: 0200000A:9C40 22D8B85D:01BB 01 00000000:00000000 00:00000000 \
+                    00000000  1000        0 12345 1 0000000000000000 20 4 30 10 -1";
+        let socket = proc_socket(line).unwrap();
+        assert_eq!(socket.local, address("10.0.0.2:40000"));
+        assert_eq!(socket.remote, address("93.184.216.34:443"));
+        assert_eq!(socket.uid, 1000);
+        assert_eq!(socket.inode, 12345);
+
+        let line = "   1: B80D0120000000000000000002000000:9C40 \
+                    B80D0120000000000000000001000000:01BB 01 00000000:00000000 \
+                    00:00000000 00000000     0        0 54321 1 0000000000000000 20 4 30 10 -1";
+        let socket = proc_socket(line).unwrap();
+        assert_eq!(socket.local, address("[2001:db8::2]:40000"));
+        assert_eq!(socket.remote, address("[2001:db8::1]:443"));
+        assert_eq!(socket.uid, 0);
+        assert_eq!(socket.inode, 54321);
+    }
+
+    #[cfg(target_endian = "little")]
+    #[test]
+    fn proc_address_maps_ipv4_of_dual_stack_sockets() {
+        assert_eq!(
+            proc_address("0000000000000000FFFF00000100007F:1F90"),
+            Some(address("127.0.0.1:8080"))
+        );
+        assert_eq!(
+            proc_address("00000000000000000000000000000000:0000"),
+            Some(address("[::]:0"))
+        );
+    }
+
+    #[test]
+    fn proc_socket_skips_malformed_lines() {
+        let header = "  sl  local_address rem_address   st tx_queue rx_queue tr tm->when \
+                      retrnsmt   uid  timeout inode";
+        assert!(proc_socket(header).is_none());
+        assert!(proc_address("0200000A").is_none());
+        assert!(proc_address("0200000:9C40").is_none());
+        assert!(proc_address("0200000A0000:9C40").is_none());
+    }
+
+    #[test]
+    fn find_socket_prefers_connected_sockets() {
+        let sockets = [
+            socket("0.0.0.0:40000", "0.0.0.0:0", 1),
+            socket("10.0.0.2:40000", "0.0.0.0:0", 2),
+            socket("10.0.0.2:40000", "10.0.0.1:53", 3),
+        ];
+        let local = address("10.0.0.2:40000");
+        let find = |remote| find_socket(&sockets, Protocol::Udp, local, address(remote));
+        assert_eq!(find("10.0.0.1:53").map(|socket| socket.inode), Some(3));
+        assert_eq!(find("10.0.0.3:53").map(|socket| socket.inode), Some(2));
+    }
+
+    #[test]
+    fn find_socket_falls_back_to_unconnected_udp_sockets() {
+        let sockets = [
+            socket("10.0.0.2:40000", "10.0.0.1:53", 1),
+            socket("0.0.0.0:40000", "0.0.0.0:0", 2),
+        ];
+        let local = address("10.0.0.2:40000");
+        let remote = address("10.0.0.3:53");
+        let found = find_socket(&sockets, Protocol::Udp, local, remote);
+        assert_eq!(found.map(|socket| socket.inode), Some(2));
+    }
+
+    #[test]
+    fn find_socket_does_not_match_listening_tcp_sockets() {
+        let sockets = [
+            socket("0.0.0.0:40000", "0.0.0.0:0", 1),
+            socket("10.0.0.2:40000", "0.0.0.0:0", 2),
+            socket("10.0.0.2:40000", "10.0.0.1:443", 0),
+        ];
+        let local = address("10.0.0.2:40000");
+        let remote = address("10.0.0.1:443");
+        assert!(find_socket(&sockets, Protocol::Tcp, local, remote).is_none());
+
+        let sockets = [socket("10.0.0.2:40000", "10.0.0.1:443", 3)];
+        let local = address("[::ffff:10.0.0.2]:40000");
+        let found = find_socket(&sockets, Protocol::Tcp, local, remote);
+        assert_eq!(found.map(|socket| socket.inode), Some(3));
+    }
+
+    #[test]
+    fn socket_inode_reads_descriptor_targets() {
+        assert_eq!(socket_inode("socket:[12345]"), Some(12345));
+        assert_eq!(socket_inode("pipe:[12345]"), None);
+        assert_eq!(socket_inode("/dev/null"), None);
+    }
+}
@@ -0,0 +1,98 @@
+// This is free and unencumbered software released into the public domain.
+//
+// Anyone is free to copy, modify, publish, use, compile, sell, or
+// distribute this software, either in source code form or as a compiled
+// binary, for any purpose, commercial or non-commercial, and by any
+// means.
+//
+// In jurisdictions that recognize copyright laws, the author or authors
+// of this software dedicate any and all copyright interest in the
+// software to the public domain. We make this dedication for the benefit
+// of the public at large and to the detriment of our heirs and
+// successors. We intend this dedication to be an overt act of
+// relinquishment in perpetuity of all present and future rights to this
+// software under copyright law.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS BE LIABLE FOR ANY CLAIM, DAMAGES OR
+// OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE,
+// ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR
+// OTHER DEALINGS IN THE SOFTWARE.
+//
+// For more information, please refer to <https://unlicense.org>
+
+use std::io::{Error, Result};
+use std::mem::MaybeUninit;
+
+pub(crate) enum Signal {
+    // SIGINT or SIGTERM.
+    Stop,
+    // SIGHUP.
+    Reload,
+    // SIGUSR1.
+    DumpSessions,
+}
+
+//
+// Signals are blocked and received synchronously with sigwait, so that they are
+// handled on an ordinary thread rather than in a signal handler. Threads inherit
+// the signal mask, therefore signals have to be blocked before any thread is
+// spawned.
+//
+pub(crate) struct Signals {
+    set: libc::sigset_t,
+}
+
+impl Signals {
+    pub(crate) fn block() -> Result<Signals> {
+        let set = Signals::set();
+        let result = unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut()) };
+        if result != 0 {
+            return Err(Error::from_raw_os_error(result));
+        }
+        Ok(Signals { set })
+    }
+
+    //
+    // The signal mask survives exec, so child processes unblock the signals
+    // again before running a program. Only calls which are async-signal-safe
+    // are made, as required between fork and exec.
+    //
+    pub(crate) fn unblock() -> Result<()> {
+        let set = Signals::set();
+        let result =
+            unsafe { libc::pthread_sigmask(libc::SIG_UNBLOCK, &set, std::ptr::null_mut()) };
+        if result != 0 {
+            return Err(Error::from_raw_os_error(result));
+        }
+        Ok(())
+    }
+
+    fn set() -> libc::sigset_t {
+        unsafe {
+            let mut set = MaybeUninit::<libc::sigset_t>::uninit();
+            libc::sigemptyset(set.as_mut_ptr());
+            for signal in [libc::SIGINT, libc::SIGTERM, libc::SIGHUP, libc::SIGUSR1] {
+                libc::sigaddset(set.as_mut_ptr(), signal);
+            }
+            set.assume_init()
+        }
+    }
+
+    pub(crate) fn wait(&self) -> Signal {
+        loop {
+            let mut signal = 0;
+            if unsafe { libc::sigwait(&self.set, &mut signal) } != 0 {
+                continue;
+            }
+            match signal {
+                libc::SIGINT | libc::SIGTERM => return Signal::Stop,
+                libc::SIGHUP => return Signal::Reload,
+                libc::SIGUSR1 => return Signal::DumpSessions,
+                _ => continue,
+            }
+        }
+    }
+}